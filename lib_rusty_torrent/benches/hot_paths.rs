@@ -0,0 +1,112 @@
+//! Benchmarks of piece verification and message encoding, the paths every downloaded block goes through
+//!
+//! Usage: cargo bench -p lib_rusty_torrent
+
+use criterion::{ black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput };
+use sha1::{ Digest, Sha1 };
+
+use lib_rusty_torrent::{
+    peer_wire_protocol::{ Message, MessageType },
+    torrent::Torrent
+};
+
+/// The size of a block requested from a peer
+const BLOCK_LENGTH: usize = 16_384;
+/// The number of blocks in a piece
+const BLOCKS_PER_PIECE: usize = 16;
+/// The size of a piece, 256 KiB
+const PIECE_LENGTH: usize = BLOCK_LENGTH * BLOCKS_PER_PIECE;
+
+/// Returns a block of the given piece filled with a pattern, so no two blocks are the same
+fn block(index: usize) -> Vec<u8> {
+    (0..BLOCK_LENGTH).map(|byte| (byte + index) as u8).collect()
+}
+
+/// Returns a piece message carrying the given block, as a peer would send it
+fn piece_message(index: usize) -> Message {
+    let mut payload: Vec<u8> = vec![];
+    payload.extend(0_u32.to_be_bytes());
+    payload.extend(((index * BLOCK_LENGTH) as u32).to_be_bytes());
+    payload.extend(block(index));
+
+    Message::new(payload.len() as u32 + 1, MessageType::Piece, Some(payload))
+}
+
+/// Loads the test torrent and replaces its pieces with the hash of a single piece
+fn single_piece_torrent(piece: &[u8]) -> Torrent {
+    let buf = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/test.torrent")).unwrap();
+    let mut torrent: Torrent = serde_bencode::from_bytes(&buf).unwrap();
+
+    torrent.info.pieces = Sha1::digest(piece).to_vec();
+    torrent.info.piece_length = piece.len() as u64;
+    torrent.info.length = Some(piece.len() as i64);
+    torrent.info.files = None;
+
+    torrent
+}
+
+fn check_piece(c: &mut Criterion) {
+    let blocks: Vec<Vec<u8>> = (0..BLOCKS_PER_PIECE).map(block).collect();
+    let piece = blocks.concat();
+    let torrent = single_piece_torrent(&piece);
+
+    let mut group = c.benchmark_group("check_piece");
+    group.throughput(Throughput::Bytes(PIECE_LENGTH as u64));
+
+    group.bench_function("hash 256 KiB", |b| {
+        b.iter(|| assert!(torrent.check_piece(black_box(&piece), 0)))
+    });
+
+    group.bench_function("assemble and hash 256 KiB from 16 blocks", |b| {
+        b.iter(|| {
+            let mut piece: Vec<u8> = Vec::with_capacity(PIECE_LENGTH);
+            for block in black_box(&blocks) {
+                piece.extend(block);
+            }
+
+            assert!(torrent.check_piece(&piece, 0))
+        })
+    });
+
+    group.finish();
+}
+
+fn message_codec(c: &mut Criterion) {
+    let message = piece_message(0);
+    let encoded: Vec<u8> = message.clone().try_into().unwrap();
+
+    let mut group = c.benchmark_group("message");
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+
+    group.bench_function("decode piece", |b| {
+        b.iter(|| Message::try_from(black_box(&encoded[..])).unwrap())
+    });
+
+    group.bench_function("encode piece", |b| {
+        b.iter_batched(|| message.clone(), |message| Vec::<u8>::try_from(message).unwrap(), BatchSize::SmallInput)
+    });
+
+    group.bench_function("encode request", |b| {
+        b.iter(|| Vec::<u8>::try_from(Message::create_piece_request(black_box(7), 0, BLOCK_LENGTH as u32)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn number_of_messages(c: &mut Criterion) {
+    // A read holding a piece message followed by a run of requests
+    let mut buf: Vec<u8> = piece_message(0).try_into().unwrap();
+    for offset in 0..BLOCKS_PER_PIECE as u32 {
+        let request: Vec<u8> = Message::create_piece_request(0, offset * BLOCK_LENGTH as u32, BLOCK_LENGTH as u32)
+            .try_into()
+            .unwrap();
+        buf.extend(request);
+    }
+
+    c.bench_function("number_of_messages", |b| {
+        b.iter(|| Message::number_of_messages(black_box(&buf)))
+    });
+}
+
+criterion_group!(benches, check_piece, message_codec, number_of_messages);
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+//! Dumps every frame exchanged with a single peer for a few seconds
+//!
+//! Usage: cargo run --example dump_frames --features wire-debug -- <torrent file> <peer address> [capture]
+//!
+//! Given a capture path, the frames are also written there, to be printed again with
+//! `rusty_torrenter replay <capture>`
+
+use std::{ net::SocketAddrV4, time::Duration };
+
+use lib_rusty_torrent::{
+    capture::CaptureFile,
+    peer::Peer,
+    peer_wire_protocol::{ Message, MessageType },
+    torrent::Torrent
+};
+
+/// How long to listen to the peer for
+const DUMP_DURATION: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let (Some(torrent_path), Some(peer_address)) = (args.next(), args.next()) else {
+        eprintln!("usage: dump_frames <torrent file> <peer address> [capture]");
+        return
+    };
+    let capture = match args.next() {
+        Some(path) => Some(CaptureFile::create(&path).await.unwrap()),
+        None => None
+    };
+
+    let torrent = Torrent::from_torrent_file(&torrent_path).await.unwrap();
+    let mut peer = Peer::create_connection(peer_address.parse::<SocketAddrV4>().unwrap()).await.unwrap();
+
+    // Subscribe before the handshake so the messages sent alongside it are captured
+    let mut frames = peer.subscribe_raw();
+    if let Some(capture) = &capture {
+        peer.set_capture(capture.sink());
+    }
+    let printer = tokio::spawn(async move {
+        while let Ok(frame) = frames.recv().await {
+            println!(
+                "{:?} {:?} type: {:?} payload: {} bytes",
+                frame.timestamp, frame.direction, frame.message_type, frame.payload.len()
+            );
+        }
+    });
+
+    peer.handshake(&torrent).await.unwrap();
+    peer.send_message_no_response(Message::new(1, MessageType::Interested, None)).await.unwrap();
+
+    let _ = tokio::time::timeout(DUMP_DURATION, async {
+        while peer.read_message().await.is_ok() { }
+    }).await;
+
+    peer.disconnect().await.unwrap();
+
+    // Dropping the peer closes the tap, which lets the printer finish
+    drop(peer);
+    printer.await.unwrap();
+
+    if let Some(capture) = capture {
+        capture.finish().await.unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+//! A compact set of piece indices, laid out as in the peer wire protocol's bitfield message
+
+use crate::error::Error;
+
+/// A set of pieces, where the high bit of the first byte represents piece 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bitfield {
+    /// The bits, with any spare bits at the end of the last byte kept clear
+    bytes: Vec<u8>,
+    /// The number of pieces in the set
+    len: usize,
+}
+
+impl Bitfield {
+    /// Creates a bitfield of the given number of pieces with no pieces set.
+    pub fn new(len: usize) -> Self {
+        Self { bytes: vec![0; len.div_ceil(8)], len }
+    }
+
+    /// Creates a bitfield of the given number of pieces with every piece set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len as u32 {
+            bitfield.set(index);
+        }
+
+        bitfield
+    }
+
+    /// Creates a bitfield of the given number of pieces from the payload of a bitfield message,
+    /// bytes past the end are ignored and missing ones taken as no pieces.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len as u32 {
+            if bytes.get(index as usize / 8).is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0) {
+                bitfield.set(index);
+            }
+        }
+
+        bitfield
+    }
+
+    /// Parses the payload of a bitfield message for a torrent with the given number of pieces.
+    ///
+    /// # Returns
+    ///
+    /// * The bitfield and whether any spare bits at the end were set, which are cleared, or an
+    ///   `Error::MalformedMessage` if the payload isn't the right length for the number of pieces.
+    pub fn parse(bytes: &[u8], len: usize) -> Result<(Self, bool), Error> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err(Error::MalformedMessage(format!("bitfield of {} bytes for {len} pieces, expected {}", bytes.len(), len.div_ceil(8))))
+        }
+
+        let bitfield = Self::from_bytes(bytes, len);
+        let spare_bits = bitfield.bytes != bytes;
+
+        Ok((bitfield, spare_bits))
+    }
+
+    /// Returns whether the given piece is in the set, pieces out of range are never in the set.
+    pub fn has(&self, index: u32) -> bool {
+        let index = index as usize;
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Adds the given piece to the set, ignoring pieces out of range.
+    pub fn set(&mut self, index: u32) {
+        let index = index as usize;
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Removes the given piece from the set.
+    pub fn clear(&mut self, index: u32) {
+        let index = index as usize;
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Returns the number of pieces the bitfield covers.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the bitfield covers no pieces.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of pieces in the set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    /// Returns whether every piece is in the set.
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Returns the indices of every piece in the set, in ascending order.
+    pub fn indices(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len as u32).filter(|index| self.has(*index))
+    }
+
+    /// Returns the bitfield as it is sent in a bitfield message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_checks_length_and_spare_bits() {
+        let (bitfield, spare_bits) = Bitfield::parse(&[0b1010_0000], 3).unwrap();
+        assert_eq!(bitfield.indices().collect::<Vec<u32>>(), vec![0, 2]);
+        assert!(!spare_bits);
+
+        let (bitfield, spare_bits) = Bitfield::parse(&[0b1010_0011], 3).unwrap();
+        assert_eq!(bitfield.as_bytes(), &[0b1010_0000]);
+        assert!(spare_bits);
+
+        assert!(Bitfield::parse(&[0xff, 0], 3).is_err());
+        assert!(Bitfield::parse(&[0xff], 9).is_err());
+    }
+
+    #[test]
+    fn set_and_clear() {
+        let mut bitfield = Bitfield::new(10);
+
+        bitfield.set(0);
+        bitfield.set(9);
+        bitfield.set(10); // out of range
+
+        assert!(bitfield.has(0));
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+        assert_eq!(bitfield.count(), 2);
+
+        bitfield.clear(0);
+        assert!(!bitfield.has(0));
+        assert_eq!(bitfield.indices().collect::<Vec<u32>>(), vec![9]);
+    }
+
+    #[test]
+    fn full_keeps_spare_bits_clear() {
+        let bitfield = Bitfield::full(10);
+
+        assert!(bitfield.is_complete());
+        assert_eq!(bitfield.as_bytes(), &[0xff, 0b1100_0000]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Peer addresses gathered during discovery, normalised before they are connected to
+
+use std::{
+    fmt,
+    net::{ IpAddr, SocketAddr, SocketAddrV4 }
+};
+
+/// A peer found during discovery, with the peer id the source listed it with, if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerCandidate {
+    /// The address of the peer.
+    pub address: SocketAddrV4,
+    /// The peer id the source listed, checked against the peer's handshake once connected.
+    pub peer_id: Option<String>,
+}
+
+impl PeerCandidate {
+    /// Creates a candidate without a peer id, as listed by sources that only give addresses.
+    pub fn new(address: SocketAddrV4) -> Self {
+        Self { address, peer_id: None }
+    }
+}
+
+impl From<SocketAddrV4> for PeerCandidate {
+    fn from(address: SocketAddrV4) -> Self {
+        Self::new(address)
+    }
+}
+
+impl fmt::Display for PeerCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.address)
+    }
+}
+
+/// Normalises a peer address from any source, returning `None` for one that can't be
+/// connected to.
+///
+/// IPv4-mapped IPv6 addresses are converted to plain IPv4, so the same peer listed both ways
+/// is only connected to once. Unspecified addresses and port 0 are dropped.
+///
+/// # Arguments
+///
+/// * `address` - The address as given by the source.
+pub fn normalize_candidate(address: SocketAddr) -> Option<SocketAddr> {
+    let ip = match address.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+
+    if ip.is_unspecified() || address.port() == 0 {
+        return None
+    }
+
+    Some(SocketAddr::new(ip, address.port()))
+}
+
+/// Normalises peer addresses with `normalize_candidate`, dropping duplicates and keeping the
+/// order they were first listed in.
+///
+/// # Arguments
+///
+/// * `addresses` - The addresses as given by the source.
+pub fn normalize_candidates<I: IntoIterator<Item = SocketAddr>>(addresses: I) -> Vec<SocketAddr> {
+    let mut candidates = vec![];
+
+    for address in addresses.into_iter().filter_map(normalize_candidate) {
+        if !candidates.contains(&address) {
+            candidates.push(address);
+        }
+    }
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weird_addresses() {
+        let cases = [
+            ("10.0.0.1:6881", Some("10.0.0.1:6881")),
+            ("[::ffff:10.0.0.1]:6881", Some("10.0.0.1:6881")),
+            ("[::ffff:0:0]:6881", None),
+            ("10.0.0.1:0", None),
+            ("[::ffff:10.0.0.1]:0", None),
+            ("0.0.0.0:6881", None),
+            ("[::]:6881", None),
+            ("[2001:db8::1]:6881", Some("[2001:db8::1]:6881")),
+            // IPv4-compatible addresses are deprecated and aren't treated as IPv4
+            ("[::10.0.0.1]:6881", Some("[::a00:1]:6881")),
+        ];
+
+        for (address, expected) in cases {
+            let normalized = normalize_candidate(address.parse().unwrap());
+            assert_eq!(normalized, expected.map(|expected| expected.parse().unwrap()), "{address}");
+        }
+    }
+
+    #[test]
+    fn duplicates_are_merged() {
+        let addresses = ["10.0.0.2:80", "[::ffff:10.0.0.1]:6881", "10.0.0.1:6881", "0.0.0.0:0", "10.0.0.2:80"];
+
+        let candidates = normalize_candidates(addresses.map(|address| address.parse().unwrap()));
+
+        assert_eq!(candidates, vec!["10.0.0.2:80".parse().unwrap(), "10.0.0.1:6881".parse().unwrap()]);
+    }
+}
@@ -0,0 +1,267 @@
+//! Captures the frames exchanged with a peer to a file, so a conversation can be replayed and
+//! decoded later when debugging interoperability with other clients
+//!
+//! A capture starts with `CAPTURE_MAGIC`, followed by a record for every frame:
+//!
+//! * 4 bytes - The length of the frame.
+//! * 8 bytes - When the frame was sent or received, in microseconds since the Unix epoch.
+//! * 1 byte - 0 if the frame was received from the peer, 1 if it was sent to the peer.
+//! * The frame, exactly as it was on the wire, handshakes included.
+//!
+//! Every integer is big endian, like the rest of the protocol.
+
+// Crate Imports
+use crate::{
+    error::Error,
+    peer_wire_protocol::{ Direction, Handshake, Message, MessageType }
+};
+
+// External imports
+use std::{
+    io,
+    time::{ Duration, SystemTime, UNIX_EPOCH }
+};
+use tokio::{
+    fs::File,
+    io::{ AsyncWriteExt, BufWriter },
+    sync::mpsc::{ self, UnboundedSender },
+    task::JoinHandle
+};
+
+/// The bytes a capture starts with, the last being the version of the format
+pub const CAPTURE_MAGIC: [u8; 8] = *b"RTCAP\0\0\x01";
+
+/// The length of a record before its frame
+const RECORD_HEADER_LENGTH: usize = 13;
+
+/// A single frame exchanged with a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaptureRecord {
+    /// When the frame was sent or received.
+    pub timestamp: SystemTime,
+    /// Whether the frame was sent or received.
+    pub direction: Direction,
+    /// The frame, exactly as it was on the wire.
+    pub frame: Vec<u8>,
+}
+
+impl CaptureRecord {
+    /// Serializes the record as it is stored in a capture
+    fn to_buffer(&self) -> Vec<u8> {
+        let micros = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+        let mut buf = Vec::with_capacity(RECORD_HEADER_LENGTH + self.frame.len());
+        buf.extend((self.frame.len() as u32).to_be_bytes());
+        buf.extend(micros.to_be_bytes());
+        buf.push(match self.direction {
+            Direction::Inbound => 0,
+            Direction::Outbound => 1,
+        });
+        buf.extend(&self.frame);
+
+        buf
+    }
+
+    /// Decodes the frame with the crate's own parsers, describing it on a single line.
+    pub fn describe(&self) -> String {
+        if self.frame.first() == Some(&19) && self.frame.get(1..20) == Some(b"BitTorrent protocol") {
+            return match Handshake::from_buffer(&self.frame) {
+                Ok(handshake) => format!("Handshake peer id {:?}", handshake.peer_id),
+                Err(err) => format!("invalid handshake, {err}"),
+            }
+        }
+
+        // A keep alive is only the length, decoding expects at least a type byte as well
+        let mut frame = self.frame.clone();
+        if frame.len() == 4 {
+            frame.push(0);
+        }
+
+        let message = match Message::try_from(frame.as_slice()) {
+            Ok(message) => message,
+            Err(err) => return format!("invalid message of {} bytes, {err}", self.frame.len()),
+        };
+
+        let payload = message.payload.unwrap_or_default();
+        let field = |at: usize| payload.get(at..at + 4).map(|field| u32::from_be_bytes([field[0], field[1], field[2], field[3]]));
+
+        match message.message_type {
+            MessageType::Have => format!("Have piece {}", field(0).unwrap_or_default()),
+            MessageType::Bitfield => format!("Bitfield of {} bytes", payload.len()),
+            MessageType::Request | MessageType::Cancel => format!(
+                "{:?} piece {} offset {} length {}",
+                message.message_type, field(0).unwrap_or_default(), field(4).unwrap_or_default(), field(8).unwrap_or_default()
+            ),
+            MessageType::Piece => format!(
+                "Piece piece {} offset {} block of {} bytes",
+                field(0).unwrap_or_default(), field(4).unwrap_or_default(), payload.len().saturating_sub(8)
+            ),
+            message_type => format!("{message_type:?}"),
+        }
+    }
+}
+
+/// Records frames into a capture without waiting for them to be written, can be cloned to
+/// record several peers into one capture.
+#[derive(Clone, Debug)]
+pub struct CaptureSink {
+    records: UnboundedSender<CaptureRecord>,
+}
+
+impl CaptureSink {
+    /// Records a frame, timestamped with the current time. Frames recorded after the capture
+    /// has failed are dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `direction` - Whether the frame was sent or received.
+    /// * `frame` - The frame, exactly as it was on the wire.
+    pub fn record(&self, direction: Direction, frame: &[u8]) {
+        let _ = self.records.send(CaptureRecord { timestamp: SystemTime::now(), direction, frame: frame.to_vec() });
+    }
+}
+
+/// A capture being written in the background, so writing it doesn't hold up the peers
+/// recorded into it.
+#[derive(Debug)]
+pub struct CaptureFile {
+    sink: CaptureSink,
+    writer: JoinHandle<io::Result<()>>,
+}
+
+impl CaptureFile {
+    /// Creates a capture, overwriting any file at the path.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Where the capture is written.
+    pub async fn create(path: &str) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        file.write_all(&CAPTURE_MAGIC).await?;
+
+        let (records, mut receiver) = mpsc::unbounded_channel::<CaptureRecord>();
+        let writer = tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                file.write_all(&record.to_buffer()).await?;
+            }
+
+            file.flush().await
+        });
+
+        Ok(Self { sink: CaptureSink { records }, writer })
+    }
+
+    /// Returns a sink recording into the capture, see `Peer::set_capture`.
+    pub fn sink(&self) -> CaptureSink {
+        self.sink.clone()
+    }
+
+    /// Waits until every sink has been dropped and their frames have been written.
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.sink);
+
+        self.writer.await.map_err(io::Error::other)?
+    }
+}
+
+/// Parses a capture into its records.
+///
+/// # Arguments
+///
+/// * `buf` - The whole capture, as written by a `CaptureFile`.
+pub fn parse_capture(buf: &[u8]) -> Result<Vec<CaptureRecord>, Error> {
+    let invalid = |reason: String| Error::IoError(io::Error::new(io::ErrorKind::InvalidData, reason));
+
+    let Some(mut rest) = buf.strip_prefix(&CAPTURE_MAGIC) else {
+        return Err(invalid(String::from("not a capture, or one of an unsupported version")))
+    };
+
+    let mut records = vec![];
+    while !rest.is_empty() {
+        if rest.len() < RECORD_HEADER_LENGTH {
+            return Err(invalid(format!("capture ends in the middle of record {}", records.len())))
+        }
+
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let micros = u64::from_be_bytes(rest[4..12].try_into().unwrap());
+        let direction = match rest[12] {
+            0 => Direction::Inbound,
+            1 => Direction::Outbound,
+            other => return Err(invalid(format!("record {} has unknown direction {other}", records.len()))),
+        };
+
+        let Some(frame) = rest.get(RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + length) else {
+            return Err(invalid(format!("capture ends in the middle of record {}", records.len())))
+        };
+
+        records.push(CaptureRecord { timestamp: UNIX_EPOCH + Duration::from_micros(micros), direction, frame: frame.to_vec() });
+        rest = &rest[RECORD_HEADER_LENGTH + length..];
+    }
+
+    Ok(records)
+}
+
+/// Reads and parses a capture written by a `CaptureFile`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the capture.
+pub async fn read_capture(path: &str) -> Result<Vec<CaptureRecord>, Error> {
+    parse_capture(&tokio::fs::read(path).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ files::tests::download_dir, peer::Peer, testing::mock_seed, torrent::Torrent };
+
+    #[tokio::test]
+    async fn loopback_session_is_captured() {
+        let data: Vec<u8> = (0..20_000).map(|byte| (byte % 251) as u8).collect();
+        let address = mock_seed(data.clone(), 20_000, Duration::ZERO).await;
+        let torrent = Torrent::from_pieces("capture", 20_000, &data);
+        let path = format!("{}/session.rtcap", download_dir("capture").await);
+
+        let capture = CaptureFile::create(&path).await.unwrap();
+        let mut peer = Peer::create_connection(address).await.unwrap();
+        peer.set_capture(capture.sink());
+
+        peer.handshake(&torrent).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+        assert_eq!(peer.request_piece(0, 20_000).await.unwrap(), data);
+
+        drop(peer);
+        capture.finish().await.unwrap();
+
+        let records = read_capture(&path).await.unwrap();
+        let described: Vec<(Direction, String)> = records.iter().map(|record| (record.direction, record.describe())).collect();
+        assert_eq!(described, vec![
+            (Direction::Outbound, String::from("Handshake peer id \"-MY0001-123456654321\"")),
+            (Direction::Inbound, String::from("Handshake peer id \"-MY0001-123456654321\"")),
+            (Direction::Inbound, String::from("Bitfield of 1 bytes")),
+            (Direction::Inbound, String::from("Unchoke")),
+            (Direction::Outbound, String::from("Interested")),
+            (Direction::Outbound, String::from("Request piece 0 offset 0 length 16384")),
+            (Direction::Inbound, String::from("Piece piece 0 offset 0 block of 16384 bytes")),
+            (Direction::Outbound, String::from("Request piece 0 offset 16384 length 3616")),
+            (Direction::Inbound, String::from("Piece piece 0 offset 16384 block of 3616 bytes")),
+        ]);
+
+        // Frames are kept exactly as they were on the wire
+        assert_eq!(records[3].frame, vec![0, 0, 0, 1, 1]);
+        assert!(records.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+    }
+
+    #[test]
+    fn truncated_capture_is_rejected() {
+        let record = CaptureRecord { timestamp: UNIX_EPOCH + Duration::from_micros(1), direction: Direction::Outbound, frame: vec![0, 0, 0, 0] };
+        let mut buf = CAPTURE_MAGIC.to_vec();
+        buf.extend(record.to_buffer());
+
+        assert_eq!(parse_capture(&buf).unwrap(), vec![record.clone()]);
+        assert_eq!(record.describe(), "KeepAlive");
+
+        assert!(parse_capture(&buf[..buf.len() - 1]).is_err());
+        assert!(parse_capture(&buf[1..]).is_err());
+    }
+}
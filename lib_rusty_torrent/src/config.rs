@@ -0,0 +1,561 @@
+//! Options controlling how a torrent is downloaded
+
+use std::{
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration
+};
+
+use crate::{
+    dump::PieceDumper,
+    error::Error,
+    peer::PeerDialer,
+    picker::{ AvailabilitySnapshot, MemoryGate, PiecePicker, RarestFirst, Sequential },
+    resolver::{ Resolver, SystemResolver },
+    rtt::TimeoutBounds,
+    tracker::{ TrackerEndpoint, TrackerUrl },
+    verifier::{ LocalVerifier, PieceVerifier, SkipVerification }
+};
+
+use serde::{ Deserialize, Serialize };
+
+/// When a downloaded piece is checked against its hash.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyPolicy {
+    /// Verify the piece in memory and only write it if it matches.
+    ///
+    /// Corrupt data never reaches the disk, but the whole piece has to be held in memory
+    /// until it has been verified.
+    #[default]
+    BeforeWrite,
+    /// Write the piece as soon as it arrives, then verify it by reading it back from disk.
+    ///
+    /// The piece doesn't need to be kept in memory once written, at the cost of reading it
+    /// back and of corrupt data sitting on disk until the piece is downloaded again.
+    AfterWrite,
+}
+
+/// The built in strategies for choosing which piece to download next.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PieceStrategy {
+    /// Download pieces in index order.
+    #[default]
+    Sequential,
+    /// Download the pieces held by the fewest peers first.
+    RarestFirst,
+}
+
+impl PieceStrategy {
+    /// Creates the picker implementing the strategy.
+    pub fn picker(&self) -> Box<dyn PiecePicker> {
+        match self {
+            PieceStrategy::Sequential => Box::new(Sequential),
+            PieceStrategy::RarestFirst => Box::new(RarestFirst::default()),
+        }
+    }
+}
+
+/// Which tracker protocol is tried first when a torrent lists both UDP and HTTP trackers.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackerPreference {
+    /// Try UDP trackers first, moving on to the HTTP trackers as soon as a UDP tracker fails or
+    /// doesn't answer within 15 seconds, as the network may block UDP.
+    #[default]
+    Auto,
+    /// Try every UDP tracker before the HTTP trackers.
+    PreferUdp,
+    /// Try HTTP trackers before UDP trackers, for networks that block UDP.
+    PreferHttp,
+}
+
+impl TrackerPreference {
+    /// Sorts trackers into the order they are tried, trackers of the same protocol keep the
+    /// order the torrent lists them in.
+    ///
+    /// # Arguments
+    ///
+    /// * `trackers` - The trackers of the torrent.
+    pub fn order(&self, trackers: &mut [TrackerEndpoint]) {
+        let udp_last = *self == TrackerPreference::PreferHttp;
+
+        trackers.sort_by_key(|tracker| matches!(tracker, TrackerEndpoint::Udp(_)) == udp_last);
+    }
+}
+
+/// Limits which trackers are announced to by hostname, so a download isn't announced to public
+/// trackers that would leak participation. Every tracker is allowed by default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct TrackerFilter {
+    /// Only the trackers with these hostnames are announced to, if set.
+    pub allow: Option<Vec<String>>,
+    /// The trackers with these hostnames are never announced to.
+    pub deny: Vec<String>,
+}
+
+impl TrackerFilter {
+    /// Returns whether a tracker can be announced to, hostnames are compared ignoring case.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The tracker's announce URL.
+    pub fn allows(&self, url: &TrackerUrl) -> bool {
+        let Some(host) = url.host() else {
+            return self.allow.is_none()
+        };
+        let listed = |hosts: &[String]| hosts.iter().any(|listed| listed.eq_ignore_ascii_case(&host));
+
+        self.allow.as_deref().is_none_or(listed) && !listed(&self.deny)
+    }
+}
+
+/// The sizes of the buffers used for a peer connection.
+///
+/// Larger buffers help on high throughput links, smaller ones keep memory use down when embedded.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    /// The size of the buffer the handshake, and any messages sent alongside it, are read into.
+    /// It is never less than the 68 bytes of a handshake, and defaults to 1024 bytes.
+    pub handshake_buffer: usize,
+    /// The size of the buffer a single read from the peer goes into, and of the response
+    /// `Peer::send_message` waits for. It defaults to 16 397 bytes which fits a piece message
+    /// carrying a 16 KiB block.
+    pub read_buffer: usize,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            handshake_buffer: 1024,
+            read_buffer: 16_397,
+        }
+    }
+}
+
+/// Options set on the sockets of peer connections and trackers, each left to the operating
+/// system when `None`.
+///
+/// An option the platform doesn't support is skipped and reported, it never fails the connection.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// The IP type of service byte, with the DSCP in its upper six bits. Marking traffic as
+    /// CS1 (`0x20`) or lower effort (`0x04`) lets routers put interactive traffic first.
+    pub tos: Option<u8>,
+    /// Whether small writes are sent straight away rather than coalesced, peer connections only.
+    pub nodelay: Option<bool>,
+    /// The size of the socket's receive buffer.
+    pub recv_buffer: Option<u32>,
+    /// The size of the socket's send buffer.
+    pub send_buffer: Option<u32>,
+    /// Probes idle peer connections so dead ones are noticed, peer connections only.
+    pub keepalive: Option<KeepaliveOptions>,
+}
+
+/// When an idle connection is probed, and how many probes it takes to give up on it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct KeepaliveOptions {
+    /// How long the connection is idle before the first probe.
+    #[serde(with = "duration")]
+    pub idle: Duration,
+    /// The time between probes, left to the operating system when `None`.
+    #[serde(default, with = "optional_duration")]
+    pub interval: Option<Duration>,
+    /// The probes left unanswered before the connection is dropped, left to the operating system when `None`.
+    pub retries: Option<u32>,
+}
+
+/// The configuration for a download.
+///
+/// It can be read from and written to configuration files with serde, leaving out the fields
+/// that only make sense in code: `memory_gate`, `resolver` and `availability_snapshot`.
+/// Durations are written the way `parse_duration` reads them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DownloadConfig {
+    /// Whether downloaded pieces are checked against their hashes, true by default.
+    ///
+    /// Turning this off saves hashing every piece when both ends trust the data and the transport,
+    /// but corrupt data is then written to disk and never detected.
+    pub verify_pieces: bool,
+    /// When downloaded pieces are verified.
+    pub verify_policy: VerifyPolicy,
+    /// How the next piece to download is chosen, a custom `PiecePicker` can be given to the `PieceLedger` instead.
+    pub piece_strategy: PieceStrategy,
+    /// Whether to disconnect from peers whose handshake doesn't carry the peer id a tracker advertised for them.
+    /// Some private trackers require this.
+    pub strict_peer_id: bool,
+    /// Whether to disconnect from peers that break protocol rules other clients tolerate, such
+    /// as setting the spare bits of a bitfield or sending it after other messages.
+    pub strict_protocol: bool,
+    /// Acknowledges that pieces may be verified by a `PieceVerifier` that doesn't hash them locally,
+    /// trusting it to reject corrupt data.
+    pub trust_external_verifier: bool,
+    /// The sizes of the buffers used for each peer connection.
+    pub buffers: BufferConfig,
+    /// Options set on the socket of every peer connection and tracker.
+    pub socket_options: SocketOptions,
+    /// The most memory, in megabytes, that pieces in progress can hold before no more are assigned.
+    /// Unlimited when `None`.
+    pub max_in_flight_mb: Option<u64>,
+    /// A gate shared with other downloads, capping the memory their pieces in progress hold
+    /// together. Takes the place of `max_in_flight_mb` when set.
+    #[serde(skip)]
+    pub memory_gate: Option<MemoryGate>,
+    /// An address or hostname to announce to trackers as the client's ip, useful behind a
+    /// dynamic DNS name. Trackers infer the ip when `None` or when the hostname can't be resolved.
+    pub announce_ip: Option<String>,
+    /// How long a peer has to respond to a request for a block, until its response time is known,
+    /// and a tracker to an announce, retransmits to a UDP tracker included.
+    #[serde(with = "duration")]
+    pub request_timeout: Duration,
+    /// The range each peer's request timeout is adapted within, from its measured response time.
+    /// The timeout stays at `request_timeout` when `None`.
+    pub adaptive_timeout: Option<TimeoutBounds>,
+    /// How long the download goes on without a piece completing before it ends with the pieces
+    /// still missing, 5 minutes by default. Unbounded when `None`.
+    #[serde(with = "optional_duration")]
+    pub idle_timeout: Option<Duration>,
+    /// Resolves tracker and peer hostnames and `announce_ip`, the operating system's resolver by default.
+    #[serde(skip)]
+    pub resolver: Arc<dyn Resolver>,
+    /// The piece lengths, in bytes, a torrent is downloaded with, 16 KiB to 32 MiB by default.
+    /// See `Torrent::check_piece_length`.
+    pub piece_length_range: RangeInclusive<u64>,
+    /// The directory the torrent is downloaded into, the current directory by default.
+    pub download_path: String,
+    /// Whether a multi file torrent's files are downloaded into a directory named after the
+    /// torrent within `download_path`, true by default.
+    pub torrent_dir: bool,
+    /// The address the socket used to announce to trackers is bound to.
+    pub listen_address: SocketAddr,
+    /// The fewest distinct peers the trackers together have to return for the download to go
+    /// ahead, at least 1.
+    pub min_peers: usize,
+    /// How long to wait for the trackers to return `min_peers` peers before giving up, across
+    /// every tracker announced to. Unbounded when `None`, each tracker is then given up on after
+    /// `request_timeout`.
+    #[serde(with = "optional_duration")]
+    pub peer_wait: Option<Duration>,
+    /// Which tracker protocol is tried first when a torrent lists both.
+    pub tracker_preference: TrackerPreference,
+    /// Which trackers can be announced to.
+    pub tracker_filter: TrackerFilter,
+    /// With `PieceStrategy::RarestFirst`, pieces held by fewer peers than this are only picked
+    /// when nothing else can be, 1 by default.
+    pub min_piece_availability: u32,
+    /// Whether a peer is connected to again, once, after its task panics. Off by default, the
+    /// peer is then dropped and its piece assigned to another peer.
+    pub redial_panicked_peers: bool,
+    /// Whether pieces are handed to the peers that cover the rarest wanted pieces first, and a
+    /// choking peer that alone has a wanted piece is waited on again rather than dropped. Off
+    /// by default, peers are then asked in the order they were connected to.
+    pub prefer_rare_peers: bool,
+    /// The availability of the previous session, from `Download::swarm_snapshot`, that pieces
+    /// are chosen by until `warm_start_peers` peers are connected.
+    #[serde(skip)]
+    pub availability_snapshot: Option<AvailabilitySnapshot>,
+    /// The number of connected peers whose availability supersedes `availability_snapshot`, 4 by default.
+    pub warm_start_peers: usize,
+    /// A directory pieces that fail verification are dumped into with how they were downloaded,
+    /// for tracing hash failures. Nothing is dumped when `None`, the default.
+    pub dump_failed_pieces: Option<PathBuf>,
+    /// The most megabytes the dumps in `dump_failed_pieces` can take up, the oldest are removed
+    /// past it. 256 by default.
+    pub max_dump_mb: u64,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            verify_pieces: true,
+            verify_policy: VerifyPolicy::default(),
+            piece_strategy: PieceStrategy::default(),
+            strict_peer_id: false,
+            strict_protocol: false,
+            trust_external_verifier: false,
+            buffers: BufferConfig::default(),
+            socket_options: SocketOptions::default(),
+            max_in_flight_mb: None,
+            memory_gate: None,
+            announce_ip: None,
+            request_timeout: Duration::from_secs(30),
+            adaptive_timeout: Some(TimeoutBounds::default()),
+            idle_timeout: Some(Duration::from_secs(300)),
+            resolver: Arc::new(SystemResolver),
+            piece_length_range: 16 * 1024..=32 * 1024 * 1024,
+            download_path: String::from("."),
+            torrent_dir: true,
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 61389)),
+            min_peers: 1,
+            peer_wait: None,
+            tracker_preference: TrackerPreference::default(),
+            tracker_filter: TrackerFilter::default(),
+            min_piece_availability: 1,
+            redial_panicked_peers: false,
+            prefer_rare_peers: false,
+            availability_snapshot: None,
+            warm_start_peers: 4,
+            dump_failed_pieces: None,
+            max_dump_mb: 256,
+        }
+    }
+}
+
+impl DownloadConfig {
+    /// Creates the verifier for downloaded pieces, one that accepts every piece when `verify_pieces` is off.
+    /// It is used unless the download is given its own with `Download::set_verifier`.
+    pub fn verifier(&self) -> Box<dyn PieceVerifier> {
+        if self.verify_pieces {
+            Box::new(LocalVerifier)
+        } else {
+            Box::new(SkipVerification)
+        }
+    }
+
+    /// Creates the picker for `piece_strategy`, applying `min_piece_availability`.
+    pub fn picker(&self) -> Box<dyn PiecePicker> {
+        match self.piece_strategy {
+            PieceStrategy::Sequential => self.piece_strategy.picker(),
+            PieceStrategy::RarestFirst => Box::new(RarestFirst { min_availability: self.min_piece_availability }),
+        }
+    }
+
+    /// Creates the dialer peers are connected to with, applying `buffers`, `socket_options`,
+    /// `request_timeout`, `adaptive_timeout`, `strict_peer_id` and `strict_protocol`.
+    pub fn dialer(&self) -> PeerDialer {
+        PeerDialer {
+            buffers: self.buffers,
+            socket_options: self.socket_options,
+            request_timeout: self.request_timeout,
+            adaptive_timeout: self.adaptive_timeout,
+            strict_peer_id: self.strict_peer_id,
+            strict_protocol: self.strict_protocol,
+        }
+    }
+
+    /// Returns when pieces are verified, pieces are always written directly when `verify_pieces` is
+    /// off as there is nothing to read them back for.
+    pub fn effective_verify_policy(&self) -> VerifyPolicy {
+        if self.verify_pieces {
+            self.verify_policy
+        } else {
+            VerifyPolicy::BeforeWrite
+        }
+    }
+
+    /// Returns the shared `memory_gate` if set, or creates the gate enforcing `max_in_flight_mb`
+    /// for a single download.
+    pub fn memory_gate(&self) -> Option<MemoryGate> {
+        self.memory_gate.clone().or_else(|| self.max_in_flight_mb.map(|mb| MemoryGate::new(mb * 1024 * 1024)))
+    }
+
+    /// Creates the dumper for `dump_failed_pieces`, if set.
+    pub fn piece_dumper(&self) -> Option<PieceDumper> {
+        self.dump_failed_pieces.clone().map(|directory| PieceDumper::new(directory, self.max_dump_mb * 1024 * 1024))
+    }
+
+    /// Checks that the values of the configuration can be used together, returning
+    /// `Error::InvalidConfig` if they can't. `Download` checks its configuration before running.
+    pub fn check(&self) -> Result<(), Error> {
+        if let Some(bounds) = &self.adaptive_timeout {
+            bounds.check()?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the configuration allows pieces to be verified by the given verifier,
+    /// returning `Error::InvalidConfig` if it doesn't.
+    pub fn check_verifier(&self, verifier: &dyn PieceVerifier) -> Result<(), Error> {
+        if verifier.is_local() || self.trust_external_verifier || !self.verify_pieces {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(String::from("Local piece verification is disabled without trust_external_verifier being set")))
+        }
+    }
+}
+
+/// Parses a duration in milliseconds, seconds, minutes or hours, e.g. `500ms`, `90s`, `15m` or
+/// `2h`. A number without a unit is in seconds.
+///
+/// # Arguments
+///
+/// * `duration` - The duration to parse.
+pub fn parse_duration(duration: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidConfig(format!("{duration} isn't a duration, expected e.g. 500ms, 90s, 15m or 2h"));
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => duration.split_at(split),
+        None => (duration, "s")
+    };
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return Err(invalid())
+    };
+
+    seconds.map(Duration::from_secs).ok_or_else(|| Error::InvalidConfig(format!("{duration} is too long a duration")))
+}
+
+/// Formats a duration the way `parse_duration` reads it, in seconds unless it has a fraction
+/// of a second.
+pub fn format_duration(duration: Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// Reads and writes a `Duration` as a string like `90s`, for `#[serde(with = "duration")]`
+pub mod duration {
+    use std::time::Duration;
+
+    use serde::{ de::Error, Deserialize, Deserializer, Serializer };
+
+    /// Writes the duration with `format_duration`
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::format_duration(*duration))
+    }
+
+    /// Reads the duration with `parse_duration`
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        super::parse_duration(&String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Reads and writes an `Option<Duration>` like `duration`, leaving it out when `None`
+pub mod optional_duration {
+    use std::time::Duration;
+
+    use serde::{ Deserializer, Serializer };
+
+    /// Writes the duration with `format_duration`, or nothing when `None`
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::duration::serialize(duration, serializer),
+            None => serializer.serialize_none()
+        }
+    }
+
+    /// Reads the duration with `parse_duration`
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        super::duration::deserialize(deserializer).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+    use async_trait::async_trait;
+
+    struct External;
+
+    #[async_trait]
+    impl PieceVerifier for External {
+        async fn verify(&self, _torrent: &Torrent, _index: u32, _piece: &[u8]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn external_verifier_needs_acknowledging() {
+        let mut config = DownloadConfig::default();
+
+        assert!(config.check_verifier(&LocalVerifier).is_ok());
+        assert!(matches!(config.check_verifier(&External), Err(Error::InvalidConfig(_))));
+
+        config.trust_external_verifier = true;
+        assert!(config.check_verifier(&External).is_ok());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(2 * 60 * 60));
+        assert!(matches!(parse_duration("2d"), Err(Error::InvalidConfig(_))));
+        assert!(parse_duration("m").is_err());
+        assert!(matches!(parse_duration("9999999999999999h"), Err(Error::InvalidConfig(reason)) if reason == "9999999999999999h is too long a duration"));
+        assert!(matches!(parse_duration(&format!("{}m", u64::MAX / 59)), Err(Error::InvalidConfig(_))));
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+    }
+
+    #[test]
+    fn inverted_timeout_bounds_are_rejected() {
+        let mut config = DownloadConfig::default();
+        assert!(config.check().is_ok());
+
+        config.adaptive_timeout = Some(TimeoutBounds { min: Duration::from_secs(10), max: Duration::from_secs(1) });
+        assert!(matches!(config.check(), Err(Error::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn tracker_order() {
+        let trackers = [
+            TrackerEndpoint::Http(String::from("http://one.example/announce")),
+            TrackerEndpoint::Udp("127.0.0.2:80".parse().unwrap()),
+            TrackerEndpoint::Http(String::from("https://three.example/announce")),
+            TrackerEndpoint::Udp("127.0.0.4:80".parse().unwrap()),
+        ];
+
+        let mut udp_first = trackers.clone();
+        TrackerPreference::PreferUdp.order(&mut udp_first);
+        assert_eq!(udp_first, [trackers[1].clone(), trackers[3].clone(), trackers[0].clone(), trackers[2].clone()]);
+
+        let mut auto = trackers.clone();
+        TrackerPreference::Auto.order(&mut auto);
+        assert_eq!(auto, udp_first);
+
+        let mut http_first = trackers.clone();
+        TrackerPreference::PreferHttp.order(&mut http_first);
+        assert_eq!(http_first, [trackers[0].clone(), trackers[2].clone(), trackers[1].clone(), trackers[3].clone()]);
+    }
+
+    #[test]
+    fn tracker_filter() {
+        let private = TrackerUrl::parse("https://private.example/announce");
+        let public = TrackerUrl::parse("udp://public.example:80/announce");
+
+        assert!(TrackerFilter::default().allows(&public));
+
+        let allow = TrackerFilter { allow: Some(vec![String::from("Private.Example")]), ..Default::default() };
+        assert!(allow.allows(&private));
+        assert!(!allow.allows(&public));
+
+        let deny = TrackerFilter { deny: vec![String::from("public.example")], ..Default::default() };
+        assert!(deny.allows(&private));
+        assert!(!deny.allows(&public));
+    }
+
+    #[tokio::test]
+    async fn verification_can_be_disabled() {
+        let torrent = Torrent::from_pieces("unverified", 16, &[1; 16]);
+        let mut config = DownloadConfig { verify_policy: VerifyPolicy::AfterWrite, ..Default::default() };
+
+        assert!(config.verify_pieces);
+        assert!(!config.verifier().verify(&torrent, 0, &[0; 16]).await);
+        assert_eq!(config.effective_verify_policy(), VerifyPolicy::AfterWrite);
+
+        config.verify_pieces = false;
+        assert!(config.verifier().verify(&torrent, 0, &[0; 16]).await);
+        assert!(config.check_verifier(config.verifier().as_ref()).is_ok());
+        assert_eq!(config.effective_verify_policy(), VerifyPolicy::BeforeWrite);
+    }
+}
@@ -0,0 +1,1239 @@
+//! Downloads pieces from several peers at once, deciding which peer downloads which piece
+
+// Crate Imports
+use crate::{
+    bitfield::Bitfield,
+    config::VerifyPolicy,
+    download::DownloadEvent,
+    dump::{ FailedPiece, PieceDumper },
+    error::{ DownloadError, IncompletePieces, PieceError, RetryHint },
+    files::Files,
+    peer::{ BlockTiming, Peer, PeerDialer },
+    picker::{ AvailabilitySnapshot, MemoryGate, PieceAssignment, PieceLedger },
+    torrent::Torrent,
+    verifier::PieceVerifier
+};
+
+// External imports
+use futures::stream::{ FuturesUnordered, StreamExt };
+use std::{
+    any::Any,
+    net::SocketAddrV4,
+    sync::{ Arc, Mutex },
+    time::Duration
+};
+use tokio::{
+    sync::mpsc::{ self, UnboundedReceiver, UnboundedSender },
+    task::{ JoinError, JoinHandle },
+    time::{ timeout, timeout_at, Instant }
+};
+
+/// What a peer has told the client about itself, as of the last report from its task.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerState {
+    /// Whether the peer is choking the client.
+    pub choking: bool,
+    /// Whether the peer is interested in the client's pieces.
+    pub interested: bool,
+}
+
+impl PeerState {
+    /// Returns the state of a peer.
+    pub fn of(peer: &Peer) -> Self {
+        Self { choking: peer.choking, interested: peer.peer_interested }
+    }
+}
+
+/// The number of connected peers of a download, by what they have and what they told the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerCounts {
+    /// The peers connected to.
+    pub connected: usize,
+    /// The connected peers that have every piece.
+    pub seeds: usize,
+    /// The connected peers that are missing pieces.
+    pub leeches: usize,
+    /// The connected peers choking the client.
+    pub choked_by: usize,
+    /// The connected peers interested in the client's pieces.
+    pub interested_in_us: usize,
+    /// The peer tasks that panicked, over every run of the download.
+    pub panicked: usize,
+}
+
+/// A message between the coordinator and the task of a peer.
+#[derive(Debug)]
+pub enum ControlMessage {
+    /// Asks a peer's task to download a piece.
+    DownloadPiece(PieceAssignment),
+    /// Asks a peer's task to tell the peer whether the client is interested in its pieces.
+    SetInterested(bool),
+    /// A peer announced pieces, or its state changed, while its task wasn't downloading a piece.
+    Announced {
+        /// The peer, as numbered by the coordinator in the order it was added.
+        peer: usize,
+        /// The pieces the peer announced.
+        haves: Vec<u32>,
+        /// The state of the peer.
+        state: PeerState,
+    },
+    /// A peer's task downloaded a piece, or failed to.
+    DownloadedPiece {
+        /// The peer, as numbered by the coordinator in the order it was added.
+        peer: usize,
+        /// The index of the piece.
+        index: u32,
+        /// The piece, or why it couldn't be downloaded.
+        result: Result<Vec<u8>, PieceError>,
+        /// The pieces the peer announced while downloading it.
+        haves: Vec<u32>,
+        /// The bytes of unrequested blocks the peer sent while downloading it.
+        wasted_bytes: u64,
+        /// The blocks requested for the piece.
+        timeline: Vec<BlockTiming>,
+        /// The state of the peer once the piece was downloaded or failed.
+        state: PeerState,
+    },
+}
+
+/// What the coordinator waits on the peers' tasks for
+enum Report {
+    /// A task reported a piece, `None` if every sender has been dropped
+    Piece(Option<ControlMessage>),
+    /// The task of a peer ended before the peer was retired, as its connection failed while it
+    /// was idle or it panicked
+    Ended(usize, Result<Box<Peer>, JoinError>),
+}
+
+/// A peer as seen by the coordinator
+struct PeerSlot {
+    /// The address of the peer
+    address: SocketAddrV4,
+    /// The peer id the peer sent in its handshake
+    peer_id: String,
+    /// Sends commands to the peer's task, dropped to retire the peer
+    commands: Option<UnboundedSender<ControlMessage>>,
+    /// The peer's task, which returns the peer once its commands are dropped
+    task: Option<JoinHandle<Peer>>,
+    /// The pieces the peer has
+    pieces: Bitfield,
+    /// The piece the peer is downloading, if any
+    assigned: Option<u32>,
+    /// Whether the peer was last told the client is interested in its pieces
+    interested: bool,
+    /// The state of the peer as last reported by its task
+    state: PeerState,
+    /// The bytes of pieces from the peer that failed verification
+    failed_hash_bytes: u64,
+    /// The bytes of pieces from the peer that had already been verified when they arrived
+    wasted_bytes: u64,
+    /// Whether the peer was connected to again after its task panicked
+    redialed: bool,
+}
+
+/// Spreads the pieces of a download across several peers, each downloading in its own task.
+///
+/// Pieces are verified and written by the coordinator alone, so writes are never interleaved,
+/// and a piece delivered again once it has been verified is discarded without being hashed.
+/// A peer whose piece fails is retired, and the piece is assigned to another peer that has it,
+/// unless the failure was being choked, in which case the peer is asked again once unchoked.
+/// Peers keep announcing pieces while idle, and are told the client is interested once they
+/// have one that is needed.
+///
+/// A peer whose task panics is dropped and its piece handed back, and it is connected to again
+/// once if a dialer is set. Should the peers left be unable to finish the download, it ends with
+/// `DownloadError::PeersPanicked`.
+///
+/// When rare peers are preferred, idle peers are handed pieces unchoked first, then by how much
+/// of the rarest wanted pieces they cover, and a choking peer that times out is kept as long as
+/// it is the only peer with a wanted piece.
+pub struct PieceCoordinator {
+    /// The state of every piece
+    ledger: PieceLedger,
+    /// The pieces that have been downloaded and verified, shared with observers
+    completed: Arc<Mutex<Bitfield>>,
+    /// Every peer added, retired ones included
+    peers: Vec<PeerSlot>,
+    /// Given to each peer's task to report its pieces
+    results_sender: UnboundedSender<ControlMessage>,
+    /// Receives the pieces reported by the peers' tasks
+    results: UnboundedReceiver<ControlMessage>,
+    /// Dumps pieces that fail verification, if set
+    dumper: Option<PieceDumper>,
+    /// How long the download goes on without a piece completing, if limited
+    idle_timeout: Option<Duration>,
+    /// The counts of the connected peers, shared with observers
+    peer_counts: Arc<Mutex<PeerCounts>>,
+    /// Whether peers covering the rarest pieces are preferred
+    prefer_rare_peers: bool,
+    /// The peer tasks that panicked, carried over from the shared counts
+    panics: usize,
+    /// Connects to peers again after their tasks panic, if set
+    redial: Option<PeerDialer>,
+}
+
+impl PieceCoordinator {
+    /// Creates a coordinator with no peers.
+    ///
+    /// # Arguments
+    ///
+    /// * `ledger` - The state of every piece, pieces already complete aren't assigned.
+    pub fn new(ledger: PieceLedger) -> Self {
+        let completed = Arc::new(Mutex::new(ledger.verified().clone()));
+        let (results_sender, results) = mpsc::unbounded_channel();
+
+        Self { ledger, completed, peers: vec![], results_sender, results, dumper: None, idle_timeout: None, peer_counts: Arc::default(), prefer_rare_peers: false, panics: 0, redial: None }
+    }
+
+    /// Dumps every piece that fails verification, with the peer and blocks it came from.
+    pub fn set_dumper(&mut self, dumper: PieceDumper) {
+        self.dumper = Some(dumper);
+    }
+
+    /// Ends the download once no piece has completed for `idle_timeout`, as the peers may never
+    /// supply the pieces they were assigned.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = Some(idle_timeout);
+    }
+
+    /// Connects to a peer again with `dialer` after its task panics, once for each peer.
+    pub fn set_redial(&mut self, dialer: PeerDialer) {
+        self.redial = Some(dialer);
+    }
+
+    /// Hands pieces to the peers covering the rarest wanted pieces first, and keeps waiting on a
+    /// choking peer that is the only one with a wanted piece, see `DownloadConfig::prefer_rare_peers`.
+    pub fn set_prefer_rare_peers(&mut self, prefer_rare_peers: bool) {
+        self.prefer_rare_peers = prefer_rare_peers;
+    }
+
+    /// Returns the pieces that have been downloaded and verified, updated as the download runs.
+    pub fn completed(&self) -> Arc<Mutex<Bitfield>> {
+        self.completed.clone()
+    }
+
+    /// Keeps `peer_counts` up to date with the connected peers as the download runs, in place
+    /// of the counts `peer_counts` returns.
+    pub fn share_peer_counts(&mut self, peer_counts: Arc<Mutex<PeerCounts>>) {
+        self.panics = peer_counts.lock().unwrap().panicked;
+        self.peer_counts = peer_counts;
+        self.publish_counts();
+    }
+
+    /// Returns the counts of the connected peers, updated as the download runs.
+    pub fn peer_counts(&self) -> Arc<Mutex<PeerCounts>> {
+        self.peer_counts.clone()
+    }
+
+    /// Adds a connected peer, spawning the task it downloads in.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - A peer that has completed the handshake and been told whether the client is interested.
+    /// * `pieces` - The pieces the peer has, already added to the ledger.
+    pub fn add_peer(&mut self, peer: Peer, pieces: Bitfield) {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (address, peer_id, interested, state) = (peer.socket_addr, peer.peer_id.clone(), peer.interested, PeerState::of(&peer));
+        let task = tokio::spawn(peer_task(self.peers.len(), peer, receiver, self.results_sender.clone()));
+
+        self.peers.push(PeerSlot { address, peer_id, commands: Some(commands), task: Some(task), pieces, assigned: None, interested, state, failed_hash_bytes: 0, wasted_bytes: 0, redialed: false });
+        self.publish_counts();
+    }
+
+    /// Downloads pieces until none can be assigned to any peer and no connected peer can announce
+    /// one that is wanted, or none has completed within the idle timeout, then disconnects every
+    /// peer. Without an idle timeout, idle peers are waited on until they disconnect.
+    ///
+    /// # Arguments
+    ///
+    /// * `files` - The torrent's files, pieces are written to them.
+    /// * `torrent` - The torrent being downloaded.
+    /// * `policy` - When pieces are verified.
+    /// * `verifier` - Verifies pieces.
+    /// * `emit` - Called with the events of the download.
+    ///
+    /// # Returns
+    ///
+    /// * The ledger, with every piece that was written marked as complete, and the availability
+    ///   among the peers before they were disconnected, or the error that ended the download.
+    ///   The pieces still missing when the idle timeout passes are returned as `DownloadError::Incomplete`,
+    ///   those still wanted once peers were lost to panics as `DownloadError::PeersPanicked`.
+    pub async fn run(mut self, files: &mut Files, torrent: &Torrent, policy: VerifyPolicy, verifier: &dyn PieceVerifier, emit: &(dyn Fn(DownloadEvent) + Sync)) -> Result<(PieceLedger, AvailabilitySnapshot), DownloadError> {
+        let mut last_completed = Instant::now();
+        let panics_before = self.panics;
+        loop {
+            self.publish_counts();
+            let releases = self.ledger.memory_gate().map(MemoryGate::releases);
+            let held_back = self.dispatch();
+
+            if self.peers.iter().all(|slot| slot.assigned.is_none()) {
+                // Pieces are only held back with nothing in progress when the gate is shared,
+                // so they are assigned once another download releases room
+                match (held_back, self.ledger.memory_gate(), releases) {
+                    (true, Some(gate), Some(releases)) => {
+                        gate.clone().released_since(releases).await;
+                        last_completed = Instant::now();
+                        continue
+                    }
+                    // A connected peer may yet announce a piece that is wanted
+                    _ if self.ledger.has_wanted_pieces() && self.peers.iter().any(|slot| slot.commands.is_some()) => (),
+                    _ => break
+                }
+            }
+
+            let next = next_report(&mut self.results, &mut self.peers);
+            let report = match self.idle_timeout {
+                None => next.await,
+                Some(idle_timeout) => match timeout_at(last_completed + idle_timeout, next).await {
+                    Ok(report) => report,
+                    Err(_) => {
+                        self.abort_all();
+                        self.publish_counts();
+                        return Err(DownloadError::Incomplete(IncompletePieces { missing: self.ledger.missing_pieces() }))
+                    }
+                }
+            };
+            let (peer, index, result, haves, wasted_bytes, timeline) = match report {
+                Report::Piece(Some(ControlMessage::DownloadedPiece { peer, index, result, haves, wasted_bytes, timeline, state })) => {
+                    self.peers[peer].state = state;
+                    (peer, index, result, haves, wasted_bytes, timeline)
+                }
+                Report::Piece(Some(ControlMessage::Announced { peer, haves, state })) => {
+                    self.peers[peer].state = state;
+                    self.peer_haves(peer, haves);
+                    continue
+                }
+                Report::Piece(_) => continue,
+                Report::Ended(peer, joined) => {
+                    self.task_ended(peer, joined, torrent, emit).await;
+                    continue
+                }
+            };
+            self.peers[peer].assigned = None;
+
+            // Pieces the peer gained can be assigned to it from now on
+            self.peer_haves(peer, haves);
+            let slot = &self.peers[peer];
+            if wasted_bytes > 0 {
+                emit(DownloadEvent::BlocksDiscarded { address: slot.address, bytes: wasted_bytes });
+            }
+
+            // Another copy was verified while this one was downloaded, it is neither hashed nor
+            // written again, and a failure to download it no longer matters
+            if self.ledger.verified().has(index) {
+                if let Ok(piece) = &result {
+                    let slot = &mut self.peers[peer];
+                    slot.wasted_bytes += piece.len() as u64;
+                    emit(DownloadEvent::BlocksDiscarded { address: slot.address, bytes: piece.len() as u64 });
+                }
+                continue
+            }
+
+            let piece = match result {
+                Ok(piece) => piece,
+                Err(err) => {
+                    emit(DownloadEvent::PieceFailed { index, reason: err.to_string(), dump: None });
+                    self.ledger.piece_failed(index);
+                    if err.retry_hint() != RetryHint::SamePeer && !self.sole_choking_source(peer) {
+                        self.retire(peer, emit).await;
+                    }
+                    continue
+                }
+            };
+
+            let length = piece.len() as u64;
+            // The piece is handed over to be written, so a copy is only kept when it may be dumped
+            let copy = self.dumper.as_ref().map(|_| piece.clone());
+            let verified = match files.write_verified_piece(torrent, index, piece, policy, verifier).await {
+                Ok(verified) => verified,
+                Err(source) => {
+                    self.ledger.piece_failed(index);
+                    self.retire_all(emit).await;
+                    self.publish_counts();
+                    return Err(DownloadError::Storage { index, source })
+                }
+            };
+
+            if verified {
+                self.ledger.piece_complete(index);
+                self.completed.lock().unwrap().set(index);
+                last_completed = Instant::now();
+                emit(DownloadEvent::PieceCompleted(index));
+            } else {
+                self.peers[peer].failed_hash_bytes += length;
+                self.ledger.piece_failed(index);
+                let dump = match (&self.dumper, copy, torrent.piece_hash(index)) {
+                    (Some(dumper), Some(piece), Some(expected)) => {
+                        let slot = &self.peers[peer];
+                        let failure = FailedPiece { index, piece: &piece, expected, address: slot.address, peer_id: &slot.peer_id, timeline: &timeline };
+                        dumper.dump(&failure).await.ok()
+                    }
+                    _ => None
+                };
+                emit(DownloadEvent::PieceFailed { index, reason: String::from("piece failed verification"), dump });
+                self.retire(peer, emit).await;
+            }
+        }
+
+        let snapshot = self.ledger.snapshot();
+        self.retire_all(emit).await;
+        self.publish_counts();
+
+        // The peers lost to panics may have had the pieces nobody else could supply
+        if self.panics > panics_before && self.ledger.has_wanted_pieces() {
+            let missing = IncompletePieces { missing: self.ledger.missing_pieces() };
+            return Err(DownloadError::PeersPanicked { panics: self.panics - panics_before, missing })
+        }
+
+        Ok((self.ledger, snapshot))
+    }
+
+    /// Assigns a piece to every idle peer that has one that is needed, returning whether any
+    /// peer was left idle by the memory gate
+    fn dispatch(&mut self) -> bool {
+        let mut order: Vec<usize> = (0..self.peers.len()).collect();
+        if self.prefer_rare_peers {
+            // A choking peer holds its piece until it unchokes, so unchoked peers come first
+            let coverage: Vec<f64> = self.peers.iter().map(|slot| self.ledger.rare_coverage(&slot.pieces)).collect();
+            order.sort_by(|a, b| {
+                self.peers[*a].state.choking.cmp(&self.peers[*b].state.choking)
+                    .then(coverage[*b].total_cmp(&coverage[*a]))
+            });
+        }
+
+        let mut held_back = false;
+        for index in order {
+            let slot = &mut self.peers[index];
+            let Some(commands) = &slot.commands else { continue };
+            if slot.assigned.is_some() {
+                continue
+            }
+
+            let Some(assignment) = self.ledger.assign(&slot.pieces) else {
+                held_back |= self.ledger.held_back();
+                continue
+            };
+            if commands.send(ControlMessage::DownloadPiece(assignment)).is_err() {
+                self.ledger.piece_failed(assignment.index);
+                continue
+            }
+
+            slot.assigned = Some(assignment.index);
+        }
+
+        held_back
+    }
+
+    /// Returns whether a peer is kept despite failing a piece, as it chokes the client but is the
+    /// only peer with a wanted piece, and rare peers are preferred
+    fn sole_choking_source(&self, index: usize) -> bool {
+        let slot = &self.peers[index];
+        self.prefer_rare_peers && slot.state.choking && self.ledger.sole_source(&slot.pieces)
+    }
+
+    /// Counts the connected peers, those whose tasks haven't been stopped
+    fn count_peers(&self) -> PeerCounts {
+        let mut counts = PeerCounts::default();
+        for slot in self.peers.iter().filter(|slot| slot.commands.is_some()) {
+            counts.connected += 1;
+            match slot.pieces.is_complete() {
+                true => counts.seeds += 1,
+                false => counts.leeches += 1,
+            }
+            counts.choked_by += slot.state.choking as usize;
+            counts.interested_in_us += slot.state.interested as usize;
+        }
+        counts.panicked = self.panics;
+
+        counts
+    }
+
+    /// Updates the shared counts of the connected peers
+    fn publish_counts(&self) {
+        *self.peer_counts.lock().unwrap() = self.count_peers();
+    }
+
+    /// Adds the pieces a peer announced, telling the peer whether the client is interested if
+    /// that has changed
+    fn peer_haves(&mut self, index: usize, haves: Vec<u32>) {
+        let slot = &mut self.peers[index];
+        for have in haves {
+            self.ledger.peer_have(&mut slot.pieces, have);
+        }
+
+        let interested = self.ledger.needed_from(&slot.pieces);
+        let Some(commands) = &slot.commands else { return };
+        if interested != slot.interested && commands.send(ControlMessage::SetInterested(interested)).is_ok() {
+            slot.interested = interested;
+        }
+    }
+
+    /// Stops a peer's task, waiting for it to finish its piece, and disconnects the peer. A task
+    /// that panics as it stops is reported and counted, but the peer isn't connected to again.
+    async fn retire(&mut self, index: usize, emit: &(dyn Fn(DownloadEvent) + Sync)) {
+        let slot = &mut self.peers[index];
+        slot.commands = None;
+        let Some(task) = slot.task.take() else { return };
+
+        self.ledger.remove_peer(&slot.pieces);
+        let (address, failed_hash_bytes, wasted_bytes) = (slot.address, slot.failed_hash_bytes, slot.wasted_bytes);
+
+        let mut peer = match task.await {
+            Ok(peer) => peer,
+            // The peer, and its connection, were dropped as the task unwound
+            Err(err) => {
+                self.task_failed(index, err, emit);
+                emit(DownloadEvent::PeerDisconnected { address, wasted_bytes, failed_hash_bytes });
+                return
+            }
+        };
+        peer.failed_hash_bytes += failed_hash_bytes;
+        peer.wasted_bytes += wasted_bytes;
+        let _ = peer.disconnect().await;
+
+        emit(DownloadEvent::PeerDisconnected {
+            address: peer.socket_addr,
+            wasted_bytes: peer.wasted_bytes,
+            failed_hash_bytes: peer.failed_hash_bytes,
+        });
+    }
+
+    /// Retires a peer whose task ended on its own, handing any piece it had assigned back to be
+    /// assigned to another peer. A peer whose task panicked is connected to again if a dialer is set.
+    async fn task_ended(&mut self, index: usize, joined: Result<Box<Peer>, JoinError>, torrent: &Torrent, emit: &(dyn Fn(DownloadEvent) + Sync)) {
+        let slot = &mut self.peers[index];
+        slot.commands = None;
+        slot.task = None;
+        self.ledger.remove_peer(&slot.pieces);
+
+        let (address, failed_hash_bytes, discarded_bytes) = (slot.address, slot.failed_hash_bytes, slot.wasted_bytes);
+        let (wasted_bytes, (reason, panicked)) = match joined {
+            Ok(mut peer) => {
+                let _ = peer.disconnect().await;
+                (peer.wasted_bytes + discarded_bytes, (String::from("peer task ended"), false))
+            }
+            // The peer, and its connection, were dropped as the task unwound
+            Err(err) => (discarded_bytes, self.task_failed(index, err, emit)),
+        };
+
+        if let Some(piece) = self.peers[index].assigned.take() {
+            self.ledger.piece_failed(piece);
+            emit(DownloadEvent::PieceFailed { index: piece, reason, dump: None });
+        }
+
+        emit(DownloadEvent::PeerDisconnected { address, wasted_bytes, failed_hash_bytes });
+
+        if panicked {
+            self.redial(index, torrent, emit).await;
+        }
+    }
+
+    /// Reports the task of a peer that failed, counting it if it panicked
+    ///
+    /// # Returns
+    ///
+    /// * Why the task failed, and whether it panicked.
+    fn task_failed(&mut self, index: usize, err: JoinError, emit: &(dyn Fn(DownloadEvent) + Sync)) -> (String, bool) {
+        if !err.is_panic() {
+            return (format!("peer task failed, {err}"), false)
+        }
+
+        let slot = &self.peers[index];
+        let message = panic_message(err.into_panic());
+        emit(DownloadEvent::PeerPanicked { address: slot.address, peer_id: slot.peer_id.clone(), message: message.clone() });
+        self.panics += 1;
+
+        (format!("peer task panicked, {message}"), true)
+    }
+
+    /// Connects to a peer whose task panicked again, as a new peer with the pieces it had, unless
+    /// there is no dialer or the peer has been connected to again already
+    async fn redial(&mut self, index: usize, torrent: &Torrent, emit: &(dyn Fn(DownloadEvent) + Sync)) {
+        let Some(dialer) = &self.redial else { return };
+        let slot = &self.peers[index];
+        if slot.redialed {
+            return
+        }
+
+        let Ok(Ok(mut peer)) = timeout(dialer.request_timeout, dialer.dial(slot.address, torrent)).await else { return };
+        // A peer doesn't lose pieces, those it gained since are announced as its task reads them
+        let pieces = slot.pieces.clone();
+        self.ledger.add_peer(&pieces);
+        if peer.set_interested(self.ledger.needed_from(&pieces)).await.is_err() {
+            self.ledger.remove_peer(&pieces);
+            return
+        }
+
+        emit(DownloadEvent::PeerConnected { address: peer.socket_addr, peer_id: peer.peer_id.clone() });
+        self.add_peer(peer, pieces);
+        if let Some(slot) = self.peers.last_mut() {
+            slot.redialed = true;
+        }
+    }
+
+    /// Retires every peer that hasn't been already
+    async fn retire_all(&mut self, emit: &(dyn Fn(DownloadEvent) + Sync)) {
+        for index in 0..self.peers.len() {
+            self.retire(index, emit).await;
+        }
+    }
+
+    /// Stops every peer's task without waiting for its piece, which a stalled peer may never
+    /// finish, dropping its connection
+    fn abort_all(&mut self) {
+        for slot in &mut self.peers {
+            slot.commands = None;
+            if let Some(task) = slot.task.take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+/// Returns what a task panicked with, as given to `panic!`
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => String::from("a panic without a message"),
+        },
+    }
+}
+
+/// Waits for a peer's task to report, or for the task of a peer that hasn't been retired to end,
+/// as a task that panics never reports its piece
+async fn next_report(results: &mut UnboundedReceiver<ControlMessage>, peers: &mut [PeerSlot]) -> Report {
+    let mut ended: FuturesUnordered<_> = peers.iter_mut()
+        .enumerate()
+        .filter_map(|(index, slot)| slot.task.as_mut().map(|task| async move { (index, task.await.map(Box::new)) }))
+        .collect();
+
+    // What a task reported before ending is taken first
+    tokio::select! {
+        biased;
+        received = results.recv() => Report::Piece(received),
+        Some((index, joined)) = ended.next() => Report::Ended(index, joined),
+    }
+}
+
+/// Downloads the pieces the coordinator asks for from a peer until the coordinator drops its
+/// sender, then returns the peer. Between pieces the peer's messages are read as they arrive,
+/// reporting the pieces it announces, until its connection fails.
+async fn peer_task(index: usize, mut peer: Peer, mut commands: UnboundedReceiver<ControlMessage>, results: UnboundedSender<ControlMessage>) -> Peer {
+    // The state the coordinator last heard of
+    let mut reported = PeerState::of(&peer);
+    loop {
+        let command = tokio::select! {
+            biased;
+            command = commands.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            readable = peer.readable() => {
+                if readable.is_err() || peer.read_idle_message().await.is_err() {
+                    break
+                }
+
+                let (haves, state) = (peer.take_haves(), PeerState::of(&peer));
+                if haves.is_empty() && state == reported {
+                    continue
+                }
+                if results.send(ControlMessage::Announced { peer: index, haves, state }).is_err() {
+                    break
+                }
+                reported = state;
+                continue
+            }
+        };
+
+        let assignment = match command {
+            ControlMessage::DownloadPiece(assignment) => assignment,
+            ControlMessage::SetInterested(interested) => {
+                if peer.set_interested(interested).await.is_err() {
+                    break
+                }
+                continue
+            }
+            _ => continue,
+        };
+        let wasted_before = peer.wasted_bytes;
+
+        let result = match peer.choking {
+            true => match peer.keep_alive_until_unchoke().await {
+                Ok(()) => peer.request_piece(assignment.index, assignment.length).await,
+                Err(err) => Err(err),
+            }
+            false => peer.request_piece(assignment.index, assignment.length).await,
+        };
+
+        let report = ControlMessage::DownloadedPiece {
+            peer: index,
+            index: assignment.index,
+            result,
+            haves: peer.take_haves(),
+            wasted_bytes: peer.wasted_bytes - wasted_before,
+            timeline: peer.take_timeline(),
+            state: PeerState::of(&peer),
+        };
+        if results.send(report).is_err() {
+            break
+        }
+        reported = PeerState::of(&peer);
+    }
+
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ error::hex, files::tests::download_dir, peer::tests::faulty_peer, peer_wire_protocol::Handshake, picker::Sequential, testing::{ block_message, mock_seed }, verifier::LocalVerifier };
+    use std::{ net::SocketAddr, time::Duration };
+    use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::TcpListener };
+
+    #[tokio::test]
+    async fn corrupt_piece_is_retried_from_another_peer() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let corrupt = mock_seed(vec![0; 40_000], 16_384, Duration::ZERO).await;
+        let good = mock_seed(data.clone(), 16_384, Duration::from_millis(20)).await;
+
+        let torrent = Torrent::from_pieces("coordinator", 16_384, &data);
+        let path = download_dir("coordinator").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        let mut peers = vec![];
+        for address in [corrupt, good] {
+            let mut peer = Peer::create_connection(address).await.unwrap();
+            peer.handshake(&torrent).await.unwrap();
+            peer.set_interested(true).await.unwrap();
+
+            let pieces = Bitfield::full(3);
+            ledger.add_peer(&pieces);
+            peers.push((peer, pieces));
+        }
+
+        let mut coordinator = PieceCoordinator::new(ledger);
+        let completed = coordinator.completed();
+        for (peer, pieces) in peers {
+            coordinator.add_peer(peer, pieces);
+        }
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        let (ledger, _) = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await.unwrap();
+
+        assert!(ledger.is_complete());
+        assert!(completed.lock().unwrap().is_complete());
+        assert_eq!(tokio::fs::read(format!("{path}/coordinator")).await.unwrap(), data);
+
+        // The corrupt peer was retired after its first piece, which the good peer downloaded
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.first(), Some(&DownloadEvent::PieceFailed { index: 0, reason: String::from("piece failed verification"), dump: None }));
+        assert!(events.contains(&DownloadEvent::PeerDisconnected { address: corrupt, wasted_bytes: 0, failed_hash_bytes: 16_384 }));
+        assert!(events.contains(&DownloadEvent::PeerDisconnected { address: good, wasted_bytes: 0, failed_hash_bytes: 0 }));
+    }
+
+    #[tokio::test]
+    async fn pieces_announced_while_downloading_are_assigned() {
+        let data: Vec<u8> = (0..3 * 16_384).map(|byte| (byte % 251) as u8).collect();
+        let torrent = Torrent::from_pieces("haves", 16_384, &data);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(address) = listener.local_addr().unwrap() else { panic!("Expected an ipv4 address") };
+
+        // A peer that only has piece 0 at first, and announces the others with its block
+        let seed = data.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; 68]).await.unwrap();
+            let mut response = Handshake::new(&[1; 20], String::from("-MY0001-123456654321")).unwrap().to_buffer();
+            response.extend([0, 0, 0, 1, 1]);
+            stream.write_all(&response).await.unwrap();
+
+            let mut length = [0; 4];
+            let mut announced = false;
+            while stream.read_exact(&mut length).await.is_ok() {
+                let mut message = vec![0; u32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut message).await.unwrap();
+                if message.first() != Some(&6) {
+                    continue
+                }
+
+                let index = u32::from_be_bytes([message[1], message[2], message[3], message[4]]);
+                if !announced {
+                    for have in [1_u32, 2] {
+                        stream.write_all(&[0, 0, 0, 5, 4]).await.unwrap();
+                        stream.write_all(&have.to_be_bytes()).await.unwrap();
+                    }
+                    announced = true;
+                }
+
+                let mut block = (16_384_u32 + 9).to_be_bytes().to_vec();
+                block.push(7);
+                block.extend(index.to_be_bytes());
+                block.extend(0_u32.to_be_bytes());
+                block.extend(&seed[index as usize * 16_384..(index as usize + 1) * 16_384]);
+                stream.write_all(&block).await.unwrap();
+            }
+        });
+
+        let path = download_dir("haves").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let mut peer = Peer::create_connection(address).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+
+        let mut pieces = Bitfield::new(3);
+        pieces.set(0);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.add_peer(peer, pieces);
+
+        let emit = |_| ();
+        let (ledger, snapshot) = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await.unwrap();
+
+        assert!(ledger.is_complete());
+        assert_eq!(snapshot.availability(), vec![1, 1, 1]);
+        assert_eq!(tokio::fs::read(format!("{path}/haves")).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn stalled_download_times_out() {
+        let torrent = Torrent::from_pieces("stalled", 16_384, &[5; 40_000]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(address) = listener.local_addr().unwrap() else { panic!("Expected an ipv4 address") };
+
+        // A peer that never unchokes the client
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; 68]).await.unwrap();
+            let response = Handshake::new(&[1; 20], String::from("-MY0001-123456654321")).unwrap().to_buffer();
+            stream.write_all(&response).await.unwrap();
+            while stream.read(&mut [0; 64]).await.is_ok_and(|read| read > 0) { }
+        });
+
+        let path = download_dir("stalled").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let mut peer = Peer::create_connection(address).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+
+        let pieces = Bitfield::full(3);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.set_idle_timeout(Duration::from_millis(200));
+        coordinator.add_peer(peer, pieces);
+
+        let emit = |_| ();
+        let result = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await;
+
+        assert!(matches!(result, Err(DownloadError::Incomplete(IncompletePieces { missing })) if missing == vec![0, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_is_dumped() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let corrupt = mock_seed(vec![0; 40_000], 16_384, Duration::ZERO).await;
+        let good = mock_seed(data.clone(), 16_384, Duration::from_millis(20)).await;
+
+        let torrent = Torrent::from_pieces("dumped", 16_384, &data);
+        let path = download_dir("dumped").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        let mut coordinator_peers = vec![];
+        for address in [corrupt, good] {
+            let mut peer = Peer::create_connection(address).await.unwrap();
+            peer.handshake(&torrent).await.unwrap();
+            peer.set_interested(true).await.unwrap();
+
+            let pieces = Bitfield::full(3);
+            ledger.add_peer(&pieces);
+            coordinator_peers.push((peer, pieces));
+        }
+
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.set_dumper(PieceDumper::new(format!("{path}/dumps").into(), 1 << 20));
+        for (peer, pieces) in coordinator_peers {
+            coordinator.add_peer(peer, pieces);
+        }
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        let (ledger, _) = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await.unwrap();
+        assert!(ledger.is_complete());
+
+        let Some(DownloadEvent::PieceFailed { index: 0, dump: Some(dump), .. }) = events.into_inner().unwrap().into_iter().next() else {
+            panic!("Expected the first piece to fail and be dumped")
+        };
+        let contents = tokio::fs::read(&dump).await.unwrap();
+        let split = contents.windows(2).position(|pair| pair == b"\n\n").unwrap();
+        let report = String::from_utf8(contents[..split].to_vec()).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(lines[0], "piece 0, 16384 bytes");
+        assert_eq!(lines[1], format!("expected {}", hex(&torrent.piece_hash(0).unwrap())));
+        assert_eq!(lines[2], "computed 897256b6709e1a4da9daba92b6bde39ccfccd8c1");
+        assert_eq!(lines[3], format!("peer {corrupt} \"-MY0001-123456654321\" sent 0..16384"));
+        assert!(lines[4].starts_with("request 0+16384 at +0us, received at +"), "{}", lines[4]);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(contents[split + 2..], [0; 16_384]);
+    }
+
+    /// Returns a connected peer with the given pieces and state, without a task
+    fn synthetic_slot(port: u16, pieces: Bitfield, state: PeerState) -> PeerSlot {
+        synthetic_slot_with(port, pieces, state, mpsc::unbounded_channel().0)
+    }
+
+    /// Returns a connected peer with the given pieces and state, its commands sent to `commands`
+    fn synthetic_slot_with(port: u16, pieces: Bitfield, state: PeerState, commands: UnboundedSender<ControlMessage>) -> PeerSlot {
+        PeerSlot {
+            address: SocketAddrV4::new([127, 0, 0, 1].into(), port),
+            peer_id: String::new(),
+            commands: Some(commands),
+            task: None,
+            pieces,
+            assigned: None,
+            interested: false,
+            state,
+            failed_hash_bytes: 0,
+            wasted_bytes: 0,
+            redialed: false,
+        }
+    }
+
+    #[test]
+    fn peers_are_counted() {
+        let torrent = Torrent::from_pieces("counted", 16, &[0; 48]);
+        let mut coordinator = PieceCoordinator::new(PieceLedger::new(&torrent, Box::new(Sequential)));
+        let mut partial = Bitfield::new(3);
+        partial.set(1);
+
+        let choking = PeerState { choking: true, interested: false };
+        let interested = PeerState { choking: false, interested: true };
+        coordinator.peers.extend([
+            synthetic_slot(1, Bitfield::full(3), choking),
+            synthetic_slot(2, Bitfield::full(3), interested),
+            synthetic_slot(3, partial, PeerState { choking: true, interested: true }),
+            synthetic_slot(4, Bitfield::new(3), interested),
+        ]);
+        // Retired, so not counted
+        let mut retired = synthetic_slot(5, Bitfield::full(3), choking);
+        retired.commands = None;
+        coordinator.peers.push(retired);
+
+        let counts = coordinator.peer_counts();
+        coordinator.publish_counts();
+
+        assert_eq!(*counts.lock().unwrap(), PeerCounts { connected: 4, seeds: 2, leeches: 2, choked_by: 2, interested_in_us: 3, panicked: 0 });
+    }
+
+    /// Returns which of the synthetic peers is handed the only piece the memory gate lets through
+    fn first_peer_dispatched(prefer_rare_peers: bool) -> usize {
+        let torrent = Torrent::from_pieces("rare", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.set_memory_gate(MemoryGate::new(16));
+
+        // Every peer has pieces 0 and 1, but only the unchoked third peer has piece 3, and only
+        // the choking fourth peer has piece 2
+        let mut common = Bitfield::new(4);
+        common.set(0);
+        common.set(1);
+        let mut rare = common.clone();
+        rare.set(3);
+        let mut rarest_but_choking = rare.clone();
+        rarest_but_choking.set(2);
+
+        let unchoked = PeerState::default();
+        let choking = PeerState { choking: true, interested: false };
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.set_prefer_rare_peers(prefer_rare_peers);
+        let mut receivers = vec![];
+        for (port, pieces, state) in [(1, common.clone(), unchoked), (2, common, unchoked), (3, rare, unchoked), (4, rarest_but_choking, choking)] {
+            coordinator.ledger.add_peer(&pieces);
+            let (commands, receiver) = mpsc::unbounded_channel();
+            coordinator.peers.push(synthetic_slot_with(port, pieces, state, commands));
+            receivers.push(receiver);
+        }
+
+        assert!(coordinator.dispatch());
+        let dispatched: Vec<usize> = receivers.iter_mut().enumerate()
+            .filter_map(|(index, receiver)| matches!(receiver.try_recv(), Ok(ControlMessage::DownloadPiece(_))).then_some(index))
+            .collect();
+        assert_eq!(dispatched.len(), 1);
+
+        dispatched[0]
+    }
+
+    #[test]
+    fn peers_covering_rare_pieces_are_preferred() {
+        assert_eq!(first_peer_dispatched(false), 0);
+        assert_eq!(first_peer_dispatched(true), 2);
+    }
+
+    #[test]
+    fn sole_source_of_a_piece_is_kept_while_choking() {
+        let torrent = Torrent::from_pieces("sole", 16, &[0; 32]);
+        let mut coordinator = PieceCoordinator::new(PieceLedger::new(&torrent, Box::new(Sequential)));
+        let mut common = Bitfield::new(2);
+        common.set(0);
+        let choking = PeerState { choking: true, interested: false };
+        for (port, pieces) in [(1, common.clone()), (2, common), (3, Bitfield::full(2))] {
+            coordinator.ledger.add_peer(&pieces);
+            coordinator.peers.push(synthetic_slot(port, pieces, choking));
+        }
+
+        // Only the third peer has piece 1, but it is only kept when rare peers are preferred
+        assert!(!coordinator.sole_choking_source(2));
+        coordinator.set_prefer_rare_peers(true);
+        assert!(!coordinator.sole_choking_source(0));
+        assert!(coordinator.sole_choking_source(2));
+
+        coordinator.peers[2].state.choking = false;
+        assert!(!coordinator.sole_choking_source(2));
+    }
+
+    /// Delivers a piece to the coordinator twice, from two peers, as a piece reassigned while its
+    /// first peer was still downloading it would be, returning the events of the download and
+    /// what was written
+    async fn deliver_twice(test: &str, second: Vec<u8>) -> (Vec<DownloadEvent>, Vec<u8>) {
+        let data: Vec<u8> = (0..16).collect();
+        let torrent = Torrent::from_pieces(test, 16, &data);
+        let path = download_dir(test).await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let pieces = Bitfield::full(1);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+        ledger.add_peer(&pieces);
+        let mut coordinator = PieceCoordinator::new(ledger);
+        let mut receivers = vec![];
+        for port in [1, 2] {
+            let (commands, receiver) = mpsc::unbounded_channel();
+            coordinator.peers.push(synthetic_slot_with(port, pieces.clone(), PeerState::default(), commands));
+            receivers.push(receiver);
+        }
+        // The piece was reassigned to the second peer, the first is assigned it as the run starts
+        coordinator.peers[1].assigned = Some(0);
+
+        for (peer, piece) in [(0, data), (1, second)] {
+            coordinator.results_sender.send(ControlMessage::DownloadedPiece {
+                peer,
+                index: 0,
+                result: Ok(piece),
+                haves: vec![],
+                wasted_bytes: 0,
+                timeline: vec![],
+                state: PeerState::default(),
+            }).unwrap();
+        }
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        let (ledger, _) = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await.unwrap();
+        assert!(ledger.is_complete());
+
+        (events.into_inner().unwrap(), tokio::fs::read(format!("{path}/{test}")).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn piece_delivered_again_is_discarded() {
+        let data: Vec<u8> = (0..16).collect();
+        let discarded = DownloadEvent::BlocksDiscarded { address: "127.0.0.1:2".parse().unwrap(), bytes: 16 };
+
+        // The same data, and corrupt data that would fail verification, are both dropped unhashed
+        for (test, second) in [("delivered_twice", data.clone()), ("delivered_corrupt", vec![0; 16])] {
+            let (events, written) = deliver_twice(test, second).await;
+
+            assert_eq!(events, vec![DownloadEvent::PieceCompleted(0), discarded.clone()]);
+            assert_eq!(written, data);
+        }
+    }
+
+    /// Stands in for a peer's task, panicking once it is assigned a piece
+    async fn panic_when_assigned(mut commands: UnboundedReceiver<ControlMessage>) -> Peer {
+        commands.recv().await;
+        panic!("peer task panicked")
+    }
+
+    /// Stands in for a peer's task, panicking once it is stopped
+    async fn panic_when_stopped(mut commands: UnboundedReceiver<ControlMessage>) -> Peer {
+        while commands.recv().await.is_some() { }
+        panic!("peer task panicked while stopping")
+    }
+
+    #[tokio::test]
+    async fn panic_while_retiring_is_reported() {
+        let torrent = Torrent::from_pieces("retired", 16, &[3; 32]);
+        let path = download_dir("retired").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        // Nothing is left to download, so the peer is stopped as soon as the run starts
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.piece_complete(0);
+        ledger.piece_complete(1);
+        let mut coordinator = PieceCoordinator::new(ledger);
+        let address: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let mut slot = synthetic_slot_with(address.port(), Bitfield::full(2), PeerState::default(), commands);
+        slot.task = Some(tokio::spawn(panic_when_stopped(receiver)));
+        coordinator.peers.push(slot);
+        let counts = coordinator.peer_counts();
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit).await.unwrap();
+
+        assert_eq!(events.into_inner().unwrap(), vec![
+            DownloadEvent::PeerPanicked { address, peer_id: String::new(), message: String::from("peer task panicked while stopping") },
+            DownloadEvent::PeerDisconnected { address, wasted_bytes: 0, failed_hash_bytes: 0 },
+        ]);
+        assert_eq!(counts.lock().unwrap().panicked, 1);
+    }
+
+    /// Returns a peer with the given pieces whose task panics once it is assigned a piece
+    fn panicking_slot(address: SocketAddrV4, pieces: Bitfield) -> PeerSlot {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let mut slot = synthetic_slot_with(address.port(), pieces, PeerState::default(), commands);
+        slot.address = address;
+        slot.task = Some(tokio::spawn(panic_when_assigned(receiver)));
+
+        slot
+    }
+
+    #[tokio::test]
+    async fn panicked_peer_task_hands_back_its_piece() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let good = mock_seed(data.clone(), 16_384, Duration::from_millis(20)).await;
+
+        let torrent = Torrent::from_pieces("panicked", 16_384, &data);
+        let path = download_dir("panicked").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let pieces = Bitfield::full(3);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+        ledger.add_peer(&pieces);
+        let mut coordinator = PieceCoordinator::new(ledger);
+
+        // Added first, so it is assigned the first piece
+        let crashed: SocketAddrV4 = "127.0.0.1:1".parse().unwrap();
+        coordinator.peers.push(panicking_slot(crashed, pieces.clone()));
+        let counts = coordinator.peer_counts();
+
+        let mut peer = Peer::create_connection(good).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+        coordinator.add_peer(peer, pieces);
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        // Without an idle timeout, a piece that is never handed back would hang the download
+        let run = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit);
+        let (ledger, snapshot) = tokio::time::timeout(Duration::from_secs(10), run).await.unwrap().unwrap();
+
+        assert!(ledger.is_complete());
+        assert_eq!(snapshot.availability(), vec![1, 1, 1]);
+        assert_eq!(tokio::fs::read(format!("{path}/panicked")).await.unwrap(), data);
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(events[0], DownloadEvent::PeerPanicked { address: crashed, peer_id: String::new(), message: String::from("peer task panicked") });
+        assert_eq!(events[1], DownloadEvent::PieceFailed { index: 0, reason: String::from("peer task panicked, peer task panicked"), dump: None });
+        assert_eq!(events[2], DownloadEvent::PeerDisconnected { address: crashed, wasted_bytes: 0, failed_hash_bytes: 0 });
+        assert_eq!(counts.lock().unwrap().panicked, 1);
+    }
+
+    #[tokio::test]
+    async fn panicked_peer_is_redialed() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        // Only the redial connects to the seed, the task that panics stands in for the first connection
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+
+        let torrent = Torrent::from_pieces("redialed", 16_384, &data);
+        let path = download_dir("redialed").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let pieces = Bitfield::full(3);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.set_redial(PeerDialer {
+            buffers: Default::default(),
+            socket_options: Default::default(),
+            request_timeout: Duration::from_secs(5),
+            adaptive_timeout: None,
+            strict_peer_id: false,
+            strict_protocol: false,
+        });
+        coordinator.peers.push(panicking_slot(seed, pieces));
+        let counts = coordinator.peer_counts();
+
+        let events = Mutex::new(vec![]);
+        let emit = |event| events.lock().unwrap().push(event);
+        let run = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit);
+        let (ledger, _) = tokio::time::timeout(Duration::from_secs(10), run).await.unwrap().unwrap();
+
+        assert!(ledger.is_complete());
+        assert_eq!(tokio::fs::read(format!("{path}/redialed")).await.unwrap(), data);
+        assert!(events.into_inner().unwrap().contains(&DownloadEvent::PeerConnected { address: seed, peer_id: String::from("-MY0001-123456654321") }));
+        assert_eq!(*counts.lock().unwrap(), PeerCounts { panicked: 1, ..PeerCounts::default() });
+    }
+
+    #[tokio::test]
+    async fn download_lost_to_panics_ends_in_error() {
+        let torrent = Torrent::from_pieces("lost", 16_384, &[7; 40_000]);
+        let path = download_dir("lost").await;
+        let mut files = Files::new();
+        files.create_files(&torrent, &path, false).await.unwrap();
+
+        let pieces = Bitfield::full(3);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.add_peer(&pieces);
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.peers.push(panicking_slot("127.0.0.1:1".parse().unwrap(), pieces));
+
+        let emit = |_| ();
+        let run = coordinator.run(&mut files, &torrent, VerifyPolicy::BeforeWrite, &LocalVerifier, &emit);
+        let result = tokio::time::timeout(Duration::from_secs(10), run).await.unwrap();
+
+        assert!(matches!(result, Err(DownloadError::PeersPanicked { panics: 1, missing: IncompletePieces { missing } }) if missing == vec![0, 1, 2]));
+    }
+
+    #[tokio::test]
+    async fn peer_task_reports_discarded_blocks() {
+        // A late block of piece 0, then the requested block of piece 1
+        let mut response = block_message(0, 0, &[0xee; 8]);
+        response.extend(block_message(1, 0, &[0x11; 8]));
+        let (peer, responder) = faulty_peer(response).await;
+
+        let (commands, command_receiver) = mpsc::unbounded_channel();
+        let (results, mut result_receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(peer_task(0, peer, command_receiver, results));
+        commands.send(ControlMessage::DownloadPiece(PieceAssignment { index: 1, length: 8 })).unwrap();
+
+        let Some(ControlMessage::DownloadedPiece { result, wasted_bytes, .. }) = result_receiver.recv().await else {
+            panic!("Expected the piece to be reported")
+        };
+        assert_eq!(result.unwrap(), vec![0x11; 8]);
+        assert_eq!(wasted_bytes, 8);
+
+        drop(commands);
+        task.await.unwrap();
+        responder.await.unwrap();
+    }
+}
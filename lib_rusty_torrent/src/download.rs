@@ -0,0 +1,1087 @@
+//! Downloads a torrent from start to finish: finding peers, requesting pieces and writing them
+
+// Crate Imports
+use crate::{
+    bitfield::Bitfield,
+    candidate::PeerCandidate,
+    config::{ DownloadConfig, TrackerPreference },
+    coordinator::{ PeerCounts, PieceCoordinator },
+    error::{ DownloadError, Error },
+    files::Files,
+    lock::DownloadLock,
+    peer::Peer,
+    peer_wire_protocol::{ Message, MessageType },
+    picker::{ AvailabilitySnapshot, PickerSwap, PieceLedger, PiecePicker },
+    socket::UnsupportedOption,
+    torrent::Torrent,
+    tracker::{ HttpTracker, Tracker, TrackerEndpoint, TrackerStatus, TrackerUrl },
+    verifier::PieceVerifier
+};
+
+// External imports
+use futures::stream::{ self, StreamExt };
+use std::{
+    collections::{ HashSet, VecDeque },
+    future::Future,
+    io,
+    net::SocketAddrV4,
+    path::PathBuf,
+    sync::{ Arc, Mutex },
+    time::Duration
+};
+use tokio::time::{ timeout, Instant };
+
+/// The peer id the client announces itself to trackers with
+const PEER_ID: &str = "-MY0001-123456654321";
+
+/// How long a UDP tracker is waited on with `TrackerPreference::Auto` while there are HTTP
+/// trackers to fall back to, the first retransmit timeout of BEP 15
+const UDP_FALLBACK_WAIT: Duration = Duration::from_secs(15);
+
+/// The most peers connected to at once
+const MAX_CONNECTING: usize = 32;
+
+/// Something that happened during a download, passed to the event hook.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DownloadEvent {
+    /// A tracker isn't announced to, as its protocol isn't supported yet.
+    TrackerSkipped(TrackerUrl),
+    /// A tracker isn't announced to, as `DownloadConfig::tracker_filter` doesn't allow it.
+    TrackerDenied(TrackerUrl),
+    /// The torrent's trackers that can be announced to, UDP trackers resolved to their addresses.
+    TrackersResolved(Vec<TrackerEndpoint>),
+    /// A tracker was announced to, successfully or not.
+    Announced(TrackerStatus),
+    /// A peer was connected to and completed the handshake.
+    PeerConnected {
+        /// The address of the peer.
+        address: SocketAddrV4,
+        /// The peer id the peer sent in its handshake.
+        peer_id: String,
+    },
+    /// A piece was downloaded, verified and written.
+    PieceCompleted(u32),
+    /// A piece failed to download or verify.
+    PieceFailed {
+        /// The index of the piece.
+        index: u32,
+        /// Why the piece failed.
+        reason: String,
+        /// Where the piece was dumped, see `DownloadConfig::dump_failed_pieces`.
+        dump: Option<PathBuf>,
+    },
+    /// A peer broke protocol rules that are tolerated without `DownloadConfig::strict_protocol`.
+    PeerTolerated {
+        /// The address of the peer.
+        address: SocketAddrV4,
+        /// The rules the peer broke.
+        reason: String,
+    },
+    /// A peer sent blocks that weren't requested while downloading a piece, or a piece that had
+    /// been verified already, they were discarded.
+    BlocksDiscarded {
+        /// The address of the peer.
+        address: SocketAddrV4,
+        /// The bytes of the blocks.
+        bytes: u64,
+    },
+    /// The connection to a peer was closed.
+    PeerDisconnected {
+        /// The address of the peer.
+        address: SocketAddrV4,
+        /// The bytes of blocks the peer sent that weren't requested.
+        wasted_bytes: u64,
+        /// The bytes of pieces the peer sent that failed verification.
+        failed_hash_bytes: u64,
+    },
+    /// The task of a peer panicked, the peer was dropped and its piece handed back.
+    PeerPanicked {
+        /// The address of the peer.
+        address: SocketAddrV4,
+        /// The peer id the peer sent in its handshake.
+        peer_id: String,
+        /// What the task panicked with.
+        message: String,
+    },
+    /// A socket option from `DownloadConfig::socket_options` couldn't be set, only reported the
+    /// first time for each option.
+    SocketOptionUnsupported(UnsupportedOption),
+}
+
+/// Observes the events of a download.
+pub type EventHook = Arc<dyn Fn(&DownloadEvent) + Send + Sync>;
+
+/// A download of a single torrent, configured by a `DownloadConfig`.
+pub struct Download {
+    /// The torrent being downloaded
+    torrent: Torrent,
+    /// How the torrent is downloaded
+    config: DownloadConfig,
+    /// Observes the download's events, if set
+    event_hook: Option<EventHook>,
+    /// Verifies downloaded pieces in place of the one `DownloadConfig::verifier` creates, if set
+    verifier: Option<Arc<dyn PieceVerifier>>,
+    /// A strategy to take over choosing pieces, shared with the ledger of a running download
+    picker_swap: PickerSwap,
+    /// The availability among the peers when the download last ended
+    swarm_snapshot: Mutex<Option<AvailabilitySnapshot>>,
+    /// The socket options that have been reported as unsupported
+    warned_options: Mutex<HashSet<&'static str>>,
+    /// The status of every tracker announced to, in the order they were first announced to
+    tracker_stats: Mutex<Vec<TrackerStatus>>,
+    /// The counts of the peers connected to, kept up to date by the running download
+    peer_counts: Arc<Mutex<PeerCounts>>,
+}
+
+impl Download {
+    /// Creates a download, nothing happens until it is run.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The torrent to download.
+    /// * `config` - How the torrent is downloaded.
+    pub fn new(torrent: Torrent, config: DownloadConfig) -> Self {
+        Self {
+            torrent,
+            config,
+            event_hook: None,
+            verifier: None,
+            picker_swap: PickerSwap::default(),
+            swarm_snapshot: Mutex::new(None),
+            warned_options: Mutex::new(HashSet::new()),
+            tracker_stats: Mutex::new(vec![]),
+            peer_counts: Arc::default(),
+        }
+    }
+
+    /// Returns the torrent being downloaded.
+    pub fn torrent(&self) -> &Torrent {
+        &self.torrent
+    }
+
+    /// Returns how the torrent is downloaded.
+    pub fn config(&self) -> &DownloadConfig {
+        &self.config
+    }
+
+    /// Returns the availability of every piece among the connected peers when the download last
+    /// ended, to persist and warm start the next session with through
+    /// `DownloadConfig::availability_snapshot`. `None` until a download has run to the end.
+    pub fn swarm_snapshot(&self) -> Option<AvailabilitySnapshot> {
+        self.swarm_snapshot.lock().unwrap().clone()
+    }
+
+    /// Returns the status of every tracker announced to, as of its last announce, in the order
+    /// they were first announced to. Trackers that were never reached are included with the
+    /// error that stopped them.
+    pub fn tracker_stats(&self) -> Vec<TrackerStatus> {
+        self.tracker_stats.lock().unwrap().clone()
+    }
+
+    /// Returns the number of peers connected to, and how many are seeds, leeches, choking the
+    /// client or interested in its pieces, and how many peer tasks have panicked. Every count
+    /// but the panics is 0 while the download isn't running.
+    pub fn peer_counts(&self) -> PeerCounts {
+        *self.peer_counts.lock().unwrap()
+    }
+
+    /// Sets a hook that is called with every event of the download.
+    pub fn set_event_hook(&mut self, hook: EventHook) {
+        self.event_hook = Some(hook);
+    }
+
+    /// Sets the verifier downloaded pieces are checked with, in place of the one
+    /// `DownloadConfig::verifier` creates. A verifier that doesn't hash pieces locally has to be
+    /// acknowledged with `DownloadConfig::trust_external_verifier`, or the download is refused.
+    pub fn set_verifier(&mut self, verifier: Arc<dyn PieceVerifier>) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Sets the strategy used to choose pieces in place of `DownloadConfig::piece_strategy`.
+    ///
+    /// It can be set while the download is running, and takes over from the next piece assigned,
+    /// pieces already assigned are unaffected. It is used until the run ends, a later run goes
+    /// back to `piece_strategy` unless it is set again.
+    pub fn set_picker(&self, picker: Box<dyn PiecePicker>) {
+        *self.picker_swap.lock().unwrap() = Some(picker);
+    }
+
+    /// Downloads the torrent into `DownloadConfig::download_path`.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok` once every piece has been downloaded and verified, or why the download ended early.
+    pub async fn run(&self) -> Result<(), DownloadError> {
+        self.run_from(0).await
+    }
+
+    /// Downloads the torrent into `DownloadConfig::download_path`, only requesting pieces from
+    /// `start_piece` on. The pieces before it are expected to be on disk already, those that
+    /// aren't, or fail verification, are reported as missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `start_piece` - The index of the first piece to request.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok` once every piece has been downloaded and verified, or why the download ended early.
+    pub async fn run_from(&self, start_piece: u32) -> Result<(), DownloadError> {
+        let verifier = self.verifier.clone().unwrap_or_else(|| Arc::from(self.config.verifier()));
+        self.config.check().map_err(DownloadError::InvalidConfig)?;
+        self.config.check_verifier(verifier.as_ref()).map_err(DownloadError::InvalidConfig)?;
+
+        if !self.torrent.metadata_complete {
+            return Err(DownloadError::InvalidTorrent(Error::InvalidTorrent(String::from("the torrent's metadata hasn't been fetched"))))
+        }
+        self.torrent.check_piece_length(&self.config.piece_length_range).map_err(DownloadError::InvalidTorrent)?;
+        // Checked before the lock file, named after the torrent, is created
+        self.torrent.check_paths().map_err(DownloadError::InvalidTorrent)?;
+
+        // Held until the download ends, so no other process writes to the same files
+        let _lock = DownloadLock::acquire(&self.config.download_path, &self.torrent.info.name)?;
+
+        // Create the files that will be written to
+        let mut files = Files::new();
+        files.create_files(&self.torrent, &self.config.download_path, self.config.torrent_dir).await.map_err(|err| match err {
+            Error::IoError(err) => DownloadError::Files(err),
+            err => DownloadError::InvalidTorrent(err),
+        })?;
+
+        let peers = self.find_peers().await?;
+
+        let mut ledger = PieceLedger::new(&self.torrent, self.config.picker());
+        ledger.watch_picker_swap(self.picker_swap.clone());
+        if let Some(gate) = self.config.memory_gate() {
+            ledger.set_memory_gate(gate);
+        }
+        if let Some(snapshot) = &self.config.availability_snapshot {
+            ledger.warm_start(snapshot, self.config.warm_start_peers);
+        }
+
+        // Pieces before the start are never requested, even if they aren't on disk
+        for index in 0..start_piece.min(self.torrent.get_num_pieces()) {
+            if files.has_piece(&self.torrent, index, verifier.as_ref()).await {
+                ledger.piece_complete(index);
+            } else {
+                ledger.skip(index);
+            }
+        }
+
+        // Peers are connected to concurrently, so unreachable ones don't hold up the rest
+        let attempts: Vec<Result<Peer, DownloadError>> = stream::iter(peers)
+            .map(|candidate| self.connect(candidate))
+            .buffer_unordered(MAX_CONNECTING)
+            .collect()
+            .await;
+
+        let mut connected = vec![];
+        let mut last_error = None;
+        for attempt in attempts {
+            match attempt.and_then(|peer| self.register(peer, &mut ledger)) {
+                Ok((mut peer, peer_pieces)) => {
+                    // A peer only unchokes interested clients, its task waits for the unchoke before requesting
+                    match peer.set_interested(ledger.needed_from(&peer_pieces)).await {
+                        Ok(()) => connected.push((peer, peer_pieces)),
+                        Err(err) => {
+                            ledger.remove_peer(&peer_pieces);
+                            last_error = Some(DownloadError::Peer(err));
+                        }
+                    }
+                }
+                Err(err) => last_error = Some(err),
+            }
+        }
+
+        if connected.is_empty() {
+            return Err(last_error.unwrap_or_else(|| DownloadError::Peer(Error::IoError(io::Error::new(io::ErrorKind::NotConnected, "no peers to connect to")))))
+        }
+
+        let mut coordinator = PieceCoordinator::new(ledger);
+        coordinator.share_peer_counts(self.peer_counts.clone());
+        if let Some(dumper) = self.config.piece_dumper() {
+            coordinator.set_dumper(dumper);
+        }
+        coordinator.set_prefer_rare_peers(self.config.prefer_rare_peers);
+        if self.config.redial_panicked_peers {
+            coordinator.set_redial(self.config.dialer());
+        }
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            coordinator.set_idle_timeout(idle_timeout);
+        }
+        for (peer, peer_pieces) in connected {
+            coordinator.add_peer(peer, peer_pieces);
+        }
+
+        let policy = self.config.effective_verify_policy();
+        let (ledger, snapshot) = coordinator.run(&mut files, &self.torrent, policy, verifier.as_ref(), &|event| self.emit(event)).await?;
+        *self.swarm_snapshot.lock().unwrap() = Some(snapshot);
+
+        // No more pieces can be assigned and there are no other peers to ask
+        ledger.ensure_complete()?;
+
+        Ok(())
+    }
+
+    /// Announces to the torrent's trackers in the order `DownloadConfig::tracker_preference`
+    /// puts them in, until together they have returned `DownloadConfig::min_peers` distinct
+    /// peers. A tracker that fails or times out is moved on from, and the trackers are given up
+    /// on once `DownloadConfig::peer_wait` has passed since the first announce.
+    async fn find_peers(&self) -> Result<Vec<PeerCandidate>, DownloadError> {
+        let filter = &self.config.tracker_filter;
+        for url in self.torrent.tracker_urls() {
+            if !filter.allows(&url) {
+                self.emit(DownloadEvent::TrackerDenied(url));
+            } else if matches!(url, TrackerUrl::WebSocket(_) | TrackerUrl::Unsupported(_)) {
+                self.emit(DownloadEvent::TrackerSkipped(url));
+            }
+        }
+
+        let mut trackers = self.torrent.get_trackers(self.config.resolver.as_ref(), filter).await
+            .map_err(DownloadError::Discovery)?;
+        self.config.tracker_preference.order(&mut trackers);
+        self.emit(DownloadEvent::TrackersResolved(trackers.clone()));
+
+        let min_peers = self.config.min_peers.max(1);
+        let deadline = self.config.peer_wait.map(|peer_wait| Instant::now() + peer_wait);
+        let mut found: Vec<PeerCandidate> = vec![];
+        let mut last_error = None;
+        let mut trackers = VecDeque::from(trackers);
+        while let Some(endpoint) = trackers.pop_front() {
+            let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                break
+            }
+
+            let fallback = self.config.tracker_preference == TrackerPreference::Auto
+                && matches!(endpoint, TrackerEndpoint::Udp(_))
+                && trackers.iter().any(|tracker| matches!(tracker, TrackerEndpoint::Http(_)));
+            let limit = match remaining {
+                Some(limit) if fallback => Some(limit.min(UDP_FALLBACK_WAIT)),
+                None if fallback => Some(UDP_FALLBACK_WAIT),
+                limit => limit
+            };
+
+            match self.announce(&endpoint, limit).await {
+                Ok(peers) => {
+                    // Trackers of the same swarm often list the same peers
+                    for peer in peers {
+                        if !found.iter().any(|known| known.address == peer.address) {
+                            found.push(peer);
+                        }
+                    }
+                    if found.len() >= min_peers {
+                        return Ok(found)
+                    }
+
+                    last_error = Some(Error::TrackerError(format!("found {} peers, at least {min_peers} needed", found.len())));
+                }
+                Err(err) => {
+                    // Too few peers is the better explanation than the last tracker's failure
+                    if found.is_empty() {
+                        last_error = Some(err);
+                    }
+
+                    // The network may block UDP, so the HTTP trackers are tried before the other UDP trackers
+                    if fallback {
+                        trackers.make_contiguous().sort_by_key(|tracker| matches!(tracker, TrackerEndpoint::Udp(_)));
+                    }
+                }
+            }
+        }
+
+        Err(DownloadError::Discovery(last_error.unwrap_or_else(|| Error::TrackerError(String::from("no trackers to announce to")))))
+    }
+
+    /// Announces to a single tracker and returns the peers it knows of, waiting at most `limit`
+    async fn announce(&self, endpoint: &TrackerEndpoint, limit: Option<Duration>) -> Result<Vec<PeerCandidate>, Error> {
+        let (peers, mut status) = match endpoint {
+            TrackerEndpoint::Udp(address) => {
+                let mut tracker = Tracker::new_with(self.config.listen_address, *address, &self.config.socket_options).await?;
+                self.warn_unsupported(tracker.unsupported_options());
+                tracker.announce_ip = self.config.announce_ip.clone();
+                tracker.resolver = self.config.resolver.clone();
+                tracker.fit_retransmits(self.config.request_timeout);
+
+                let peers = self.wait_for_peers(limit, tracker.find_peers(&self.torrent, PEER_ID)).await;
+                (peers, tracker.status().clone())
+            }
+            TrackerEndpoint::Http(url) => {
+                let mut tracker = HttpTracker::new(url);
+                tracker.announce_ip = self.config.announce_ip.clone();
+                tracker.resolver = self.config.resolver.clone();
+                tracker.request_timeout = self.config.request_timeout;
+
+                let peers = self.wait_for_peers(limit, tracker.find_peers(&self.torrent, PEER_ID)).await;
+                (peers, tracker.status().clone())
+            }
+        };
+        // An announce cut short isn't recorded by the tracker itself
+        let peers = peers.unwrap_or_else(|err| {
+            status.record_failure(&err.to_string());
+            Err(err)
+        });
+
+        self.record_status(&status);
+        self.emit(DownloadEvent::Announced(status));
+
+        peers
+    }
+
+    /// Stores the status of a tracker, replacing the one from its previous announce
+    fn record_status(&self, status: &TrackerStatus) {
+        let mut stats = self.tracker_stats.lock().unwrap();
+
+        match stats.iter_mut().find(|stored| stored.address == status.address) {
+            Some(stored) => *stored = status.clone(),
+            None => stats.push(status.clone()),
+        }
+    }
+
+    /// Waits for an announce to return peers, for at most `limit` if set
+    ///
+    /// # Returns
+    ///
+    /// * The result of the announce, or why it was cut short.
+    async fn wait_for_peers(&self, limit: Option<Duration>, find_peers: impl Future<Output = Result<Vec<PeerCandidate>, Error>>) -> Result<Result<Vec<PeerCandidate>, Error>, Error> {
+        match limit {
+            None => Ok(find_peers.await),
+            Some(limit) => timeout(limit, find_peers).await
+                .map_err(|_| Error::TrackerError(format!("no peers after {}s", limit.as_secs())))
+        }
+    }
+
+    /// Registers the pieces a connected peer has with the ledger
+    ///
+    /// # Arguments
+    ///
+    /// * `peer` - The peer, connected to and handshaken with.
+    /// * `ledger` - The ledger of the download, the peer's pieces are added to it.
+    ///
+    /// # Returns
+    ///
+    /// * The peer and the pieces it has.
+    fn register(&self, mut peer: Peer, ledger: &mut PieceLedger) -> Result<(Peer, Bitfield), DownloadError> {
+        let num_pieces = self.torrent.get_num_pieces() as usize;
+        let peer_pieces = match register_peer(ledger, peer.take_early_messages(), num_pieces, self.config.strict_protocol) {
+            Ok((peer_pieces, tolerated)) => {
+                if !tolerated.is_empty() {
+                    self.emit(DownloadEvent::PeerTolerated { address: peer.socket_addr, reason: tolerated.join(", ") });
+                }
+
+                peer_pieces
+            }
+            Err(reason) => {
+                // Dropping the peer closes the connection
+                return Err(DownloadError::Peer(Error::MalformedMessage(format!("peer {} violated the protocol, {reason}", peer.socket_addr))))
+            }
+        };
+
+        Ok((peer, peer_pieces))
+    }
+
+    /// Connects to a peer and completes the handshake, giving up after `DownloadConfig::request_timeout`
+    async fn connect(&self, candidate: PeerCandidate) -> Result<Peer, DownloadError> {
+        let address = candidate.address;
+
+        timeout(self.config.request_timeout, self.handshake(candidate)).await
+            .unwrap_or_else(|_| Err(DownloadError::Peer(Error::HandshakeFailed(format!("no handshake from {address} after {}s", self.config.request_timeout.as_secs())))))
+    }
+
+    /// Connects to a peer and completes the handshake
+    async fn handshake(&self, candidate: PeerCandidate) -> Result<Peer, DownloadError> {
+        let peer = self.config.dialer().dial(candidate, &self.torrent).await.map_err(DownloadError::Peer)?;
+        self.warn_unsupported(peer.unsupported_options());
+
+        self.emit(DownloadEvent::PeerConnected { address: peer.socket_addr, peer_id: peer.peer_id.clone() });
+
+        Ok(peer)
+    }
+
+    /// Reports each socket option that couldn't be set the first time it fails, as it fails
+    /// the same way for every socket
+    fn warn_unsupported(&self, unsupported: &[UnsupportedOption]) {
+        for option in unsupported {
+            if self.warned_options.lock().unwrap().insert(option.option) {
+                self.emit(DownloadEvent::SocketOptionUnsupported(option.clone()));
+            }
+        }
+    }
+
+    /// Passes an event to the event hook, if set
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(hook) = &self.event_hook {
+            hook(&event);
+        }
+    }
+}
+
+/// Adds a peer to the ledger, applying the bitfield and haves it sent alongside its handshake in
+/// the order they arrived, and returns the pieces it has.
+///
+/// A peer that sent no bitfield has only the pieces it sent haves for, those it announces later
+/// are added as its task reports them.
+///
+/// # Arguments
+///
+/// * `ledger` - The ledger of the download.
+/// * `early_messages` - The messages the peer sent alongside its handshake.
+/// * `num_pieces` - The number of pieces in the torrent.
+/// * `strict` - Whether a bitfield with spare bits set, or sent after other messages, is an error
+///   rather than tolerated.
+///
+/// # Returns
+///
+/// * The pieces the peer has and the rules it broke that were tolerated, or an
+///   `Error::MalformedMessage` naming the rule it broke if the peer should be disconnected. A
+///   bitfield of the wrong length is never tolerated.
+fn register_peer(ledger: &mut PieceLedger, early_messages: Vec<Message>, num_pieces: usize, strict: bool) -> Result<(Bitfield, Vec<String>), Error> {
+    let mut bitfield = None;
+    let mut haves = Bitfield::new(num_pieces);
+    let mut tolerated = vec![];
+
+    for (position, message) in early_messages.into_iter().enumerate() {
+        let payload = message.payload.unwrap_or_default();
+
+        match message.message_type {
+            MessageType::Bitfield => {
+                let (parsed, spare_bits) = Bitfield::parse(&payload, num_pieces)?;
+
+                let mut rules = vec![];
+                if spare_bits {
+                    rules.push("bitfield has spare bits set");
+                }
+                if position > 0 {
+                    rules.push("bitfield sent after other messages");
+                }
+
+                if strict && !rules.is_empty() {
+                    return Err(Error::MalformedMessage(rules.join(", ")))
+                }
+                tolerated.extend(rules.into_iter().map(String::from));
+                bitfield = Some(parsed);
+            }
+            MessageType::Have if payload.len() == 4 => {
+                haves.set(u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]));
+            }
+            _ => ()
+        }
+    }
+
+    // Haves sent before a late bitfield are merged into it
+    let peer_pieces = match bitfield {
+        None => haves,
+        Some(mut bitfield) => {
+            for index in haves.indices() {
+                bitfield.set(index);
+            }
+
+            bitfield
+        }
+    };
+
+    ledger.add_peer(&peer_pieces);
+
+    Ok((peer_pieces, tolerated))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{ error::IncompletePieces, files::tests::download_dir, lock::LockError, picker::{ tests::Reverse, MemoryGate, Sequential }, testing::{ block_message, mock_peer_with, mock_seed, mock_tracker }, tracker::tests::mock_http };
+    use async_trait::async_trait;
+    use std::net::SocketAddr;
+    use tokio::{ io::{ AsyncReadExt, AsyncWriteExt }, net::UdpSocket };
+
+    /// Returns a torrent of the data announced to the tracker and a config downloading it into a new directory
+    pub(crate) async fn tracked_torrent(test: &str, data: &[u8], piece_length: u64, tracker: SocketAddr) -> (Torrent, DownloadConfig) {
+        let mut torrent = Torrent::from_pieces(test, piece_length, data);
+        torrent.announce = Some(format!("udp://{}:{}/announce", tracker.ip(), tracker.port()));
+
+        let config = DownloadConfig {
+            download_path: download_dir(test).await,
+            listen_address: "127.0.0.1:0".parse().unwrap(),
+            piece_length_range: 1..=u64::MAX,
+            ..Default::default()
+        };
+
+        (torrent, config)
+    }
+
+    #[tokio::test]
+    async fn download_from_mock_swarm() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("download_from_mock_swarm", &data, 16_384, tracker).await;
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        let mut download = Download::new(torrent, config);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone())));
+
+        download.run().await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.first(), Some(&DownloadEvent::TrackersResolved(vec![TrackerEndpoint::Udp(tracker)])));
+        let completed: Vec<u32> = events.iter().filter_map(|event| match event {
+            DownloadEvent::PieceCompleted(index) => Some(*index),
+            _ => None
+        }).collect();
+        assert_eq!(completed, vec![0, 1, 2]);
+        assert!(matches!(events.last(), Some(DownloadEvent::PeerDisconnected { wasted_bytes: 0, failed_hash_bytes: 0, .. })));
+        assert_eq!(download.swarm_snapshot().map(|snapshot| snapshot.availability()), Some(vec![1, 1, 1]));
+        assert_eq!(download.peer_counts(), PeerCounts::default());
+    }
+
+    #[tokio::test]
+    async fn download_from_http_tracker() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend(seed.ip().octets());
+        body.extend(seed.port().to_be_bytes());
+        body.push(b'e');
+        let tracker = mock_http(&[], body).await;
+
+        let (mut torrent, config) = tracked_torrent("download_from_http_tracker", &data, 16_384, tracker).await;
+        torrent.announce = Some(format!("http://{tracker}/announce"));
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+
+        Download::new(torrent, config).run().await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn peer_announcing_pieces_later_is_downloaded_from() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+
+        // A peer that has nothing at first, then announces every piece and unchokes the client
+        // once it is interested
+        let (seed, mock) = mock_peer_with(vec![]).await;
+        let seeded = data.clone();
+        tokio::spawn(async move {
+            let mut stream = mock.await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            for have in 0..3_u32 {
+                stream.write_all(&[0, 0, 0, 5, 4]).await.unwrap();
+                stream.write_all(&have.to_be_bytes()).await.unwrap();
+            }
+
+            let mut length = [0; 4];
+            while stream.read_exact(&mut length).await.is_ok() {
+                let mut message = vec![0; u32::from_be_bytes(length) as usize];
+                stream.read_exact(&mut message).await.unwrap();
+
+                let field = |at: usize| u32::from_be_bytes([message[at], message[at + 1], message[at + 2], message[at + 3]]);
+                match message.first() {
+                    Some(2) => stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap(),
+                    Some(6) => {
+                        let (index, offset, length) = (field(1), field(5), field(9));
+                        let start = (index * 16_384 + offset) as usize;
+                        stream.write_all(&block_message(index, offset, &seeded[start..start + length as usize])).await.unwrap();
+                    }
+                    _ => ()
+                }
+            }
+        });
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("peer_announcing_pieces_later", &data, 16_384, tracker).await;
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        let download = Download::new(torrent, config);
+
+        tokio::time::timeout(Duration::from_secs(10), download.run()).await.unwrap().unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        assert_eq!(download.swarm_snapshot().map(|snapshot| snapshot.availability()), Some(vec![1, 1, 1]));
+    }
+
+    #[tokio::test]
+    async fn dead_tracker_is_moved_on_from() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        // Never answers, so the announce to it times out
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead_address = dead.local_addr().unwrap();
+
+        let (mut torrent, mut config) = tracked_torrent("dead_tracker_is_moved_on_from", &data, 16_384, dead_address).await;
+        torrent.announce_list = Some(vec![vec![format!("udp://{tracker}/announce")]]);
+        config.request_timeout = Duration::from_millis(500);
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        let download = Download::new(torrent, config);
+
+        download.run().await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+
+        // Both trackers are kept in the stats, the dead one with its failure
+        let stats = download.tracker_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].address, TrackerEndpoint::Udp(dead_address));
+        assert_eq!((stats[0].consecutive_failures, stats[0].last_peer_count), (1, 0));
+        assert!(stats[0].last_error.is_some());
+        assert_eq!(stats[1].address, TrackerEndpoint::Udp(tracker));
+        assert_eq!((stats[1].consecutive_failures, stats[1].last_peer_count), (0, 1));
+        assert_eq!(stats[1].interval, Some(Duration::from_secs(1800)));
+    }
+
+    #[tokio::test]
+    async fn silent_tracker_is_given_up_on_within_request_timeout() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        // Never answers, and there is no peer_wait to cut the announce to it short
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_address = silent.local_addr().unwrap();
+
+        let (mut torrent, mut config) = tracked_torrent("silent_tracker_is_given_up_on", &data, 16_384, silent_address).await;
+        torrent.announce_list = Some(vec![vec![format!("udp://{tracker}/announce")]]);
+        config.request_timeout = Duration::from_secs(1);
+        let download = Download::new(torrent, config);
+
+        let started = std::time::Instant::now();
+        download.run().await.unwrap();
+
+        let stats = download.tracker_stats();
+        assert_eq!(stats[0].address, TrackerEndpoint::Udp(silent_address));
+        assert!(stats[0].last_error.as_deref().is_some_and(|err| err.ends_with("didn't respond, sent 3 times")), "{:?}", stats[0].last_error);
+        assert!(started.elapsed() < Duration::from_secs(2), "{:?}", started.elapsed());
+    }
+
+    /// Returns the trackers announced to by a download of a torrent listing two dead UDP trackers
+    /// ahead of an HTTP tracker that knows of a seed
+    async fn announces_with_preference(test: &str, preference: TrackerPreference) -> Vec<TrackerEndpoint> {
+        let data = vec![3; 20_000];
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend(seed.ip().octets());
+        body.extend(seed.port().to_be_bytes());
+        body.push(b'e');
+        let http = mock_http(&[], body).await;
+
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let dead = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+        let (mut torrent, mut config) = tracked_torrent(test, &data, 16_384, dead[0]).await;
+        torrent.announce_list = Some(vec![
+            vec![format!("http://{http}/announce")],
+            vec![format!("udp://{}/announce", dead[1])],
+        ]);
+        config.request_timeout = Duration::from_millis(300);
+        config.tracker_preference = preference;
+        let mut download = Download::new(torrent, config);
+
+        let announced = Arc::new(Mutex::new(vec![]));
+        let recorded = announced.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| if let DownloadEvent::Announced(status) = event {
+            recorded.lock().unwrap().push(status.address.clone());
+        }));
+
+        download.run().await.unwrap();
+
+        let announced = announced.lock().unwrap();
+        announced.clone()
+    }
+
+    #[tokio::test]
+    async fn tracker_preference_orders_announces() {
+        let auto = announces_with_preference("tracker_preference_auto", TrackerPreference::Auto).await;
+        assert_eq!(auto.len(), 2);
+        assert!(matches!(auto[0], TrackerEndpoint::Udp(_)));
+        assert!(matches!(auto[1], TrackerEndpoint::Http(_)));
+
+        let udp_first = announces_with_preference("tracker_preference_udp", TrackerPreference::PreferUdp).await;
+        assert_eq!(udp_first.len(), 3);
+        assert!(matches!((&udp_first[0], &udp_first[1], &udp_first[2]), (TrackerEndpoint::Udp(_), TrackerEndpoint::Udp(_), TrackerEndpoint::Http(_))));
+
+        let http_first = announces_with_preference("tracker_preference_http", TrackerPreference::PreferHttp).await;
+        assert_eq!(http_first.len(), 1);
+        assert!(matches!(http_first[0], TrackerEndpoint::Http(_)));
+    }
+
+    #[tokio::test]
+    async fn downloads_share_a_memory_gate() {
+        // Room for a single piece between both downloads
+        let gate = MemoryGate::new(16_384);
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+
+        let mut downloads = vec![];
+        for test in ["shared_gate_first", "shared_gate_second"] {
+            let seed = mock_seed(data.clone(), 16_384, Duration::from_millis(10)).await;
+            let tracker = mock_tracker(vec![seed]).await;
+            let (torrent, mut config) = tracked_torrent(test, &data, 16_384, tracker).await;
+            config.memory_gate = Some(gate.clone());
+            downloads.push(Download::new(torrent, config));
+        }
+
+        // Each waits for the other to release room rather than ending incomplete
+        let (first, second) = tokio::join!(downloads[0].run(), downloads[1].run());
+        first.unwrap();
+        second.unwrap();
+
+        for download in &downloads {
+            let path = format!("{}/{}", download.config().download_path, download.torrent().info.name);
+            assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        }
+        assert_eq!(gate.in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn run_from_skips_earlier_pieces() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+
+        // The seed's first piece is corrupt, so requesting it would fail
+        let mut seeded = data.clone();
+        seeded[..16_384].fill(0);
+        let seed = mock_seed(seeded, 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("run_from_skips_earlier_pieces", &data, 16_384, tracker).await;
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        tokio::fs::write(&path, &data[..16_384]).await.unwrap();
+        let mut download = Download::new(torrent, config);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone())));
+
+        download.run_from(1).await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        let completed: Vec<u32> = events.lock().unwrap().iter().filter_map(|event| match event {
+            DownloadEvent::PieceCompleted(index) => Some(*index),
+            _ => None
+        }).collect();
+        assert_eq!(completed, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn run_from_reports_earlier_pieces_missing() {
+        let data = vec![7; 40_000];
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("run_from_reports_missing", &data, 16_384, tracker).await;
+        let result = Download::new(torrent, config).run_from(2).await;
+
+        assert!(matches!(result, Err(DownloadError::Incomplete(IncompletePieces { missing })) if missing == vec![0, 1]));
+    }
+
+    #[tokio::test]
+    async fn corrupt_piece_is_counted_as_waste() {
+        let data = vec![1; 20_000];
+        let seed = mock_seed(vec![0; 20_000], 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("corrupt_piece_is_counted_as_waste", &data, 16_384, tracker).await;
+        let mut download = Download::new(torrent, config);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone())));
+
+        let result = download.run().await;
+        assert!(matches!(result, Err(DownloadError::Incomplete(missing)) if missing.missing == vec![0, 1]));
+
+        let events = events.lock().unwrap();
+        assert!(events.contains(&DownloadEvent::PieceFailed { index: 0, reason: String::from("piece failed verification"), dump: None }));
+        assert!(matches!(events.last(), Some(DownloadEvent::PeerDisconnected { wasted_bytes: 0, failed_hash_bytes: 16_384, .. })));
+    }
+
+    #[tokio::test]
+    async fn custom_picker_chooses_pieces() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, config) = tracked_torrent("custom_picker_chooses_pieces", &data, 16_384, tracker).await;
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        let mut download = Download::new(torrent, config);
+        download.set_picker(Box::new(Reverse));
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone())));
+
+        download.run().await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        let completed: Vec<u32> = events.lock().unwrap().iter().filter_map(|event| match event {
+            DownloadEvent::PieceCompleted(index) => Some(*index),
+            _ => None
+        }).collect();
+        assert_eq!(completed, vec![2, 1, 0]);
+    }
+
+    /// A verifier that hashes nothing, accepting only the pieces it is given
+    struct AcceptOnly(Vec<u32>);
+
+    #[async_trait]
+    impl PieceVerifier for AcceptOnly {
+        async fn verify(&self, _torrent: &Torrent, index: u32, _piece: &[u8]) -> bool {
+            self.0.contains(&index)
+        }
+    }
+
+    #[tokio::test]
+    async fn external_verifier_decides_pieces() {
+        let data = vec![5; 20_000];
+        let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+        let tracker = mock_tracker(vec![seed]).await;
+
+        let (torrent, mut config) = tracked_torrent("external_verifier_decides_pieces", &data, 16_384, tracker).await;
+        config.trust_external_verifier = true;
+        let mut download = Download::new(torrent, config);
+        download.set_verifier(Arc::new(AcceptOnly(vec![0])));
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        download.set_event_hook(Arc::new(move |event: &DownloadEvent| recorded.lock().unwrap().push(event.clone())));
+
+        // Both pieces are correct, but only the verifier's verdict counts
+        let result = download.run().await;
+        assert!(matches!(result, Err(DownloadError::Incomplete(missing)) if missing.missing == vec![1]));
+
+        let events = events.lock().unwrap();
+        assert!(events.contains(&DownloadEvent::PieceCompleted(0)));
+        assert!(events.contains(&DownloadEvent::PieceFailed { index: 1, reason: String::from("piece failed verification"), dump: None }));
+        assert!(matches!(events.last(), Some(DownloadEvent::PeerDisconnected { failed_hash_bytes: 3_616, .. })));
+    }
+
+    #[tokio::test]
+    async fn external_verifier_needs_acknowledging() {
+        let tracker = mock_tracker(vec![]).await;
+        let (torrent, config) = tracked_torrent("external_verifier_needs_acknowledging", &[0; 16], 16, tracker).await;
+        let mut download = Download::new(torrent, config);
+        download.set_verifier(Arc::new(AcceptOnly(vec![0])));
+
+        assert!(matches!(download.run().await, Err(DownloadError::InvalidConfig(Error::InvalidConfig(_)))));
+    }
+
+    #[test]
+    fn early_messages_are_replayed() {
+        let torrent = Torrent::from_pieces("early", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        let early = vec![
+            Message::new(2, MessageType::Bitfield, Some(vec![0b1010_0000])),
+            Message::new(1, MessageType::Unchoke, None),
+            Message::new(5, MessageType::Have, Some(vec![0, 0, 0, 1])),
+        ];
+        let (peer_pieces, tolerated) = register_peer(&mut ledger, early, 4, true).unwrap();
+
+        assert_eq!(peer_pieces.indices().collect::<Vec<u32>>(), vec![0, 1, 2]);
+        assert_eq!(ledger.availability(), &[1, 1, 1, 0]);
+        assert!(tolerated.is_empty());
+
+        // Without a bitfield the peer only has the pieces it sent haves for
+        let haves = vec![Message::new(5, MessageType::Have, Some(vec![0, 0, 0, 3]))];
+        assert_eq!(register_peer(&mut ledger, haves, 4, true).unwrap().0.indices().collect::<Vec<u32>>(), vec![3]);
+        assert_eq!(register_peer(&mut ledger, vec![], 4, true).unwrap().0.indices().count(), 0);
+        assert_eq!(ledger.availability(), &[1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn bitfield_strictness() {
+        let torrent = Torrent::from_pieces("strictness", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        let bitfield = |byte: u8| Message::new(2, MessageType::Bitfield, Some(vec![byte]));
+        let have = Message::new(5, MessageType::Have, Some(vec![0, 0, 0, 3]));
+        let spare_bits = vec![bitfield(0b1000_0001)];
+        let late = vec![have.clone(), bitfield(0b1000_0000)];
+
+        let broken = |messages: &Vec<Message>, ledger: &mut PieceLedger| match register_peer(ledger, messages.clone(), 4, true) {
+            Err(Error::MalformedMessage(rule)) => rule,
+            result => panic!("{result:?}"),
+        };
+        assert_eq!(broken(&spare_bits, &mut ledger), "bitfield has spare bits set");
+        assert_eq!(broken(&late, &mut ledger), "bitfield sent after other messages");
+
+        // Leniently, spare bits are cleared and a late bitfield is merged with the haves before it
+        let (peer_pieces, tolerated) = register_peer(&mut ledger, spare_bits, 4, false).unwrap();
+        assert_eq!(peer_pieces.indices().collect::<Vec<u32>>(), vec![0]);
+        assert_eq!(tolerated, vec![String::from("bitfield has spare bits set")]);
+
+        let (peer_pieces, tolerated) = register_peer(&mut ledger, late, 4, false).unwrap();
+        assert_eq!(peer_pieces.indices().collect::<Vec<u32>>(), vec![0, 3]);
+        assert_eq!(tolerated, vec![String::from("bitfield sent after other messages")]);
+
+        // The wrong length is never tolerated
+        let long = vec![Message::new(3, MessageType::Bitfield, Some(vec![0xf0, 0]))];
+        assert!(register_peer(&mut ledger, long.clone(), 4, false).is_err());
+        assert!(register_peer(&mut ledger, long, 4, true).is_err());
+    }
+
+    #[tokio::test]
+    async fn too_few_peers() {
+        let tracker = mock_tracker(vec!["10.0.0.1:6881".parse().unwrap()]).await;
+        let (torrent, mut config) = tracked_torrent("too_few_peers", &[0; 16], 16, tracker).await;
+        config.min_peers = 2;
+
+        let result = Download::new(torrent, config).run().await;
+
+        assert!(matches!(result, Err(DownloadError::Discovery(Error::TrackerError(reason))) if reason == "found 1 peers, at least 2 needed"));
+    }
+
+    #[tokio::test]
+    async fn peers_are_combined_across_trackers() {
+        let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+        let first = mock_tracker(vec![mock_seed(data.clone(), 16_384, Duration::ZERO).await]).await;
+        let second = mock_tracker(vec![mock_seed(data.clone(), 16_384, Duration::ZERO).await]).await;
+
+        // Neither tracker returns enough peers on its own
+        let (mut torrent, mut config) = tracked_torrent("peers_are_combined", &data, 16_384, first).await;
+        torrent.announce_list = Some(vec![vec![format!("udp://{second}/announce")]]);
+        config.min_peers = 2;
+        let path = format!("{}/{}", config.download_path, torrent.info.name);
+        let download = Download::new(torrent, config);
+
+        download.run().await.unwrap();
+
+        assert_eq!(tokio::fs::read(path).await.unwrap(), data);
+        assert_eq!(download.tracker_stats().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn peer_wait_bounds_every_tracker_together() {
+        let tracker = mock_tracker(vec!["10.0.0.1:6881".parse().unwrap()]).await;
+        // Never answer, each would be waited on for all of peer_wait if it bounded a single tracker
+        let silent = [UdpSocket::bind("127.0.0.1:0").await.unwrap(), UdpSocket::bind("127.0.0.1:0").await.unwrap()];
+
+        let (mut torrent, mut config) = tracked_torrent("peer_wait_bounds_every_tracker", &[0; 16], 16, tracker).await;
+        torrent.announce_list = Some(silent.iter().map(|socket| vec![format!("udp://{}/announce", socket.local_addr().unwrap())]).collect());
+        config.min_peers = 2;
+        config.peer_wait = Some(Duration::from_secs(1));
+
+        let started = std::time::Instant::now();
+        let result = Download::new(torrent, config).run().await;
+
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(1_800), "{elapsed:?}");
+        assert!(matches!(result, Err(DownloadError::Discovery(Error::TrackerError(reason))) if reason == "found 1 peers, at least 2 needed"));
+    }
+
+    #[tokio::test]
+    async fn locked_download_is_refused() {
+        let tracker = mock_tracker(vec![]).await;
+        let (torrent, config) = tracked_torrent("locked", &[0; 16], 16, tracker).await;
+        let _lock = DownloadLock::acquire(&config.download_path, &torrent.info.name).unwrap();
+
+        let result = Download::new(torrent, config).run().await;
+
+        assert!(matches!(result, Err(DownloadError::Locked(LockError::Held { holder: Some(_), .. }))));
+    }
+}
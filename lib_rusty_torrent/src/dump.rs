@@ -0,0 +1,168 @@
+//! Dumps pieces that fail verification, with how they were downloaded, so a rare hash failure
+//! can be traced back to a peer or a block
+//!
+//! A dump is a text report, a blank line, then the piece exactly as it was received. Dumps are
+//! named after the time they were written, so the oldest are evicted first.
+
+// Crate Imports
+use crate::{ error::hex, peer::BlockTiming };
+
+// External imports
+use sha1::{ Digest, Sha1 };
+use std::{
+    fmt::Write,
+    io,
+    net::SocketAddrV4,
+    path::{ Path, PathBuf },
+    time::{ SystemTime, UNIX_EPOCH }
+};
+
+/// A piece that failed verification, and how it was downloaded.
+#[derive(Debug)]
+pub struct FailedPiece<'a> {
+    /// The index of the piece.
+    pub index: u32,
+    /// The piece, as it was received.
+    pub piece: &'a [u8],
+    /// The hash the torrent gives for the piece.
+    pub expected: [u8; 20],
+    /// The peer the piece was downloaded from.
+    pub address: SocketAddrV4,
+    /// The peer id the peer sent in its handshake.
+    pub peer_id: &'a str,
+    /// The blocks requested from the peer for the piece, in the order they were requested.
+    pub timeline: &'a [BlockTiming],
+}
+
+impl FailedPiece<'_> {
+    /// Describes the piece and how it was downloaded, for the start of a dump
+    fn report(&self) -> String {
+        let computed: [u8; 20] = Sha1::digest(self.piece).into();
+        let received: Vec<String> = self.timeline.iter()
+            .filter(|block| block.received_at.is_some())
+            .map(|block| format!("{}..{}", block.offset, block.offset + block.length))
+            .collect();
+
+        let mut report = String::new();
+        let _ = writeln!(report, "piece {}, {} bytes", self.index, self.piece.len());
+        let _ = writeln!(report, "expected {}", hex(&self.expected));
+        let _ = writeln!(report, "computed {}", hex(&computed));
+        let _ = writeln!(report, "peer {} {:?} sent {}", self.address, self.peer_id, received.join(", "));
+
+        // Times are relative to the first request, so the report reads the same in any timezone
+        let start = self.timeline.first().map_or(UNIX_EPOCH, |block| block.requested_at);
+        let since_start = |time: SystemTime| time.duration_since(start).unwrap_or_default().as_micros();
+        for block in self.timeline {
+            let _ = match block.received_at {
+                Some(received_at) => writeln!(
+                    report, "request {}+{} at +{}us, received at +{}us",
+                    block.offset, block.length, since_start(block.requested_at), since_start(received_at)
+                ),
+                None => writeln!(report, "request {}+{} at +{}us, never received", block.offset, block.length, since_start(block.requested_at)),
+            };
+        }
+
+        report
+    }
+}
+
+/// Writes dumps of failed pieces into a directory, evicting the oldest once they take up more
+/// than a set size.
+#[derive(Clone, Debug)]
+pub struct PieceDumper {
+    /// Where dumps are written
+    directory: PathBuf,
+    /// The most bytes the dumps in the directory can take up together
+    max_bytes: u64,
+}
+
+impl PieceDumper {
+    /// Creates a dumper, the directory is created with the first dump.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - Where dumps are written, other files in it are left alone.
+    /// * `max_bytes` - The most bytes the dumps can take up together, the latest dump is kept
+    ///   even if it is larger on its own.
+    pub fn new(directory: PathBuf, max_bytes: u64) -> Self {
+        Self { directory, max_bytes }
+    }
+
+    /// Dumps a failed piece, then evicts the oldest dumps until they fit.
+    ///
+    /// # Returns
+    ///
+    /// * The path of the dump.
+    pub async fn dump(&self, failure: &FailedPiece<'_>) -> io::Result<PathBuf> {
+        tokio::fs::create_dir_all(&self.directory).await?;
+
+        let micros = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros();
+        let path = self.directory.join(format!("{micros:020}-piece-{}.dump", failure.index));
+
+        let mut contents = failure.report().into_bytes();
+        contents.push(b'\n');
+        contents.extend(failure.piece);
+        tokio::fs::write(&path, contents).await?;
+
+        self.evict(&path).await?;
+        Ok(path)
+    }
+
+    /// Removes the oldest dumps, other than the one just written, until they fit in `max_bytes`
+    async fn evict(&self, latest: &Path) -> io::Result<()> {
+        let mut dumps = vec![];
+        let mut entries = tokio::fs::read_dir(&self.directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.path().extension().is_some_and(|extension| extension == "dump") {
+                dumps.push((entry.path(), entry.metadata().await?.len()));
+            }
+        }
+        dumps.sort();
+
+        let mut total: u64 = dumps.iter().map(|(_, length)| length).sum();
+        for (path, length) in dumps {
+            if total <= self.max_bytes {
+                break
+            }
+
+            if path != latest {
+                tokio::fs::remove_file(&path).await?;
+                total -= length;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::tests::download_dir;
+
+    #[tokio::test]
+    async fn oldest_dumps_are_evicted() {
+        let directory = PathBuf::from(download_dir("dump_eviction").await);
+        tokio::fs::write(directory.join("notes.txt"), vec![0; 4096]).await.unwrap();
+        let piece = vec![0xab; 1000];
+        let failure = |index| FailedPiece { index, piece: &piece, expected: [0; 20], address: "10.0.0.1:6881".parse().unwrap(), peer_id: "", timeline: &[] };
+
+        // Room for two dumps, but not three
+        let dumper = PieceDumper::new(directory.clone(), 2500);
+        let mut dumps = vec![];
+        for index in 0..3 {
+            dumps.push(dumper.dump(&failure(index)).await.unwrap());
+        }
+
+        assert!(!tokio::fs::try_exists(&dumps[0]).await.unwrap());
+        assert!(tokio::fs::try_exists(&dumps[1]).await.unwrap());
+        assert!(tokio::fs::try_exists(&dumps[2]).await.unwrap());
+        assert!(tokio::fs::try_exists(directory.join("notes.txt")).await.unwrap());
+
+        // A dump larger than the limit on its own is still kept
+        let dumper = PieceDumper::new(directory.clone(), 10);
+        let latest = dumper.dump(&failure(3)).await.unwrap();
+        assert!(tokio::fs::try_exists(&latest).await.unwrap());
+        assert!(!tokio::fs::try_exists(&dumps[2]).await.unwrap());
+    }
+}
@@ -0,0 +1,303 @@
+//! Errors describing why a download, or an attempt to download a piece, failed
+
+use std::{ fmt, io, net::SocketAddrV4 };
+
+use crate::lock::LockError;
+
+/// Why an operation on a peer, tracker or torrent failed.
+#[derive(Debug)]
+pub enum Error {
+    /// A connection to a peer couldn't be opened.
+    PeerConnectionFailed(SocketAddrV4, io::Error),
+    /// A peer's handshake was missing, malformed, or not the one expected.
+    HandshakeFailed(String),
+    /// A peer sent a message of a type the client doesn't know.
+    InvalidMessage(u8),
+    /// A message was too short, the wrong length, or missing its payload.
+    MalformedMessage(String),
+    /// A piece doesn't match its hash in the torrent.
+    PieceHashMismatch {
+        /// The index of the piece.
+        index: u32,
+        /// The hash of the piece in the torrent.
+        expected: [u8; 20],
+        /// The SHA-1 digest of the piece.
+        got: [u8; 20],
+    },
+    /// A tracker couldn't be reached, or responded with an error or something unreadable.
+    TrackerError(String),
+    /// A torrent file couldn't be read.
+    UnreadableTorrent {
+        /// The path of the torrent file.
+        path: String,
+        /// Why it couldn't be read.
+        source: io::Error,
+    },
+    /// A torrent file isn't bencoded as a torrent.
+    TorrentParseError(serde_bencode::Error),
+    /// A torrent parsed, but its contents can't be downloaded.
+    InvalidTorrent(String),
+    /// Reading from or writing to a connection failed.
+    IoError(io::Error),
+    /// The configuration doesn't allow what was asked of it.
+    InvalidConfig(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::PeerConnectionFailed(address, err) => write!(f, "unable to connect to {address}, {err}"),
+            Error::HandshakeFailed(reason) => write!(f, "{reason}"),
+            Error::InvalidMessage(message_type) => write!(f, "Invalid Message Type {message_type}"),
+            Error::MalformedMessage(reason) => write!(f, "{reason}"),
+            Error::PieceHashMismatch { index, expected, got } => {
+                write!(f, "piece {index} hash mismatch, got {} expected {}", hex(got), hex(expected))
+            }
+            Error::TrackerError(reason) => write!(f, "{reason}"),
+            Error::UnreadableTorrent { path, .. } => write!(f, "unable to read file at {path}"),
+            Error::TorrentParseError(err) => write!(f, "unable to parse torrent file, {err}"),
+            Error::InvalidTorrent(reason) => write!(f, "{reason}"),
+            Error::IoError(err) => write!(f, "{err}"),
+            Error::InvalidConfig(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::PeerConnectionFailed(_, err) | Error::UnreadableTorrent { source: err, .. } | Error::IoError(err) => Some(err),
+            Error::TorrentParseError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<serde_bencode::Error> for Error {
+    fn from(err: serde_bencode::Error) -> Self {
+        Error::TorrentParseError(err)
+    }
+}
+
+/// Whether a failed piece is downloaded again, and from which peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryHint {
+    /// The piece is requested again from the same peer once it unchokes the client.
+    SamePeer,
+    /// The piece is requested again from another peer.
+    OtherPeer,
+    /// The piece isn't retried, the problem lies with the client rather than a peer.
+    Never,
+}
+
+/// Why an attempt to download a piece failed.
+#[derive(Debug)]
+pub enum PieceError {
+    /// The peer choked the client before the piece was complete.
+    PeerChoked,
+    /// The connection to the peer was closed or reset.
+    PeerDisconnected,
+    /// The downloaded piece doesn't match the hash in the torrent.
+    HashMismatch {
+        /// The SHA-1 digest of the downloaded piece.
+        got: [u8; 20],
+        /// The hash of the piece in the torrent.
+        expected: [u8; 20],
+    },
+    /// The peer didn't respond to a request in time.
+    Timeout,
+    /// The peer sent something the protocol doesn't allow.
+    ProtocolViolation(String),
+    /// The piece couldn't be written to or read back from disk.
+    StorageError(io::Error),
+    /// The piece index isn't in the torrent, so no peer can supply it.
+    InvalidIndex(u32),
+}
+
+impl PieceError {
+    /// Returns whether, and from which peers, the piece is downloaded again.
+    pub fn retry_hint(&self) -> RetryHint {
+        match self {
+            PieceError::PeerChoked => RetryHint::SamePeer,
+            PieceError::PeerDisconnected
+            | PieceError::HashMismatch { .. }
+            | PieceError::Timeout
+            | PieceError::ProtocolViolation(_) => RetryHint::OtherPeer,
+            PieceError::StorageError(_) | PieceError::InvalidIndex(_) => RetryHint::Never,
+        }
+    }
+
+    /// Returns whether the piece is downloaded again.
+    pub fn will_retry(&self) -> bool {
+        self.retry_hint() != RetryHint::Never
+    }
+}
+
+impl fmt::Display for PieceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PieceError::PeerChoked => write!(f, "peer choked the client"),
+            PieceError::PeerDisconnected => write!(f, "peer disconnected"),
+            PieceError::HashMismatch { got, expected } => {
+                write!(f, "piece hash mismatch, got {} expected {}", hex(got), hex(expected))
+            }
+            PieceError::Timeout => write!(f, "peer timed out"),
+            PieceError::ProtocolViolation(reason) => write!(f, "peer violated the protocol, {reason}"),
+            PieceError::StorageError(err) => write!(f, "storage error, {err}"),
+            PieceError::InvalidIndex(index) => write!(f, "piece {index} isn't in the torrent"),
+        }
+    }
+}
+
+impl std::error::Error for PieceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PieceError::StorageError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PieceError {
+    fn from(err: io::Error) -> Self {
+        PieceError::StorageError(err)
+    }
+}
+
+/// A download ended with pieces that none of the connected peers could supply.
+#[derive(Debug, PartialEq)]
+pub struct IncompletePieces {
+    /// The indices of the pieces that couldn't be obtained.
+    pub missing: Vec<u32>,
+}
+
+impl fmt::Display for IncompletePieces {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let missing: Vec<String> = self.missing.iter().map(u32::to_string).collect();
+
+        write!(f, "download incomplete, {} pieces couldn't be obtained: {}", missing.len(), missing.join(", "))
+    }
+}
+
+impl std::error::Error for IncompletePieces {}
+
+/// Why a download ended without every piece being downloaded.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The configuration doesn't allow the download, see `Error::InvalidConfig`.
+    InvalidConfig(Error),
+    /// The torrent can't be downloaded with the configuration given.
+    InvalidTorrent(Error),
+    /// No trackers, or not enough peers, could be found.
+    Discovery(Error),
+    /// Connecting to a peer, or completing the handshake, failed.
+    Peer(Error),
+    /// A piece couldn't be written to or read back from disk.
+    Storage {
+        /// The index of the piece.
+        index: u32,
+        /// Why it couldn't be written.
+        source: PieceError,
+    },
+    /// No more pieces could be downloaded from the peers available.
+    Incomplete(IncompletePieces),
+    /// Peer tasks panicked and the peers left couldn't download the pieces still missing, a bug
+    /// rather than a problem with the swarm.
+    PeersPanicked {
+        /// The number of peer tasks that panicked during the run.
+        panics: usize,
+        /// The pieces that couldn't be obtained.
+        missing: IncompletePieces,
+    },
+    /// Another process is downloading the torrent into the same files.
+    Locked(LockError),
+    /// The torrent's files couldn't be created.
+    Files(io::Error),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::InvalidConfig(err) => write!(f, "invalid configuration, {err}"),
+            DownloadError::InvalidTorrent(err) => write!(f, "invalid torrent, {err}"),
+            DownloadError::Discovery(err) => write!(f, "peer discovery failed, {err}"),
+            DownloadError::Peer(err) => write!(f, "peer connection failed, {err}"),
+            DownloadError::Storage { index, source } => write!(f, "unable to write piece {index}, {source}"),
+            DownloadError::Incomplete(missing) => write!(f, "{missing}"),
+            DownloadError::PeersPanicked { panics, missing } => write!(f, "{panics} peer tasks panicked, {missing}"),
+            DownloadError::Locked(err) => write!(f, "{err}"),
+            DownloadError::Files(err) => write!(f, "unable to create the torrent's files, {err}"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::InvalidConfig(err)
+            | DownloadError::InvalidTorrent(err)
+            | DownloadError::Discovery(err)
+            | DownloadError::Peer(err) => Some(err),
+            DownloadError::Storage { source, .. } => Some(source),
+            DownloadError::Incomplete(missing) | DownloadError::PeersPanicked { missing, .. } => Some(missing),
+            DownloadError::Locked(err) => Some(err),
+            DownloadError::Files(err) => Some(err),
+        }
+    }
+}
+
+impl From<IncompletePieces> for DownloadError {
+    fn from(missing: IncompletePieces) -> Self {
+        DownloadError::Incomplete(missing)
+    }
+}
+
+impl From<LockError> for DownloadError {
+    fn from(err: LockError) -> Self {
+        DownloadError::Locked(err)
+    }
+}
+
+/// Formats a hash as lowercase hex
+pub(crate) fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_hints() {
+        assert_eq!(PieceError::PeerChoked.retry_hint(), RetryHint::SamePeer);
+        assert_eq!(PieceError::PeerDisconnected.retry_hint(), RetryHint::OtherPeer);
+        assert_eq!(PieceError::HashMismatch { got: [0; 20], expected: [1; 20] }.retry_hint(), RetryHint::OtherPeer);
+        assert_eq!(PieceError::Timeout.retry_hint(), RetryHint::OtherPeer);
+        assert_eq!(PieceError::ProtocolViolation(String::new()).retry_hint(), RetryHint::OtherPeer);
+
+        let storage = PieceError::from(io::Error::new(io::ErrorKind::StorageFull, "disk full"));
+        assert!(matches!(storage, PieceError::StorageError(_)));
+        assert!(!storage.will_retry());
+        assert!(!PieceError::InvalidIndex(3).will_retry());
+    }
+
+    #[test]
+    fn hash_mismatch_message() {
+        let error = PieceError::HashMismatch { got: [0xab; 20], expected: [0x01; 20] };
+
+        assert_eq!(error.to_string(), format!("piece hash mismatch, got {} expected {}", "ab".repeat(20), "01".repeat(20)));
+    }
+
+    #[test]
+    fn incomplete_pieces_message() {
+        let error = IncompletePieces { missing: vec![3, 5, 8] };
+
+        assert_eq!(error.to_string(), "download incomplete, 3 pieces couldn't be obtained: 3, 5, 8");
+    }
+}
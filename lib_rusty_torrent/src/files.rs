@@ -1,30 +1,40 @@
 use tokio::{
   fs::try_exists as dir_exists,
   fs::create_dir as create_dir,
-  fs::File, 
-  io::AsyncWriteExt
+  fs::{File, OpenOptions},
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom}
 };
 
-use crate::torrent::Torrent;
+use std::io;
+
+use crate::{config::VerifyPolicy, error::{Error, PieceError}, torrent::Torrent, verifier::PieceVerifier};
 
 /// Represents information about a file being downloaded.
 #[derive(Debug)]
 struct FileInfo {
   file: File,
+  /// The offset of the start of the file within the torrent
+  offset: u64,
   length: u64,
-  current_length: u64,
   name: String,
-  complete: bool
 } 
 
 /// Represents a collection of files being downloaded.
 #[derive(Debug)]
-pub struct Files(Vec<FileInfo>);
+pub struct Files {
+  files: Vec<FileInfo>,
+}
+
+impl Default for Files {
+  fn default() -> Self {
+    Self::new()
+  }
+}
 
 impl Files {
   /// Creates a new `Files` instance.
   pub fn new() -> Self {
-    Self(vec![])
+    Self { files: vec![] }
   }
   
   /// Creates the files in the local system for downloading.
@@ -33,70 +43,374 @@ impl Files {
   ///
   /// * `torrent` - The `Torrent` instance describing the torrent.
   /// * `download_path` - The path where the files will be downloaded.
-  pub async fn create_files(&mut self, torrent: &Torrent, download_path: &str) {
+  /// * `torrent_dir` - Whether a multi file torrent's files are nested in a directory named
+  ///   after the torrent, as other clients do.
+  ///
+  /// # Returns
+  ///
+  /// * `Error::InvalidTorrent` if a name in the torrent would place a file outside
+  ///   `download_path`, see `Torrent::check_paths`, or `Error::IoError` if a directory or file
+  ///   couldn't be created.
+  pub async fn create_files(&mut self, torrent: &Torrent, download_path: &str, torrent_dir: bool) -> Result<(), Error> {
+    // Names are checked before anything is created
+    torrent.check_paths()?;
+
     match &torrent.info.files {
       // Single File Mode
       None => {
         let path = &format!("{download_path}/{}", torrent.info.name);
-        let file = File::create(&path).await.unwrap();
-        
         let length = torrent.info.length.unwrap_or(0) as u64;
+        let file = open_file(path, length).await?;
         
-        self.0.push(FileInfo { file, length, current_length: 0, name: path.to_string(), complete: false })
+        self.files.push(FileInfo { file, offset: 0, length, name: path.to_string() })
       }
       
       // Multi File Mode
       Some(files) => {
+        let mut offset = 0;
+
+        let mut root = download_path.to_string();
+        if torrent_dir {
+          root.push('/');
+          root.push_str(&torrent.info.name);
+
+          if !dir_exists(&root).await? {
+            create_dir(&root).await?;
+          }
+        }
+
         for t_file in files {
-          let mut path = download_path.to_string();
+          let mut path = root.clone();
+          let file_path = t_file.local_path();
           
-          for dir in &t_file.path[..t_file.path.len() - 1] {
+          for dir in &file_path[..file_path.len() - 1] {
             path.push('/');
             path.push_str(dir);
             
-            if !dir_exists(&path).await.unwrap() {
-              create_dir(&path).await.unwrap();
+            if !dir_exists(&path).await? {
+              create_dir(&path).await?;
             }
           }
           
           path.push('/');
-          path.push_str(&t_file.path[t_file.path.len() - 1]);
+          path.push_str(&file_path[file_path.len() - 1]);
           
-          let file = File::create(&path).await.unwrap();
           let length = t_file.length;
+          let file = open_file(&path, length).await?;
           
-          self.0.push(FileInfo { file, length, current_length: 0, name: path.to_string(), complete: false });
+          self.files.push(FileInfo { file, offset, length, name: path.to_string() });
+          offset += length;
         }
       }
     }
+
+    Ok(())
   }
   
-  /// Writes a piece of data to the appropriate files.
+  /// Verifies a piece against its hash and writes it to its place in the files.
+  ///
+  /// With `VerifyPolicy::BeforeWrite` a piece that doesn't match its hash is never written.
+  /// With `VerifyPolicy::AfterWrite` the piece is written first and verified by reading it back,
+  /// a piece that doesn't match is left on disk to be overwritten when it is downloaded again.
   ///
   /// # Arguments
   ///
-  /// * `piece` - The piece of data to write.
-  pub async fn write_piece(&mut self, piece: Vec<u8>) {
-    let mut j = 0;
-    
-    let mut piece_len = piece.len() as u64;
-    let file_iterator = self.0.iter_mut();
-    
-    for file in file_iterator {
-      
-      if file.complete { continue }
-      
-      if file.current_length + piece_len > file.length {
-        let n = file.file.write(&piece[j..(file.length - file.current_length) as usize]).await.unwrap();
-        j = (file.length - file.current_length) as usize;
-        file.current_length += j as u64;
-        piece_len -= j as u64;
-        file.complete = true;
-      } else {
-        let n = file.file.write(&piece[j..]).await.unwrap();
-        file.current_length += piece_len;
-        return
+  /// * `torrent` - The `Torrent` the piece belongs to.
+  /// * `index` - The index of the piece.
+  /// * `piece` - The downloaded piece.
+  /// * `policy` - When the piece is verified.
+  /// * `verifier` - Decides whether the piece is correct.
+  ///
+  /// # Returns
+  ///
+  /// * `true` if the piece was accepted by the verifier, `false` otherwise, or a
+  ///   `PieceError::StorageError` if it couldn't be written or read back.
+  pub async fn write_verified_piece(&mut self, torrent: &Torrent, index: u32, piece: Vec<u8>, policy: VerifyPolicy, verifier: &dyn PieceVerifier) -> Result<bool, PieceError> {
+    let offset = index as u64 * torrent.info.piece_length;
+
+    match policy {
+      VerifyPolicy::BeforeWrite => {
+        if !verifier.verify(torrent, index, &piece).await {
+          return Ok(false)
+        }
+
+        self.write_at(offset, &piece).await?;
+        Ok(true)
+      }
+      VerifyPolicy::AfterWrite => {
+        let length = piece.len() as u64;
+        self.write_at(offset, &piece).await?;
+        drop(piece);
+
+        let written = self.read_at(offset, length).await?;
+        Ok(verifier.verify(torrent, index, &written).await)
+      }
+    }
+  }
+
+  /// Returns whether a piece is already on disk and accepted by the verifier, a piece that
+  /// can't be read, as the files are too short, isn't.
+  ///
+  /// # Arguments
+  ///
+  /// * `torrent` - The `Torrent` the piece belongs to.
+  /// * `index` - The index of the piece.
+  /// * `verifier` - Decides whether the piece is correct.
+  pub async fn has_piece(&mut self, torrent: &Torrent, index: u32, verifier: &dyn PieceVerifier) -> bool {
+    let offset = index as u64 * torrent.info.piece_length;
+
+    match self.read_at(offset, torrent.get_piece_length(index)).await {
+      Ok(piece) => verifier.verify(torrent, index, &piece).await,
+      Err(_) => false
+    }
+  }
+
+  /// Writes data at the given offset within the torrent, spanning files where needed.
+  ///
+  /// # Arguments
+  ///
+  /// * `offset` - The offset within the torrent to write at.
+  /// * `data` - The data to write.
+  pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+    let end = offset + data.len() as u64;
+
+    for file in self.files.iter_mut() {
+      let Some((start, stop)) = file.overlap(offset, end) else { continue };
+
+      let position = SeekFrom::Start(start - file.offset);
+      let bytes = &data[(start - offset) as usize..(stop - offset) as usize];
+
+      if let Err(err) = file.file.seek(position).await {
+        return Err(io::Error::new(err.kind(), format!("Error seeking in {}: {err}", file.name)))
+      }
+
+      if let Err(err) = file.file.write_all(bytes).await {
+        return Err(io::Error::new(err.kind(), format!("Error writing to {}: {err}", file.name)))
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Reads data from the given offset within the torrent, spanning files where needed.
+  ///
+  /// # Arguments
+  ///
+  /// * `offset` - The offset within the torrent to read from.
+  /// * `length` - The number of bytes to read.
+  pub async fn read_at(&mut self, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let end = offset + length;
+    let mut buf = vec![0; length as usize];
+
+    for file in self.files.iter_mut() {
+      let Some((start, stop)) = file.overlap(offset, end) else { continue };
+
+      let position = SeekFrom::Start(start - file.offset);
+      let bytes = &mut buf[(start - offset) as usize..(stop - offset) as usize];
+
+      if let Err(err) = file.file.seek(position).await {
+        return Err(io::Error::new(err.kind(), format!("Error seeking in {}: {err}", file.name)))
+      }
+
+      if let Err(err) = file.file.read_exact(bytes).await {
+        return Err(io::Error::new(err.kind(), format!("Error reading from {}: {err}", file.name)))
       }
     }
+
+    Ok(buf)
+  }
+}
+
+impl FileInfo {
+  /// Returns the part of the range `start..end` within the torrent that lies in this file.
+  fn overlap(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+    let start = start.max(self.offset);
+    let end = end.min(self.offset + self.length);
+
+    if start < end {
+      Some((start, end))
+    } else {
+      None
+    }
+  }
+}
+
+/// Opens or creates a file that can be both written to and read back from, keeping what an
+/// earlier download wrote to it but cutting it to at most `length` bytes.
+async fn open_file(path: &str, length: u64) -> std::io::Result<File> {
+  let file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(path)
+    .await?;
+
+  if file.metadata().await?.len() > length {
+    file.set_len(length).await?;
+  }
+
+  Ok(file)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::{torrent::File as TorrentFile, verifier::LocalVerifier};
+  use async_trait::async_trait;
+
+  /// A verifier that gives the same verdict for every piece
+  struct FixedVerdict(bool);
+
+  #[async_trait]
+  impl PieceVerifier for FixedVerdict {
+    async fn verify(&self, _torrent: &Torrent, _index: u32, _piece: &[u8]) -> bool {
+      self.0
+    }
+  }
+
+  /// Creates an empty directory to download into, unique to each test
+  pub(crate) async fn download_dir(test: &str) -> String {
+    let path = std::env::temp_dir().join(format!("rusty_torrent_{test}_{}", std::process::id()));
+    let _ = tokio::fs::remove_dir_all(&path).await;
+    tokio::fs::create_dir_all(&path).await.unwrap();
+
+    path.to_str().unwrap().to_string()
+  }
+
+  #[tokio::test]
+  async fn write_verified_piece_before_write() {
+    let data: Vec<u8> = (0..=255).collect();
+    let torrent = Torrent::from_pieces("before_write", 128, &data);
+    let path = download_dir("before_write").await;
+
+    let mut files = Files::new();
+    files.create_files(&torrent, &path, true).await.unwrap();
+
+    let valid = files.write_verified_piece(&torrent, 1, data[128..].to_vec(), VerifyPolicy::BeforeWrite, &LocalVerifier).await;
+    assert!(matches!(valid, Ok(true)));
+
+    let invalid = files.write_verified_piece(&torrent, 0, vec![0; 128], VerifyPolicy::BeforeWrite, &LocalVerifier).await;
+    assert!(matches!(invalid, Ok(false)));
+
+    // The corrupt piece was never written
+    assert_eq!(files.read_at(0, 128).await.unwrap(), vec![0; 128]);
+    assert_eq!(files.read_at(128, 128).await.unwrap(), data[128..].to_vec());
+  }
+
+  #[tokio::test]
+  async fn write_verified_piece_after_write() {
+    let data: Vec<u8> = (0..=255).collect();
+    let torrent = Torrent::from_pieces("after_write", 128, &data);
+    let path = download_dir("after_write").await;
+
+    let mut files = Files::new();
+    files.create_files(&torrent, &path, true).await.unwrap();
+
+    let invalid = files.write_verified_piece(&torrent, 0, vec![1; 128], VerifyPolicy::AfterWrite, &LocalVerifier).await;
+    assert!(matches!(invalid, Ok(false)));
+
+    // Downloading the piece again overwrites the corrupt data
+    let valid = files.write_verified_piece(&torrent, 0, data[..128].to_vec(), VerifyPolicy::AfterWrite, &LocalVerifier).await;
+    assert!(matches!(valid, Ok(true)));
+    assert_eq!(files.read_at(0, 128).await.unwrap(), data[..128].to_vec());
+  }
+
+  #[tokio::test]
+  async fn write_verified_piece_respects_verifier() {
+    let data: Vec<u8> = (0..=255).collect();
+    let torrent = Torrent::from_pieces("verifier", 128, &data);
+    let path = download_dir("verifier").await;
+
+    let mut files = Files::new();
+    files.create_files(&torrent, &path, true).await.unwrap();
+
+    // A corrupt piece the verifier accepts is written
+    let accepted = files.write_verified_piece(&torrent, 0, vec![1; 128], VerifyPolicy::BeforeWrite, &FixedVerdict(true)).await;
+    assert!(matches!(accepted, Ok(true)));
+    assert_eq!(files.read_at(0, 128).await.unwrap(), vec![1; 128]);
+
+    // A correct piece the verifier rejects isn't
+    let rejected = files.write_verified_piece(&torrent, 1, data[128..].to_vec(), VerifyPolicy::BeforeWrite, &FixedVerdict(false)).await;
+    assert!(matches!(rejected, Ok(false)));
+    assert_eq!(tokio::fs::metadata(format!("{path}/verifier")).await.unwrap().len(), 128);
+
+    let rejected = files.write_verified_piece(&torrent, 1, data[128..].to_vec(), VerifyPolicy::AfterWrite, &FixedVerdict(false)).await;
+    assert!(matches!(rejected, Ok(false)));
+  }
+
+  #[tokio::test]
+  async fn multi_file_torrent_dir() {
+    let mut torrent = Torrent::from_pieces("album", 16, &[0; 16]);
+    torrent.info.length = None;
+    torrent.info.files = Some(vec![
+      serde_bencode::from_str::<TorrentFile>("d6:lengthi4e4:pathl9:cover.jpgee").unwrap(),
+      serde_bencode::from_str::<TorrentFile>("d6:lengthi12e4:pathl6:disc 110:track.flacee").unwrap(),
+    ]);
+
+    let path = download_dir("torrent_dir").await;
+    Files::new().create_files(&torrent, &path, true).await.unwrap();
+    assert!(tokio::fs::try_exists(format!("{path}/album/cover.jpg")).await.unwrap());
+    assert!(tokio::fs::try_exists(format!("{path}/album/disc 1/track.flac")).await.unwrap());
+
+    let path = download_dir("no_torrent_dir").await;
+    Files::new().create_files(&torrent, &path, false).await.unwrap();
+    assert!(tokio::fs::try_exists(format!("{path}/cover.jpg")).await.unwrap());
+    assert!(!tokio::fs::try_exists(format!("{path}/album")).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn create_files_reports_errors() {
+    let torrent = Torrent::from_pieces("blocked", 16, &[0; 16]);
+    let path = download_dir("create_files_error").await;
+    tokio::fs::write(format!("{path}/file"), b"").await.unwrap();
+
+    // The download path is a file rather than a directory
+    assert!(Files::new().create_files(&torrent, &format!("{path}/file"), true).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn escaping_paths_are_rejected() {
+    let path = download_dir("escaping").await;
+    let escaping = [
+      "d6:lengthi16e4:pathl2:..7:escapedee",
+      "d6:lengthi16e4:pathl13:/tmp/absoluteee",
+      "d6:lengthi16e4:pathl1:a0:ee",
+      "d6:lengthi16e4:pathl9:a/escapedee",
+      "d6:lengthi16e4:pathlee",
+    ];
+
+    for file in escaping {
+      let mut torrent = Torrent::from_pieces("escaping", 16, &[0; 16]);
+      torrent.info.length = None;
+      torrent.info.files = Some(vec![serde_bencode::from_str::<TorrentFile>(file).unwrap()]);
+
+      let result = Files::new().create_files(&torrent, &path, false).await;
+      assert!(matches!(result, Err(Error::InvalidTorrent(_))), "{file}");
+    }
+
+    // The torrent's name is checked too, it names the file or directory the torrent is downloaded to
+    for name in ["..", "/tmp/absolute", "a/../.."] {
+      let torrent = Torrent::from_pieces(name, 16, &[0; 16]);
+      assert!(matches!(Files::new().create_files(&torrent, &path, true).await, Err(Error::InvalidTorrent(_))), "{name}");
+    }
+
+    assert!(!tokio::fs::try_exists(std::env::temp_dir().join("escaped")).await.unwrap());
+    assert!(!tokio::fs::try_exists("/tmp/absolute").await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn path_utf8_is_preferred() {
+    // The path is UTF-8 read as Latin-1, the way some clients encode it
+    let mut torrent = Torrent::from_pieces("dual", 16, &[0; 16]);
+    torrent.info.length = None;
+    torrent.info.files = Some(vec![
+      serde_bencode::from_str::<TorrentFile>("d6:lengthi16e4:pathl11:caf\u{c3}\u{a9}.txte10:path.utf-8l9:caf\u{e9}.txtee").unwrap(),
+    ]);
+
+    let path = download_dir("path_utf8").await;
+    Files::new().create_files(&torrent, &path, false).await.unwrap();
+
+    assert!(tokio::fs::try_exists(format!("{path}/caf\u{e9}.txt")).await.unwrap());
   }
 }
\ No newline at end of file
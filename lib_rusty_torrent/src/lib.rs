@@ -2,4 +2,21 @@ pub mod torrent;
 pub mod peer_wire_protocol;
 pub mod peer;
 pub mod files;
-pub mod tracker;
\ No newline at end of file
+pub mod tracker;
+pub mod config;
+pub mod bitfield;
+pub mod picker;
+pub mod verifier;
+pub mod error;
+pub mod rtt;
+pub mod resolver;
+pub mod download;
+pub mod lock;
+pub mod candidate;
+pub mod coordinator;
+pub mod socket;
+pub mod dump;
+#[cfg(feature = "wire-debug")]
+pub mod capture;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
@@ -0,0 +1,225 @@
+//! A lock preventing two processes from downloading a torrent into the same files
+
+use std::{
+    fmt,
+    fs::{ self, File, OpenOptions, TryLockError },
+    io::{ self, Write },
+    path::{ Path, PathBuf },
+    time::SystemTime
+};
+
+use crate::{ error::Error, torrent::check_path_component };
+
+/// The process holding the lock on a torrent's files, as recorded in the lock file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LockHolder {
+    /// The process id of the holder.
+    pub pid: u32,
+    /// When the holder took the lock, in seconds since the unix epoch.
+    pub started: u64,
+}
+
+impl LockHolder {
+    /// Reads the holder from the contents of a lock file, `None` if it isn't recorded.
+    fn parse(contents: &str) -> Option<Self> {
+        let (pid, started) = contents.trim().split_once(' ')?;
+
+        Some(Self { pid: pid.parse().ok()?, started: started.parse().ok()? })
+    }
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "process {}, started {}s after the unix epoch", self.pid, self.started)
+    }
+}
+
+/// Why a torrent's files couldn't be locked.
+#[derive(Debug)]
+pub enum LockError {
+    /// Another live process, or another download in this one, holds the lock.
+    Held {
+        /// The lock file.
+        path: PathBuf,
+        /// The holder, if it could be read from the lock file.
+        holder: Option<LockHolder>,
+    },
+    /// The torrent's name would place the lock file outside the download directory, see
+    /// `check_path_component`.
+    InvalidName(Error),
+    /// The lock file couldn't be created or written.
+    Io(io::Error),
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Held { path, holder: Some(holder) } => write!(f, "{} is held by {holder}", path.display()),
+            LockError::Held { path, holder: None } => write!(f, "{} is held by another process", path.display()),
+            LockError::InvalidName(err) => write!(f, "unable to lock the download, {err}"),
+            LockError::Io(err) => write!(f, "unable to lock the download, {err}"),
+        }
+    }
+}
+
+impl std::error::Error for LockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LockError::InvalidName(err) => Some(err),
+            LockError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LockError {
+    fn from(err: io::Error) -> Self {
+        LockError::Io(err)
+    }
+}
+
+/// An advisory lock on the files of a torrent, held until dropped.
+///
+/// The lock file is removed as the lock is dropped. The operating system releases the lock
+/// when the holding process exits, so a lock file left behind by a process that died is taken
+/// over rather than refusing the download.
+#[derive(Debug)]
+pub struct DownloadLock {
+    /// The lock file, removed on drop
+    path: PathBuf,
+    /// The locked file, closing it releases the lock
+    _file: File,
+}
+
+impl DownloadLock {
+    /// Locks a torrent's files, recording this process as the holder.
+    ///
+    /// # Arguments
+    ///
+    /// * `download_path` - The directory the torrent is downloaded into.
+    /// * `name` - The name of the torrent, each torrent in a directory has its own lock.
+    pub fn acquire(download_path: &str, name: &str) -> Result<Self, LockError> {
+        check_path_component(name).map_err(LockError::InvalidName)?;
+        let path = Path::new(download_path).join(format!(".{name}.lock"));
+
+        let mut file = loop {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+
+            match file.try_lock() {
+                Ok(()) => (),
+                Err(TryLockError::WouldBlock) => {
+                    let holder = fs::read_to_string(&path).ok().and_then(|contents| LockHolder::parse(&contents));
+                    return Err(LockError::Held { path, holder })
+                }
+                Err(TryLockError::Error(err)) => return Err(LockError::Io(err)),
+            }
+
+            // The holder removed the file as it released the lock, which leaves the one opened
+            // here locked but no longer found by anyone else
+            if is_at_path(&file, &path)? {
+                break file
+            }
+        };
+
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        file.set_len(0)?;
+        writeln!(file, "{} {started}", std::process::id())?;
+
+        Ok(Self { path, _file: file })
+    }
+}
+
+impl Drop for DownloadLock {
+    fn drop(&mut self) {
+        // Removed while the lock is still held, so it's never removed from under a new holder
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Whether an open file is still the one found at a path.
+#[cfg(unix)]
+fn is_at_path(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let opened = file.metadata()?;
+    match fs::metadata(path) {
+        Ok(current) => Ok(opened.dev() == current.dev() && opened.ino() == current.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether an open file is still the one found at a path, always true where an open file can't
+/// be removed.
+#[cfg(not(unix))]
+fn is_at_path(_file: &File, _path: &Path) -> io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::tests::download_dir;
+
+    #[tokio::test]
+    async fn second_lock_names_the_holder() {
+        let path = download_dir("lock").await;
+
+        let first = DownloadLock::acquire(&path, "torrent").unwrap();
+        let Err(LockError::Held { holder, .. }) = DownloadLock::acquire(&path, "torrent") else {
+            panic!("the lock was taken twice")
+        };
+        assert_eq!(holder.map(|holder| holder.pid), Some(std::process::id()));
+
+        // Other torrents in the directory have their own lock
+        assert!(DownloadLock::acquire(&path, "other").is_ok());
+
+        drop(first);
+        assert!(!Path::new(&path).join(".torrent.lock").exists());
+        assert!(DownloadLock::acquire(&path, "torrent").is_ok());
+    }
+
+    #[tokio::test]
+    async fn lock_stays_in_the_download_directory() {
+        let path = download_dir("escaping_lock").await;
+
+        for name in ["../escaped", "/tmp/escaped", "a/b", ".."] {
+            assert!(matches!(DownloadLock::acquire(&path, name), Err(LockError::InvalidName(_))), "{name}");
+        }
+        assert!(!Path::new(&path).join("../.escaped.lock").exists());
+    }
+
+    #[tokio::test]
+    async fn stale_lock_file_is_reclaimed() {
+        let path = download_dir("stale_lock").await;
+        fs::write(format!("{path}/.torrent.lock"), "4294967295 0\n").unwrap();
+
+        let _lock = DownloadLock::acquire(&path, "torrent").unwrap();
+
+        let holder = LockHolder::parse(&fs::read_to_string(format!("{path}/.torrent.lock")).unwrap()).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn lock_file_of_a_dead_holder_is_reacquired() {
+        let path = download_dir("dead_holder_lock").await;
+        let lock_file = Path::new(&path).join(".torrent.lock");
+
+        // A holder that exits without dropping its lock leaves the file naming it, but not the lock
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("echo \"$$ 0\" > '{}'", lock_file.display()))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(lock_file.exists());
+
+        let lock = DownloadLock::acquire(&path, "torrent").unwrap();
+        let holder = LockHolder::parse(&fs::read_to_string(&lock_file).unwrap()).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+
+        drop(lock);
+        assert!(!lock_file.exists());
+    }
+}
@@ -2,16 +2,120 @@
 
 // Crate Imports
 use crate::{
-    peer_wire_protocol::{ Handshake, Message, MessageType }, 
+    bitfield::Bitfield,
+    candidate::PeerCandidate,
+    config::{ BufferConfig, SocketOptions },
+    error::{ Error, PieceError },
+    peer_wire_protocol::{ parse_bitfield, Handshake, Message, MessageType }, 
+    rtt::{ RttEstimator, TimeoutBounds },
+    socket::{ apply_socket_options, UnsupportedOption },
     torrent::Torrent
 };
+#[cfg(feature = "wire-debug")]
+use crate::{
+    capture::CaptureSink,
+    peer_wire_protocol::{ Direction, RawFrame }
+};
 
 // External imports
-use std::net::SocketAddrV4;
+use sha1::{ Digest, Sha1 };
+use socket2::SockRef;
+use std::{
+    net::SocketAddrV4,
+    sync::Arc,
+    time::{ Duration, Instant, SystemTime }
+};
 use tokio::{
     io::{ AsyncReadExt, AsyncWriteExt },
-    net::TcpStream
+    net::{ TcpSocket, TcpStream },
+    time::{ self as clock, timeout, timeout_at }
 };
+#[cfg(feature = "wire-debug")]
+use bytes::Bytes;
+#[cfg(feature = "wire-debug")]
+use tokio::sync::broadcast;
+
+/// How long a peer has to respond to a request by default
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The longest message accepted from a peer, longer than any block or bitfield a peer should send
+const MAX_FRAME_LENGTH: u32 = 1 << 20;
+
+/// The number of frames a raw subscriber can fall behind before it starts missing frames
+#[cfg(feature = "wire-debug")]
+const RAW_TAP_CAPACITY: usize = 256;
+
+/// Observes, and may modify, every message exchanged with a peer.
+///
+/// Any `Fn(&Message)` can be used as a hook that only observes messages.
+pub trait MessageHook: Send + Sync {
+    /// Called with every message before it is sent.
+    fn on_send(&self, _message: &mut Message) { }
+
+    /// Called with every message after it is received, before it is handled.
+    fn on_receive(&self, _message: &mut Message) { }
+}
+
+impl<F: Fn(&Message) + Send + Sync> MessageHook for F {
+    fn on_send(&self, message: &mut Message) {
+        self(message)
+    }
+
+    fn on_receive(&self, message: &mut Message) {
+        self(message)
+    }
+}
+
+/// How connections to peers are made, so a peer can be connected to again the way it was at first.
+#[derive(Clone, Debug)]
+pub struct PeerDialer {
+    /// The sizes of the buffers used for each connection.
+    pub buffers: BufferConfig,
+    /// The options set on each connection's socket.
+    pub socket_options: SocketOptions,
+    /// How long each peer has to respond to a request, see `Peer::request_timeout`.
+    pub request_timeout: Duration,
+    /// The range each peer's request timeout is adapted within, see `Peer::adaptive_timeout`.
+    pub adaptive_timeout: Option<TimeoutBounds>,
+    /// Whether peers whose handshake doesn't carry the peer id they were listed with are
+    /// disconnected, see `Peer::strict_peer_id`.
+    pub strict_peer_id: bool,
+    /// Whether peers that send a bitfield late are disconnected, see `Peer::strict_protocol`.
+    pub strict_protocol: bool,
+}
+
+impl PeerDialer {
+    /// Connects to a peer and completes the handshake.
+    ///
+    /// # Arguments
+    ///
+    /// * `candidate` - The peer as found during discovery, or just its socket address.
+    /// * `torrent` - The torrent the handshake is for.
+    pub async fn dial(&self, candidate: impl Into<PeerCandidate>, torrent: &Torrent) -> Result<Peer, Error> {
+        let mut peer = Peer::create_connection_with(candidate, self.buffers, &self.socket_options).await?;
+
+        peer.strict_peer_id = self.strict_peer_id;
+        peer.strict_protocol = self.strict_protocol;
+        peer.request_timeout = self.request_timeout;
+        peer.adaptive_timeout = self.adaptive_timeout;
+        peer.handshake(torrent).await?;
+
+        Ok(peer)
+    }
+}
+
+/// A block requested from a peer, and when the request was sent and answered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockTiming {
+    /// The offset of the block within its piece.
+    pub offset: u32,
+    /// The length of the block.
+    pub length: u32,
+    /// When the request was sent.
+    pub requested_at: SystemTime,
+    /// When the block arrived, `None` if it never did.
+    pub received_at: Option<SystemTime>,
+}
 
 /// Structure to abstract interaction with a peer.
 pub struct Peer {
@@ -23,6 +127,52 @@ pub struct Peer {
     pub peer_id: String,
     /// Whether the peer is choking the client
     pub choking: bool,
+    /// Whether the client has told the peer it is interested
+    pub interested: bool,
+    /// Whether the peer has told the client it is interested in the client's pieces
+    pub peer_interested: bool,
+    /// The number of bytes received in blocks that weren't asked for, such as late blocks of a
+    /// piece that is already complete
+    pub wasted_bytes: u64,
+    /// The number of bytes received in pieces that didn't match their hash
+    pub failed_hash_bytes: u64,
+    /// How long the peer has to respond to a request for a block
+    pub request_timeout: Duration,
+    /// The range the request timeout is adapted within as the peer's response time is measured,
+    /// the timeout is fixed when `None`
+    pub adaptive_timeout: Option<TimeoutBounds>,
+    /// The peer's measured response time to block requests
+    rtt: RttEstimator,
+    /// The peer id a tracker advertised for the peer, checked against the handshake
+    pub expected_peer_id: Option<String>,
+    /// Whether the handshake fails when the peer id doesn't match the expected one
+    pub strict_peer_id: bool,
+    /// Whether a bitfield sent after the messages alongside the handshake is a protocol
+    /// violation, rather than added to the pieces the peer announced
+    pub strict_protocol: bool,
+    /// The number of pieces in the torrent, known once the handshake is sent
+    num_pieces: Option<usize>,
+    /// The sizes of the buffers used for the connection
+    buffers: BufferConfig,
+    /// Observes every message sent to or received from the peer
+    message_hook: Option<Arc<dyn MessageHook>>,
+    /// Messages sent alongside the handshake, kept until the download takes them
+    early_messages: Vec<Message>,
+    /// The pieces the peer announced with haves after the handshake, kept until the download takes them
+    haves: Vec<u32>,
+    /// Every piece the peer announced, with its bitfields or haves. `None` until the peer sends
+    /// a bitfield or a have.
+    pub bitfield: Option<Vec<u8>>,
+    /// The blocks requested for the last piece requested
+    timeline: Vec<BlockTiming>,
+    /// The socket options that couldn't be set on the connection
+    unsupported_options: Vec<UnsupportedOption>,
+    /// Mirrors every frame sent to or received from the peer
+    #[cfg(feature = "wire-debug")]
+    raw_tap: broadcast::Sender<RawFrame>,
+    /// Records every frame sent to or received from the peer, exactly as it was on the wire
+    #[cfg(feature = "wire-debug")]
+    capture: Option<CaptureSink>,
 }
 
 impl Peer {
@@ -30,11 +180,37 @@ impl Peer {
     ///
     /// # Arguments
     ///
-    /// * `socket_address` - The socket address of the peer.
-    pub async fn create_connection(socket_address: SocketAddrV4) -> Result<Self, String> {
-        let connection_stream = match TcpStream::connect(socket_address).await {
+    /// * `candidate` - The peer as found during discovery, or just its socket address. A peer id
+    ///   it was listed with becomes the `expected_peer_id`.
+    pub async fn create_connection(candidate: impl Into<PeerCandidate>) -> Result<Self, Error> {
+        Self::create_connection_with(candidate, BufferConfig::default(), &SocketOptions::default()).await
+    }
+
+    /// Creates a connection to the peer using the given buffer sizes.
+    ///
+    /// Socket options the platform refuses are left at its defaults, and listed by
+    /// `unsupported_options`.
+    ///
+    /// # Arguments
+    ///
+    /// * `candidate` - The peer as found during discovery, or just its socket address.
+    /// * `buffers` - The sizes of the buffers used for the connection.
+    /// * `socket_options` - The options set on the connection's socket.
+    pub async fn create_connection_with(candidate: impl Into<PeerCandidate>, buffers: BufferConfig, socket_options: &SocketOptions) -> Result<Self, Error> {
+        let PeerCandidate { address: socket_address, peer_id: expected_peer_id } = candidate.into();
+
+        let socket = match TcpSocket::new_v4() {
+            Err(err) => {
+                return Err(Error::PeerConnectionFailed(socket_address, err))
+            },
+            Ok(socket) => socket
+        };
+
+        let unsupported_options = apply_socket_options(SockRef::from(&socket), socket_options);
+
+        let connection_stream = match socket.connect(socket_address.into()).await {
             Err(err) => {
-                return Err(format!("unable to connect to {}, err: {}", socket_address, err))
+                return Err(Error::PeerConnectionFailed(socket_address, err))
             },
             Ok(stream) => {
                 stream
@@ -46,6 +222,28 @@ impl Peer {
             socket_addr: socket_address,
             peer_id: String::new(),
             choking: true,
+            interested: false,
+            peer_interested: false,
+            wasted_bytes: 0,
+            failed_hash_bytes: 0,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            adaptive_timeout: None,
+            rtt: RttEstimator::default(),
+            expected_peer_id,
+            strict_peer_id: false,
+            strict_protocol: false,
+            num_pieces: None,
+            buffers,
+            message_hook: None,
+            early_messages: vec![],
+            haves: vec![],
+            bitfield: None,
+            timeline: vec![],
+            unsupported_options,
+            #[cfg(feature = "wire-debug")]
+            raw_tap: broadcast::channel(RAW_TAP_CAPACITY).0,
+            #[cfg(feature = "wire-debug")]
+            capture: None,
         })
     }
 }
@@ -56,36 +254,250 @@ impl Peer {
     /// # Arguments
     ///
     /// * `torrent` - The `Torrent` instance associated with the peer.
-    pub async fn handshake(&mut self, torrent: &Torrent) -> Result<(), String>{
-        let mut buf = vec![0; 1024];
+    pub async fn handshake(&mut self, torrent: &Torrent) -> Result<(), Error>{
+        let mut buf = vec![0; self.buffers.handshake_buffer.max(68)];
         
-        let handshake_message = Handshake::new(&torrent.get_info_hash(), String::from("-RT0001-123456012345")).unwrap();
+        let handshake_message = Handshake::new(&torrent.get_info_hash(), String::from("-RT0001-123456012345"))?;
+        self.num_pieces = Some(torrent.get_num_pieces() as usize);
         
-        self.connection_stream.writable().await.unwrap();
-        self.connection_stream.write_all(&handshake_message.to_buffer()).await.unwrap();
+        self.connection_stream.writable().await?;
+        self.connection_stream.write_all(&handshake_message.to_buffer()).await?;
+
+        #[cfg(feature = "wire-debug")]
+        self.capture(Direction::Outbound, &handshake_message.to_buffer());
         
-        self.connection_stream.readable().await.unwrap();
-        let _ = self.connection_stream.read(&mut buf).await.unwrap();
+        self.connection_stream.readable().await?;
+        let read = self.connection_stream.read(&mut buf).await?;
+
+        #[cfg(feature = "wire-debug")]
+        self.capture(Direction::Inbound, &buf[..read.min(68)]);
         
-        let handshake = Handshake::from_buffer(&buf[..68].to_vec()).unwrap();
+        let handshake = Handshake::from_buffer(&buf[..read])?;
         
-        for message_buf in Message::number_of_messages(&buf[68..]).0 {
-            let message: Message = (&*message_buf).try_into()?;
-            
-            if message.message_type == MessageType::Unchoke {
-                self.choking = false;
+        // Messages sent alongside the handshake are only there if more than the handshake was read
+        if read > 68 {
+            let mut extra = buf[68..read].to_vec();
+            let mut messages = Message::number_of_messages(&extra).0;
+
+            // The last message may be cut off by the end of the buffer, such as a large bitfield
+            let consumed: usize = messages.iter().map(Vec::len).sum();
+            if consumed < extra.len() {
+                let mut frame = extra.split_off(consumed);
+                self.complete_frame(&mut frame).await?;
+                messages.push(frame);
+            }
+
+            for message_buf in messages {
+                // A keep alive is only the length
+                if message_buf.len() == 4 {
+                    continue
+                }
+
+                let message = self.decode_message(&message_buf)?;
+                
+                match message.message_type {
+                    MessageType::Unchoke => self.choking = false,
+                    MessageType::Choke => self.choking = true,
+                    MessageType::Interested => self.peer_interested = true,
+                    MessageType::NotInterested => self.peer_interested = false,
+                    MessageType::Bitfield => self.merge_bitfield(message.payload.as_deref().unwrap_or_default()),
+                    MessageType::Have => if let Some(index) = have_index(&message) {
+                        self.mark_piece(index);
+                    }
+                    _ => ()
+                }
+
+                self.early_messages.push(message);
             }
         }
         
         self.peer_id = handshake.peer_id;
 
+        if self.strict_peer_id && !self.has_expected_peer_id() {
+            let _ = self.disconnect().await;
+            return Err(Error::HandshakeFailed(format!(
+                "peer {} sent peer id {:?} but {:?} was expected",
+                self.socket_addr, self.peer_id, self.expected_peer_id.as_deref().unwrap_or_default()
+            )))
+        }
+
+        Ok(())
+    }
+
+    /// Reads the rest of a message whose start was read alongside the handshake
+    async fn complete_frame(&mut self, frame: &mut Vec<u8>) -> Result<(), Error> {
+        let start = frame.len();
+        if start < 4 {
+            frame.resize(4, 0);
+            self.connection_stream.read_exact(&mut frame[start..]).await?;
+        }
+
+        let length = u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]);
+        if length > MAX_FRAME_LENGTH {
+            return Err(Error::MalformedMessage(format!("message of {length} bytes is too long")))
+        }
+
+        let start = frame.len();
+        frame.resize(4 + length as usize, 0);
+        self.connection_stream.read_exact(&mut frame[start..]).await?;
+
         Ok(())
     }
+
+    /// Returns the messages the peer sent alongside its handshake, in the order they were sent,
+    /// so the bitfield and haves among them can be applied once the peer is registered.
+    /// Choke and unchoke messages have already been applied to `choking`, and interested and
+    /// not interested messages to `peer_interested`.
+    pub fn take_early_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.early_messages)
+    }
+
+    /// Returns the pieces the peer has announced since the handshake, in the order they were
+    /// announced, so they can be applied to the pieces it is known to have.
+    pub fn take_haves(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.haves)
+    }
+
+    /// Returns the blocks requested for the last piece requested with `request_piece`, and when
+    /// they were requested and received.
+    pub fn take_timeline(&mut self) -> Vec<BlockTiming> {
+        std::mem::take(&mut self.timeline)
+    }
+
+    /// Records a have received after the handshake, ignoring one without a piece index
+    fn record_have(&mut self, message: &Message) {
+        if let Some(index) = have_index(message) {
+            self.mark_piece(index);
+            self.haves.push(index);
+        }
+    }
+
+    /// Records a bitfield received after the handshake, later than the protocol allows. It is
+    /// checked as one sent alongside the handshake is when the peer is registered, spare bits
+    /// are cleared, and its pieces are added to those already announced and kept as haves so
+    /// the download can apply them.
+    ///
+    /// # Returns
+    ///
+    /// * A `PieceError::ProtocolViolation` if the bitfield isn't the right length for the
+    ///   torrent, or with `strict_protocol`, as it was sent late.
+    fn record_bitfield(&mut self, message: &Message) -> Result<(), PieceError> {
+        if self.strict_protocol {
+            return Err(PieceError::ProtocolViolation(String::from("bitfield sent after other messages")))
+        }
+
+        let payload = message.payload.as_deref().unwrap_or_default();
+        let pieces = match self.num_pieces {
+            Some(num_pieces) => Bitfield::parse(payload, num_pieces).map_err(|err| PieceError::ProtocolViolation(err.to_string()))?.0,
+            None => Bitfield::from_bytes(payload, payload.len() * 8),
+        };
+
+        for index in pieces.indices() {
+            self.mark_piece(index);
+            self.haves.push(index);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the pieces of a bitfield sent alongside the handshake to those already announced,
+    /// it is checked when the peer is registered
+    fn merge_bitfield(&mut self, payload: &[u8]) {
+        let bitfield = self.bitfield.get_or_insert_with(Vec::new);
+        if bitfield.len() < payload.len() {
+            bitfield.resize(payload.len(), 0);
+        }
+
+        for (byte, bits) in bitfield.iter_mut().zip(payload) {
+            *byte |= bits;
+        }
+    }
+
+    /// Sets a piece in the bitfield, growing it to fit the piece
+    fn mark_piece(&mut self, index: u32) {
+        let bitfield = self.bitfield.get_or_insert_with(Vec::new);
+        let byte = index as usize / 8;
+        if bitfield.len() <= byte {
+            bitfield.resize(byte + 1, 0);
+        }
+
+        bitfield[byte] |= 0x80 >> (index % 8);
+    }
+
+    /// Returns whether the peer has announced a piece, with its bitfield or a have.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.bitfield.as_ref()
+            .and_then(|bitfield| bitfield.get(index as usize / 8))
+            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+    }
+
+    /// Returns the first piece the peer has announced, if any
+    fn first_piece(&self) -> Option<u32> {
+        parse_bitfield(self.bitfield.as_deref()?).iter().position(|has| *has).map(|index| index as u32)
+    }
+
+    /// Returns the socket options that couldn't be set on the connection.
+    pub fn unsupported_options(&self) -> &[UnsupportedOption] {
+        &self.unsupported_options
+    }
+
+    /// Returns the peer's measured response time to block requests.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Sets the hook that observes every message sent to or received from the peer.
+    pub fn set_message_hook(&mut self, hook: Arc<dyn MessageHook>) {
+        self.message_hook = Some(hook);
+    }
+
+    /// Returns whether the peer id from the handshake matches the one the tracker advertised,
+    /// always true when no peer id was advertised.
+    pub fn has_expected_peer_id(&self) -> bool {
+        match &self.expected_peer_id {
+            Some(expected) => *expected == self.peer_id,
+            None => true
+        }
+    }
     
-    /// Keeps the connection alive and sends interested messages until the peer unchokes
-    pub async fn keep_alive_until_unchoke(&mut self) -> Result<(), String> {
+    /// Tells the peer whether the client is interested in its pieces, nothing is sent if that
+    /// hasn't changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `interested` - Whether the peer has any piece the client still needs.
+    pub async fn set_interested(&mut self, interested: bool) -> Result<(), Error> {
+        if self.interested == interested {
+            return Ok(())
+        }
+
+        let message_type = if interested { MessageType::Interested } else { MessageType::NotInterested };
+        self.send_message_no_response(Message::new(1, message_type, None)).await?;
+        self.interested = interested;
+
+        Ok(())
+    }
+    
+    /// Keeps the connection alive and sends interested messages until the peer unchokes, waiting
+    /// at most `request_timeout` for it to. The peer is told the client is interested first, as
+    /// peers only unchoke interested clients.
+    pub async fn keep_alive_until_unchoke(&mut self) -> Result<(), PieceError> {
+        self.set_interested(true).await.map_err(|_| PieceError::PeerDisconnected)?;
+
+        match timeout(self.request_timeout, self.wait_for_unchoke()).await {
+            Err(_) => Err(PieceError::Timeout),
+            Ok(result) => result
+        }
+    }
+
+    /// Reads messages until the peer unchokes, answering keep alives
+    async fn wait_for_unchoke(&mut self) -> Result<(), PieceError> {
         loop {
-            let message = self.read_message().await?;
+            let message = self.read_frame().await?;
             
             match message.message_type {
                 MessageType::Unchoke => {
@@ -93,135 +505,513 @@ impl Peer {
                     break
                 }
                 MessageType::KeepAlive => {
-                    self.send_message_no_response(Message::new(0, MessageType::KeepAlive, None)).await?;
-                    self.send_message_no_response(Message::new(1, MessageType::Interested, None)).await?;
+                    self.send_message_no_response(Message::new(0, MessageType::KeepAlive, None)).await
+                        .map_err(|_| PieceError::PeerDisconnected)?;
+                    self.send_message_no_response(Message::new(1, MessageType::Interested, None)).await
+                        .map_err(|_| PieceError::PeerDisconnected)?;
+                    self.interested = true;
                 }
                 MessageType::Choke => {
                     self.choking = true;
                 }
+                MessageType::Interested => self.peer_interested = true,
+                MessageType::NotInterested => self.peer_interested = false,
+                MessageType::Have => self.record_have(&message),
+                MessageType::Bitfield => self.record_bitfield(&message)?,
                 _ => { continue }
             }
         }
 
         Ok(())
     }
+
+    /// Waits until the peer has sent something, without reading it. Nothing is lost if the wait
+    /// is cancelled, so it can be raced against other work before `read_idle_message`.
+    pub async fn readable(&mut self) -> Result<(), PieceError> {
+        match self.connection_stream.peek(&mut [0]).await {
+            Ok(0) | Err(_) => Err(PieceError::PeerDisconnected),
+            Ok(_) => Ok(())
+        }
+    }
+
+    /// Reads a message the peer sent while no piece was being requested from it, recording the
+    /// pieces it announces, whether it is choking the client and whether it is interested.
+    /// Blocks are discarded and counted as wasted.
+    pub async fn read_idle_message(&mut self) -> Result<(), PieceError> {
+        let message = self.read_frame().await?;
+
+        match message.message_type {
+            MessageType::Choke => self.choking = true,
+            MessageType::Unchoke => self.choking = false,
+            MessageType::Interested => self.peer_interested = true,
+            MessageType::NotInterested => self.peer_interested = false,
+            MessageType::Have => self.record_have(&message),
+            MessageType::Bitfield => self.record_bitfield(&message)?,
+            MessageType::Piece => self.wasted_bytes += message.payload.map_or(0, |payload| payload.len().saturating_sub(8) as u64),
+            _ => ()
+        }
+
+        Ok(())
+    }
     
-    /// Sends a message to the peer and waits for a response, which it returns
-    pub async fn send_message(&mut self, message: Message) -> Result<Message, String> {
-        let mut response = vec![0; 16_397];
+    /// Sends a message to the peer and waits for a response of `BufferConfig::read_buffer`
+    /// bytes, which it returns
+    pub async fn send_message(&mut self, message: Message) -> Result<Message, Error> {
+        let mut response = vec![0; self.buffers.read_buffer];
 
-        let message: Vec<u8> = message.try_into()?;
-        
-        self.connection_stream.writable().await.unwrap();
-        self.connection_stream.write_all(&message).await.unwrap();
+        self.write_message(message).await?;
         
-        self.connection_stream.readable().await.unwrap();
-        let _ = self.connection_stream.read_exact(&mut response).await.unwrap();
+        self.connection_stream.readable().await?;
+        let _ = self.connection_stream.read_exact(&mut response).await?;
         
-        Ok((*response).try_into()?)
+        self.decode_message(&response)
     }
     
     /// Sends a message to the peer and waits for a response, which it returns
-    pub async fn send_message_exact_size_response(&mut self, message: Message, size: usize) -> Result<Message, String> {
+    pub async fn send_message_exact_size_response(&mut self, message: Message, size: usize) -> Result<Message, Error> {
         let mut response = vec![0; size];
 
-        let message: Vec<u8> = message.try_into()?;
-        
-        self.connection_stream.writable().await.unwrap();
-        self.connection_stream.write_all(&message).await.unwrap();
+        self.write_message(message).await?;
         
-        self.connection_stream.readable().await.unwrap();
-        let _ = self.connection_stream.read_exact(&mut response).await.unwrap();
+        self.connection_stream.readable().await?;
+        let _ = self.connection_stream.read_exact(&mut response).await?;
         
-        Ok((*response).try_into()?)
+        self.decode_message(&response)
     }
     
     /// Sends a message but doesn't wait for a response
-    pub async fn send_message_no_response(&mut self, message: Message) -> Result<(), String> {
-
-        let message: Vec<u8> = message.try_into()?;
-        self.connection_stream.writable().await.unwrap();
-        self.connection_stream.write_all(&message).await.unwrap();
-
-        Ok(())
+    pub async fn send_message_no_response(&mut self, message: Message) -> Result<(), Error> {
+        self.write_message(message).await
     }
     
     /// reads a message from the peer
-    pub async fn read_message(&mut self) -> Result<Message, String> {
-        let mut response = vec![0; 16_397];
+    pub async fn read_message(&mut self) -> Result<Message, Error> {
+        let mut response = vec![0; self.buffers.read_buffer];
         
-        self.connection_stream.readable().await.unwrap();
-        let _ = self.connection_stream.read(&mut response).await.unwrap();
+        self.connection_stream.readable().await?;
+        let _ = self.connection_stream.read(&mut response).await?;
         
-        Ok((*response).try_into()?)
+        self.decode_message(&response)
     }
     
     /// Shutsdown the connection stream
-    pub async fn disconnect(&mut self) -> Result<(), String>{
-        match self.connection_stream.shutdown().await {
-            Err(err) => {
-                return Err(format!("Error disconnecting from {}: {}", self.socket_addr, err));
-            },
-            Ok(_) => {
-                Ok(())
+    pub async fn disconnect(&mut self) -> Result<(), Error>{
+        self.connection_stream.shutdown().await?;
+
+        Ok(())
+    }
+}
+
+impl Peer {
+    /// Reads a single length prefixed message from the peer, waiting at most `request_timeout`
+    async fn read_frame(&mut self) -> Result<Message, PieceError> {
+        self.read_frame_by(clock::Instant::now() + self.request_timeout).await
+    }
+
+    /// Reads a single length prefixed message from the peer, waiting until at most `deadline`
+    async fn read_frame_by(&mut self, deadline: clock::Instant) -> Result<Message, PieceError> {
+        let read = async {
+            let mut length = [0; 4];
+            self.connection_stream.read_exact(&mut length).await?;
+
+            let length = u32::from_be_bytes(length);
+            if length > MAX_FRAME_LENGTH {
+                return Ok(Err(PieceError::ProtocolViolation(format!("message of {length} bytes is too long"))))
             }
+
+            let mut frame = length.to_be_bytes().to_vec();
+            frame.resize(4 + length as usize, 0);
+            self.connection_stream.read_exact(&mut frame[4..]).await?;
+
+            Ok::<_, std::io::Error>(Ok(frame))
+        };
+
+        let mut frame = match timeout_at(deadline, read).await {
+            Err(_) => return Err(PieceError::Timeout),
+            Ok(Err(_)) => return Err(PieceError::PeerDisconnected),
+            Ok(Ok(frame)) => frame?
+        };
+
+        // A keep alive is only the length, decoding expects at least a type byte as well
+        if frame.len() == 4 {
+            frame.push(0);
+        }
+
+        self.decode_message(&frame).map_err(|err| PieceError::ProtocolViolation(err.to_string()))
+    }
+
+    /// Serializes a message and writes it to the connection stream
+    async fn write_message(&mut self, mut message: Message) -> Result<(), Error> {
+        if let Some(hook) = &self.message_hook {
+            hook.on_send(&mut message);
+        }
+
+        #[cfg(feature = "wire-debug")]
+        let frame = RawFrame::from_message(Direction::Outbound, &message);
+
+        let message: Vec<u8> = message.try_into()?;
+
+        #[cfg(feature = "wire-debug")]
+        let _ = self.raw_tap.send(frame);
+
+        #[cfg(feature = "wire-debug")]
+        self.capture(Direction::Outbound, &message);
+        
+        self.connection_stream.writable().await?;
+        self.connection_stream.write_all(&message).await?;
+
+        Ok(())
+    }
+
+    /// Decodes a message read from the connection stream
+    fn decode_message(&self, buf: &[u8]) -> Result<Message, Error> {
+        // Buffers read in one go can hold more than the message, only the message is captured
+        #[cfg(feature = "wire-debug")]
+        if let Some(length) = buf.get(..4) {
+            let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize;
+            self.capture(Direction::Inbound, &buf[..(4 + length).min(buf.len())]);
+        }
+
+        let mut message: Message = buf.try_into()?;
+
+        #[cfg(feature = "wire-debug")]
+        let _ = self.raw_tap.send(RawFrame::from_message(Direction::Inbound, &message));
+
+        if let Some(hook) = &self.message_hook {
+            hook.on_receive(&mut message);
+        }
+
+        Ok(message)
+    }
+}
+
+#[cfg(feature = "wire-debug")]
+impl Peer {
+    /// Sends an arbitrary frame to the peer, bypassing the checks done on a `Message`.
+    ///
+    /// # Arguments
+    ///
+    /// * `message_type` - The type byte of the frame, which doesn't have to be a known `MessageType`.
+    /// * `payload` - The payload following the type byte.
+    pub async fn send_raw(&mut self, message_type: u8, payload: Bytes) -> Result<(), Error> {
+        let mut buf: Vec<u8> = Vec::with_capacity(payload.len() + 5);
+        buf.extend((payload.len() as u32 + 1).to_be_bytes());
+        buf.push(message_type);
+        buf.extend(&payload);
+
+        let _ = self.raw_tap.send(RawFrame::new(Direction::Outbound, Some(message_type), payload.to_vec()));
+        self.capture(Direction::Outbound, &buf);
+        
+        self.connection_stream.writable().await?;
+        self.connection_stream.write_all(&buf).await?;
+
+        Ok(())
+    }
+
+    /// Subscribes to a copy of every frame sent to or received from the peer.
+    ///
+    /// Subscribing doesn't affect the normal processing of messages, a subscriber that falls
+    /// too far behind will miss frames rather than slow down the connection.
+    pub fn subscribe_raw(&self) -> broadcast::Receiver<RawFrame> {
+        self.raw_tap.subscribe()
+    }
+
+    /// Records every frame sent to or received from the peer from now on into a capture,
+    /// handshakes included.
+    ///
+    /// # Arguments
+    ///
+    /// * `sink` - A sink of a `CaptureFile`, written to in the background.
+    pub fn set_capture(&mut self, sink: CaptureSink) {
+        self.capture = Some(sink);
+    }
+
+    /// Records a frame into the capture, if there is one
+    fn capture(&self, direction: Direction, frame: &[u8]) {
+        if let Some(sink) = &self.capture {
+            sink.record(direction, frame);
         }
     }
 }
 
 impl Peer {
-    // Sends the requests and reads responses to put a piece together
-    pub async fn request_piece(&mut self, index: u32, piece_length: u32, len: &mut u32, total_len: u32) -> Result<Vec<u8>, String> {
+    /// Sends the requests and reads responses to put a piece together
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    /// * `piece_length` - The length of the piece, the last piece of a torrent may be shorter than the rest.
+    pub async fn request_piece(&mut self, index: u32, piece_length: u32) -> Result<Vec<u8>, PieceError> {
         let mut buf = vec![];
+        self.timeline.clear();
         // Sequentially requests piece from the peer
         for offset in (0..piece_length).step_by(16_384) {
-            let mut length = 16_384;
-            
-            let response: Message;
-            
-            if *len + 16_384 >= total_len {
-                length = total_len - *len;
-                
-                response = self.send_message_exact_size_response(
-                    Message::create_piece_request(index, offset, length),
-                    length as usize + 13
-                ).await?;
-            } else {
-                response = self.send_message(Message::create_piece_request(index, offset, length)).await?;
-            };
-            
-            match response.message_type {
+            let length = 16_384.min(piece_length - offset);
+            buf.extend(self.request_block(index, offset, length).await?);
+        }
+        
+        Ok(buf)
+    }
+
+    /// Sends a single request and reads responses until the requested block arrives.
+    ///
+    /// A peer that responds with a block of the wrong length, or otherwise breaks the protocol,
+    /// is disconnected.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    /// * `begin` - The offset of the block within the piece.
+    /// * `length` - The length of the block, peers commonly refuse requests over 16 KiB.
+    pub async fn request_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, PieceError> {
+        let result = self.fetch_block(index, begin, length).await;
+
+        if let Err(PieceError::ProtocolViolation(_)) = result {
+            let _ = self.disconnect().await;
+        }
+
+        result
+    }
+
+    /// Sends a request and reads the block sent in response, checking its length
+    async fn fetch_block(&mut self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, PieceError> {
+        let requested_at = Instant::now();
+        self.timeline.push(BlockTiming { offset: begin, length, requested_at: SystemTime::now(), received_at: None });
+        if self.write_message(Message::create_piece_request(index, begin, length)).await.is_err() {
+            return Err(PieceError::PeerDisconnected)
+        }
+
+        let deadline = clock::Instant::now() + self.request_timeout;
+        let block = self.read_block(index, begin, deadline).await?;
+        self.sample_rtt(requested_at.elapsed());
+        if let Some(timing) = self.timeline.last_mut() {
+            timing.received_at = Some(SystemTime::now());
+        }
+        if block.len() != length as usize {
+            return Err(PieceError::ProtocolViolation(format!(
+                "block at {begin} of piece {index} is {} bytes, {length} were requested", block.len()
+            )))
+        }
+
+        Ok(block)
+    }
+
+    /// Measures the peer's latency by timing a request for the first byte of the first piece
+    /// it has announced, adding it to the peer's response time estimate.
+    ///
+    /// A peer that doesn't respond within `request_timeout` is taken to have a latency of the
+    /// whole timeout, and the request is cancelled so the block isn't sent late. A peer that
+    /// can't be asked, as it is choking the client, hasn't announced any pieces or disconnects,
+    /// is also taken to have a latency of the whole timeout, but isn't added to the estimate.
+    pub async fn measure_latency(&mut self) -> Duration {
+        let timeout = self.request_timeout;
+        let Some(index) = self.first_piece().filter(|_| !self.choking) else {
+            return timeout
+        };
+
+        let requested_at = Instant::now();
+        match self.request_block(index, 0, 1).await {
+            Ok(_) => requested_at.elapsed(),
+            Err(PieceError::Timeout) => {
+                self.sample_rtt(timeout);
+                let _ = self.send_message_no_response(Message::create_cancel(index, 0, 1)).await;
+
+                timeout
+            }
+            Err(_) => timeout,
+        }
+    }
+
+    /// Downloads a piece and checks it against its hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The torrent the piece belongs to.
+    /// * `index` - The index of the piece, `PieceError::InvalidIndex` is returned without
+    ///   requesting anything if it isn't in the torrent.
+    pub async fn download_piece(&mut self, torrent: &Torrent, index: u32) -> Result<Vec<u8>, PieceError> {
+        let expected = torrent.piece_hash(index).ok_or(PieceError::InvalidIndex(index))?;
+        let piece = self.request_piece(index, torrent.get_piece_length(index) as u32).await?;
+
+        let got: [u8; 20] = Sha1::digest(&piece).into();
+        if got != expected {
+            self.failed_hash_bytes += piece.len() as u64;
+            return Err(PieceError::HashMismatch { got, expected })
+        }
+
+        Ok(piece)
+    }
+
+    /// Adds a response time to the peer's estimate, adapting the request timeout if enabled
+    fn sample_rtt(&mut self, rtt: Duration) {
+        self.rtt.sample(rtt);
+
+        if let Some(timeout) = self.adaptive_timeout.and_then(|bounds| self.rtt.timeout(bounds)) {
+            self.request_timeout = timeout;
+        }
+    }
+
+    /// Reads messages until the requested block arrives, returning its data.
+    ///
+    /// Blocks that weren't asked for are discarded and counted as wasted, other messages
+    /// don't affect the request and are skipped. They don't extend it either, the block has to
+    /// arrive by `deadline` however many keep alives or haves the peer sends meanwhile.
+    async fn read_block(&mut self, index: u32, offset: u32, deadline: clock::Instant) -> Result<Vec<u8>, PieceError> {
+        loop {
+            let message = self.read_frame_by(deadline).await?;
+
+            match message.message_type {
                 MessageType::Piece => {
-                    let mut data = response.payload.unwrap();
-                    *len += data.len() as u32;
-                    *len -= 8;
-                    
-                    for byte in data.drain(..).skip(8) {
-                        buf.push(byte)
+                    if message.payload.as_ref().map_or(0, Vec::len) < 8 {
+                        return Err(PieceError::ProtocolViolation(String::from("piece message without a block header")))
                     }
-                },
+
+                    if let Some(wasted) = unrequested_block(&message, index, offset) {
+                        self.wasted_bytes += wasted;
+                        continue
+                    }
+
+                    return Ok(message.payload.unwrap().split_off(8))
+                }
+                MessageType::Choke => {
+                    self.choking = true;
+                    return Err(PieceError::PeerChoked)
+                }
+                MessageType::Unchoke => {
+                    self.choking = false;
+                }
+                MessageType::Interested => self.peer_interested = true,
+                MessageType::NotInterested => self.peer_interested = false,
+                MessageType::Have => self.record_have(&message),
                 _ => { }
-            };
-            
-            if *len >= total_len - 1 {
-                return Ok(buf);
             }
         }
-        
-        Ok(buf)
+    }
+}
+
+/// Returns the piece index of a have message, if it has one
+fn have_index(message: &Message) -> Option<u32> {
+    match message.payload.as_deref() {
+        Some(&[a, b, c, d]) => Some(u32::from_be_bytes([a, b, c, d])),
+        _ => None
+    }
+}
+
+/// Returns the length of the block carried by a piece message, if it isn't the requested block.
+///
+/// # Arguments
+///
+/// * `message` - The message received in response to a request.
+/// * `index` - The index of the requested piece.
+/// * `offset` - The offset of the requested block within the piece.
+fn unrequested_block(message: &Message, index: u32, offset: u32) -> Option<u64> {
+    let payload = message.payload.as_ref()?;
+
+    if message.message_type != MessageType::Piece || payload.len() < 8 {
+        return None
+    }
+
+    let block_index = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    let block_offset = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+
+    if block_index == index && block_offset == offset {
+        None
+    } else {
+        Some(payload.len() as u64 - 8)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use crate::torrent::Torrent;
-    use std::net::Ipv4Addr;
+    use crate::{
+        bitfield::Bitfield,
+        picker::{ PieceLedger, Sequential },
+        testing::{ block_message, mock_peer, mock_peer_with, mock_seed },
+        torrent::Torrent
+    };
+    use std::net::SocketAddr;
+    use tokio::{ net::TcpListener, task::JoinHandle };
+
+    #[tokio::test]
+    async fn messages_alongside_handshake_are_kept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(socket_address) = listener.local_addr().unwrap() else {
+            panic!("Expected an ipv4 address")
+        };
+
+        let mock = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; 68]).await.unwrap();
+
+            // The handshake, a bitfield, an unchoke and a have in a single write
+            let mut response = Handshake::new(&[1; 20], String::from("-MY0001-123456654321")).unwrap().to_buffer();
+            response.extend([0, 0, 0, 2, 5, 0b1010_0000]);
+            response.extend([0, 0, 0, 1, 1]);
+            response.extend([0, 0, 0, 5, 4, 0, 0, 0, 1]);
+            stream.write_all(&response).await.unwrap();
+
+            stream
+        });
+
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        let _stream = mock.await.unwrap();
+
+        assert!(!peer.choking);
+        let early: Vec<MessageType> = peer.take_early_messages().into_iter().map(|message| message.message_type).collect();
+        assert_eq!(early, vec![MessageType::Bitfield, MessageType::Unchoke, MessageType::Have]);
+        assert!(peer.take_early_messages().is_empty());
+
+        // The bitfield has pieces 0 and 2, the have adds piece 1
+        assert_eq!(peer.bitfield, Some(vec![0b1110_0000]));
+        assert!((0..3).all(|index| peer.has_piece(index)));
+        assert!(!peer.has_piece(3));
+        assert!(!peer.has_piece(100));
+    }
+
+    #[tokio::test]
+    async fn bitfield_larger_than_handshake_buffer_is_read_whole() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(socket_address) = listener.local_addr().unwrap() else {
+            panic!("Expected an ipv4 address")
+        };
+
+        let mock = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.read_exact(&mut [0; 68]).await.unwrap();
+
+            // The handshake, a 300 byte bitfield and an unchoke in a single write
+            let mut response = Handshake::new(&[1; 20], String::from("-MY0001-123456654321")).unwrap().to_buffer();
+            response.extend(301_u32.to_be_bytes());
+            response.push(5);
+            response.extend([0xff; 300]);
+            response.extend([0, 0, 0, 1, 1]);
+            stream.write_all(&response).await.unwrap();
+
+            stream
+        });
+
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        let buffers = BufferConfig { handshake_buffer: 128, read_buffer: 64 };
+        let mut peer = Peer::create_connection_with(socket_address, buffers, &SocketOptions::default()).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        let _stream = mock.await.unwrap();
+
+        let early = peer.take_early_messages();
+        assert_eq!(early.len(), 1);
+        assert_eq!(early[0].message_type, MessageType::Bitfield);
+        assert_eq!(early[0].payload, Some(vec![0xff; 300]));
+
+        // The unchoke after the bitfield was left to be read as a message of its own
+        assert!(peer.choking);
+        assert_eq!(peer.read_frame().await.unwrap().message_type, MessageType::Unchoke);
+    }
 
     #[tokio::test]
     async fn peer_create_connection() {
-        // Replace the IP and port with the actual values
-        let socket_address = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881);
+        let (socket_address, _mock) = mock_peer().await;
 
         match Peer::create_connection(socket_address).await {
             Ok(peer) => {
@@ -234,11 +1024,566 @@ mod tests {
 
     #[tokio::test]
     async fn peer_handshake() {
-        let socket_address = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881);
-        let mut peer = Peer::create_connection(socket_address.clone()).await.unwrap();
+        let (socket_address, _mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        assert!(peer.handshake(&torrent).await.is_ok());
+        assert!(!peer.choking);
+    }
+
+    #[tokio::test]
+    async fn peer_configured_buffers() {
+        let (socket_address, _mock) = mock_peer().await;
+        let buffers = BufferConfig { handshake_buffer: 0, read_buffer: 64 };
+        let socket_options = SocketOptions { recv_buffer: Some(1 << 20), send_buffer: Some(1 << 20), nodelay: Some(true), ..Default::default() };
+        let mut peer = Peer::create_connection_with(socket_address, buffers, &socket_options).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        assert_eq!(peer.unsupported_options(), []);
+        assert!(peer.connection_stream.nodelay().unwrap());
+
+        // A handshake buffer too small for a handshake is grown to fit one exactly, which
+        // leaves the unchoke sent alongside it to be read separately
+        peer.handshake(&torrent).await.unwrap();
+        assert!(peer.choking);
+        assert_eq!(peer.read_message().await.unwrap().message_type, MessageType::Unchoke);
+    }
+
+    #[tokio::test]
+    async fn send_message_reads_configured_buffer() {
+        let (socket_address, mock) = mock_peer().await;
+        let buffers = BufferConfig { read_buffer: 9, ..Default::default() };
+        let mut peer = Peer::create_connection_with(socket_address, buffers, &SocketOptions::default()).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut stream = mock.await.unwrap();
+            stream.read_exact(&mut [0; 5]).await.unwrap();
+            stream.write_all(&[0, 0, 0, 5, 4, 0, 0, 0, 7]).await.unwrap();
+            stream
+        });
+
+        // A response shorter than a block message is returned as soon as the buffer is full
+        let response = timeout(Duration::from_secs(5), peer.send_message(Message::new(1, MessageType::Interested, None))).await.unwrap().unwrap();
+        assert_eq!(response.message_type, MessageType::Have);
+        assert_eq!(response.payload, Some(vec![0, 0, 0, 7]));
+        responder.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peer_handshake_expected_peer_id() {
+        let (socket_address, _mock) = mock_peer().await;
+        let candidate = PeerCandidate { address: socket_address, peer_id: Some(String::from("-MY0001-123456654321")) };
+        let mut peer = Peer::create_connection(candidate).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        assert_eq!(peer.expected_peer_id.as_deref(), Some("-MY0001-123456654321"));
+        peer.strict_peer_id = true;
+
+        assert!(peer.handshake(&torrent).await.is_ok());
+        assert!(peer.has_expected_peer_id());
+    }
+
+    #[tokio::test]
+    async fn peer_handshake_unexpected_peer_id() {
         let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
 
+        // Without strict mode the mismatch is only reported
+        let (socket_address, _mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.expected_peer_id = Some(String::from("-XX0001-000000000000"));
+
         assert!(peer.handshake(&torrent).await.is_ok());
+        assert!(!peer.has_expected_peer_id());
+
+        let (socket_address, _mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.expected_peer_id = Some(String::from("-XX0001-000000000000"));
+        peer.strict_peer_id = true;
+
+        assert!(peer.handshake(&torrent).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_message_hook_observes() {
+        let (socket_address, _mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        let observed = Arc::new(std::sync::Mutex::new(vec![]));
+        let log = observed.clone();
+        peer.set_message_hook(Arc::new(move |message: &Message| {
+            log.lock().unwrap().push(message.message_type.clone());
+        }));
+
+        peer.handshake(&torrent).await.unwrap();
+
+        assert_eq!(*observed.lock().unwrap(), vec![MessageType::Unchoke]);
+    }
+
+    #[tokio::test]
+    async fn peer_message_hook_modifies() {
+        /// Rewrites every outgoing interested message into a not interested message
+        struct Uninterested;
+
+        impl MessageHook for Uninterested {
+            fn on_send(&self, message: &mut Message) {
+                if message.message_type == MessageType::Interested {
+                    message.message_type = MessageType::NotInterested;
+                }
+            }
+        }
+
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.set_message_hook(Arc::new(Uninterested));
+
+        peer.handshake(&torrent).await.unwrap();
+        peer.send_message_no_response(Message::new(1, MessageType::Interested, None)).await.unwrap();
+
+        let mut stream = mock.await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 3]);
+    }
+
+    #[tokio::test]
+    async fn peer_set_interested() {
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        peer.handshake(&torrent).await.unwrap();
+        peer.set_interested(false).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+        peer.set_interested(true).await.unwrap();
+        peer.set_interested(false).await.unwrap();
+        assert!(!peer.interested);
+        peer.disconnect().await.unwrap();
+
+        // Only the changes in interest are sent
+        let mut stream = mock.await.unwrap();
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 1, 2, 0, 0, 0, 1, 3]);
+    }
+
+    /// Connects and handshakes with a mock peer, which answers the first request with `response`
+    pub(crate) async fn faulty_peer(response: Vec<u8>) -> (Peer, JoinHandle<TcpStream>) {
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+
+        let responder = tokio::spawn(async move {
+            let mut stream = mock.await.unwrap();
+            let mut request = [0; 17];
+            stream.read_exact(&mut request).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+            stream
+        });
+
+        (peer, responder)
+    }
+
+    #[tokio::test]
+    async fn peer_discards_unrequested_block() {
+        // A late block of piece 0, then the requested block of piece 1
+        let mut response = block_message(0, 0, &[0xee; 8]);
+        response.extend(block_message(1, 0, &[0x11; 8]));
+        let (mut peer, responder) = faulty_peer(response).await;
+
+        let piece = peer.request_piece(1, 8).await.unwrap();
+        responder.await.unwrap();
+
+        assert_eq!(piece, vec![0x11; 8]);
+        assert_eq!(peer.wasted_bytes, 8);
+        assert_eq!(peer.failed_hash_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn peer_skips_messages_before_block() {
+        // A keep alive and a have arrive before the block
+        let mut response = vec![0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0, 3];
+        response.extend(block_message(0, 0, &[0x22; 8]));
+        let (mut peer, responder) = faulty_peer(response).await;
+
+        assert_eq!(peer.request_piece(0, 8).await.unwrap(), vec![0x22; 8]);
+        responder.await.unwrap();
+
+        // The have is kept for the download to apply
+        assert_eq!(peer.take_haves(), vec![3]);
+        assert!(peer.take_haves().is_empty());
+    }
+
+    #[tokio::test]
+    async fn peer_request_piece_choked() {
+        let (mut peer, _responder) = faulty_peer(vec![0, 0, 0, 1, 0]).await;
+
+        assert!(matches!(peer.request_piece(0, 8).await, Err(PieceError::PeerChoked)));
+        assert!(peer.choking);
+    }
+
+    #[tokio::test]
+    async fn peer_request_piece_disconnected() {
+        // Nothing holds on to the mock's stream, so it closes once the request arrives
+        let (mut peer, responder) = faulty_peer(vec![]).await;
+        drop(responder);
+
+        assert!(matches!(peer.request_piece(0, 8).await, Err(PieceError::PeerDisconnected)));
+    }
+
+    #[tokio::test]
+    async fn peer_request_piece_timeout() {
+        let (mut peer, responder) = faulty_peer(vec![]).await;
+        peer.request_timeout = Duration::from_millis(50);
+
+        assert!(matches!(peer.request_piece(0, 8).await, Err(PieceError::Timeout)));
+        drop(responder);
+    }
+
+    #[tokio::test]
+    async fn chatter_doesnt_extend_a_request() {
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.request_timeout = Duration::from_millis(100);
+        peer.adaptive_timeout = None;
+
+        // Keep alives and haves arrive well within the timeout, the block never does
+        let _responder = tokio::spawn(async move {
+            let mut stream = mock.await.unwrap();
+            let mut request = [0; 17];
+            stream.read_exact(&mut request).await.unwrap();
+            for _ in 0..50 {
+                stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0, 0]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        let requested_at = Instant::now();
+        assert!(matches!(peer.request_block(0, 0, 8).await, Err(PieceError::Timeout)));
+        assert!(requested_at.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn peer_unchoke_wait() {
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection_with(socket_address, BufferConfig { handshake_buffer: 0, read_buffer: 64 }, &SocketOptions::default()).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.request_timeout = Duration::from_millis(100);
+
+        // The unchoke sent alongside the handshake, read separately as the handshake buffer only fits the handshake
+        peer.keep_alive_until_unchoke().await.unwrap();
+        assert!(!peer.choking);
+
+        // A choke, a bitfield, a have and an unchoke in a single write are each read
+        let mut stream = mock.await.unwrap();
+        let mut bitfield = vec![0; torrent.get_num_pieces().div_ceil(8) as usize];
+        bitfield[0] = 0b1000_0000;
+        let mut messages = vec![0, 0, 0, 1, 0];
+        messages.extend(bitfield_message(&bitfield));
+        messages.extend([0, 0, 0, 5, 4, 0, 0, 0, 2, 0, 0, 0, 1, 1]);
+        stream.write_all(&messages).await.unwrap();
+        peer.choking = true;
+        peer.keep_alive_until_unchoke().await.unwrap();
+        assert!(!peer.choking);
+        assert_eq!(peer.take_haves(), vec![0, 2]);
+        assert_eq!(peer.bitfield, Some(vec![0b1010_0000]));
+        assert!(peer.has_piece(2) && !peer.has_piece(1));
+
+        // A peer that never unchokes times out
+        peer.choking = true;
+        assert!(matches!(peer.keep_alive_until_unchoke().await, Err(PieceError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn idle_peer_is_read_and_asked_to_unchoke() {
+        let (socket_address, mock) = mock_peer_with(vec![]).await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.request_timeout = Duration::from_millis(500);
+
+        // An interested message and a have, then an unchoke only once the client says it is interested
+        let mut stream = mock.await.unwrap();
+        stream.write_all(&[0, 0, 0, 1, 2, 0, 0, 0, 5, 4, 0, 0, 0, 3]).await.unwrap();
+        let responder = tokio::spawn(async move {
+            let mut interested = [0; 5];
+            stream.read_exact(&mut interested).await.unwrap();
+            assert_eq!(interested, [0, 0, 0, 1, 2]);
+            stream.write_all(&[0, 0, 0, 1, 1]).await.unwrap();
+            stream
+        });
+
+        for _ in 0..2 {
+            peer.readable().await.unwrap();
+            peer.read_idle_message().await.unwrap();
+        }
+        assert!(peer.peer_interested);
+        assert_eq!(peer.take_haves(), vec![3]);
+
+        peer.keep_alive_until_unchoke().await.unwrap();
+        assert!(peer.interested && !peer.choking);
+
+        // A closed connection is noticed while idle
+        drop(responder.await.unwrap());
+        assert!(matches!(peer.readable().await, Err(PieceError::PeerDisconnected)));
+    }
+
+    /// Serializes a bitfield message with the given payload
+    fn bitfield_message(payload: &[u8]) -> Vec<u8> {
+        let mut message = (payload.len() as u32 + 1).to_be_bytes().to_vec();
+        message.push(5);
+        message.extend(payload);
+        message
+    }
+
+    /// Connects to a mock peer of a torrent of 10 pieces, which sends `messages` once the
+    /// handshake is done, and reads them while idle
+    ///
+    /// # Returns
+    ///
+    /// * The peer, the result of reading the messages up to the first error, and the mock's end
+    ///   of the connection.
+    async fn read_late_messages(messages: &[Vec<u8>], strict_protocol: bool) -> (Peer, Result<(), PieceError>, TcpStream) {
+        let (socket_address, mock) = mock_peer_with(vec![]).await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.strict_protocol = strict_protocol;
+        peer.handshake(&Torrent::from_pieces("late", 16, &[0; 160])).await.unwrap();
+
+        let mut stream = mock.await.unwrap();
+        stream.write_all(&messages.concat()).await.unwrap();
+
+        let mut result = Ok(());
+        for _ in messages {
+            result = peer.read_idle_message().await;
+            if result.is_err() {
+                break
+            }
+        }
+
+        (peer, result, stream)
+    }
+
+    #[tokio::test]
+    async fn late_bitfield_of_wrong_length_is_a_violation() {
+        for payload in [vec![0xff], vec![0xff, 0xc0, 0]] {
+            let (mut peer, result, _stream) = read_late_messages(&[bitfield_message(&payload)], false).await;
+
+            assert!(matches!(result, Err(PieceError::ProtocolViolation(_))), "{result:?}");
+            assert!(peer.take_haves().is_empty() && peer.bitfield.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn late_bitfield_is_added_to_haves() {
+        // A have of piece 9, then a bitfield with piece 0 and every spare bit set
+        let have = vec![0, 0, 0, 5, 4, 0, 0, 0, 9];
+        let (mut peer, result, _stream) = read_late_messages(&[have, bitfield_message(&[0x80, 0x3f])], false).await;
+
+        result.unwrap();
+        assert!(peer.has_piece(0) && peer.has_piece(9));
+        assert!(!peer.has_piece(10));
+        assert_eq!(peer.bitfield, Some(vec![0x80, 0x40]));
+        assert_eq!(peer.take_haves(), vec![9, 0]);
+    }
+
+    #[tokio::test]
+    async fn late_bitfield_is_a_violation_when_strict() {
+        let (_, result, _stream) = read_late_messages(&[bitfield_message(&[0x80, 0])], true).await;
+
+        assert!(matches!(result, Err(PieceError::ProtocolViolation(reason)) if reason == "bitfield sent after other messages"));
+    }
+
+    #[tokio::test]
+    async fn measure_latency() {
+        let data: Vec<u8> = (0..64).collect();
+        let torrent = Torrent::from_pieces("latency", 32, &data);
+
+        let socket_address = mock_seed(data, 32, Duration::from_millis(30)).await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+        peer.adaptive_timeout = None;
+
+        // A peer that hasn't announced any pieces can't be asked for one
+        peer.bitfield = None;
+        assert_eq!(peer.measure_latency().await, peer.request_timeout);
+        assert_eq!(peer.rtt().srtt(), None);
+
+        // Only piece 1
+        peer.bitfield = Some(vec![0x40]);
+        let latency = peer.measure_latency().await;
+        assert!(latency >= Duration::from_millis(30));
+        assert!(peer.rtt().srtt().unwrap() >= Duration::from_millis(30));
+
+        // A peer that never responds counts as slow as the timeout
+        let (mut silent, responder) = faulty_peer(vec![]).await;
+        silent.request_timeout = Duration::from_millis(50);
+        silent.adaptive_timeout = None;
+        silent.bitfield = Some(vec![0x80]);
+
+        assert_eq!(silent.measure_latency().await, Duration::from_millis(50));
+        assert_eq!(silent.rtt().srtt(), Some(Duration::from_millis(50)));
+
+        // The probe is cancelled once it times out
+        let mut stream = responder.await.unwrap();
+        let mut cancel = [0; 17];
+        stream.read_exact(&mut cancel).await.unwrap();
+        assert_eq!(cancel, [0, 0, 0, 13, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn peer_request_piece_protocol_violation() {
+        // The block is shorter than the one requested
+        let (mut peer, _responder) = faulty_peer(block_message(0, 0, &[0; 4])).await;
+        assert!(matches!(peer.request_piece(0, 8).await, Err(PieceError::ProtocolViolation(_))));
+
+        // The length prefix is absurdly long
+        let (mut peer, _responder) = faulty_peer(vec![0xff; 5]).await;
+        assert!(matches!(peer.request_piece(0, 8).await, Err(PieceError::ProtocolViolation(_))));
+    }
+
+    #[tokio::test]
+    async fn peer_oversized_block_disconnects() {
+        let (mut peer, responder) = faulty_peer(block_message(0, 0, &[0; 16])).await;
+
+        match peer.request_block(0, 0, 8).await {
+            Err(PieceError::ProtocolViolation(reason)) => assert_eq!(reason, "block at 0 of piece 0 is 16 bytes, 8 were requested"),
+            other => panic!("expected a protocol violation, got {other:?}"),
+        }
+
+        // The peer was disconnected rather than asked for anything else
+        let mut stream = responder.await.unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_download_piece_hash_mismatch() {
+        let torrent = Torrent::from_pieces("hash_mismatch", 8, &[1; 8]);
+
+        let (mut peer, _responder) = faulty_peer(block_message(0, 0, &[1; 8])).await;
+        assert_eq!(peer.download_piece(&torrent, 0).await.unwrap(), vec![1; 8]);
+
+        let (mut peer, _responder) = faulty_peer(block_message(0, 0, &[0; 8])).await;
+        match peer.download_piece(&torrent, 0).await {
+            Err(PieceError::HashMismatch { got, expected }) => {
+                assert_eq!(got, <[u8; 20]>::from(Sha1::digest([0; 8])));
+                assert_eq!(Some(expected), torrent.piece_hash(0));
+            }
+            other => panic!("Expected a hash mismatch, got {other:?}"),
+        }
+        assert_eq!(peer.failed_hash_bytes, 8);
+        assert_eq!(peer.wasted_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn peer_download_piece_out_of_range() {
+        let torrent = Torrent::from_pieces("out_of_range", 8, &[1; 8]);
+        let (mut peer, responder) = faulty_peer(block_message(0, 0, &[1; 8])).await;
+
+        assert!(matches!(peer.download_piece(&torrent, 1).await, Err(PieceError::InvalidIndex(1))));
+
+        // Nothing was requested, so the mock is still waiting for a request when the peer closes
+        drop(peer);
+        assert!(responder.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn download_degenerate_torrents() {
+        let piece_length = 2 * 16_384;
+
+        // 1 byte, exactly one block, exactly one piece and one piece and a byte
+        for total_length in [1, 16_384, piece_length, piece_length + 1] {
+            let data: Vec<u8> = (0..total_length).map(|byte| (byte % 251) as u8).collect();
+            let torrent = Torrent::from_pieces("degenerate", piece_length as u64, &data);
+            let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+            let peer_pieces = Bitfield::full(torrent.get_num_pieces() as usize);
+
+            let socket_address = mock_seed(data.clone(), piece_length as u64, Duration::ZERO).await;
+            let mut peer = Peer::create_connection(socket_address).await.unwrap();
+            peer.handshake(&torrent).await.unwrap();
+            peer.set_interested(ledger.needed_from(&peer_pieces)).await.unwrap();
+            assert!(peer.interested);
+
+            let mut downloaded = vec![];
+            while let Some(assignment) = ledger.assign(&peer_pieces) {
+                downloaded.extend(peer.download_piece(&torrent, assignment.index).await.unwrap());
+                ledger.piece_complete(assignment.index);
+            }
+
+            peer.set_interested(ledger.needed_from(&peer_pieces)).await.unwrap();
+            assert!(!peer.interested);
+            assert!(ledger.is_complete());
+            assert_eq!(downloaded, data, "torrent of {total_length} bytes");
+        }
+    }
+
+    #[tokio::test]
+    async fn request_single_block() {
+        let data: Vec<u8> = (0..64).collect();
+        let torrent = Torrent::from_pieces("block", 32, &data);
+
+        let socket_address = mock_seed(data, 32, Duration::ZERO).await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        peer.handshake(&torrent).await.unwrap();
+
+        assert_eq!(peer.request_block(1, 5, 10).await.unwrap(), (37..47).collect::<Vec<u8>>());
+        assert_eq!(peer.request_block(0, 0, 1).await.unwrap(), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn adaptive_request_timeout() {
+        let data = vec![0; 8 * 16_384];
+        let torrent = Torrent::from_pieces("adaptive", data.len() as u64, &data);
+        let bounds = TimeoutBounds { min: Duration::from_millis(1), max: Duration::from_secs(5) };
+
+        let mut timeouts = vec![];
+        for latency in [Duration::from_millis(2), Duration::from_millis(60)] {
+            let socket_address = mock_seed(data.clone(), data.len() as u64, latency).await;
+            let mut peer = Peer::create_connection(socket_address).await.unwrap();
+            peer.adaptive_timeout = Some(bounds);
+            peer.handshake(&torrent).await.unwrap();
+
+            peer.download_piece(&torrent, 0).await.unwrap();
+
+            assert!(peer.rtt().srtt().unwrap() >= latency);
+            assert_ne!(peer.request_timeout, DEFAULT_REQUEST_TIMEOUT);
+            timeouts.push(peer.request_timeout);
+        }
+
+        assert!(timeouts[0] < timeouts[1]);
+        assert!(timeouts[1] >= Duration::from_millis(60));
+    }
+
+    #[cfg(feature = "wire-debug")]
+    #[tokio::test]
+    async fn peer_send_raw() {
+        let (socket_address, mock) = mock_peer().await;
+        let mut peer = Peer::create_connection(socket_address).await.unwrap();
+        let mut frames = peer.subscribe_raw();
+        let torrent = Torrent::from_torrent_file("test.torrent").await.unwrap();
+
+        peer.handshake(&torrent).await.unwrap();
+        let unchoke = frames.recv().await.unwrap();
+        assert_eq!(unchoke.direction, Direction::Inbound);
+        assert_eq!(unchoke.message_type, Some(1));
+
+        peer.send_raw(20, Bytes::from_static(&[0, 1, 2])).await.unwrap();
+        let raw = frames.recv().await.unwrap();
+        assert_eq!(raw.direction, Direction::Outbound);
+        assert_eq!(raw.message_type, Some(20));
+        assert_eq!(raw.payload, vec![0, 1, 2]);
+
+        let mut stream = mock.await.unwrap();
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 4, 20, 0, 1, 2]);
     }
 
     // Add more tests for other methods in the Peer structure
@@ -1,3 +1,8 @@
+use crate::error::Error;
+
+#[cfg(feature = "wire-debug")]
+use std::time::SystemTime;
+
 /// Represents the handshake message that will be sent to a client.
 #[derive(Debug)]
 pub struct Handshake {
@@ -23,13 +28,13 @@ impl Handshake {
   /// # Returns
   ///
   /// A new `Handshake` instance on success, or an empty `Result` indicating an error.
-  pub fn new(info_hash: &[u8], peer_id: String) ->  Result<Self, String> {
+  pub fn new(info_hash: &[u8], peer_id: String) ->  Result<Self, Error> {
     if info_hash.len() != 20 {
-      return Err(String::from("Incorrect infohash length"));
+      return Err(Error::HandshakeFailed(String::from("Incorrect infohash length")));
     }
     
     if peer_id.len() != 20 {
-        return Err(String::from("Incorrect Peer_Id Length"))
+        return Err(Error::HandshakeFailed(String::from("Incorrect Peer_Id Length")))
     }
     
     Ok(Self {
@@ -71,10 +76,10 @@ impl Handshake {
   /// # Errors
   ///
   /// Returns an error if the provided buffer is not long enough (at least 68 bytes).
-  pub fn from_buffer(buf: &Vec<u8>) -> Result<Self, String> {
+  pub fn from_buffer(buf: &[u8]) -> Result<Self, Error> {
     // Verify that buffer is at least the correct size, if not error
     if buf.len() < 68 {
-      return Err(String::from("buffer provided to handshake was too short"));
+      return Err(Error::HandshakeFailed(String::from("buffer provided to handshake was too short")));
     }
     
     let mut p_str = String::new();
@@ -116,7 +121,8 @@ impl Message {
     ///
     /// # Arguments
     ///
-    /// * `message_length` - The length of the message.
+    /// * `message_length` - The length of the message after the length prefix, the type byte
+    ///   and the payload, so 1 for a message without a payload.
     /// * `message_type` - The type of message.
     /// * `payload` - The payload of the message, if any.
     pub fn new(message_length: u32, message_type: MessageType, payload: Option<Vec<u8>>) -> Self {
@@ -125,7 +131,7 @@ impl Message {
 }
 
 impl TryFrom<&[u8]> for Message {
-    type Error = String;
+    type Error = Error;
     /// Decodes a message from a given buffer.
     ///
     /// # Arguments
@@ -139,7 +145,7 @@ impl TryFrom<&[u8]> for Message {
         let mut message_length: [u8; 4] = [0; 4];
 
         if value.len() < 5 {
-            return Err(format!("Buffer not long enough to be a message: Length {}, should be at least 4 bytes", value.len()));
+            return Err(Error::MalformedMessage(format!("Buffer not long enough to be a message: Length {}, should be at least 4 bytes", value.len())));
         }
 
         message_length[..4].copy_from_slice(&value[..4]);
@@ -152,7 +158,7 @@ impl TryFrom<&[u8]> for Message {
         if message_length == 0 {
             message_type = MessageType::KeepAlive;
             payload = None;
-        } else if message_length == 5 {
+        } else if message_length == 1 {
             message_type = value[4].try_into()?;
             payload = None;
         } else {
@@ -161,7 +167,7 @@ impl TryFrom<&[u8]> for Message {
             let end_of_message = 4 + message_length as usize;
             
             if end_of_message > value.len() {
-                return Err(format!("Invalid message length {} expected {}", value.len(), end_of_message))
+                return Err(Error::MalformedMessage(format!("Invalid message length {} expected {}", value.len(), end_of_message)))
             } else {
                 payload = Some(value[5..end_of_message].to_vec());
             } 
@@ -177,7 +183,7 @@ impl TryFrom<&[u8]> for Message {
 
 
 impl TryFrom<Message> for Vec<u8> {
-    type Error = String;
+    type Error = Error;
     /// Converts the `Message` instance to a byte buffer for sending.
     ///
     /// # Returns
@@ -205,7 +211,7 @@ impl TryFrom<Message> for Vec<u8> {
         
         match value.payload {
             None => { 
-                return Err(String::from("Error you are trying to create a message that needs a payload with no payload")) 
+                return Err(Error::MalformedMessage(String::from("Error you are trying to create a message that needs a payload with no payload")))
             }
             Some(payload) => {
                 buf.extend(payload);
@@ -249,9 +255,23 @@ impl Message {
             payload: Some(payload) 
         }
     }
+
+    /// Create a cancel message for a request made with `create_piece_request`
+    ///
+    /// # Arguments
+    ///
+    /// * `piece_index` - The index of the piece in the torrent
+    /// * `offset` - The offset within the piece the request was for
+    /// * `length` - The length of the request
+    pub fn create_cancel(piece_index: u32, offset: u32, length: u32) -> Self {
+        Self { message_type: MessageType::Cancel, ..Self::create_piece_request(piece_index, offset, length) }
+    }
     
     /// Returns the number of messages in the given buffer and their contents.
     ///
+    /// A message cut off by the end of the buffer isn't returned, so the bytes of the messages
+    /// returned are where the rest of it starts.
+    ///
     /// # Arguments
     ///
     /// * `buf` - The byte buffer containing multiple serialized messages.
@@ -263,27 +283,80 @@ impl Message {
         let mut message_num = 0;
         let mut messages: Vec<Vec<u8>> = vec![];
         
-        // Find the length of message one
-        // put that into an array and increment counter by one
         let mut i = 0; // points to the front
-        let mut j; // points to the back
         
-        loop {
-            j = u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]) as usize + 4;
+        while let Some(&[a, b, c, d]) = buf.get(i..i + 4) {
+            // points to the back
+            let j = i + 4 + u32::from_be_bytes([a, b, c, d]) as usize;
+            let Some(message) = buf.get(i..j) else { break };
             
-            messages.push(buf[i..i+j].to_vec());
-            i += j;
+            messages.push(message.to_vec());
+            i = j;
             message_num += 1;
-            
-            if buf[i] == 0 && buf[i + 1] == 0 && buf[i + 2] == 0 && buf[i + 3] == 0 {
-                break;
-            }
         }
         
         (messages, message_num)
     }
 }
 
+/// Parses the payload of a bitfield message into whether the peer has each piece, the high bit
+/// of the first byte being piece 0.
+///
+/// # Arguments
+///
+/// * `payload` - The payload of a bitfield message.
+///
+/// # Returns
+///
+/// Whether the peer has each piece, 8 for every byte of the payload. The spare bits at the end
+/// are included, so the result may be longer than the torrent has pieces.
+pub fn parse_bitfield(payload: &[u8]) -> Vec<bool> {
+    payload.iter()
+        .flat_map(|byte| (0..8).map(move |bit| byte & (0x80 >> bit) != 0))
+        .collect()
+}
+
+/// The direction a frame travelled in, relative to the client.
+#[cfg(feature = "wire-debug")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Sent by the peer to the client.
+    Inbound,
+    /// Sent by the client to the peer.
+    Outbound,
+}
+
+/// A copy of a single frame exchanged with a peer, used for debugging the wire protocol.
+#[cfg(feature = "wire-debug")]
+#[derive(Clone, Debug)]
+pub struct RawFrame {
+    /// The type byte of the frame, `None` for a keepalive.
+    pub message_type: Option<u8>,
+    /// The payload following the type byte.
+    pub payload: Vec<u8>,
+    /// Whether the frame was sent or received.
+    pub direction: Direction,
+    /// When the frame was sent or decoded.
+    pub timestamp: SystemTime,
+}
+
+#[cfg(feature = "wire-debug")]
+impl RawFrame {
+    /// Creates a new frame timestamped with the current time.
+    pub fn new(direction: Direction, message_type: Option<u8>, payload: Vec<u8>) -> Self {
+        Self { message_type, payload, direction, timestamp: SystemTime::now() }
+    }
+
+    /// Creates a frame mirroring a decoded `Message`.
+    pub fn from_message(direction: Direction, message: &Message) -> Self {
+        Self::new(
+            direction,
+            message.message_type.clone().try_into().ok(),
+            message.payload.clone().unwrap_or_default()
+        )
+    }
+}
+
 /// An enum representing all possible message types in the BitTorrent peer wire protocol.
 #[derive(Clone, Debug, PartialEq)]
 #[repr(u8)]
@@ -315,7 +388,7 @@ pub enum MessageType {
 }
 
 impl TryFrom<MessageType> for u8 {
-    type Error = String;
+    type Error = Error;
     fn try_from(value: MessageType) -> Result<Self, Self::Error> {
         match value {
             MessageType::Choke => Ok(0),
@@ -329,14 +402,14 @@ impl TryFrom<MessageType> for u8 {
             MessageType::Cancel => Ok(8),
             MessageType::Port => Ok(9),
             _ => {
-                Err(format!("Invalid Message Type {:?}", value))
+                Err(Error::MalformedMessage(format!("Invalid Message Type {:?}", value)))
             }
         }
     }
 }
 
 impl TryFrom<u8> for MessageType {
-    type Error = String;
+    type Error = Error;
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
             0 => Ok(MessageType::Choke),
@@ -350,7 +423,7 @@ impl TryFrom<u8> for MessageType {
             8 => Ok(MessageType::Cancel),
             9 => Ok(MessageType::Port),
             _ => {
-                Err(format!("Invalid Message Type {}", value))
+                Err(Error::InvalidMessage(value))
             }
         }
     }
@@ -383,7 +456,7 @@ mod tests {
         let peer_id = String::from("-MY0001-123456654321");
 
         match Handshake::new(&invalid_info_hash, peer_id.clone()) {
-            Err(err) => assert_eq!(err, "Incorrect infohash length"),
+            Err(err) => assert_eq!(err.to_string(), "Incorrect infohash length"),
             Ok(_) => panic!("Expected an error creating handshake, but got Ok"),
         }
     }
@@ -394,7 +467,7 @@ mod tests {
         let invalid_peer_id = String::from("-INVALID");
 
         match Handshake::new(&info_hash, invalid_peer_id) {
-            Err(err) => assert_eq!(err, "Incorrect Peer_Id Length"),
+            Err(err) => assert_eq!(err.to_string(), "Incorrect Peer_Id Length"),
             Ok(_) => panic!("Expected an error creating handshake, but got Ok"),
         }
     }
@@ -427,39 +500,39 @@ mod tests {
     fn handshake_from_buffer_invalid_size() {
         let short_buffer: Vec<u8> = vec![0; 67]; // Invalid size
         match Handshake::from_buffer(&short_buffer) {
-            Err(err) => assert_eq!(err, "buffer provided to handshake was too short"),
+            Err(err) => assert_eq!(err.to_string(), "buffer provided to handshake was too short"),
             Ok(_) => panic!("Expected an error, but got Ok"),
         }
     }
 
     #[test]
     fn u8_to_message_type() {
-        assert_eq!(TryInto::<MessageType>::try_into(0 as u8), Ok(MessageType::Choke));
-        assert_eq!(TryInto::<MessageType>::try_into(1 as u8), Ok(MessageType::Unchoke));
-        assert_eq!(TryInto::<MessageType>::try_into(2 as u8), Ok(MessageType::Interested));
-        assert_eq!(TryInto::<MessageType>::try_into(3 as u8), Ok(MessageType::NotInterested));
-        assert_eq!(TryInto::<MessageType>::try_into(4 as u8), Ok(MessageType::Have));
-        assert_eq!(TryInto::<MessageType>::try_into(5 as u8), Ok(MessageType::Bitfield));
-        assert_eq!(TryInto::<MessageType>::try_into(6 as u8), Ok(MessageType::Request));
-        assert_eq!(TryInto::<MessageType>::try_into(7 as u8), Ok(MessageType::Piece));
-        assert_eq!(TryInto::<MessageType>::try_into(8 as u8), Ok(MessageType::Cancel));
-        assert_eq!(TryInto::<MessageType>::try_into(9 as u8), Ok(MessageType::Port));
-        assert_eq!(TryInto::<MessageType>::try_into(10 as u8), Err(String::from("Invalid Message Type 10")));
+        assert_eq!(TryInto::<MessageType>::try_into(0_u8).ok(), Some(MessageType::Choke));
+        assert_eq!(TryInto::<MessageType>::try_into(1_u8).ok(), Some(MessageType::Unchoke));
+        assert_eq!(TryInto::<MessageType>::try_into(2_u8).ok(), Some(MessageType::Interested));
+        assert_eq!(TryInto::<MessageType>::try_into(3_u8).ok(), Some(MessageType::NotInterested));
+        assert_eq!(TryInto::<MessageType>::try_into(4_u8).ok(), Some(MessageType::Have));
+        assert_eq!(TryInto::<MessageType>::try_into(5_u8).ok(), Some(MessageType::Bitfield));
+        assert_eq!(TryInto::<MessageType>::try_into(6_u8).ok(), Some(MessageType::Request));
+        assert_eq!(TryInto::<MessageType>::try_into(7_u8).ok(), Some(MessageType::Piece));
+        assert_eq!(TryInto::<MessageType>::try_into(8_u8).ok(), Some(MessageType::Cancel));
+        assert_eq!(TryInto::<MessageType>::try_into(9_u8).ok(), Some(MessageType::Port));
+        assert!(matches!(TryInto::<MessageType>::try_into(10_u8), Err(Error::InvalidMessage(10))));
     }
 
     #[test]
     fn message_type_to_u8() {
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Choke),         Ok(0 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Unchoke),       Ok(1 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Interested),    Ok(2 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::NotInterested), Ok(3 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Have),          Ok(4 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Bitfield),      Ok(5 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Request),       Ok(6 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Piece),         Ok(7 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Cancel),        Ok(8 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::Port),          Ok(9 as u8));
-        assert_eq!(TryInto::<u8>::try_into(MessageType::KeepAlive),     Err(String::from("Invalid Message Type KeepAlive")));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Choke).ok(),         Some(0_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Unchoke).ok(),       Some(1_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Interested).ok(),    Some(2_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::NotInterested).ok(), Some(3_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Have).ok(),          Some(4_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Bitfield).ok(),      Some(5_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Request).ok(),       Some(6_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Piece).ok(),         Some(7_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Cancel).ok(),        Some(8_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::Port).ok(),          Some(9_u8));
+        assert_eq!(TryInto::<u8>::try_into(MessageType::KeepAlive).map_err(|err| err.to_string()), Err(String::from("Invalid Message Type KeepAlive")));
     }
 
     #[test]
@@ -487,13 +560,23 @@ mod tests {
         }
     }
 
+    #[test]
+    fn create_cancel() {
+        let cancel = Message::create_cancel(42, 1024, 16384);
+        let request = Message::create_piece_request(42, 1024, 16384);
+
+        assert_eq!(cancel.message_length, 13);
+        assert_eq!(cancel.message_type, MessageType::Cancel);
+        assert_eq!(cancel.payload, request.payload);
+    }
+
     #[test]
     fn try_from_valid_message() {
-        let message_bytes = vec![0, 0, 0, 5, 1]; // Unchoke message
+        let message_bytes = [0, 0, 0, 1, 1]; // Unchoke message
 
         match Message::try_from(&message_bytes[..]) {
             Ok(message) => {
-                assert_eq!(message.message_length, 5);
+                assert_eq!(message.message_length, 1);
                 assert_eq!(message.message_type, MessageType::Unchoke);
                 assert!(message.payload.is_none());
             }
@@ -501,15 +584,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn try_from_have_keeps_piece_index() {
+        let message_bytes = [0, 0, 0, 5, 4, 0, 0, 0, 3];
+
+        let message = Message::try_from(&message_bytes[..]).unwrap();
+        assert_eq!(message.message_type, MessageType::Have);
+        assert_eq!(message.payload, Some(vec![0, 0, 0, 3]));
+    }
+
     #[test]
     fn try_from_invalid_message() {
-        let invalid_message_bytes = vec![0, 0, 0, 2]; // Message length indicates 2 bytes, but no payload provided
+        let invalid_message_bytes = [0, 0, 0, 2]; // Message length indicates 2 bytes, but no payload provided
 
         match Message::try_from(&invalid_message_bytes[..]) {
             Ok(_) => panic!("Expected an error but got Ok"),
             Err(err) => {
                 assert_eq!(
-                    err,
+                    err.to_string(),
                     "Buffer not long enough to be a message: Length 4, should be at least 4 bytes"
                 );
             }
@@ -519,14 +611,14 @@ mod tests {
     #[test]
     fn try_into_valid_message() {
         let message = Message {
-            message_length: 5,
+            message_length: 1,
             message_type: MessageType::Unchoke,
             payload: None,
         };
 
         match Vec::<u8>::try_from(message) {
             Ok(serialized_message) => {
-                assert_eq!(serialized_message, vec![0, 0, 0, 5, 1]); // Unchoke message
+                assert_eq!(serialized_message, vec![0, 0, 0, 1, 1]); // Unchoke message
             }
             Err(err) => panic!("Unexpected error: {}", err),
         }
@@ -551,4 +643,26 @@ mod tests {
             Err(err) => panic!("Unexpected error: {}", err),
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn number_of_messages_stops_at_cut_off_message() {
+        // An unchoke, a keep alive, then a have missing its last byte
+        let buf = [0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0];
+        let (messages, count) = Message::number_of_messages(&buf);
+
+        assert_eq!(count, 2);
+        assert_eq!(messages, vec![vec![0, 0, 0, 1, 1], vec![0, 0, 0, 0]]);
+
+        // Too short to hold even a length
+        assert_eq!(Message::number_of_messages(&[0, 0]).1, 0);
+    }
+
+    #[test]
+    fn parse_bitfield_bits() {
+        assert_eq!(parse_bitfield(&[0b1010_0000, 0b0000_0001]), vec![
+            true, false, true, false, false, false, false, false,
+            false, false, false, false, false, false, false, true,
+        ]);
+        assert!(parse_bitfield(&[]).is_empty());
+    }
+}
@@ -0,0 +1,983 @@
+//! Strategies for choosing which piece to download next, and the state they choose from
+
+// Crate Imports
+use crate::{
+    bitfield::Bitfield,
+    error::{ Error, IncompletePieces },
+    torrent::Torrent
+};
+
+// External imports
+use std::sync::{
+    atomic::{ AtomicU64, Ordering },
+    Arc,
+    Mutex
+};
+use tokio::sync::Notify;
+
+/// A piece chosen to be downloaded from a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PieceAssignment {
+    /// The index of the piece.
+    pub index: u32,
+    /// The length of the piece, the last piece may be shorter than the rest.
+    pub length: u32,
+}
+
+/// The state of the download that a `PiecePicker` chooses from.
+pub struct PickerContext<'a> {
+    /// The number of known peers that have each piece.
+    pub availability: &'a [u32],
+    /// The pieces that have been downloaded and verified.
+    pub verified: &'a Bitfield,
+    /// The pieces currently assigned to a peer.
+    pub in_progress: &'a Bitfield,
+    /// The pieces that are never assigned, see `PieceLedger::skip`.
+    pub skipped: &'a Bitfield,
+    /// The pieces the peer being assigned to has.
+    pub peer_pieces: &'a Bitfield,
+    /// The length of every piece except possibly the last.
+    piece_length: u64,
+    /// The total length of the torrent.
+    total_length: u64,
+}
+
+impl PickerContext<'_> {
+    /// Returns the number of pieces in the torrent.
+    pub fn num_pieces(&self) -> u32 {
+        self.availability.len() as u32
+    }
+
+    /// Returns whether the piece still needs downloading, isn't already assigned or skipped, and is held by the peer.
+    pub fn is_candidate(&self, index: u32) -> bool {
+        !self.verified.has(index) && !self.in_progress.has(index) && !self.skipped.has(index) && self.peer_pieces.has(index)
+    }
+
+    /// Creates the assignment of the given piece.
+    pub fn assign(&self, index: u32) -> PieceAssignment {
+        let start = index as u64 * self.piece_length;
+        let length = self.total_length.saturating_sub(start).min(self.piece_length);
+
+        PieceAssignment { index, length: length as u32 }
+    }
+}
+
+/// A strategy for choosing which piece to download next.
+///
+/// The notification methods let a strategy keep its own state, they do nothing by default.
+pub trait PiecePicker: Send {
+    /// Chooses the next piece to download from a peer, or `None` if the peer has nothing to offer.
+    fn pick(&mut self, context: &PickerContext) -> Option<PieceAssignment>;
+
+    /// Called when a peer announces that it has a piece.
+    fn on_have(&mut self, _index: u32) { }
+
+    /// Called when a piece has been downloaded and verified.
+    fn on_piece_complete(&mut self, _index: u32) { }
+
+    /// Called when a piece failed to download or verify, and needs downloading again.
+    fn on_piece_failed(&mut self, _index: u32) { }
+
+    /// Called when the strategy is selected to choose a download's pieces, as its ledger is
+    /// created or when it is swapped in for another strategy, with the state it takes over.
+    ///
+    /// # Arguments
+    ///
+    /// * `availability` - The number of known peers that have each piece.
+    /// * `verified` - The pieces that have been downloaded and verified.
+    fn on_selection_changed(&mut self, _availability: &[u32], _verified: &Bitfield) { }
+}
+
+/// A strategy waiting to replace the one choosing a download's pieces, shared with the ledger
+/// so it can be set from outside the task the download runs in.
+pub type PickerSwap = Arc<Mutex<Option<Box<dyn PiecePicker>>>>;
+
+/// Downloads pieces in index order, useful for streaming.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, context: &PickerContext) -> Option<PieceAssignment> {
+        (0..context.num_pieces())
+            .find(|index| context.is_candidate(*index))
+            .map(|index| context.assign(index))
+    }
+}
+
+/// Downloads the pieces held by the fewest peers first, which keeps the swarm healthy.
+/// Ties are broken by index.
+#[derive(Debug, Default)]
+pub struct RarestFirst {
+    /// Pieces held by fewer peers than this are only picked when there is nothing else to pick,
+    /// so a download doesn't stall on a piece only one flaky peer has.
+    pub min_availability: u32,
+}
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, context: &PickerContext) -> Option<PieceAssignment> {
+        (0..context.num_pieces())
+            .filter(|index| context.is_candidate(*index))
+            .min_by_key(|index| {
+                let availability = context.availability[*index as usize];
+                (availability < self.min_availability, availability)
+            })
+            .map(|index| context.assign(index))
+    }
+}
+
+/// A ceiling on the bytes held by pieces that are in progress, which can be shared by the
+/// ledgers of several downloads.
+///
+/// A piece is always allowed when nothing else is in progress, so a ceiling smaller than a
+/// piece slows a download down rather than stalling it.
+#[derive(Clone, Debug)]
+pub struct MemoryGate {
+    /// The most bytes that can be in progress at once
+    limit: u64,
+    /// The bytes currently in progress, across every ledger sharing the gate
+    in_use: Arc<AtomicU64>,
+    /// The number of times room has been released
+    releases: Arc<AtomicU64>,
+    /// Wakes those waiting for room to be released
+    released: Arc<Notify>,
+}
+
+impl MemoryGate {
+    /// Creates a gate allowing up to `limit` bytes to be in progress.
+    pub fn new(limit: u64) -> Self {
+        Self { limit, in_use: Arc::new(AtomicU64::new(0)), releases: Arc::new(AtomicU64::new(0)), released: Arc::new(Notify::new()) }
+    }
+
+    /// Reserves room for a piece, returning whether there was room.
+    pub fn try_reserve(&self, bytes: u64) -> bool {
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                (in_use == 0 || in_use + bytes <= self.limit).then_some(in_use + bytes)
+            })
+            .is_ok()
+    }
+
+    /// Releases the room reserved for a piece.
+    pub fn release(&self, bytes: u64) {
+        let _ = self.in_use.fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+            Some(in_use.saturating_sub(bytes))
+        });
+
+        self.releases.fetch_add(1, Ordering::AcqRel);
+        self.released.notify_waiters();
+    }
+
+    /// Returns the bytes currently in progress.
+    pub fn in_use(&self) -> u64 {
+        self.in_use.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of times room has been released, to wait for the next release with
+    /// `released_since`.
+    pub fn releases(&self) -> u64 {
+        self.releases.load(Ordering::Acquire)
+    }
+
+    /// Waits until room has been released by any ledger sharing the gate.
+    ///
+    /// # Arguments
+    ///
+    /// * `releases` - The number of releases, from `releases`, before the room was found lacking.
+    pub async fn released_since(&self, releases: u64) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registered before checking, so a release in between isn't missed
+            released.as_mut().enable();
+
+            if self.releases() != releases {
+                return
+            }
+
+            released.await;
+        }
+    }
+}
+
+/// The highest bucket an availability count is quantized to, for counts of 16 384 and over.
+const MAX_AVAILABILITY_BUCKET: u8 = 15;
+
+/// The availability of every piece at the end of a session, kept to warm start the next one.
+///
+/// Counts are quantized to logarithmic buckets, 0, 1, 2-3, 4-7 and so on, which keeps the
+/// rarest pieces apart while letting runs of similar pieces compress well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvailabilitySnapshot {
+    /// The bucket of every piece
+    buckets: Vec<u8>,
+}
+
+impl AvailabilitySnapshot {
+    /// Captures the availability of every piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `availability` - The number of peers that have each piece.
+    pub fn capture(availability: &[u32]) -> Self {
+        let buckets = availability.iter()
+            .map(|count| match count {
+                0 => 0,
+                count => (count.ilog2() as u8 + 1).min(MAX_AVAILABILITY_BUCKET)
+            })
+            .collect();
+
+        Self { buckets }
+    }
+
+    /// Returns the number of peers each piece is taken to be held by, the lowest of its bucket.
+    pub fn availability(&self) -> Vec<u32> {
+        self.buckets.iter()
+            .map(|bucket| match bucket {
+                0 => 0,
+                bucket => 1 << (bucket - 1)
+            })
+            .collect()
+    }
+
+    /// Encodes the snapshot as runs of equal buckets, each a byte holding the bucket followed by
+    /// the length of the run as a LEB128 varint.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+
+        for run in self.buckets.chunk_by(|a, b| a == b) {
+            buf.push(run[0]);
+
+            let mut length = run.len();
+            while length >= 0x80 {
+                buf.push(length as u8 | 0x80);
+                length >>= 7;
+            }
+            buf.push(length as u8);
+        }
+
+        buf
+    }
+
+    /// Decodes a snapshot encoded by `encode`.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The encoded snapshot.
+    /// * `num_pieces` - The number of pieces in the torrent, a snapshot of another number is an error.
+    ///
+    /// # Returns
+    ///
+    /// * The snapshot, or an `Error::InvalidConfig` if it's malformed or of another torrent, as
+    ///   it's only decoded to be set as `DownloadConfig::availability_snapshot`.
+    pub fn decode(buf: &[u8], num_pieces: usize) -> Result<Self, Error> {
+        let mut buckets = vec![];
+        let mut bytes = buf.iter();
+
+        while let Some(&bucket) = bytes.next() {
+            if bucket > MAX_AVAILABILITY_BUCKET {
+                return Err(Error::InvalidConfig(format!("availability bucket {bucket} is out of range")))
+            }
+
+            let mut length = 0_usize;
+            let mut shift = 0;
+            loop {
+                let Some(&byte) = bytes.next() else {
+                    return Err(Error::InvalidConfig(String::from("availability snapshot ends in the middle of a run")))
+                };
+                if shift > 28 {
+                    return Err(Error::InvalidConfig(String::from("availability run is too long")))
+                }
+
+                length |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    break
+                }
+            }
+
+            if buckets.len() + length > num_pieces {
+                return Err(Error::InvalidConfig(format!("availability snapshot has more than {num_pieces} pieces")))
+            }
+            buckets.resize(buckets.len() + length, bucket);
+        }
+
+        if buckets.len() != num_pieces {
+            return Err(Error::InvalidConfig(format!("availability snapshot has {} pieces, expected {num_pieces}", buckets.len())))
+        }
+
+        Ok(Self { buckets })
+    }
+}
+
+/// Tracks the state of every piece in a download and assigns pieces using a `PiecePicker`.
+pub struct PieceLedger {
+    /// The strategy used to choose pieces
+    picker: Box<dyn PiecePicker>,
+    /// The number of known peers that have each piece
+    availability: Vec<u32>,
+    /// The pieces that have been downloaded and verified
+    verified: Bitfield,
+    /// The pieces currently assigned to a peer
+    in_progress: Bitfield,
+    /// The pieces that are never assigned
+    skipped: Bitfield,
+    /// The length of every piece except possibly the last
+    piece_length: u64,
+    /// The total length of the torrent
+    total_length: u64,
+    /// Limits the bytes in progress, if set
+    memory_gate: Option<MemoryGate>,
+    /// Whether the last piece chosen wasn't assigned as the memory gate was full
+    held_back: bool,
+    /// The availability from a previous session, and the number of peers that supersedes it
+    warm_start: Option<(Vec<u32>, usize)>,
+    /// The number of peers added and not yet removed
+    live_peers: usize,
+    /// A strategy to replace `picker` with before the next piece is assigned, if watched
+    picker_swap: Option<PickerSwap>,
+}
+
+impl PieceLedger {
+    /// Creates a ledger for a torrent with no pieces downloaded.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The torrent being downloaded.
+    /// * `picker` - The strategy used to choose pieces.
+    pub fn new(torrent: &Torrent, mut picker: Box<dyn PiecePicker>) -> Self {
+        let num_pieces = torrent.get_num_pieces() as usize;
+        let verified = Bitfield::new(num_pieces);
+        picker.on_selection_changed(&vec![0; num_pieces], &verified);
+
+        Self {
+            picker,
+            availability: vec![0; num_pieces],
+            verified,
+            in_progress: Bitfield::new(num_pieces),
+            skipped: Bitfield::new(num_pieces),
+            piece_length: torrent.info.piece_length,
+            total_length: torrent.get_total_length(),
+            memory_gate: None,
+            held_back: false,
+            warm_start: None,
+            live_peers: 0,
+            picker_swap: None,
+        }
+    }
+
+    /// Limits the bytes held by pieces in progress, no new pieces are assigned while the gate is full.
+    pub fn set_memory_gate(&mut self, gate: MemoryGate) {
+        self.memory_gate = Some(gate);
+    }
+
+    /// Returns the gate limiting the bytes in progress, if set.
+    pub fn memory_gate(&self) -> Option<&MemoryGate> {
+        self.memory_gate.as_ref()
+    }
+
+    /// Returns whether the last call to `assign` chose a piece but couldn't assign it, as the
+    /// memory gate was full.
+    pub fn held_back(&self) -> bool {
+        self.held_back
+    }
+
+    /// Chooses pieces by the availability of a previous session until enough peers have been
+    /// added for their availability to be trusted instead. A snapshot of a different torrent
+    /// is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `snapshot` - The availability at the end of the previous session.
+    /// * `min_live_peers` - The number of connected peers whose availability supersedes the snapshot.
+    pub fn warm_start(&mut self, snapshot: &AvailabilitySnapshot, min_live_peers: usize) {
+        let availability = snapshot.availability();
+
+        if availability.len() == self.availability.len() {
+            self.warm_start = Some((availability, min_live_peers));
+        }
+    }
+
+    /// Captures the availability among the connected peers, to warm start a later session with.
+    pub fn snapshot(&self) -> AvailabilitySnapshot {
+        AvailabilitySnapshot::capture(&self.availability)
+    }
+
+    /// Replaces the strategy used to choose pieces, pieces already assigned are unaffected.
+    pub fn set_picker(&mut self, mut picker: Box<dyn PiecePicker>) {
+        picker.on_selection_changed(&self.availability, &self.verified);
+        self.picker = picker;
+    }
+
+    /// Replaces the strategy used to choose pieces with any put in `swap`, checked each time a
+    /// piece is assigned.
+    pub fn watch_picker_swap(&mut self, swap: PickerSwap) {
+        self.picker_swap = Some(swap);
+    }
+
+    /// Chooses the next piece to download from a peer and marks it as in progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer has.
+    pub fn assign(&mut self, peer_pieces: &Bitfield) -> Option<PieceAssignment> {
+        if let Some(picker) = self.picker_swap.as_ref().and_then(|swap| swap.lock().unwrap().take()) {
+            self.set_picker(picker);
+        }
+
+        let availability = match &self.warm_start {
+            Some((snapshot, min_live_peers)) if self.live_peers < *min_live_peers => snapshot,
+            _ => &self.availability,
+        };
+
+        let context = PickerContext {
+            availability,
+            verified: &self.verified,
+            in_progress: &self.in_progress,
+            skipped: &self.skipped,
+            peer_pieces,
+            piece_length: self.piece_length,
+            total_length: self.total_length,
+        };
+
+        self.held_back = false;
+        let assignment = self.picker.pick(&context)?;
+
+        if let Some(gate) = &self.memory_gate {
+            if !gate.try_reserve(assignment.length as u64) {
+                self.held_back = true;
+                return None
+            }
+        }
+
+        self.in_progress.set(assignment.index);
+
+        Some(assignment)
+    }
+
+    /// Records that a peer has all the pieces in the given bitfield.
+    pub fn add_peer(&mut self, peer_pieces: &Bitfield) {
+        self.live_peers += 1;
+        for index in peer_pieces.indices() {
+            self.peer_has(index);
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected, so they aren't counted again when the
+    /// peer reconnects and sends a fresh bitfield.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer had, including those it announced with a have.
+    pub fn remove_peer(&mut self, peer_pieces: &Bitfield) {
+        self.live_peers = self.live_peers.saturating_sub(1);
+        for index in peer_pieces.indices() {
+            if let Some(count) = self.availability.get_mut(index as usize) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Records a have from a connected peer, ignoring it if the peer already has the piece.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer has, updated with the new piece.
+    /// * `index` - The piece the peer announced.
+    pub fn peer_have(&mut self, peer_pieces: &mut Bitfield, index: u32) {
+        if index as usize >= peer_pieces.len() || peer_pieces.has(index) {
+            return
+        }
+
+        peer_pieces.set(index);
+        self.peer_has(index);
+    }
+
+    /// Returns the number of connected peers known to have each piece.
+    pub fn availability(&self) -> &[u32] {
+        &self.availability
+    }
+
+    /// Records that a peer has a piece.
+    pub fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+            self.picker.on_have(index);
+        }
+    }
+
+    /// Records that a piece was downloaded and verified.
+    pub fn piece_complete(&mut self, index: u32) {
+        self.release(index);
+        self.in_progress.clear(index);
+        self.verified.set(index);
+        self.picker.on_piece_complete(index);
+    }
+
+    /// Records that a piece failed to download or verify, so it can be assigned again.
+    pub fn piece_failed(&mut self, index: u32) {
+        self.release(index);
+        self.in_progress.clear(index);
+        self.picker.on_piece_failed(index);
+    }
+
+    /// Never assigns a piece, such as one before the piece a download was started from. It is
+    /// still missing until it is marked as complete.
+    pub fn skip(&mut self, index: u32) {
+        self.skipped.set(index);
+    }
+
+    /// Returns whether a peer has any piece that still needs downloading.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer has.
+    pub fn needed_from(&self, peer_pieces: &Bitfield) -> bool {
+        peer_pieces.indices().any(|index| !self.verified.has(index) && !self.skipped.has(index))
+    }
+
+    /// Scores how much of what is still wanted a peer covers, each wanted piece it has weighted
+    /// by 1 over the number of connected peers that have it. A peer that alone has a piece
+    /// scores at least 1, one that only has pieces every peer has scores little.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer has.
+    pub fn rare_coverage(&self, peer_pieces: &Bitfield) -> f64 {
+        peer_pieces.indices()
+            .filter(|index| !self.verified.has(*index) && !self.skipped.has(*index))
+            .map(|index| 1.0 / self.availability.get(index as usize).copied().unwrap_or(1).max(1) as f64)
+            .sum()
+    }
+
+    /// Returns whether a peer is the only connected peer with a piece that is still wanted.
+    ///
+    /// # Arguments
+    ///
+    /// * `peer_pieces` - The pieces the peer has.
+    pub fn sole_source(&self, peer_pieces: &Bitfield) -> bool {
+        peer_pieces.indices()
+            .any(|index| !self.verified.has(index) && !self.skipped.has(index) && self.availability.get(index as usize) == Some(&1))
+    }
+
+    /// Returns whether any piece that can be assigned still needs downloading, so a peer that
+    /// announces it would be asked for it.
+    pub fn has_wanted_pieces(&self) -> bool {
+        (0..self.availability.len() as u32).any(|index| !self.verified.has(index) && !self.skipped.has(index))
+    }
+
+    /// Returns the indices of the pieces that haven't been downloaded and verified, including
+    /// those in progress.
+    pub fn missing_pieces(&self) -> Vec<u32> {
+        (0..self.availability.len() as u32)
+            .filter(|index| !self.verified.has(*index))
+            .collect()
+    }
+
+    /// Returns an error listing the missing pieces unless every piece has been downloaded and
+    /// verified, for when no more pieces can be assigned and no more peers can be found.
+    pub fn ensure_complete(&self) -> Result<(), IncompletePieces> {
+        if self.is_complete() {
+            Ok(())
+        } else {
+            Err(IncompletePieces { missing: self.missing_pieces() })
+        }
+    }
+
+    /// Returns the pieces that have been downloaded and verified.
+    pub fn verified(&self) -> &Bitfield {
+        &self.verified
+    }
+
+    /// Returns whether every piece has been downloaded and verified.
+    pub fn is_complete(&self) -> bool {
+        self.verified.is_complete()
+    }
+
+    /// Releases the memory reserved for a piece, if it is in progress.
+    fn release(&mut self, index: u32) {
+        let Some(gate) = &self.memory_gate else {
+            return
+        };
+
+        if self.in_progress.has(index) {
+            let start = index as u64 * self.piece_length;
+            gate.release(self.total_length.saturating_sub(start).min(self.piece_length));
+        }
+    }
+}
+
+impl Drop for PieceLedger {
+    /// Releases the memory of pieces still in progress, so a gate shared with other downloads
+    /// isn't left holding it
+    fn drop(&mut self) {
+        for index in self.in_progress.indices().collect::<Vec<u32>>() {
+            self.release(index);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Downloads pieces from the last to the first
+    pub(crate) struct Reverse;
+
+    impl PiecePicker for Reverse {
+        fn pick(&mut self, context: &PickerContext) -> Option<PieceAssignment> {
+            (0..context.num_pieces())
+                .rev()
+                .find(|index| context.is_candidate(*index))
+                .map(|index| context.assign(index))
+        }
+    }
+
+    /// Assigns and completes pieces until the ledger has nothing left to assign
+    fn download_order(ledger: &mut PieceLedger, peer_pieces: &Bitfield) -> Vec<u32> {
+        let mut order = vec![];
+
+        while let Some(assignment) = ledger.assign(peer_pieces) {
+            ledger.piece_complete(assignment.index);
+            order.push(assignment.index);
+        }
+
+        order
+    }
+
+    #[test]
+    fn sequential_order() {
+        let torrent = Torrent::from_pieces("sequential", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(4)), vec![0, 1, 2, 3]);
+        assert!(ledger.is_complete());
+    }
+
+    #[test]
+    fn rarest_first_order() {
+        let torrent = Torrent::from_pieces("rarest_first", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(RarestFirst::default()));
+
+        let mut common = Bitfield::full(4);
+        common.clear(2);
+        ledger.add_peer(&common);
+        ledger.add_peer(&common);
+        ledger.add_peer(&Bitfield::full(4));
+        ledger.peer_has(0);
+
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(4)), vec![2, 1, 3, 0]);
+    }
+
+    #[test]
+    fn rarest_first_min_availability() {
+        let torrent = Torrent::from_pieces("min_availability", 16, &[0; 80]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(RarestFirst { min_availability: 2 }));
+
+        // Piece 0 is held by one other peer, 1 and 4 by two, 2 by three and 3 by none
+        for index in [0, 1, 1, 2, 2, 2, 4, 4] {
+            ledger.peer_has(index);
+        }
+
+        // The rarest of the pieces held by at least two peers go first, then the single source ones
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(5)), vec![1, 4, 2, 3, 0]);
+    }
+
+    #[test]
+    fn availability_survives_reconnects() {
+        let torrent = Torrent::from_pieces("reconnects", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(RarestFirst::default()));
+        ledger.add_peer(&Bitfield::full(4));
+
+        for _ in 0..5 {
+            let mut peer_pieces = Bitfield::new(4);
+            peer_pieces.set(0);
+            ledger.add_peer(&peer_pieces);
+
+            // Repeated and out of range haves don't count twice
+            for index in [1, 1, 0, 9] {
+                ledger.peer_have(&mut peer_pieces, index);
+            }
+            assert_eq!(ledger.availability(), &[2, 2, 1, 1]);
+
+            ledger.remove_peer(&peer_pieces);
+            assert_eq!(ledger.availability(), &[1, 1, 1, 1]);
+        }
+    }
+
+    #[test]
+    fn warm_start_prefers_persisted_rare_pieces() {
+        let torrent = Torrent::from_pieces("warm_start", 16, &[0; 96]);
+        let snapshot = AvailabilitySnapshot::capture(&[9, 30, 2, 1, 12, 5]);
+        let snapshot = AvailabilitySnapshot::decode(&snapshot.encode(), 6).unwrap();
+
+        let mut ledger = PieceLedger::new(&torrent, Box::new(RarestFirst::default()));
+        ledger.warm_start(&snapshot, 2);
+        ledger.piece_complete(3);
+        ledger.add_peer(&Bitfield::full(6));
+
+        // One live peer isn't enough to trust, so the rare pieces of the last session go first
+        let first = ledger.assign(&Bitfield::full(6)).unwrap();
+        let second = ledger.assign(&Bitfield::full(6)).unwrap();
+        assert_eq!([first.index, second.index], [2, 5]);
+
+        // Once enough peers are connected their availability, equal for every piece, takes over
+        ledger.add_peer(&Bitfield::full(6));
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(6)), vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn availability_snapshot_encoding() {
+        let mut availability = vec![3; 100_000];
+        availability[..10].copy_from_slice(&[0, 1, 2, 3, 4, 7, 8, 1000, 100_000, u32::MAX]);
+
+        let snapshot = AvailabilitySnapshot::capture(&availability);
+        let encoded = snapshot.encode();
+
+        assert!(encoded.len() < 32, "{} bytes", encoded.len());
+        assert_eq!(AvailabilitySnapshot::decode(&encoded, 100_000).ok(), Some(snapshot.clone()));
+        assert_eq!(&snapshot.availability()[..10], &[0, 1, 2, 2, 4, 4, 8, 512, 16_384, 16_384]);
+
+        assert!(AvailabilitySnapshot::decode(&encoded, 99_999).is_err());
+        assert!(AvailabilitySnapshot::decode(&encoded[..encoded.len() - 1], 100_000).is_err());
+        assert!(AvailabilitySnapshot::decode(&[16, 1], 1).is_err());
+    }
+
+    #[test]
+    fn custom_picker() {
+        let torrent = Torrent::from_pieces("custom", 16, &[0; 56]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Reverse));
+
+        let last = ledger.assign(&Bitfield::full(4)).unwrap();
+        assert_eq!(last, PieceAssignment { index: 3, length: 8 });
+        ledger.piece_complete(last.index);
+
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(4)), vec![2, 1, 0]);
+        assert!(ledger.is_complete());
+    }
+
+    #[test]
+    fn swap_picker_at_runtime() {
+        let torrent = Torrent::from_pieces("swap", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        let first = ledger.assign(&Bitfield::full(4)).unwrap();
+        ledger.set_picker(Box::new(Reverse));
+
+        // The piece already in progress isn't handed out again
+        assert_eq!(first.index, 0);
+        assert_eq!(download_order(&mut ledger, &Bitfield::full(4)), vec![3, 2, 1]);
+    }
+
+    /// Records the verified pieces it is handed each time it is selected
+    struct Selections(Arc<Mutex<Vec<Vec<u32>>>>);
+
+    impl PiecePicker for Selections {
+        fn pick(&mut self, context: &PickerContext) -> Option<PieceAssignment> {
+            Sequential.pick(context)
+        }
+
+        fn on_selection_changed(&mut self, _availability: &[u32], verified: &Bitfield) {
+            self.0.lock().unwrap().push(verified.indices().collect());
+        }
+    }
+
+    #[test]
+    fn selection_changes_hand_over_state() {
+        let torrent = Torrent::from_pieces("selection", 16, &[0; 64]);
+        let selections = Arc::new(Mutex::new(vec![]));
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Selections(selections.clone())));
+
+        let first = ledger.assign(&Bitfield::full(4)).unwrap();
+        ledger.piece_complete(first.index);
+
+        // A swap is taken up as the next piece is assigned
+        let swap = PickerSwap::default();
+        ledger.watch_picker_swap(swap.clone());
+        *swap.lock().unwrap() = Some(Box::new(Selections(selections.clone())));
+        assert_eq!(ledger.assign(&Bitfield::full(4)).map(|assignment| assignment.index), Some(1));
+
+        assert_eq!(*selections.lock().unwrap(), vec![vec![], vec![0]]);
+        assert!(swap.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_piece_is_reassigned() {
+        let torrent = Torrent::from_pieces("failed", 16, &[0; 32]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        let peer_pieces = Bitfield::full(2);
+
+        let first = ledger.assign(&peer_pieces).unwrap();
+        ledger.piece_failed(first.index);
+
+        assert_eq!(ledger.assign(&peer_pieces).map(|assignment| assignment.index), Some(0));
+    }
+
+    #[test]
+    fn pieces_the_peer_lacks_are_skipped() {
+        let torrent = Torrent::from_pieces("lacks", 16, &[0; 32]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        let mut peer_pieces = Bitfield::new(2);
+        peer_pieces.set(1);
+
+        assert_eq!(download_order(&mut ledger, &peer_pieces), vec![1]);
+        assert!(!ledger.is_complete());
+    }
+
+    #[test]
+    fn assignment_pauses_at_memory_limit() {
+        let torrent = Torrent::from_pieces("memory", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        let gate = MemoryGate::new(32);
+        ledger.set_memory_gate(gate.clone());
+        let peer_pieces = Bitfield::full(4);
+
+        assert_eq!(ledger.assign(&peer_pieces).map(|assignment| assignment.index), Some(0));
+        assert_eq!(ledger.assign(&peer_pieces).map(|assignment| assignment.index), Some(1));
+        assert_eq!(ledger.assign(&peer_pieces), None);
+        assert_eq!(gate.in_use(), 32);
+
+        ledger.piece_complete(0);
+        assert_eq!(ledger.assign(&peer_pieces).map(|assignment| assignment.index), Some(2));
+
+        ledger.piece_failed(1);
+        ledger.piece_complete(2);
+        assert_eq!(gate.in_use(), 0);
+    }
+
+    #[test]
+    fn memory_gate_is_shared_between_ledgers() {
+        let torrent = Torrent::from_pieces("shared", 16, &[0; 32]);
+        let gate = MemoryGate::new(20);
+        let mut first = PieceLedger::new(&torrent, Box::new(Sequential));
+        let mut second = PieceLedger::new(&torrent, Box::new(Sequential));
+        first.set_memory_gate(gate.clone());
+        second.set_memory_gate(gate.clone());
+
+        // A piece larger than the limit is still allowed when nothing else is in progress
+        assert!(MemoryGate::new(8).try_reserve(16));
+
+        assert!(first.assign(&Bitfield::full(2)).is_some());
+        assert_eq!(second.assign(&Bitfield::full(2)), None);
+        assert!(second.held_back());
+
+        first.piece_complete(0);
+        assert!(second.assign(&Bitfield::full(2)).is_some());
+
+        // A ledger dropped with pieces in progress releases them
+        drop(second);
+        assert_eq!(gate.in_use(), 0);
+    }
+
+    #[test]
+    fn zero_and_single_piece_torrents() {
+        let empty = Torrent::from_pieces("empty", 16, &[]);
+        let mut ledger = PieceLedger::new(&empty, Box::new(RarestFirst::default()));
+
+        assert_eq!(ledger.assign(&Bitfield::new(0)), None);
+        assert!(!ledger.needed_from(&Bitfield::new(0)));
+        assert!(ledger.missing_pieces().is_empty());
+        assert!(ledger.is_complete());
+
+        let single = Torrent::from_pieces("single", 16, &[0; 1]);
+        let mut ledger = PieceLedger::new(&single, Box::new(RarestFirst::default()));
+        ledger.add_peer(&Bitfield::full(1));
+
+        assert_eq!(ledger.assign(&Bitfield::full(1)), Some(PieceAssignment { index: 0, length: 1 }));
+        assert_eq!(ledger.assign(&Bitfield::full(1)), None);
+        assert!(!ledger.is_complete());
+
+        ledger.piece_complete(0);
+        assert!(ledger.is_complete());
+        assert_eq!(ledger.verified().as_bytes(), &[0b1000_0000]);
+    }
+
+    #[test]
+    fn needed_and_missing_pieces() {
+        let torrent = Torrent::from_pieces("needed", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        ledger.piece_complete(0);
+        ledger.piece_complete(2);
+        let in_progress = ledger.assign(&Bitfield::full(4)).unwrap();
+
+        // A piece in progress is still missing until it has been verified
+        assert_eq!(in_progress.index, 1);
+        assert_eq!(ledger.missing_pieces(), vec![1, 3]);
+
+        let mut complete_only = Bitfield::new(4);
+        complete_only.set(0);
+        complete_only.set(2);
+        assert!(!ledger.needed_from(&complete_only));
+        assert!(!ledger.needed_from(&Bitfield::new(4)));
+
+        complete_only.set(3);
+        assert!(ledger.needed_from(&complete_only));
+    }
+
+    #[test]
+    fn skipped_pieces_are_never_assigned() {
+        let torrent = Torrent::from_pieces("skipped", 16, &[0; 48]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+        ledger.skip(0);
+        ledger.skip(1);
+
+        let mut skipped_only = Bitfield::new(3);
+        skipped_only.set(0);
+        skipped_only.set(1);
+        assert!(!ledger.needed_from(&skipped_only));
+        assert_eq!(ledger.assign(&Bitfield::full(3)).map(|assignment| assignment.index), Some(2));
+        assert!(ledger.has_wanted_pieces());
+
+        // Skipped pieces are still missing, but nothing more is wanted
+        ledger.piece_complete(2);
+        assert!(!ledger.has_wanted_pieces());
+        assert_eq!(ledger.missing_pieces(), vec![0, 1]);
+    }
+
+    #[test]
+    fn rare_pieces_weigh_on_coverage() {
+        let torrent = Torrent::from_pieces("coverage", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        // Every peer has pieces 0 and 1, only one has piece 3
+        let mut common = Bitfield::new(4);
+        common.set(0);
+        common.set(1);
+        let mut rare = common.clone();
+        rare.set(3);
+        for pieces in [&common, &common, &common, &rare] {
+            ledger.add_peer(pieces);
+        }
+
+        assert_eq!(ledger.rare_coverage(&common), 0.5);
+        assert_eq!(ledger.rare_coverage(&rare), 1.5);
+        assert!(ledger.sole_source(&rare) && !ledger.sole_source(&common));
+
+        // Pieces already downloaded aren't wanted
+        ledger.piece_complete(3);
+        assert_eq!(ledger.rare_coverage(&rare), 0.5);
+        assert!(!ledger.sole_source(&rare));
+    }
+
+    #[test]
+    fn unobtainable_pieces_leave_download_incomplete() {
+        let torrent = Torrent::from_pieces("incomplete", 16, &[0; 64]);
+        let mut ledger = PieceLedger::new(&torrent, Box::new(Sequential));
+
+        let mut peer_pieces = Bitfield::new(4);
+        peer_pieces.set(0);
+        peer_pieces.set(2);
+        ledger.add_peer(&peer_pieces);
+
+        while let Some(assignment) = ledger.assign(&peer_pieces) {
+            ledger.piece_complete(assignment.index);
+        }
+
+        assert_eq!(ledger.ensure_complete(), Err(IncompletePieces { missing: vec![1, 3] }));
+
+        ledger.piece_complete(1);
+        ledger.piece_complete(3);
+        assert_eq!(ledger.ensure_complete(), Ok(()));
+    }
+}
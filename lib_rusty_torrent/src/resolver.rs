@@ -0,0 +1,62 @@
+//! Resolution of tracker and announce hostnames
+
+use std::{ fmt, io, net::{ IpAddr, SocketAddr }, sync::Arc };
+
+// Crate Imports
+use crate::error::Error;
+
+// External imports
+use async_trait::async_trait;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{ Addrs, Resolve, Resolving };
+use tokio::net::lookup_host;
+
+/// Resolves hostnames to ip addresses.
+///
+/// The default `SystemResolver` asks the operating system, an embedder can provide one that
+/// uses DNS over HTTPS, a specific server, or keeps lookups inside a VPN.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Returns every address the host resolves to.
+    ///
+    /// # Arguments
+    ///
+    /// * `host` - The hostname to resolve.
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error>;
+}
+
+impl fmt::Debug for dyn Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+/// Resolves hostnames with the operating system's resolver.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        match lookup_host((host, 0)).await {
+            Err(err) => Err(Error::IoError(io::Error::new(err.kind(), format!("unable to resolve {host}, {err}")))),
+            Ok(addresses) => Ok(addresses.map(|address| address.ip()).collect())
+        }
+    }
+}
+
+/// Lets reqwest look up the hosts of HTTP trackers, and any they redirect to, with a `Resolver`.
+pub(crate) struct ReqwestResolver(pub Arc<dyn Resolver>);
+
+impl Resolve for ReqwestResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.0.clone();
+
+        Box::pin(async move {
+            let ips = resolver.resolve(name.as_str()).await?;
+            // The port is replaced with the one in the url
+            let addresses: Addrs = Box::new(ips.into_iter().map(|ip| SocketAddr::new(ip, 0)));
+            Ok(addresses)
+        })
+    }
+}
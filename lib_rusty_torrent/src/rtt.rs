@@ -0,0 +1,145 @@
+//! Estimates how long a peer takes to respond, to time out requests adaptively
+
+use std::time::Duration;
+
+use serde::{ Deserialize, Serialize };
+
+use crate::{ config::duration, error::Error };
+
+/// The range an adaptive request timeout is kept within.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct TimeoutBounds {
+    /// The shortest timeout, so a fast peer isn't timed out over a brief hiccup.
+    #[serde(with = "duration")]
+    pub min: Duration,
+    /// The longest timeout, so a stalled peer is eventually given up on.
+    #[serde(with = "duration")]
+    pub max: Duration,
+}
+
+impl Default for TimeoutBounds {
+    fn default() -> Self {
+        Self { min: Duration::from_secs(2), max: Duration::from_secs(60) }
+    }
+}
+
+impl TimeoutBounds {
+    /// Checks that the bounds form a range, returning `Error::InvalidConfig` if `min` is above `max`.
+    pub fn check(&self) -> Result<(), Error> {
+        if self.min <= self.max {
+            Ok(())
+        } else {
+            Err(Error::InvalidConfig(format!(
+                "the adaptive timeout's min of {}ms is above its max of {}ms",
+                self.min.as_millis(), self.max.as_millis()
+            )))
+        }
+    }
+}
+
+/// A smoothed estimate of a peer's response time and its variation, calculated the way TCP
+/// calculates its retransmission timeout (RFC 6298).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RttEstimator {
+    /// The smoothed response time, `None` until the first sample
+    srtt: Option<Duration>,
+    /// The smoothed variation in response time
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Adds the time taken to respond to a request to the estimate.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Returns the smoothed response time, `None` until a response has been sampled.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Returns the smoothed variation in response time.
+    pub fn rttvar(&self) -> Duration {
+        self.rttvar
+    }
+
+    /// Returns the request timeout of `srtt + 4 * rttvar` within the given bounds, or `None`
+    /// until a response has been sampled. Bounds that `TimeoutBounds::check` rejects give `max`.
+    pub fn timeout(&self, bounds: TimeoutBounds) -> Option<Duration> {
+        let srtt = self.srtt?;
+
+        Some((srtt + self.rttvar * 4).max(bounds.min).min(bounds.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: TimeoutBounds = TimeoutBounds { min: Duration::from_millis(10), max: Duration::from_secs(10) };
+
+    /// Returns an estimator after a run of identical samples
+    fn converged(rtt: Duration) -> RttEstimator {
+        let mut estimator = RttEstimator::default();
+        for _ in 0..50 {
+            estimator.sample(rtt);
+        }
+
+        estimator
+    }
+
+    #[test]
+    fn first_sample() {
+        let mut estimator = RttEstimator::default();
+        assert_eq!(estimator.timeout(BOUNDS), None);
+
+        estimator.sample(Duration::from_millis(100));
+        assert_eq!(estimator.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(estimator.rttvar(), Duration::from_millis(50));
+        assert_eq!(estimator.timeout(BOUNDS), Some(Duration::from_millis(300)));
+    }
+
+    #[test]
+    fn converges_to_steady_latency() {
+        let lan = converged(Duration::from_millis(5));
+        let intercontinental = converged(Duration::from_millis(300));
+
+        assert_eq!(lan.srtt(), Some(Duration::from_millis(5)));
+        assert!(intercontinental.rttvar() < Duration::from_millis(1));
+
+        // The fast peer is held to the lower bound, the slow one gets just over its latency
+        assert_eq!(lan.timeout(BOUNDS), Some(BOUNDS.min));
+        let timeout = intercontinental.timeout(BOUNDS).unwrap();
+        assert!(timeout >= Duration::from_millis(300) && timeout < Duration::from_millis(310));
+    }
+
+    #[test]
+    fn jitter_widens_timeout() {
+        let mut estimator = converged(Duration::from_millis(100));
+        let steady = estimator.timeout(BOUNDS).unwrap();
+
+        estimator.sample(Duration::from_millis(900));
+        assert!(estimator.timeout(BOUNDS).unwrap() > steady + Duration::from_millis(500));
+        assert_eq!(converged(Duration::from_secs(30)).timeout(BOUNDS), Some(BOUNDS.max));
+    }
+
+    #[test]
+    fn inverted_bounds_are_rejected() {
+        let inverted = TimeoutBounds { min: BOUNDS.max, max: BOUNDS.min };
+
+        assert!(BOUNDS.check().is_ok());
+        assert!(matches!(inverted.check(), Err(Error::InvalidConfig(_))));
+        // Used anyway, they don't panic
+        assert_eq!(converged(Duration::from_millis(100)).timeout(inverted), Some(BOUNDS.min));
+    }
+}
@@ -0,0 +1,151 @@
+//! Applies `SocketOptions` to the sockets of peer connections and trackers
+
+// Crate Imports
+use crate::config::SocketOptions;
+
+// External imports
+use socket2::{ SockRef, TcpKeepalive, Type };
+use std::{ fmt, io };
+
+/// An option that couldn't be set on a socket, such as one the platform doesn't support.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnsupportedOption {
+    /// The name of the option, as in `SocketOptions`.
+    pub option: &'static str,
+    /// Why the option couldn't be set.
+    pub reason: String,
+}
+
+impl fmt::Display for UnsupportedOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unable to set socket option {}, {}", self.option, self.reason)
+    }
+}
+
+/// Sets the options on a socket, before it connects. Options only TCP has are skipped for a
+/// UDP socket.
+///
+/// # Arguments
+///
+/// * `socket` - A TCP or UDP socket.
+/// * `options` - The options to set, those left as `None` aren't touched.
+///
+/// # Returns
+///
+/// * The options that couldn't be set, the rest are set regardless.
+pub fn apply_socket_options(socket: SockRef<'_>, options: &SocketOptions) -> Vec<UnsupportedOption> {
+    let mut unsupported = vec![];
+    let mut check = |option, result: io::Result<()>| if let Err(err) = result {
+        unsupported.push(UnsupportedOption { option, reason: err.to_string() });
+    };
+
+    if let Some(tos) = options.tos {
+        check("tos", set_tos(&socket, tos));
+    }
+
+    if let Some(size) = options.recv_buffer {
+        check("recv_buffer", socket.set_recv_buffer_size(size as usize));
+    }
+
+    if let Some(size) = options.send_buffer {
+        check("send_buffer", socket.set_send_buffer_size(size as usize));
+    }
+
+    if socket.r#type().ok() != Some(Type::STREAM) {
+        return unsupported
+    }
+
+    if let Some(nodelay) = options.nodelay {
+        check("nodelay", socket.set_tcp_nodelay(nodelay));
+    }
+
+    if let Some(keepalive) = options.keepalive {
+        let mut params = TcpKeepalive::new().with_time(keepalive.idle);
+        if let Some(interval) = keepalive.interval {
+            params = params.with_interval(interval);
+        }
+        if let Some(retries) = keepalive.retries {
+            params = params.with_retries(retries);
+        }
+
+        check("keepalive", socket.set_tcp_keepalive(&params));
+    }
+
+    unsupported
+}
+
+/// Sets the type of service byte of an IPv4 socket
+#[cfg(not(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "haiku", target_os = "wasi")))]
+fn set_tos(socket: &SockRef<'_>, tos: u8) -> io::Result<()> {
+    socket.set_tos_v4(tos as u32)
+}
+
+/// Sets the type of service byte of an IPv4 socket
+#[cfg(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "haiku", target_os = "wasi"))]
+fn set_tos(_socket: &SockRef<'_>, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeepaliveOptions;
+    use std::time::Duration;
+    use tokio::net::{ TcpSocket, UdpSocket };
+
+    fn options() -> SocketOptions {
+        SocketOptions {
+            tos: Some(0x20),
+            nodelay: Some(true),
+            recv_buffer: Some(1 << 18),
+            send_buffer: Some(1 << 18),
+            keepalive: Some(KeepaliveOptions { idle: Duration::from_secs(60), interval: Some(Duration::from_secs(10)), retries: Some(3) }),
+        }
+    }
+
+    #[test]
+    fn tcp_options_read_back() {
+        let socket = TcpSocket::new_v4().unwrap();
+
+        assert_eq!(apply_socket_options(SockRef::from(&socket), &options()), vec![]);
+
+        let socket = SockRef::from(&socket);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        // Linux doubles the buffer sizes it is given for its own bookkeeping
+        assert!(socket.recv_buffer_size().unwrap() >= 1 << 18);
+        assert!(socket.send_buffer_size().unwrap() >= 1 << 18);
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.tos_v4().unwrap(), 0x20);
+            assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(60));
+            assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(10));
+            assert_eq!(socket.tcp_keepalive_retries().unwrap(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn udp_skips_tcp_options() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        assert_eq!(apply_socket_options(SockRef::from(&socket), &options()), vec![]);
+
+        let socket = SockRef::from(&socket);
+        assert!(socket.recv_buffer_size().unwrap() >= 1 << 18);
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.tos_v4().unwrap(), 0x20);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn failed_options_are_reported() {
+        // A unix socket is a stream, but has none of the options of TCP or IP
+        let (socket, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let options = SocketOptions { nodelay: Some(true), send_buffer: Some(1 << 16), ..Default::default() };
+
+        let unsupported = apply_socket_options(SockRef::from(&socket), &options);
+
+        assert_eq!(unsupported.iter().map(|option| option.option).collect::<Vec<_>>(), vec!["nodelay"]);
+        assert!(SockRef::from(&socket).send_buffer_size().unwrap() >= 1 << 16);
+    }
+}
@@ -0,0 +1,130 @@
+//! Mock peers and trackers on local ports, for tests of this crate and of clients built on it
+//!
+//! Only compiled for the crate's own tests, or with the `test-util` feature.
+
+// Crate Imports
+use crate::{ bitfield::Bitfield, peer_wire_protocol::Handshake };
+
+// External imports
+use std::{
+    net::{ SocketAddr, SocketAddrV4 },
+    time::Duration
+};
+use tokio::{
+    io::{ AsyncReadExt, AsyncWriteExt },
+    net::{ TcpListener, TcpStream, UdpSocket },
+    task::JoinHandle
+};
+
+/// Binds a mock peer to a random local port, it answers a handshake with an unchoke
+pub async fn mock_peer() -> (SocketAddrV4, JoinHandle<TcpStream>) {
+    mock_peer_with(vec![0, 0, 0, 1, 1]).await
+}
+
+/// Binds a mock peer to a random local port, it answers a handshake with `messages` in the same write
+pub async fn mock_peer_with(messages: Vec<u8>) -> (SocketAddrV4, JoinHandle<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let SocketAddr::V4(socket_address) = listener.local_addr().unwrap() else {
+        panic!("Expected an ipv4 address")
+    };
+
+    let mock = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut handshake = vec![0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+
+        let mut response = Handshake::new(&[1; 20], String::from("-MY0001-123456654321")).unwrap().to_buffer();
+        response.extend(messages);
+        stream.write_all(&response).await.unwrap();
+
+        stream
+    });
+
+    (socket_address, mock)
+}
+
+/// Serializes a piece message carrying a block
+pub fn block_message(index: u32, offset: u32, block: &[u8]) -> Vec<u8> {
+    let mut message = (block.len() as u32 + 9).to_be_bytes().to_vec();
+    message.push(7);
+    message.extend(index.to_be_bytes());
+    message.extend(offset.to_be_bytes());
+    message.extend(block);
+    message
+}
+
+/// Serializes a bitfield message of a peer that has every one of `num_pieces` pieces
+pub fn full_bitfield_message(num_pieces: usize) -> Vec<u8> {
+    let bitfield = Bitfield::full(num_pieces);
+
+    let mut message = (bitfield.as_bytes().len() as u32 + 1).to_be_bytes().to_vec();
+    message.push(5);
+    message.extend(bitfield.as_bytes());
+    message
+}
+
+/// Binds a mock peer that seeds `data`, announcing every piece with a bitfield and answering
+/// every request after `latency` until the connection closes
+pub async fn mock_seed(data: Vec<u8>, piece_length: u64, latency: Duration) -> SocketAddrV4 {
+    let num_pieces = (data.len() as u64).div_ceil(piece_length) as usize;
+    let mut messages = full_bitfield_message(num_pieces);
+    messages.extend([0, 0, 0, 1, 1]);
+    let (socket_address, mock) = mock_peer_with(messages).await;
+
+    tokio::spawn(async move {
+        let mut stream = mock.await.unwrap();
+        let mut length = [0; 4];
+
+        while stream.read_exact(&mut length).await.is_ok() {
+            let mut message = vec![0; u32::from_be_bytes(length) as usize];
+            stream.read_exact(&mut message).await.unwrap();
+
+            // Only requests are answered
+            if message.first() != Some(&6) {
+                continue
+            }
+
+            let field = |at: usize| u32::from_be_bytes([message[at], message[at + 1], message[at + 2], message[at + 3]]);
+            let (index, offset, length) = (field(1), field(5), field(9));
+            let start = (index as u64 * piece_length + offset as u64) as usize;
+
+            tokio::time::sleep(latency).await;
+            stream.write_all(&block_message(index, offset, &data[start..start + length as usize])).await.unwrap();
+        }
+    });
+
+    socket_address
+}
+
+/// Binds a mock UDP tracker that answers a connect and an announce with the given peers
+pub async fn mock_tracker(peers: Vec<SocketAddrV4>) -> SocketAddr {
+    let mock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = mock.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut buf = [0; 128];
+
+        let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+        let mut response: Vec<u8> = vec![];
+        response.extend(0_i32.to_be_bytes());
+        response.extend(&buf[12..16]);
+        response.extend(7_i64.to_be_bytes());
+        mock.send_to(&response, from).await.unwrap();
+
+        let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+        let mut response: Vec<u8> = vec![];
+        response.extend(1_i32.to_be_bytes());
+        response.extend(&buf[12..16]);
+        response.extend(1800_i32.to_be_bytes());
+        response.extend(0_i32.to_be_bytes());
+        response.extend((peers.len() as i32).to_be_bytes());
+        for peer in &peers {
+            response.extend(peer.ip().octets());
+            response.extend(peer.port().to_be_bytes());
+        }
+        mock.send_to(&response, from).await.unwrap();
+    });
+
+    address
+}
@@ -1,8 +1,14 @@
-use regex::Regex;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use tokio::{fs::File as TokioFile, io::AsyncReadExt};
-use std::net::{IpAddr, SocketAddrV4};
+
+use crate::{config::TrackerFilter, error::Error, resolver::Resolver, tracker::{TrackerEndpoint, TrackerUrl}};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr, SocketAddrV4},
+    ops::{Range, RangeInclusive},
+    path::{Component, Path}
+};
 
 /// Represents a node in a DHT network.
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -12,11 +18,39 @@ struct Node(String, i64);
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct File {
     pub path: Vec<String>,
+    /// The path in UTF-8, given alongside `path` by clients that encode `path` otherwise.
+    #[serde(default, rename = "path.utf-8")]
+    pub path_utf8: Option<Vec<String>>,
     pub length: u64,
     #[serde(default)]
     md5sum: Option<String>,
 }
 
+impl File {
+    /// Returns the path the file is created at, `path_utf8` when the torrent gives it.
+    pub fn local_path(&self) -> &[String] {
+        self.path_utf8.as_deref().unwrap_or(&self.path)
+    }
+}
+
+/// Checks that a name the torrent gives, its own name or a component of a file's path, names a
+/// single file or directory. Components like `..`, absolute paths and names containing a
+/// separator would otherwise place files outside the directory the torrent is downloaded into.
+///
+/// # Arguments
+///
+/// * `component` - The name to check.
+pub fn check_path_component(component: &str) -> Result<(), Error> {
+    let mut components = Path::new(component).components();
+    let single = matches!((components.next(), components.next()), (Some(Component::Normal(name)), None) if name == component);
+
+    if single && !component.contains(['/', '\\', '\0']) {
+        Ok(())
+    } else {
+        Err(Error::InvalidTorrent(format!("{component:?} isn't a valid file name")))
+    }
+}
+
 /// Represents the metadata of a torrent.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Info {
@@ -54,7 +88,7 @@ pub struct Torrent {
     httpseeds: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
-    announce_list: Option<Vec<Vec<String>>>,
+    pub(crate) announce_list: Option<Vec<Vec<String>>>,
     #[serde(default)]
     #[serde(rename = "creation date")]
     creation_date: Option<i64>,
@@ -62,7 +96,19 @@ pub struct Torrent {
     comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
-    created_by: Option<String>
+    created_by: Option<String>,
+    /// Whether `info` was read in full. A torrent from a magnet link only has a name, so its
+    /// pieces and lengths are empty until the metadata is fetched.
+    #[serde(skip, default = "metadata_is_complete")]
+    pub metadata_complete: bool,
+    /// The info hash given by a magnet link, used in place of hashing the incomplete `info`.
+    #[serde(skip)]
+    magnet_info_hash: Option<[u8; 20]>,
+}
+
+/// Torrents read from a file have all of their metadata
+fn metadata_is_complete() -> bool {
+    true
 }
 
 impl Torrent {
@@ -71,28 +117,155 @@ impl Torrent {
     /// # Arguments
     ///
     /// * `path` - The path to the `.torrent` file.
-    pub async fn from_torrent_file(path: &str) -> Result<Self, String> {
-        let Ok(mut file) = TokioFile::open(path).await else {
-            return Err(format!("Unable to read file at {path}"));
-        };
+    pub async fn from_torrent_file(path: &str) -> Result<Self, Error> {
+        let unreadable = |source| Error::UnreadableTorrent { path: path.to_string(), source };
+
+        let mut file = TokioFile::open(path).await.map_err(unreadable)?;
 
         let mut buf: Vec<u8> = Vec::new();
-        let Ok(_) = file.read_to_end(&mut buf).await else {
-            return Err(format!("Error reading file > {path}"));
-        };
+        file.read_to_end(&mut buf).await.map_err(unreadable)?;
 
-        let torrent: Torrent = match serde_bencode::from_bytes(&buf) {
-            Err(_) => return Err(format!("Error deserializing file > {path}")),
-            Ok(torrent) => torrent,
-        };
+        let torrent: Torrent = serde_bencode::from_bytes(&buf)?;
+
+        if let Err(err) = torrent.validate() {
+            return Err(Error::InvalidTorrent(format!("invalid torrent file {path}, {err}")));
+        }
 
         Ok(torrent)
     }
+
+    /// Parses a magnet link into a torrent with its info hash and trackers, but without
+    /// metadata, see `metadata_complete`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The magnet link, with its info hash as `xt=urn:btih:` in hex or base32, and
+    ///   optionally trackers as `tr=` and a name as `dn=`.
+    pub fn from_magnet(uri: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidTorrent(format!("invalid magnet link {uri}, {reason}"));
+
+        let url = reqwest::Url::parse(uri).map_err(|err| invalid(&err.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(invalid("not a magnet link"));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    info_hash = Some(decode_info_hash(hash).ok_or_else(|| invalid("info hash is neither 40 hex nor 32 base32 characters"))?);
+                }
+                "dn" => name = Some(value.into_owned()),
+                // Each tracker is its own tier, so every one of them is announced to
+                "tr" => trackers.push(vec![value.into_owned()]),
+                _ => ()
+            }
+        }
+
+        let Some(info_hash) = info_hash else {
+            return Err(invalid("no BitTorrent info hash"));
+        };
+
+        Ok(Torrent {
+            info: Info {
+                name: name.unwrap_or_else(|| info_hash.iter().map(|byte| format!("{byte:02x}")).collect()),
+                pieces: vec![],
+                piece_length: 0,
+                md5sum: None,
+                length: None,
+                files: None,
+                private: None,
+                path: None,
+                root_hash: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: (!trackers.is_empty()).then_some(trackers),
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            metadata_complete: false,
+            magnet_info_hash: Some(info_hash),
+        })
+    }
+
+    /// Checks that the torrent's pieces are consistent with the length of its files.
+    ///
+    /// # Returns
+    ///
+    /// * A description of the first problem found, if the torrent is corrupt.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.info.piece_length == 0 {
+            return Err(Error::InvalidTorrent(String::from("piece length is 0")));
+        }
+
+        if !self.info.pieces.len().is_multiple_of(20) {
+            return Err(Error::InvalidTorrent(format!("pieces is {} bytes, which isn't a whole number of 20 byte hashes", self.info.pieces.len())));
+        }
+
+        let total_length = self.get_total_length();
+        let expected = total_length.div_ceil(self.info.piece_length);
+        let actual = self.info.pieces.len() as u64 / 20;
+
+        if expected != actual {
+            return Err(Error::InvalidTorrent(format!(
+                "{total_length} bytes in pieces of {} bytes needs {expected} piece hashes, but there are {actual}",
+                self.info.piece_length
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the torrent's piece length is within the given range.
+    ///
+    /// A tiny piece length makes the piece hashes enormous and a huge one has to be held in
+    /// memory a piece at a time, so torrents outside protocol norms can be refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - The acceptable piece lengths in bytes.
+    pub fn check_piece_length(&self, range: &RangeInclusive<u64>) -> Result<(), Error> {
+        if range.contains(&self.info.piece_length) {
+            Ok(())
+        } else {
+            Err(Error::InvalidTorrent(format!(
+                "piece length of {} bytes is outside the accepted {} to {} bytes",
+                self.info.piece_length, range.start(), range.end()
+            )))
+        }
+    }
+
+    /// Checks that the torrent's name and the path of each of its files stay within the
+    /// directory it is downloaded into, see `check_path_component`.
+    pub fn check_paths(&self) -> Result<(), Error> {
+        check_path_component(&self.info.name)?;
+
+        for file in self.info.files.iter().flatten() {
+            if file.local_path().is_empty() {
+                return Err(Error::InvalidTorrent(String::from("a file has an empty path")))
+            }
+            for component in file.local_path() {
+                check_path_component(component)?;
+            }
+        }
+
+        Ok(())
+    }
 }
     
 impl Torrent {
-    /// Calculates the info hash of the torrent.
+    /// Calculates the info hash of the torrent, or returns the one from its magnet link.
     pub fn get_info_hash(&self) -> Vec<u8> {
+        if let Some(info_hash) = self.magnet_info_hash {
+            return info_hash.to_vec()
+        }
+
         let buf = serde_bencode::to_bytes(&self.info).unwrap();
         
         let mut hasher = Sha1::new();
@@ -116,14 +289,106 @@ impl Torrent {
         hasher.update(piece);
         let result = hasher.finalize();  
         
-        let piece_hash = &self.info.pieces[(index * 20) as usize..(index * 20 + 20) as usize];
-        
-        if &result[..] == piece_hash {
-            true
+        self.verify_hash(index, &result.into())
+    }
+
+    /// Checks a piece against its hash like `check_piece`, describing a mismatch.
+    ///
+    /// # Arguments
+    ///
+    /// * `piece` - The downloaded piece.
+    /// * `index` - The index of the piece.
+    pub fn verify_piece(&self, piece: &[u8], index: u32) -> Result<(), Error> {
+        let Some(expected) = self.piece_hash(index) else {
+            return Err(Error::InvalidTorrent(format!("piece {index} is out of range")))
+        };
+        let got: [u8; 20] = Sha1::digest(piece).into();
+
+        if got == expected {
+            Ok(())
         } else {
-            false
+            Err(Error::PieceHashMismatch { index, expected, got })
         }
     }
+
+    /// Checks if the SHA-1 digest of a piece matches its hash, for callers that hashed the
+    /// piece incrementally and no longer hold all of it.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    /// * `digest` - The SHA-1 digest of the downloaded piece.
+    ///
+    /// # Returns
+    ///
+    /// * `true` if the digest is correct, `false` otherwise or if the index is out of range.
+    pub fn verify_hash(&self, index: u32, digest: &[u8; 20]) -> bool {
+        self.piece_hash(index).is_some_and(|piece_hash| &piece_hash == digest)
+    }
+
+    /// Returns the SHA-1 hash of a piece, or `None` if the index is out of range.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    pub fn piece_hash(&self, index: u32) -> Option<[u8; 20]> {
+        let start = index as usize * 20;
+
+        self.info.pieces.get(start..start + 20)?.try_into().ok()
+    }
+    
+    /// Returns the number of pieces in the torrent.
+    pub fn get_num_pieces(&self) -> u32 {
+        (self.info.pieces.len() / 20) as u32
+    }
+
+    /// Returns the length of the given piece, the last piece may be shorter than the rest.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the piece.
+    pub fn get_piece_length(&self, index: u32) -> u64 {
+        let start = index as u64 * self.info.piece_length;
+        
+        self.get_total_length().saturating_sub(start).min(self.info.piece_length)
+    }
+    
+    /// Returns the index of the piece holding a byte of the torrent, with its files laid end to end.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - The offset of the byte from the start of the torrent.
+    ///
+    /// # Returns
+    ///
+    /// * The index of the piece, or `None` if the offset is beyond the end of the torrent.
+    pub fn piece_for_offset(&self, offset: u64) -> Option<u32> {
+        if offset >= self.get_total_length() || self.info.piece_length == 0 {
+            return None
+        }
+
+        Some((offset / self.info.piece_length) as u32)
+    }
+
+    /// Returns the bytes a file takes up within the torrent, with its files laid end to end.
+    ///
+    /// # Arguments
+    ///
+    /// * `file_index` - The index of the file, a single file torrent only has file 0.
+    ///
+    /// # Returns
+    ///
+    /// * The range of offsets from the start of the torrent, or `None` if there is no such file.
+    pub fn file_byte_range(&self, file_index: usize) -> Option<Range<u64>> {
+        let Some(files) = &self.info.files else {
+            return (file_index == 0).then(|| 0..self.get_total_length())
+        };
+
+        let length = files.get(file_index)?.length;
+        let start: u64 = files[..file_index].iter().map(|file| file.length).sum();
+
+        Some(start..start + length)
+    }
     
     pub fn get_total_length(&self) -> u64 {
         if let Some(n) = self.info.length {
@@ -143,54 +408,127 @@ impl Torrent {
         0
     }
     
-    pub fn get_trackers(&self) -> Result<Vec<SocketAddrV4>, String> {
-        let mut addresses = vec![];
-
-        // This is the current regex as I haven't implemented support for http trackers yet
-        let re = Regex::new(r"^udp://([^:/]+):(\d+)/announce$").unwrap();
-        
-        if let Some(url) = &self.announce {
-            if let Some(captures) = re.captures(url) {
-                let hostname = captures.get(1).unwrap().as_str();
-                let port = captures.get(2).unwrap().as_str();
+    /// Returns the torrent's trackers, from `announce` and the first of each `announce_list`
+    /// tier, with a tracker listed more than once only returned once.
+    pub fn tracker_urls(&self) -> Vec<TrackerUrl> {
+        let urls = self.announce.iter()
+            .chain(self.announce_list.iter().flatten().filter_map(|tier| tier.first()));
+
+        let mut seen = HashSet::new();
+        urls.map(|url| TrackerUrl::parse(url))
+            .filter(|url| seen.insert(url.clone()))
+            .collect()
+    }
 
-                if let Ok(ip) = dns_lookup::lookup_host(hostname) {
+    /// Returns the torrent's trackers that can be announced to, with UDP trackers resolved to
+    /// their addresses. A tracker listed more than once between `announce` and `announce_list`
+    /// is only returned once.
+    ///
+    /// # Arguments
+    ///
+    /// * `resolver` - Resolves the UDP trackers' hostnames, HTTP trackers are resolved when announced to.
+    /// * `filter` - Which trackers can be announced to, others are skipped.
+    pub async fn get_trackers(&self, resolver: &dyn Resolver, filter: &TrackerFilter) -> Result<Vec<TrackerEndpoint>, Error> {
+        let mut trackers = vec![];
+
+        for url in self.tracker_urls().into_iter().filter(|url| filter.allows(url)) {
+            match url {
+                TrackerUrl::Udp { host, port } => if let Ok(ip) = resolver.resolve(&host).await {
                     for i in ip { 
                         if let IpAddr::V4(j) = i {
-                            addresses.push(SocketAddrV4::new(j, port.parse().unwrap()))
+                            trackers.push(TrackerEndpoint::Udp(SocketAddr::V4(SocketAddrV4::new(j, port))))
                         }
                     }
                 }
+                TrackerUrl::Http(url) => trackers.push(TrackerEndpoint::Http(url)),
+                // WebSocket trackers aren't supported yet
+                TrackerUrl::WebSocket(_) | TrackerUrl::Unsupported(_) => ()
             }
         }
         
-        if let Some(urls) = &self.announce_list {
-            for url in urls.iter() {
-                if let Some(captures) = re.captures(&url[0]) {
-                    let hostname = captures.get(1).unwrap().as_str();
-                    let port = captures.get(2).unwrap().as_str();
-                    
-                    if let Ok(ip) = dns_lookup::lookup_host(hostname) {
-                        for i in ip { 
-                            if let IpAddr::V4(j) = i {
-                                addresses.push(SocketAddrV4::new(j, port.parse().unwrap()));
-                            }
-                        }
-                    }
+        if !trackers.is_empty() {
+            Ok(trackers)
+        } else {
+            Err(Error::TrackerError(String::from("Unable to find trackers")))
+        }
+    }
+}
+
+/// Decodes an info hash from a magnet link, given as 40 hex or 32 base32 characters
+fn decode_info_hash(hash: &str) -> Option<[u8; 20]> {
+    let mut info_hash = [0; 20];
+
+    match hash.len() {
+        40 => for (byte, pair) in info_hash.iter_mut().zip(hash.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        32 => {
+            // Every base32 character is 5 bits, 32 of them are exactly 20 bytes
+            let mut bits: u64 = 0;
+            let mut count = 0;
+            let mut bytes = info_hash.iter_mut();
+
+            for character in hash.bytes() {
+                let value = match character.to_ascii_uppercase() {
+                    letter @ b'A'..=b'Z' => letter - b'A',
+                    digit @ b'2'..=b'7' => digit - b'2' + 26,
+                    _ => return None
+                };
+
+                bits = (bits << 5) | value as u64;
+                count += 5;
+                if count >= 8 {
+                    count -= 8;
+                    *bytes.next()? = (bits >> count) as u8;
                 }
             }
         }
-        
-        if addresses.len() > 0 {
-            Ok(addresses)
-        } else {
-            Err(String::from("Unable to find trackers"))
+        _ => return None
+    }
+
+    Some(info_hash)
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Torrent {
+    /// Creates a single file torrent containing the given data, for use in tests.
+    pub fn from_pieces(name: &str, piece_length: u64, data: &[u8]) -> Self {
+        let mut pieces = vec![];
+
+        for piece in data.chunks(piece_length as usize) {
+            let mut hasher = Sha1::new();
+            hasher.update(piece);
+            pieces.extend(hasher.finalize());
+        }
+
+        Torrent {
+            info: Info {
+                name: String::from(name),
+                pieces,
+                piece_length,
+                length: Some(data.len() as i64),
+                files: None,
+                md5sum: None,
+                private: None,
+                path: None,
+                root_hash: None,
+            },
+            announce: None,
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use tokio::runtime::Runtime;
 
@@ -238,6 +576,8 @@ mod tests {
             creation_date: None,
             comment: None,
             created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         };
 
         let result = torrent.get_info_hash();
@@ -272,6 +612,8 @@ mod tests {
             creation_date: None,
             comment: None,
             created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         };
 
         // Mock a valid piece
@@ -305,6 +647,8 @@ mod tests {
             creation_date: None,
             comment: None,
             created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         };
 
         // Mock an invalid piece
@@ -326,6 +670,7 @@ mod tests {
                 length: Some(2048),
                 files: Some(vec![File {
                     path: vec![String::from("test_file.txt")],
+                    path_utf8: None,
                     length: 2048,
                     md5sum: None,
                 }]),
@@ -342,6 +687,8 @@ mod tests {
             creation_date: None,
             comment: None,
             created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         };
 
         let result = torrent.get_total_length();
@@ -361,11 +708,13 @@ mod tests {
                 files: Some(vec![
                     File {
                         path: vec![String::from("file1.txt")],
+                        path_utf8: None,
                         length: 1024,
                         md5sum: None,
                     },
                     File {
                         path: vec![String::from("file2.txt")],
+                        path_utf8: None,
                         length: 2048,
                         md5sum: None,
                     },
@@ -383,6 +732,8 @@ mod tests {
             creation_date: None,
             comment: None,
             created_by: None,
+            metadata_complete: true,
+            magnet_info_hash: None,
         };
 
         let result = torrent.get_total_length();
@@ -390,5 +741,260 @@ mod tests {
         assert_eq!(result, 3072);
     }
 
+    #[test]
+    fn verify_hash_matches_check_piece() {
+        let data: Vec<u8> = (0..48).collect();
+        let torrent = Torrent::from_pieces("verify_hash", 16, &data);
+
+        for (index, piece) in data.chunks(16).enumerate() {
+            // Hash the piece a block at a time, as a streaming verifier would
+            let mut hasher = Sha1::new();
+            for block in piece.chunks(4) {
+                hasher.update(block);
+            }
+            let digest: [u8; 20] = hasher.finalize().into();
+
+            assert!(torrent.check_piece(piece, index as u32));
+            assert!(torrent.verify_hash(index as u32, &digest));
+            assert!(!torrent.verify_hash((index as u32 + 1) % 3, &digest));
+        }
+
+        assert!(!torrent.verify_hash(0, &[0; 20]));
+        assert!(!torrent.verify_hash(3, &[0; 20]));
+    }
+
+    /// Resolves hostnames from a fixed table
+    pub(crate) struct MockResolver;
+
+    #[async_trait::async_trait]
+    impl Resolver for MockResolver {
+        async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+            match host {
+                "one.example" => Ok(vec!["10.0.0.1".parse().unwrap(), "::1".parse().unwrap()]),
+                "two.example" => Ok(vec!["10.0.0.2".parse().unwrap()]),
+                _ => Err(Error::IoError(std::io::Error::new(std::io::ErrorKind::NotFound, format!("unknown host {host}"))))
+            }
+        }
+    }
+
+    /// A resolved UDP tracker at the given address
+    fn udp(address: &str) -> TrackerEndpoint {
+        TrackerEndpoint::Udp(address.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn get_trackers_uses_resolver() {
+        let mut torrent = Torrent::from_pieces("resolver", 16, &[0; 16]);
+        torrent.announce = Some(String::from("udp://one.example:1337/announce"));
+        torrent.announce_list = Some(vec![
+            vec![String::from("udp://missing.example:80/announce")],
+            vec![String::from("udp://two.example:6969/announce")],
+            vec![String::from("http://two.example/announce")],
+        ]);
+
+        // Only the IPv4 addresses of UDP hosts that resolve are kept, HTTP trackers are kept as they are
+        assert_eq!(
+            torrent.get_trackers(&MockResolver, &TrackerFilter::default()).await.ok(),
+            Some(vec![udp("10.0.0.1:1337"), udp("10.0.0.2:6969"), TrackerEndpoint::Http(String::from("http://two.example/announce"))])
+        );
+
+        torrent.announce = None;
+        torrent.announce_list = None;
+        assert!(torrent.get_trackers(&MockResolver, &TrackerFilter::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn get_trackers_skips_duplicates() {
+        let mut torrent = Torrent::from_pieces("duplicates", 16, &[0; 16]);
+        torrent.announce = Some(String::from("udp://one.example:1337/announce"));
+        torrent.announce_list = Some(vec![
+            vec![String::from("udp://one.example:1337/announce")],
+            vec![String::from("udp://ONE.example:01337/announce")],
+        ]);
+
+        assert_eq!(torrent.get_trackers(&MockResolver, &TrackerFilter::default()).await.ok(), Some(vec![udp("10.0.0.1:1337")]));
+    }
+
+    #[tokio::test]
+    async fn get_trackers_applies_filter() {
+        let mut torrent = Torrent::from_pieces("filter", 16, &[0; 16]);
+        torrent.announce = Some(String::from("udp://one.example:1337/announce"));
+        torrent.announce_list = Some(vec![
+            vec![String::from("udp://two.example:6969/announce")],
+            vec![String::from("http://one.example/announce")],
+        ]);
+
+        let filter = TrackerFilter { allow: Some(vec![String::from("two.example")]), ..Default::default() };
+        assert_eq!(torrent.get_trackers(&MockResolver, &filter).await.ok(), Some(vec![udp("10.0.0.2:6969")]));
+
+        let filter = TrackerFilter { allow: Some(vec![String::from("three.example")]), ..Default::default() };
+        assert!(torrent.get_trackers(&MockResolver, &filter).await.is_err());
+    }
+
+    #[test]
+    fn tracker_urls_from_mixed_announce_list() {
+        let mut torrent = Torrent::from_pieces("mixed", 16, &[0; 16]);
+        torrent.announce = Some(String::from("udp://one.example:1337/announce"));
+        torrent.announce_list = Some(vec![
+            vec![String::from("udp://one.example:1337/announce")],
+            vec![String::from("http://two.example/announce"), String::from("udp://backup.example:80/announce")],
+            vec![String::from("wss://three.example")],
+            vec![String::from("magnet:?xt=urn:btih:0")],
+        ]);
+
+        assert_eq!(torrent.tracker_urls(), vec![
+            TrackerUrl::Udp { host: String::from("one.example"), port: 1337 },
+            TrackerUrl::Http(String::from("http://two.example/announce")),
+            TrackerUrl::WebSocket(String::from("wss://three.example")),
+            TrackerUrl::Unsupported(String::from("magnet:?xt=urn:btih:0")),
+        ]);
+    }
+
+    #[test]
+    fn verify_piece_reports_mismatch() {
+        let torrent = Torrent::from_pieces("verify", 16, &[1; 32]);
+        assert!(torrent.verify_piece(&[1; 16], 1).is_ok());
+
+        let expected = torrent.piece_hash(0).unwrap();
+        let got: [u8; 20] = Sha1::digest([0; 16]).into();
+        assert!(matches!(
+            torrent.verify_piece(&[0; 16], 0),
+            Err(Error::PieceHashMismatch { index: 0, expected: e, got: g }) if e == expected && g == got
+        ));
+        assert!(matches!(torrent.verify_piece(&[1; 16], 2), Err(Error::InvalidTorrent(_))));
+    }
+
+    #[test]
+    fn validate_piece_count() {
+        let mut torrent = Torrent::from_pieces("validate", 16, &[0; 40]);
+        assert!(torrent.validate().is_ok());
+
+        // One byte more needs a fourth piece
+        torrent.info.length = Some(49);
+        assert_eq!(
+            torrent.validate().map_err(|err| err.to_string()),
+            Err(String::from("49 bytes in pieces of 16 bytes needs 4 piece hashes, but there are 3"))
+        );
+
+        torrent.info.length = Some(40);
+        torrent.info.pieces.pop();
+        assert!(torrent.validate().is_err());
+
+        torrent.info.piece_length = 0;
+        assert!(torrent.validate().is_err());
+    }
+
+    #[test]
+    fn piece_length_range() {
+        let range = 16 * 1024..=32 * 1024 * 1024;
+        let mut torrent = Torrent::from_pieces("piece_length", 16, &[0; 16]);
+        assert!(torrent.check_piece_length(&range).is_err());
+
+        torrent.info.piece_length = 16 * 1024 - 1;
+        assert_eq!(
+            torrent.check_piece_length(&range).map_err(|err| err.to_string()),
+            Err(String::from("piece length of 16383 bytes is outside the accepted 16384 to 33554432 bytes"))
+        );
+
+        for piece_length in [16 * 1024, 32 * 1024 * 1024] {
+            torrent.info.piece_length = piece_length;
+            assert!(torrent.check_piece_length(&range).is_ok());
+        }
+
+        torrent.info.piece_length = 32 * 1024 * 1024 + 1;
+        assert!(torrent.check_piece_length(&range).is_err());
+    }
+
+    #[test]
+    fn piece_for_offset_boundaries() {
+        let torrent = Torrent::from_pieces("piece_for_offset", 16, &[0; 40]);
+
+        assert_eq!(torrent.piece_for_offset(0), Some(0));
+        assert_eq!(torrent.piece_for_offset(15), Some(0));
+        assert_eq!(torrent.piece_for_offset(16), Some(1));
+        assert_eq!(torrent.piece_for_offset(32), Some(2));
+        assert_eq!(torrent.piece_for_offset(39), Some(2));
+        assert_eq!(torrent.piece_for_offset(40), None);
+        assert_eq!(torrent.piece_for_offset(u64::MAX), None);
+    }
+
+    #[test]
+    fn file_byte_range_multiple_files() {
+        let mut torrent = Torrent::from_pieces("file_byte_range", 16, &[0; 40]);
+        assert_eq!(torrent.file_byte_range(0), Some(0..40));
+        assert_eq!(torrent.file_byte_range(1), None);
+
+        torrent.info.length = None;
+        torrent.info.files = Some(vec![
+            File { path: vec![String::from("a")], path_utf8: None, length: 10, md5sum: None },
+            File { path: vec![String::from("b")], path_utf8: None, length: 20, md5sum: None },
+            File { path: vec![String::from("c")], path_utf8: None, length: 10, md5sum: None },
+        ]);
+
+        assert_eq!(torrent.file_byte_range(1), Some(10..30));
+        assert_eq!(torrent.file_byte_range(3), None);
+
+        // Seeking into the middle of the second file starts at the piece holding that byte
+        let seek = torrent.file_byte_range(1).unwrap().start + 7;
+        assert_eq!(torrent.piece_for_offset(seek), Some(1));
+    }
+
+    #[test]
+    fn get_piece_length_last_piece() {
+        let torrent = Torrent::from_pieces("test_torrent", 1024, &[0; 2500]);
+
+        assert_eq!(torrent.get_num_pieces(), 3);
+        assert_eq!(torrent.get_piece_length(0), 1024);
+        assert_eq!(torrent.get_piece_length(2), 452);
+        assert_eq!(torrent.get_piece_length(3), 0);
+    }
+
+    #[test]
+    fn from_magnet_hex_and_base32() {
+        let info_hash = [
+            0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9,
+            0xf5, 0x19, 0xb3, 0x35, 0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a,
+        ];
+
+        let torrent = Torrent::from_magnet(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=My+File%21\
+             &tr=udp%3A%2F%2Fone.example%3A1337%2Fannounce&tr=http%3A%2F%2Ftwo.example%2Fannounce"
+        ).unwrap();
+
+        assert!(!torrent.metadata_complete);
+        assert_eq!(torrent.get_info_hash(), info_hash);
+        assert_eq!(torrent.info.name, "My File!");
+        assert!(torrent.info.pieces.is_empty());
+        assert_eq!(torrent.tracker_urls(), vec![
+            TrackerUrl::Udp { host: String::from("one.example"), port: 1337 },
+            TrackerUrl::Http(String::from("http://two.example/announce")),
+        ]);
+
+        let torrent = Torrent::from_magnet("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(torrent.get_info_hash(), info_hash);
+        assert_eq!(torrent.info.name, "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert!(torrent.tracker_urls().is_empty());
+    }
+
+    #[test]
+    fn from_magnet_invalid() {
+        for uri in [
+            "http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+            "magnet:?dn=no+hash",
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a8",
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a8zz",
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1",
+        ] {
+            assert!(matches!(Torrent::from_magnet(uri), Err(Error::InvalidTorrent(_))), "{uri}");
+        }
+    }
+
+    #[test]
+    fn torrent_files_have_complete_metadata() {
+        let torrent: Torrent = serde_bencode::from_bytes(&serde_bencode::to_bytes(&Torrent::from_pieces("complete", 16, &[0; 16])).unwrap()).unwrap();
+
+        assert!(torrent.metadata_complete);
+    }
+
     // Add more tests for other methods and edge cases as needed
 }
\ No newline at end of file
@@ -1,16 +1,265 @@
-use std::net::{SocketAddr, Ipv4Addr, SocketAddrV4};
+use std::{
+  fmt,
+  net::{IpAddr, SocketAddr, SocketAddrV4, Ipv4Addr},
+  sync::Arc,
+  time::{Duration, SystemTime}
+};
 
-use tokio::net::UdpSocket;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_bencode::value::Value;
+use socket2::SockRef;
+use tokio::{net::UdpSocket, time::timeout};
 
-use crate::torrent::Torrent;
+use crate::{
+  candidate::{normalize_candidate, PeerCandidate},
+  config::SocketOptions,
+  error::Error,
+  resolver::{ReqwestResolver, Resolver, SystemResolver},
+  socket::{apply_socket_options, UnsupportedOption},
+  torrent::Torrent
+};
+
+/// How long a connection id handed out by a tracker can be used for.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The shortest time between announces to a tracker by default, whatever the tracker allows.
+pub const DEFAULT_MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The longest error message kept in a tracker's status, in characters.
+const MAX_ERROR_LENGTH: usize = 256;
+
+/// The longest interval a tracker can ask to be announced to at, longer ones are taken to be
+/// mistakes and capped.
+const MAX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The number of redirects an HTTP tracker is followed through by default.
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// How long an HTTP tracker has to respond to an announce by default, redirects included.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The query parameters of an announce request, removed from the url a tracker redirected to
+const ANNOUNCE_PARAMETERS: [&str; 9] = ["info_hash", "peer_id", "port", "uploaded", "downloaded", "left", "compact", "event", "ip"];
+
+/// The connection id a connect request is sent with, identifying the UDP tracker protocol.
+const PROTOCOL_ID: i64 = 0x41727101980;
+
+/// How long a response from a UDP tracker is waited for before the request is first sent
+/// again, as BEP 15 specifies.
+pub const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The number of times a request to a UDP tracker is sent again before giving up. BEP 15
+/// allows 8, waiting over two hours in all, so a dead tracker would hold up the others.
+pub const DEFAULT_MAX_RETRANSMITS: u32 = 2;
+
+/// The action a tracker responds with when a request fails.
+const ERROR_ACTION: i32 = 3;
+
+/// Returns a random transaction id for a request to a UDP tracker, a new one for every request.
+pub fn new_transaction_id() -> i32 {
+  rand::random()
+}
 
 pub struct Tracker {
   /// A UdpSocket used for communication.
   connection_stream: UdpSocket,
   /// The local socket address requests are made from
+  #[allow(dead_code)]
   listen_address: SocketAddr,
   /// The remote socket address of the tracker.
-  remote_address: SocketAddr
+  remote_address: SocketAddr,
+  /// The last connection id received from the tracker and when it was received.
+  connection_id: Option<(i64, SystemTime)>,
+  /// The history of announces made to the tracker.
+  status: TrackerStatus,
+  /// An address or hostname announced as the client's ip, instead of the tracker inferring it.
+  /// A hostname is resolved again before every announce, so a dynamic DNS name stays current.
+  pub announce_ip: Option<String>,
+  /// Resolves `announce_ip` when it is a hostname.
+  pub resolver: Arc<dyn Resolver>,
+  /// The shortest time between announces, or the tracker's `min interval` if that is longer.
+  pub min_announce_interval: Duration,
+  /// How long a response is waited for before the request is sent again, doubled after every
+  /// retransmit.
+  pub retransmit_timeout: Duration,
+  /// The number of times a request is sent again before giving up.
+  pub max_retransmits: u32,
+  /// The socket options that couldn't be set on the socket.
+  unsupported_options: Vec<UnsupportedOption>
+}
+
+/// A tracker's announce URL, classified by the protocol used to announce to it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TrackerUrl {
+  /// A tracker announced to with the UDP tracker protocol, its host in lowercase.
+  Udp { host: String, port: u16 },
+  /// An HTTP or HTTPS tracker.
+  Http(String),
+  /// A WebSocket tracker, as used by WebTorrent.
+  WebSocket(String),
+  /// A URL that isn't a tracker the client recognises.
+  Unsupported(String),
+}
+
+impl TrackerUrl {
+  /// Classifies an announce URL by its scheme.
+  ///
+  /// # Arguments
+  ///
+  /// * `url` - The announce URL from the torrent.
+  pub fn parse(url: &str) -> Self {
+    // Only UDP trackers with an explicit port are announced to
+    let udp = Regex::new(r"^udp://([^:/]+):(\d+)/announce$").unwrap();
+
+    if let Some(captures) = udp.captures(url) {
+      if let Ok(port) = captures[2].parse() {
+        return TrackerUrl::Udp { host: captures[1].to_ascii_lowercase(), port }
+      }
+    }
+
+    let scheme = url.split_once("://").map(|(scheme, _)| scheme.to_ascii_lowercase());
+    match scheme.as_deref() {
+      Some("http" | "https") => TrackerUrl::Http(url.to_string()),
+      Some("ws" | "wss") => TrackerUrl::WebSocket(url.to_string()),
+      _ => TrackerUrl::Unsupported(url.to_string())
+    }
+  }
+
+  /// Returns the tracker's hostname in lowercase, `None` for an unsupported URL.
+  pub fn host(&self) -> Option<String> {
+    match self {
+      TrackerUrl::Udp { host, .. } => Some(host.clone()),
+      TrackerUrl::Http(url) | TrackerUrl::WebSocket(url) => {
+        let (_, rest) = url.split_once("://")?;
+        let end = rest.find([':', '/', '?']).unwrap_or(rest.len());
+
+        Some(rest[..end].to_ascii_lowercase())
+      }
+      TrackerUrl::Unsupported(_) => None
+    }
+  }
+}
+
+/// A tracker ready to be announced to, keeping the protocol used to announce to it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TrackerEndpoint {
+  /// A UDP tracker, resolved to its address.
+  Udp(SocketAddr),
+  /// An HTTP or HTTPS tracker, announced to at its announce url.
+  Http(String),
+}
+
+impl fmt::Display for TrackerEndpoint {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TrackerEndpoint::Udp(address) => write!(f, "udp://{address}"),
+      TrackerEndpoint::Http(url) => write!(f, "{url}")
+    }
+  }
+}
+
+impl Serialize for TrackerEndpoint {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+/// Diagnostic information about the announces made to a tracker.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackerStatus {
+  /// The tracker announced to.
+  pub address: TrackerEndpoint,
+  /// When the last announce was started.
+  pub last_announce: Option<SystemTime>,
+  /// When the tracker asked to be announced to next.
+  pub next_announce: Option<SystemTime>,
+  /// The error from the last failed announce, truncated to a bounded length.
+  pub last_error: Option<String>,
+  /// The number of announces that have failed in a row.
+  pub consecutive_failures: u32,
+  /// The number of announces that have failed in total.
+  pub total_failures: u32,
+  /// The number of peers returned by the last successful announce.
+  pub last_peer_count: usize,
+  /// The number of peers returned by every successful announce.
+  pub total_peers: u64,
+  /// The number of peers listed by the last successful announce that were dropped, as duplicates,
+  /// addresses that can't be connected to or hostnames that don't resolve, see
+  /// `AnnounceMessageResponse::peers`.
+  pub dropped_peers: usize,
+  /// The number of seeders reported by the last successful announce.
+  pub seeders: Option<u32>,
+  /// The number of leechers reported by the last successful announce.
+  pub leechers: Option<u32>,
+  /// The interval the tracker asked to be announced to at.
+  pub interval: Option<Duration>,
+  /// The shortest interval the tracker allows between announces, if it sets one.
+  pub min_interval: Option<Duration>,
+}
+
+impl TrackerStatus {
+  /// Creates the status of a tracker that hasn't been announced to yet.
+  fn new(address: TrackerEndpoint) -> Self {
+    Self {
+      address,
+      last_announce: None,
+      next_announce: None,
+      last_error: None,
+      consecutive_failures: 0,
+      total_failures: 0,
+      last_peer_count: 0,
+      total_peers: 0,
+      dropped_peers: 0,
+      seeders: None,
+      leechers: None,
+      interval: None,
+      min_interval: None
+    }
+  }
+
+  /// Returns when the tracker can next be announced to, `None` if it can be now.
+  ///
+  /// # Arguments
+  ///
+  /// * `floor` - The shortest time between announces, used when the tracker allows a shorter one.
+  pub fn announce_allowed_at(&self, floor: Duration) -> Option<SystemTime> {
+    let allowed_at = self.last_announce? + self.min_interval.map_or(floor, |min_interval| min_interval.max(floor));
+
+    (allowed_at > SystemTime::now()).then_some(allowed_at)
+  }
+
+  /// Waits until the tracker can next be announced to, then records the announce as started.
+  async fn start_announce(&mut self, floor: Duration) {
+    if let Some(allowed_at) = self.announce_allowed_at(floor) {
+      if let Ok(delay) = allowed_at.duration_since(SystemTime::now()) {
+        tokio::time::sleep(delay).await;
+      }
+    }
+
+    self.last_announce = Some(SystemTime::now());
+  }
+
+  /// Records a failed announce.
+  pub(crate) fn record_failure(&mut self, err: &str) {
+    self.last_error = Some(err.chars().take(MAX_ERROR_LENGTH).collect());
+    self.consecutive_failures += 1;
+    self.total_failures += 1;
+    self.next_announce = None;
+  }
+
+  /// Records a successful announce.
+  fn record_success(&mut self, response: &AnnounceMessageResponse, peer_count: usize) {
+    self.consecutive_failures = 0;
+    self.last_peer_count = peer_count;
+    self.total_peers += peer_count as u64;
+    self.dropped_peers = response.skipped_peers + response.ips.len().saturating_sub(peer_count);
+    self.seeders = Some(response.seeders);
+    self.leechers = Some(response.leechers);
+    self.interval = Some(Duration::from_secs(response.interval as u64).min(MAX_ANNOUNCE_INTERVAL));
+    self.min_interval = response.min_interval.map(|min_interval| Duration::from_secs(min_interval as u64).min(MAX_ANNOUNCE_INTERVAL));
+    self.next_announce = self.last_announce.zip(self.interval).map(|(last, interval)| last + interval);
+  }
 }
 
 impl Tracker {
@@ -25,70 +274,305 @@ impl Tracker {
   /// # Panics
   ///
   /// Panics if there is an error parsing the given address or creating the UDP socket.
-  pub async fn new(listen_address: SocketAddr, remote_address: SocketAddr) -> Result<Self, String> {
+  pub async fn new(listen_address: SocketAddr, remote_address: SocketAddr) -> Result<Self, Error> {
+    Self::new_with(listen_address, remote_address, &SocketOptions::default()).await
+  }
+
+  /// Creates a new `Tracker` like `new`, setting options on its socket. Options the platform
+  /// refuses are left at its defaults, and listed by `unsupported_options`.
+  ///
+  /// # Arguments
+  ///
+  /// * `listen_address` - Local socket address for binding.
+  /// * `remote_address` - The address of the tracker.
+  /// * `socket_options` - The options set on the socket, those only TCP has are ignored.
+  pub async fn new_with(listen_address: SocketAddr, remote_address: SocketAddr, socket_options: &SocketOptions) -> Result<Self, Error> {
     let Ok(connection_stream) = UdpSocket::bind(listen_address).await else {
-        return Err(format!("error binding to udpsocket {listen_address}"))
+        return Err(Error::TrackerError(format!("error binding to udpsocket {listen_address}")))
     };
+
+    let unsupported_options = apply_socket_options(SockRef::from(&connection_stream), socket_options);
     
-    match connection_stream.connect(remote_address).await {
-      Err(err) => {
-        return Err(format!("error creating udpsocket, {}", err));
-      },
-      Ok(()) => { }
+    if let Err(err) = connection_stream.connect(remote_address).await {
+      return Err(Error::TrackerError(format!("error creating udpsocket, {}", err)));
     };
     
     
     Ok(Self {
       connection_stream,
       listen_address,
-      remote_address
+      remote_address,
+      connection_id: None,
+      status: TrackerStatus::new(TrackerEndpoint::Udp(remote_address)),
+      announce_ip: None,
+      resolver: Arc::new(SystemResolver),
+      min_announce_interval: DEFAULT_MIN_ANNOUNCE_INTERVAL,
+      retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT,
+      max_retransmits: DEFAULT_MAX_RETRANSMITS,
+      unsupported_options
     })
   }
+
+  /// Returns the history of announces made to the tracker.
+  pub fn status(&self) -> &TrackerStatus {
+    &self.status
+  }
+
+  /// Returns the socket options that couldn't be set on the tracker's socket.
+  pub fn unsupported_options(&self) -> &[UnsupportedOption] {
+    &self.unsupported_options
+  }
+
+  /// Seeds the tracker with a connection id from a previous session, so the connect
+  /// handshake can be skipped while the id is still valid.
+  ///
+  /// # Arguments
+  ///
+  /// * `connection_id` - The connection id previously received from this tracker.
+  /// * `acquired_at` - When the connection id was received.
+  pub fn seed_connection_id(&mut self, connection_id: i64, acquired_at: SystemTime) {
+    self.connection_id = Some((connection_id, acquired_at));
+  }
+
+  /// Returns the current connection id and when it was received, for persisting between sessions.
+  pub fn connection_id(&self) -> Option<(i64, SystemTime)> {
+    self.connection_id
+  }
+
+  /// Shortens `retransmit_timeout` so an announce gives up within `request_timeout` after
+  /// `max_retransmits`, the connect and the announce exchange each taking at most half of it.
+  pub fn fit_retransmits(&mut self, request_timeout: Duration) {
+    // Each exchange waits for the timeout, then double it, and so on
+    let waits = 2_u32.saturating_pow(self.max_retransmits.saturating_add(1)) - 1;
+    self.retransmit_timeout = self.retransmit_timeout.min(request_timeout / 2 / waits);
+  }
   
-  /// Sends a message to the tracker and receives a response asynchronously.
+  /// Sends a message to the tracker and receives a response asynchronously. A request that
+  /// isn't answered within `retransmit_timeout * 2^n` is sent again, up to `max_retransmits`
+  /// times.
   ///
   /// # Arguments
   ///
   /// * `message` - A type that implements the `ToBuffer` trait, representing the message to send.
+  /// * `transaction_id` - The transaction id the message was created with, the response must echo it.
   ///
   /// # Returns
   ///
-  /// A byte vector containing the received response.
-  pub async fn send_message<T: ToBuffer>(&mut self, message: &T) -> Vec<u8> {
+  /// A byte vector containing the received response, truncated to the length received, or an
+  /// error if the response is for another transaction or never arrives.
+  pub async fn send_message<T: ToBuffer>(&mut self, message: &T, transaction_id: i32) -> Result<Vec<u8>, Error> {
     let mut buf: Vec<u8> = vec![ 0; 16_384 ];
-    
-    self.connection_stream.send(&message.to_buffer()).await.unwrap();
-    self.connection_stream.recv(&mut buf).await.unwrap();
-    
-    buf
+    let request = message.to_buffer();
+
+    let mut retransmits = 0;
+    let len = loop {
+      if let Err(err) = self.connection_stream.send(&request).await {
+        return Err(Error::TrackerError(format!("error sending to tracker {}, {}", self.remote_address, err)));
+      }
+
+      let wait = self.retransmit_timeout.saturating_mul(2_u32.saturating_pow(retransmits));
+      match timeout(wait, self.connection_stream.recv(&mut buf)).await {
+        Ok(Err(err)) => return Err(Error::TrackerError(format!("error receiving from tracker {}, {}", self.remote_address, err))),
+        Ok(Ok(len)) => break len,
+        Err(_) if retransmits < self.max_retransmits => retransmits += 1,
+        Err(_) => return Err(Error::TrackerError(format!("tracker {} didn't respond, sent {} times", self.remote_address, retransmits + 1)))
+      }
+    };
+    buf.truncate(len);
+
+    // A stale or spoofed response could otherwise be taken as the answer to this request
+    if buf.len() >= 8 && buf[4..8] != transaction_id.to_be_bytes() {
+      return Err(Error::TrackerError(format!(
+        "tracker {} responded to transaction {}, expected {transaction_id}",
+        self.remote_address, i32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]])
+      )))
+    }
+
+    Ok(buf)
   }
 
-  pub async fn send_handshake(&mut self) -> i64 {
-    ConnectionMessage::from_buffer(
-        &self.send_message(&ConnectionMessage::create_basic_connection()).await
-    ).connection_id
+  /// Sends a connect request, storing the connection id the tracker responds with.
+  async fn connect(&mut self) -> Result<i64, Error> {
+    let transaction_id = new_transaction_id();
+    let response = self.send_message(&ConnectionMessage::create_basic_connection(transaction_id), transaction_id).await?;
+    check_error_action(&response)?;
+
+    let connection_id = ConnectionMessage::from_buffer(&response)?.connection_id;
+
+    self.connection_id = Some((connection_id, SystemTime::now()));
+    Ok(connection_id)
   }
 
-  pub async fn find_peers(&mut self, torrent: &Torrent, peer_id: &str) -> Vec<SocketAddrV4> {
-    let id = self.send_handshake().await;
+  /// Returns a valid connection id, reusing the stored one unless it has expired, in which
+  /// case a fresh connect handshake is sent.
+  pub async fn get_connection_id(&mut self) -> Result<i64, Error> {
+    if let Some((connection_id, acquired_at)) = self.connection_id {
+      // A time in the future can't be trusted, so it is treated as expired
+      if let Ok(age) = acquired_at.elapsed() {
+        if age < CONNECTION_ID_LIFETIME {
+          return Ok(connection_id)
+        }
+      }
+    }
+
+    self.connect().await
+  }
+
+  /// Announces to the tracker and returns the peers it knows about, recording the outcome in
+  /// the tracker's status. Waits first if the tracker was announced to too recently, see
+  /// `min_announce_interval`.
+  pub async fn find_peers(&mut self, torrent: &Torrent, peer_id: &str) -> Result<Vec<PeerCandidate>, Error> {
+    self.status.start_announce(self.min_announce_interval).await;
+
+    let announce_message_response = match self.announce(torrent, peer_id).await {
+      Err(err) => {
+        self.status.record_failure(&err.to_string());
+        return Err(err)
+      }
+      Ok(response) => response
+    };
+
+    let peer_addresses = announce_message_response.peers();
+    self.status.record_success(&announce_message_response, peer_addresses.len());
 
-    let message = AnnounceMessage::new(
+    Ok(peer_addresses)
+  }
+
+  /// Sends an announce message to the tracker, connecting first if needed.
+  async fn announce(&mut self, torrent: &Torrent, peer_id: &str) -> Result<AnnounceMessageResponse, Error> {
+    let id = self.get_connection_id().await?;
+
+    let transaction_id = new_transaction_id();
+    let mut message = AnnounceMessage::new(
         id, 
+        transaction_id,
         &torrent.get_info_hash(), 
         peer_id, 
         torrent.get_total_length() as i64
     );
 
-    let announce_message_response = AnnounceMessageResponse::from_buffer(&self.send_message(&message).await);
+    // If the address can't be resolved the tracker is left to infer it
+    if let Some(host) = &self.announce_ip {
+      if let Some(ip) = resolve_ipv4(self.resolver.as_ref(), host).await {
+        message = message.with_ip(ip);
+      }
+    }
+
+    let response = self.send_message(&message, transaction_id).await?;
+    check_error_action(&response)?;
+
+    AnnounceMessageResponse::from_buffer(&response)
+  }
+}
+
+/// A tracker announced to over HTTP or HTTPS.
+pub struct HttpTracker {
+  /// The announce url of the tracker, as found in the torrent or as last redirected to.
+  announce_url: String,
+  /// The history of announces made to the tracker.
+  status: TrackerStatus,
+  /// An address or hostname announced as the client's ip, instead of the tracker inferring it.
+  /// HTTP trackers resolve a hostname themselves.
+  pub announce_ip: Option<String>,
+  /// The most redirects followed for an announce.
+  pub max_redirects: usize,
+  /// Whether the url an announce was redirected to is announced to directly from then on,
+  /// false by default.
+  pub remember_redirects: bool,
+  /// Resolves the tracker's hostname, those it redirects to and peers it lists by hostname.
+  pub resolver: Arc<dyn Resolver>,
+  /// The shortest time between announces, or the tracker's `min interval` if that is longer.
+  pub min_announce_interval: Duration,
+  /// How long the tracker has to respond to an announce, redirects included.
+  pub request_timeout: Duration
+}
+
+impl HttpTracker {
+  /// Creates a tracker that hasn't been announced to yet.
+  ///
+  /// # Arguments
+  ///
+  /// * `announce_url` - The announce url of the tracker, as found in the torrent.
+  pub fn new(announce_url: &str) -> Self {
+    Self {
+      announce_url: announce_url.to_string(),
+      status: TrackerStatus::new(TrackerEndpoint::Http(announce_url.to_string())),
+      announce_ip: None,
+      max_redirects: DEFAULT_MAX_REDIRECTS,
+      remember_redirects: false,
+      resolver: Arc::new(SystemResolver),
+      min_announce_interval: DEFAULT_MIN_ANNOUNCE_INTERVAL,
+      request_timeout: DEFAULT_REQUEST_TIMEOUT
+    }
+  }
+
+  /// Returns the history of announces made to the tracker.
+  pub fn status(&self) -> &TrackerStatus {
+    &self.status
+  }
+
+  /// Returns the url announces are sent to, see `remember_redirects`.
+  pub fn announce_url(&self) -> &str {
+    &self.announce_url
+  }
 
-    let mut peer_addresses = vec![];
+  /// Announces to the tracker and returns the peers it knows about, recording the outcome in
+  /// the tracker's status. Waits first if the tracker was announced to too recently, see
+  /// `min_announce_interval`.
+  pub async fn find_peers(&mut self, torrent: &Torrent, peer_id: &str) -> Result<Vec<PeerCandidate>, Error> {
+    self.status.start_announce(self.min_announce_interval).await;
 
-    for i in 0..announce_message_response.ips.len() {
-        peer_addresses.push(SocketAddrV4::new(announce_message_response.ips[i], announce_message_response.ports[i]))
+    let mut request = HttpAnnounceRequest::new(&torrent.get_info_hash(), peer_id, torrent.get_total_length());
+    if let Some(ip) = &self.announce_ip {
+      request = request.with_ip(ip);
     }
 
-    peer_addresses
+    let response = match http_get(&request.to_url(&self.announce_url), self.max_redirects, self.request_timeout, self.resolver.clone()).await {
+      Ok((body, final_url)) => {
+        if self.remember_redirects {
+          self.announce_url = strip_announce_request(&final_url);
+        }
+
+        AnnounceMessageResponse::from_bencode(&body, self.resolver.as_ref()).await
+      }
+      Err(err) => Err(err)
+    };
+
+    let announce_message_response = match response {
+      Err(err) => {
+        self.status.record_failure(&err.to_string());
+        return Err(err)
+      }
+      Ok(response) => response
+    };
+
+    let peer_addresses = announce_message_response.peers();
+    self.status.record_success(&announce_message_response, peer_addresses.len());
+
+    Ok(peer_addresses)
+  }
+}
+
+/// Resolves an address or hostname to its first IPv4 address.
+async fn resolve_ipv4(resolver: &dyn Resolver, host: &str) -> Option<Ipv4Addr> {
+  if let Ok(ip) = host.parse() {
+    return Some(ip)
   }
+
+  resolver.resolve(host).await.ok()?.into_iter().find_map(|ip| match ip {
+    IpAddr::V4(ip) => Some(ip),
+    IpAddr::V6(_) => None
+  })
+}
+
+/// Returns the tracker's error message if the response is an error.
+fn check_error_action(buf: &[u8]) -> Result<(), Error> {
+  if buf.len() < 8 || i32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) != ERROR_ACTION {
+    return Ok(())
+  }
+
+  let message = String::from_utf8_lossy(&buf[8..]);
+  Err(Error::TrackerError(format!("tracker responded with an error, {}", message.trim_end_matches('\0'))))
 }
 
 /// A trait for converting a type into a byte buffer.
@@ -100,7 +584,11 @@ pub trait ToBuffer {
 /// A trait for converting a type from a byte buffer.
 pub trait FromBuffer {
   /// Converts a byte buffer into the implementing type.
-  fn from_buffer(buf: &[u8]) -> Self;
+  ///
+  /// # Returns
+  ///
+  /// The parsed type, or an error if the buffer is too short to hold it.
+  fn from_buffer(buf: &[u8]) -> Result<Self, Error> where Self: Sized;
 }
 
 #[derive(Debug)]
@@ -113,11 +601,15 @@ pub struct ConnectionMessage {
 
 impl ConnectionMessage {
   /// Creates a new basic connection message
-  pub fn create_basic_connection() -> Self {
+  ///
+  /// # Arguments
+  ///
+  /// * `transaction_id` - A random id the tracker's response must echo, see `new_transaction_id`.
+  pub fn create_basic_connection(transaction_id: i32) -> Self {
     Self { 
-      connection_id: 4497486125440,
+      connection_id: PROTOCOL_ID,
       action: 0, 
-      transaction_id: 123 
+      transaction_id
     }
   }
 }
@@ -135,7 +627,11 @@ impl ToBuffer for ConnectionMessage {
 }
 
 impl FromBuffer for ConnectionMessage {
-  fn from_buffer(buf: &[u8]) -> Self {
+  fn from_buffer(buf: &[u8]) -> Result<Self, Error> {
+    if buf.len() < 16 {
+      return Err(Error::TrackerError(format!("connect response too short, expected 16 bytes got {}", buf.len())))
+    }
+
     let mut action: [u8; 4] = [0; 4];
     action[..4].copy_from_slice(&buf[..4]);
     let action = i32::from_be_bytes(action);
@@ -148,11 +644,11 @@ impl FromBuffer for ConnectionMessage {
     connection_id[..8].copy_from_slice(&buf[8..16]);
     let connection_id = i64::from_be_bytes(connection_id);
     
-    Self {
+    Ok(Self {
       connection_id,
       action,
       transaction_id
-    }
+    })
   }
 }
 
@@ -192,7 +688,15 @@ pub struct AnnounceMessage {
 
 impl AnnounceMessage {
   /// Creates a new announce message.
-  pub fn new(connection_id: i64, infohash: &[u8], peerid: &str, total_length: i64) -> Self {
+  ///
+  /// # Arguments
+  ///
+  /// * `connection_id` - The connection id received from the tracker.
+  /// * `transaction_id` - A random id the tracker's response must echo, see `new_transaction_id`.
+  /// * `infohash` - The info hash of the torrent.
+  /// * `peerid` - The client's peer id.
+  /// * `total_length` - The number of bytes left to download.
+  pub fn new(connection_id: i64, transaction_id: i32, infohash: &[u8], peerid: &str, total_length: i64) -> Self {
     let mut info_hash: [u8; 20] = [ 0; 20 ];
     info_hash[..20].copy_from_slice(&infohash[..20]);
     
@@ -204,7 +708,7 @@ impl AnnounceMessage {
     Self { 
       connection_id, 
       action: 1, 
-      transaction_id: 132,
+      transaction_id,
       info_hash, 
       peer_id, 
       downloaded: 0, 
@@ -218,6 +722,12 @@ impl AnnounceMessage {
       extensions: 0
     }
   }
+
+  /// Announces the given address as the client's ip, rather than the one the tracker sees.
+  pub fn with_ip(mut self, ip: Ipv4Addr) -> Self {
+    self.ip = u32::from(ip);
+    self
+  }
 }
 
 impl ToBuffer for AnnounceMessage {
@@ -243,21 +753,413 @@ impl ToBuffer for AnnounceMessage {
   }
 }
 
+#[derive(Debug)]
+/// Represents an announce request to an HTTP tracker, sent as query parameters.
+pub struct HttpAnnounceRequest {
+  /// The 20-byte SHA-1 hash of the info dictionary in the torrent metainfo.
+  info_hash: [u8; 20],
+  /// The unique ID identifying the peer/client sending the announce.
+  peer_id: [u8; 20],
+  /// The port on which the client is listening for incoming peer connections.
+  port: u16,
+  /// The total amount of data uploaded by the client in this torrent, in bytes.
+  uploaded: u64,
+  /// The total amount of data downloaded by the client in this torrent, in bytes.
+  downloaded: u64,
+  /// The amount of data left to download for the client in this torrent, in bytes.
+  left: u64,
+  /// The purpose of the announce, `None` for a regular re-announce.
+  event: Option<&'static str>,
+  /// An address or hostname announced as the client's ip, `None` to let the tracker infer it.
+  ip: Option<String>,
+}
+
+impl HttpAnnounceRequest {
+  /// Creates a new announce request for starting a download.
+  pub fn new(infohash: &[u8], peerid: &str, total_length: u64) -> Self {
+    let mut info_hash: [u8; 20] = [ 0; 20 ];
+    info_hash[..20].copy_from_slice(&infohash[..20]);
+    
+    let mut peer_id: [u8; 20] = [0; 20];
+    for (i, character) in peerid.chars().enumerate() {
+      peer_id[i] = character as u8;
+    }
+
+    Self {
+      info_hash,
+      peer_id,
+      port: 61389,
+      uploaded: 0,
+      downloaded: 0,
+      left: total_length,
+      event: Some("started"),
+      ip: None
+    }
+  }
+
+  /// Announces the given address or hostname as the client's ip, rather than the one the tracker sees.
+  pub fn with_ip(mut self, ip: &str) -> Self {
+    self.ip = Some(ip.to_string());
+    self
+  }
+
+  /// Appends the request to a tracker's announce url as query parameters.
+  ///
+  /// The announce url is otherwise kept intact, private trackers often embed a passkey in its
+  /// path or query which must be sent back unchanged.
+  ///
+  /// # Arguments
+  ///
+  /// * `announce_url` - The announce url of the tracker, as found in the torrent.
+  pub fn to_url(&self, announce_url: &str) -> String {
+    let mut url = announce_url.to_string();
+    url.push(if announce_url.contains('?') { '&' } else { '?' });
+
+    url.push_str(&format!(
+      "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+      url_encode(&self.info_hash),
+      url_encode(&self.peer_id),
+      self.port,
+      self.uploaded,
+      self.downloaded,
+      self.left
+    ));
+
+    if let Some(event) = self.event {
+      url.push_str(&format!("&event={event}"));
+    }
+
+    if let Some(ip) = &self.ip {
+      url.push_str(&format!("&ip={}", url_encode(ip.as_bytes())));
+    }
+
+    url
+  }
+}
+
+/// Sends a GET request to an HTTP tracker, following redirects to a tracker's new announce url.
+///
+/// # Arguments
+///
+/// * `url` - The url to request.
+/// * `max_redirects` - The most redirects that are followed.
+/// * `timeout` - How long the whole request can take, redirects and reading the body included.
+/// * `resolver` - Resolves the hostname in the url and in any redirects.
+///
+/// # Returns
+///
+/// * The body of the response and the url it was finally served from, to announce to directly
+///   next time, or an error if the request failed, timed out, redirected too many times or in
+///   a loop.
+pub async fn http_get(url: &str, max_redirects: usize, timeout: Duration, resolver: Arc<dyn Resolver>) -> Result<(Vec<u8>, String), Error> {
+  let policy = reqwest::redirect::Policy::custom(move |attempt| {
+    if attempt.previous().contains(attempt.url()) {
+      attempt.error("redirect loop")
+    } else if attempt.previous().len() > max_redirects {
+      attempt.error(format!("more than {max_redirects} redirects"))
+    } else {
+      attempt.follow()
+    }
+  });
+
+  let client = reqwest::Client::builder()
+    .redirect(policy)
+    .timeout(timeout)
+    .dns_resolver(Arc::new(ReqwestResolver(resolver)))
+    .build().map_err(|err| Error::TrackerError(err.to_string()))?;
+  let response = client.get(url).send().await
+    .and_then(|response| response.error_for_status())
+    .map_err(|err| Error::TrackerError(format!("request to {url} failed, {}", error_chain(&err))))?;
+
+  let final_url = response.url().to_string();
+  let body = response.bytes().await.map_err(|err| Error::TrackerError(format!("request to {url} failed, {err}")))?;
+
+  Ok((body.to_vec(), final_url))
+}
+
+/// Removes the parameters of an announce request from a url, leaving the announce url it was
+/// made to along with any query the tracker itself needs, such as a passkey
+fn strip_announce_request(url: &str) -> String {
+  let Some((base, query)) = url.split_once('?') else {
+    return url.to_string()
+  };
+
+  let kept: Vec<&str> = query.split('&')
+    .filter(|pair| !ANNOUNCE_PARAMETERS.contains(&pair.split('=').next().unwrap_or_default()))
+    .collect();
+
+  if kept.is_empty() {
+    base.to_string()
+  } else {
+    format!("{base}?{}", kept.join("&"))
+  }
+}
+
+/// Formats an error along with its sources, which is where reqwest keeps the reason
+fn error_chain(err: &dyn std::error::Error) -> String {
+  let mut message = err.to_string();
+  let mut source = err.source();
+
+  while let Some(err) = source {
+    message.push_str(&format!(": {err}"));
+    source = err.source();
+  }
+
+  message
+}
+
+/// Percent encodes bytes for use in a url query, leaving unreserved characters as they are.
+fn url_encode(bytes: &[u8]) -> String {
+  let mut encoded = String::new();
+
+  for byte in bytes {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(*byte as char),
+      _ => encoded.push_str(&format!("%{byte:02X}"))
+    }
+  }
+
+  encoded
+}
+
 #[derive(Debug)]
 /// Represents a response to an announcement message.
 pub struct AnnounceMessageResponse {
   pub action: i32,
   pub transaction_id: i32,
-  pub interval: i32,
-  pub leechers: i32,
-  pub seeders: i32,
+  /// The interval in seconds, read as unsigned like the counts so the high bit can't make it negative.
+  pub interval: u32,
+  pub leechers: u32,
+  pub seeders: u32,
+  /// The shortest interval in seconds allowed between announces, only HTTP trackers set one.
+  pub min_interval: Option<u32>,
   pub ips: Vec<Ipv4Addr>,
-  pub ports: Vec<u16>
+  pub ports: Vec<u16>,
+  /// The peer id of each peer, for trackers that list them. Empty for UDP responses.
+  pub peer_ids: Vec<Option<String>>,
+  /// The number of peers listed that aren't in `ips`, as their hostname doesn't resolve or
+  /// their address isn't IPv4. Always 0 for UDP responses.
+  pub skipped_peers: usize
+}
+
+/// The bencoded response of an HTTP tracker to an announce
+#[derive(Debug, Deserialize)]
+struct HttpAnnounceResponse {
+  /// Why the announce failed, no other keys are present if it is
+  #[serde(default, rename = "failure reason")]
+  failure_reason: Option<String>,
+  /// The interval in seconds the tracker asks to be announced to at
+  #[serde(default)]
+  interval: u32,
+  /// The shortest interval in seconds allowed between announces
+  #[serde(default, rename = "min interval")]
+  min_interval: Option<u32>,
+  /// The number of seeders
+  #[serde(default)]
+  complete: u32,
+  /// The number of leechers
+  #[serde(default)]
+  incomplete: u32,
+  /// The peers, in either format
+  #[serde(default)]
+  peers: HttpPeers,
+}
+
+/// The peers in the response of an HTTP tracker
+#[derive(Debug)]
+enum HttpPeers {
+  /// The compact format, six bytes for each peer
+  Compact(Vec<u8>),
+  /// A dictionary for each peer, sent by trackers that ignore `compact=1`
+  Dictionaries(Vec<HttpPeer>),
+}
+
+impl Default for HttpPeers {
+  fn default() -> Self {
+    HttpPeers::Compact(vec![])
+  }
+}
+
+impl<'de> Deserialize<'de> for HttpPeers {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    /// Tells the peer formats apart by their bencoded type
+    struct PeersVisitor;
+
+    impl<'de> de::Visitor<'de> for PeersVisitor {
+      type Value = HttpPeers;
+
+      fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string of compact peers or a list of peer dictionaries")
+      }
+
+      fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(HttpPeers::Compact(bytes.to_vec()))
+      }
+
+      fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        // Each entry is read loosely, so one malformed peer doesn't lose the rest
+        let mut peers = vec![];
+        while let Some(value) = seq.next_element::<Value>()? {
+          peers.extend(HttpPeer::from_value(value));
+        }
+
+        Ok(HttpPeers::Dictionaries(peers))
+      }
+    }
+
+    deserializer.deserialize_any(PeersVisitor)
+  }
+}
+
+impl HttpPeers {
+  /// Returns the IPv4 peers, resolving those listed by hostname, and the number of peers skipped
+  /// as they can't be resolved or aren't IPv4.
+  async fn resolve(self, resolver: &dyn Resolver) -> (Vec<PeerCandidate>, usize) {
+    let mut candidates = vec![];
+    let mut skipped = 0;
+
+    match self {
+      HttpPeers::Compact(peers) => for peer in peers.chunks_exact(6) {
+        let address = SocketAddrV4::new(Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]), u16::from_be_bytes([peer[4], peer[5]]));
+        candidates.push(PeerCandidate::from(address));
+      }
+      HttpPeers::Dictionaries(peers) => for peer in peers {
+        let ip = match peer.ip.parse::<IpAddr>() {
+          Ok(ip) => ip,
+          Err(_) => match resolve_ipv4(resolver, &peer.ip).await {
+            Some(ip) => IpAddr::V4(ip),
+            None => {
+              skipped += 1;
+              continue
+            }
+          }
+        };
+        let Some(SocketAddr::V4(address)) = normalize_candidate(SocketAddr::new(ip, peer.port)) else {
+          skipped += 1;
+          continue
+        };
+
+        // Read the same way as the peer id in a handshake, so the two can be compared
+        let peer_id = peer.peer_id.map(|peer_id| peer_id.iter().map(|byte| *byte as char).collect());
+        candidates.push(PeerCandidate { address, peer_id });
+      }
+    }
+
+    (candidates, skipped)
+  }
+}
+
+/// A peer in the dictionary format
+#[derive(Debug)]
+struct HttpPeer {
+  /// An IPv4 or IPv6 address, or a hostname
+  ip: String,
+  port: u16,
+  peer_id: Option<Vec<u8>>,
+}
+
+impl HttpPeer {
+  /// Reads a peer from its dictionary, `None` if it isn't one or its address or port is missing
+  /// or invalid.
+  fn from_value(value: Value) -> Option<Self> {
+    let Value::Dict(mut peer) = value else { return None };
+    let Some(Value::Bytes(ip)) = peer.remove(&b"ip"[..]) else { return None };
+    let Some(Value::Int(port)) = peer.remove(&b"port"[..]) else { return None };
+    let peer_id = match peer.remove(&b"peer id"[..]) {
+      Some(Value::Bytes(peer_id)) => Some(peer_id),
+      _ => None
+    };
+
+    Some(Self { ip: String::from_utf8(ip).ok()?, port: u16::try_from(port).ok()?, peer_id })
+  }
+}
+
+/// Parses the `peers` value of an HTTP tracker's response, in either the compact or the
+/// dictionary format.
+///
+/// # Arguments
+///
+/// * `peers` - The bencoded value, a string of compact peers or a list of peer dictionaries.
+/// * `resolver` - Resolves peers listed by hostname.
+///
+/// # Returns
+///
+/// * The IPv4 peers, with the peer ids they were listed with. Malformed entries and peers that
+///   can't be resolved are skipped.
+pub async fn parse_peers(peers: &[u8], resolver: &dyn Resolver) -> Result<Vec<PeerCandidate>, Error> {
+  let peers: HttpPeers = serde_bencode::from_bytes(peers)
+    .map_err(|err| Error::TrackerError(format!("unable to parse peers, {err}")))?;
+
+  Ok(peers.resolve(resolver).await.0)
+}
+
+impl AnnounceMessageResponse {
+  /// Converts the bencoded response of an HTTP tracker into the same shape as a UDP tracker's
+  /// response. Peers may be in the compact or the dictionary format, only IPv4 peers are kept
+  /// and a peer listed by hostname is resolved, see `parse_peers`.
+  ///
+  /// # Arguments
+  ///
+  /// * `buf` - The body of the response.
+  /// * `resolver` - Resolves peers listed by hostname.
+  ///
+  /// # Returns
+  ///
+  /// The parsed response, or the tracker's failure reason if the announce failed.
+  pub async fn from_bencode(buf: &[u8], resolver: &dyn Resolver) -> Result<Self, Error> {
+    let response: HttpAnnounceResponse = serde_bencode::from_bytes(buf)
+      .map_err(|err| Error::TrackerError(format!("unable to parse announce response, {err}")))?;
+
+    if let Some(reason) = response.failure_reason {
+      return Err(Error::TrackerError(format!("tracker responded with an error, {reason}")))
+    }
+
+    let (candidates, skipped_peers) = response.peers.resolve(resolver).await;
+    let (mut ips, mut ports, mut peer_ids) = (vec![], vec![], vec![]);
+    for candidate in candidates {
+      ips.push(*candidate.address.ip());
+      ports.push(candidate.address.port());
+      peer_ids.push(candidate.peer_id);
+    }
+
+    Ok(Self {
+      action: 1,
+      transaction_id: 0,
+      interval: response.interval,
+      leechers: response.incomplete,
+      seeders: response.complete,
+      min_interval: response.min_interval,
+      ips,
+      ports,
+      peer_ids,
+      skipped_peers
+    })
+  }
+
+  /// Returns the peers in the response, normalised and without duplicates, with the peer ids
+  /// the tracker listed them with.
+  pub fn peers(&self) -> Vec<PeerCandidate> {
+    let mut candidates: Vec<PeerCandidate> = vec![];
+
+    for (i, (ip, port)) in self.ips.iter().zip(&self.ports).enumerate() {
+      let Some(SocketAddr::V4(address)) = normalize_candidate(SocketAddr::from((*ip, *port))) else { continue };
+
+      if !candidates.iter().any(|candidate| candidate.address == address) {
+        candidates.push(PeerCandidate { address, peer_id: self.peer_ids.get(i).cloned().flatten() });
+      }
+    }
+
+    candidates
+  }
 }
 
 impl FromBuffer for AnnounceMessageResponse {
   /// Converts a byte buffer into an `AnnounceMessageResponse` instance.
-  fn from_buffer(buf: &[u8]) -> Self {
+  fn from_buffer(buf: &[u8]) -> Result<Self, Error> {
+    if buf.len() < 20 {
+      return Err(Error::TrackerError(format!("announce response too short, expected at least 20 bytes got {}", buf.len())))
+    }
+
     let mut action: [u8; 4] = [0; 4];
     action[..4].copy_from_slice(&buf[0..4]);
     let action = i32::from_be_bytes(action);
@@ -268,24 +1170,24 @@ impl FromBuffer for AnnounceMessageResponse {
     
     let mut interval: [u8; 4] = [0; 4];
     interval[..4].copy_from_slice(&buf[8..12]);
-    let interval = i32::from_be_bytes(interval);
+    let interval = u32::from_be_bytes(interval);
     
     let mut leechers: [u8; 4] = [0; 4];
     leechers[..4].copy_from_slice(&buf[12..16]);
-    let leechers = i32::from_be_bytes(leechers);
+    let leechers = u32::from_be_bytes(leechers);
     
     let mut seeders: [u8; 4] = [0; 4];
     seeders[..4].copy_from_slice(&buf[16..20]);
-    let seeders = i32::from_be_bytes(seeders);
+    let seeders = u32::from_be_bytes(seeders);
     
     let mut ips: Vec<Ipv4Addr> = vec![];
     let mut ports: Vec<u16> = vec![];
     
-    for i in (20..buf.len()-6).step_by(6) {
-      let ip = Ipv4Addr::new(buf[i], buf[i+1], buf[i+2], buf[i+3]);
-      let port = u16::from_be_bytes([buf[i+4], buf[i+5]]);
+    for peer in buf[20..].chunks_exact(6) {
+      let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+      let port = u16::from_be_bytes([peer[4], peer[5]]);
       
-      if ip.to_string() == "0.0.0.0" && port == 0 {
+      if ip.is_unspecified() && port == 0 {
         break;
       }
       
@@ -293,6 +1195,737 @@ impl FromBuffer for AnnounceMessageResponse {
       ports.push(port)
     }
     
-    Self { action, transaction_id, interval, leechers, seeders, ips: ips[1..].to_vec(), ports: ports[1..].to_vec() }
+    Ok(Self { action, transaction_id, interval, leechers, seeders, min_interval: None, ips, ports, peer_ids: vec![], skipped_peers: 0 })
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::torrent::tests::MockResolver;
+  use std::collections::HashSet;
+  use tokio::time::timeout;
+
+  /// Binds a mock tracker and a `Tracker` connected to it
+  async fn mock_tracker() -> (UdpSocket, Tracker) {
+    let mock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let tracker = Tracker::new("127.0.0.1:0".parse().unwrap(), mock.local_addr().unwrap()).await.unwrap();
+
+    (mock, tracker)
+  }
+
+  #[test]
+  fn classify_tracker_urls() {
+    assert_eq!(
+      TrackerUrl::parse("udp://Tracker.Example:1337/announce"),
+      TrackerUrl::Udp { host: String::from("tracker.example"), port: 1337 }
+    );
+    assert!(matches!(TrackerUrl::parse("https://tracker.example/announce"), TrackerUrl::Http(_)));
+    assert!(matches!(TrackerUrl::parse("wss://tracker.example"), TrackerUrl::WebSocket(_)));
+
+    for url in ["udp://tracker.example:99999/announce", "udp://tracker.example/announce", "dht://abc", "tracker.example"] {
+      assert_eq!(TrackerUrl::parse(url), TrackerUrl::Unsupported(String::from(url)));
+      assert_eq!(TrackerUrl::parse(url).host(), None);
+    }
+
+    assert_eq!(TrackerUrl::parse("https://Tracker.Example:443/abc/announce").host().as_deref(), Some("tracker.example"));
+    assert_eq!(TrackerUrl::parse("wss://tracker.example?x=1").host().as_deref(), Some("tracker.example"));
+  }
+
+  #[test]
+  fn http_announce_url_keeps_passkey_path() {
+    let request = HttpAnnounceRequest::new(&[0xab; 20], "-MY0001-123456654321", 1024);
+
+    let url = request.to_url("http://tracker.example.com/0123456789abcdef/announce");
+
+    assert_eq!(
+      url,
+      format!(
+        "http://tracker.example.com/0123456789abcdef/announce?info_hash={}&peer_id=-MY0001-123456654321\
+        &port=61389&uploaded=0&downloaded=0&left=1024&compact=1&event=started",
+        "%AB".repeat(20)
+      )
+    );
+  }
+
+  /// Binds a mock HTTP server that redirects each path in `redirects` to another, and serves
+  /// `body` from any other path
+  pub(crate) async fn mock_http(redirects: &'static [(&'static str, &'static str)], body: Vec<u8>) -> SocketAddr {
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+      while let Ok((mut stream, _)) = listener.accept().await {
+        let mut request = vec![0; 4096];
+        let read = stream.read(&mut request).await.unwrap();
+        let request = String::from_utf8_lossy(&request[..read]).to_string();
+        let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+        let path = path.split('?').next().unwrap_or_default();
+
+        let mut response = match redirects.iter().find(|(from, _)| *from == path) {
+          Some((_, to)) => format!("HTTP/1.1 302 Found\r\nLocation: {to}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").into_bytes(),
+          None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes()
+        };
+        if !response.starts_with(b"HTTP/1.1 302") {
+          response.extend(&body);
+        }
+        stream.write_all(&response).await.unwrap();
+      }
+    });
+
+    address
+  }
+
+  #[tokio::test]
+  async fn http_get_follows_redirects() {
+    let address = mock_http(&[("/old", "/announce")], b"d8:intervali1800ee".to_vec()).await;
+
+    let (body, final_url) = http_get(&format!("http://{address}/old"), DEFAULT_MAX_REDIRECTS, DEFAULT_REQUEST_TIMEOUT, Arc::new(SystemResolver)).await.unwrap();
+
+    assert_eq!(body, b"d8:intervali1800ee");
+    assert_eq!(final_url, format!("http://{address}/announce"));
+    assert!(http_get(&format!("http://{address}/old"), 0, DEFAULT_REQUEST_TIMEOUT, Arc::new(SystemResolver)).await.unwrap_err().to_string().contains("more than 0 redirects"));
+  }
+
+  #[tokio::test]
+  async fn http_get_rejects_redirect_loops() {
+    let address = mock_http(&[("/a", "/b"), ("/b", "/a")], vec![]).await;
+
+    let err = http_get(&format!("http://{address}/a"), DEFAULT_MAX_REDIRECTS, DEFAULT_REQUEST_TIMEOUT, Arc::new(SystemResolver)).await.unwrap_err().to_string();
+
+    assert!(err.contains("redirect loop"), "{err}");
+  }
+
+  #[tokio::test]
+  async fn http_get_times_out() {
+    // Accepts the connection but never responds
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let _silent = tokio::spawn(async move { listener.accept().await.map(|(stream, _)| stream) });
+
+    let started = std::time::Instant::now();
+    let err = http_get(&format!("http://{address}/announce"), DEFAULT_MAX_REDIRECTS, Duration::from_millis(100), Arc::new(SystemResolver)).await.unwrap_err();
+
+    assert!(err.to_string().contains("timed out"), "{err}");
+    assert!(started.elapsed() < Duration::from_secs(5));
+  }
+
+  #[tokio::test]
+  async fn http_tracker_remembers_redirects() {
+    let torrent = Torrent::from_pieces("http_redirect", 16, &[0; 32]);
+    let address = mock_http(&[("/old", "/announce")], b"d8:intervali1800e5:peers0:e".to_vec()).await;
+    let old_url = format!("http://{address}/old");
+
+    // By default every announce goes through the redirect
+    let mut tracker = HttpTracker::new(&old_url);
+    tracker.min_announce_interval = Duration::ZERO;
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(tracker.announce_url(), old_url);
+
+    let mut tracker = HttpTracker::new(&old_url);
+    tracker.min_announce_interval = Duration::ZERO;
+    tracker.remember_redirects = true;
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(tracker.announce_url(), format!("http://{address}/announce"));
+
+    // The next announce goes straight to the new url, so no redirect needs following
+    tracker.max_redirects = 0;
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(tracker.status().address.to_string(), old_url);
+  }
+
+  #[test]
+  fn announce_request_is_stripped_from_redirects() {
+    assert_eq!(strip_announce_request("http://one.example/announce?info_hash=%01&peer_id=x&port=1&compact=1"), "http://one.example/announce");
+    assert_eq!(strip_announce_request("http://one.example/announce?passkey=abc&info_hash=%01&event=started"), "http://one.example/announce?passkey=abc");
+    assert_eq!(strip_announce_request("http://one.example/announce"), "http://one.example/announce");
+  }
+
+  #[tokio::test]
+  async fn http_tracker_finds_peers() {
+    let torrent = Torrent::from_pieces("http_tracker", 16, &[0; 32]);
+    let mut body = b"d8:completei3e10:incompletei2e8:intervali1800e12:min intervali900e5:peers12:".to_vec();
+    body.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe1]);
+    body.push(b'e');
+    let address = mock_http(&[], body).await;
+
+    let mut tracker = HttpTracker::new(&format!("http://{address}/announce"));
+    let peers = tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    assert_eq!(peers, vec![PeerCandidate::new("10.0.0.1:6881".parse().unwrap()), PeerCandidate::new("10.0.0.2:6881".parse().unwrap())]);
+    let status = tracker.status();
+    assert_eq!(status.address.to_string(), format!("http://{address}/announce"));
+    assert_eq!(status.seeders, Some(3));
+    assert_eq!(status.leechers, Some(2));
+    assert_eq!(status.interval, Some(Duration::from_secs(1800)));
+    assert_eq!(status.min_interval, Some(Duration::from_secs(900)));
+
+    // The tracker's minimum is kept to unless the floor is longer
+    let last_announce = status.last_announce.unwrap();
+    assert_eq!(status.announce_allowed_at(Duration::ZERO), Some(last_announce + Duration::from_secs(900)));
+    assert_eq!(status.announce_allowed_at(Duration::from_secs(1200)), Some(last_announce + Duration::from_secs(1200)));
+  }
+
+  #[tokio::test]
+  async fn http_tracker_counts_dropped_peers() {
+    let torrent = Torrent::from_pieces("http_dropped", 16, &[0; 32]);
+    let body = b"d8:intervali1800e5:peersl\
+      d2:ip8:10.0.0.14:porti6881ee\
+      d2:ip15:tracker.example4:porti6882ee\
+      d2:ip3:::14:porti6883ee\
+      d2:ip8:10.0.0.14:porti6881ee\
+      ee";
+    let address = mock_http(&[], body.to_vec()).await;
+
+    let mut tracker = HttpTracker::new(&format!("http://{address}/announce"));
+    tracker.resolver = Arc::new(MockResolver);
+    let peers = tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    // A hostname that doesn't resolve, an IPv6 peer and a duplicate
+    assert_eq!(peers, vec![PeerCandidate::new("10.0.0.1:6881".parse().unwrap())]);
+    assert_eq!(tracker.status().dropped_peers, 3);
+  }
+
+  #[tokio::test]
+  async fn http_tracker_host_uses_resolver() {
+    /// Resolves every host to localhost, remembering the hosts asked for
+    #[derive(Default)]
+    struct RecordingResolver(std::sync::Mutex<Vec<String>>);
+
+    #[async_trait::async_trait]
+    impl Resolver for RecordingResolver {
+      async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        self.0.lock().unwrap().push(host.to_string());
+        Ok(vec![IpAddr::from([127, 0, 0, 1])])
+      }
+    }
+
+    let torrent = Torrent::from_pieces("http_resolver", 16, &[0; 32]);
+    let address = mock_http(&[], b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1e".to_vec()).await;
+    let resolver = Arc::new(RecordingResolver::default());
+
+    // The hostname only resolves through the resolver
+    let mut tracker = HttpTracker::new(&format!("http://tracker.invalid:{}/announce", address.port()));
+    tracker.resolver = resolver.clone();
+    let peers = tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    assert_eq!(peers, vec![PeerCandidate::new("10.0.0.1:6881".parse().unwrap())]);
+    assert_eq!(*resolver.0.lock().unwrap(), vec![String::from("tracker.invalid")]);
+  }
+
+  #[tokio::test]
+  async fn http_tracker_records_failure_reason() {
+    let torrent = Torrent::from_pieces("http_failure", 16, &[0; 32]);
+    let address = mock_http(&[], b"d14:failure reason12:unregisterede".to_vec()).await;
+
+    let mut tracker = HttpTracker::new(&format!("http://{address}/announce"));
+
+    assert_eq!(tracker.find_peers(&torrent, "-MY0001-123456654321").await.map_err(|err| err.to_string()), Err(String::from("tracker responded with an error, unregistered")));
+    assert_eq!(tracker.status().consecutive_failures, 1);
+  }
+
+  #[test]
+  fn http_announce_url_includes_ip() {
+    let request = HttpAnnounceRequest::new(&[b'a'; 20], "-MY0001-123456654321", 0).with_ip("my host");
+
+    assert!(request.to_url("http://tracker.example.com/announce").ends_with("&event=started&ip=my%20host"));
+  }
+
+  #[test]
+  fn http_announce_url_keeps_existing_query() {
+    let request = HttpAnnounceRequest::new(&[b'a'; 20], "-MY0001-123456654321", 0);
+
+    let url = request.to_url("https://tracker.example.com/announce.php?passkey=secret");
+
+    assert!(url.starts_with("https://tracker.example.com/announce.php?passkey=secret&info_hash=aaaaaaaaaaaaaaaaaaaa&"));
+  }
+
+  #[tokio::test]
+  async fn seeded_connection_id_is_reused() {
+    let (_mock, mut tracker) = mock_tracker().await;
+    tracker.seed_connection_id(42, SystemTime::now() - Duration::from_secs(30));
+
+    // The mock never answers, so this would time out if a connect was sent
+    let connection_id = timeout(Duration::from_secs(1), tracker.get_connection_id()).await.map(Result::ok);
+
+    assert_eq!(connection_id, Ok(Some(42)));
+  }
+
+  #[tokio::test]
+  async fn expired_connection_id_forces_connect() {
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.seed_connection_id(42, SystemTime::now() - Duration::from_secs(61));
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 16];
+      let (len, from) = mock.recv_from(&mut buf).await.unwrap();
+      assert_eq!(len, 16);
+      assert_eq!(&buf[8..12], &0_i32.to_be_bytes()); // connect action
+
+      let mut response: Vec<u8> = vec![];
+      response.extend(0_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend(7_i64.to_be_bytes());
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    let connection_id = timeout(Duration::from_secs(1), tracker.get_connection_id()).await.map(Result::ok);
+    responder.await.unwrap();
+
+    assert_eq!(connection_id, Ok(Some(7)));
+    assert_eq!(tracker.connection_id().map(|(id, _)| id), Some(7));
+  }
+
+  #[tokio::test]
+  async fn announces_reconnect_once_connection_id_expires() {
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.min_announce_interval = Duration::ZERO;
+    let torrent = Torrent::from_pieces("expiry", 16, &[0; 32]);
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 128];
+      let mut actions = vec![];
+
+      for connection_id in [7_i64, 8] {
+        let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..8], &PROTOCOL_ID.to_be_bytes());
+        actions.push(i32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]));
+        let mut response: Vec<u8> = vec![];
+        response.extend(0_i32.to_be_bytes());
+        response.extend(&buf[12..16]);
+        response.extend(connection_id.to_be_bytes());
+        mock.send_to(&response, from).await.unwrap();
+
+        let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[0..8], &connection_id.to_be_bytes());
+        actions.push(i32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]));
+        let mut response: Vec<u8> = vec![];
+        response.extend(1_i32.to_be_bytes());
+        response.extend(&buf[12..16]);
+        response.extend([0; 12]);
+        mock.send_to(&response, from).await.unwrap();
+      }
+
+      actions
+    });
+
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    // Age the connection id as if the next announce came 61 seconds later
+    let (connection_id, acquired_at) = tracker.connection_id().unwrap();
+    assert_eq!(connection_id, 7);
+    tracker.seed_connection_id(connection_id, acquired_at - Duration::from_secs(61));
+
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    assert_eq!(timeout(Duration::from_secs(1), responder).await.unwrap().unwrap(), vec![0, 1, 0, 1]);
+    assert_eq!(tracker.connection_id().map(|(id, _)| id), Some(8));
+  }
+
+  #[tokio::test]
+  async fn rapid_announces_are_spaced_out() {
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.min_announce_interval = Duration::from_millis(200);
+    let torrent = Torrent::from_pieces("spacing", 16, &[0; 32]);
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 128];
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+      let mut response: Vec<u8> = vec![];
+      response.extend(0_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend(7_i64.to_be_bytes());
+      mock.send_to(&response, from).await.unwrap();
+
+      // The connection id is still valid, so only announces follow
+      let mut received = vec![];
+      for _ in 0..2 {
+        let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+        received.push(std::time::Instant::now());
+        let mut response: Vec<u8> = vec![];
+        response.extend(1_i32.to_be_bytes());
+        response.extend(&buf[12..16]);
+        response.extend([0; 12]);
+        mock.send_to(&response, from).await.unwrap();
+      }
+
+      received[1] - received[0]
+    });
+
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert!(tracker.status().announce_allowed_at(tracker.min_announce_interval).is_some());
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+
+    assert!(responder.await.unwrap() >= Duration::from_millis(190));
+  }
+
+  #[tokio::test]
+  async fn find_peers_records_status() {
+    let (mock, mut tracker) = mock_tracker().await;
+    let torrent = Torrent::from_pieces("status", 16, &[0; 32]);
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 128];
+
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+      let mut response: Vec<u8> = vec![];
+      response.extend(0_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend(7_i64.to_be_bytes());
+      mock.send_to(&response, from).await.unwrap();
+
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+      let mut response: Vec<u8> = vec![];
+      response.extend(1_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend(1800_i32.to_be_bytes());
+      response.extend(2_i32.to_be_bytes());
+      response.extend(3_i32.to_be_bytes());
+      for peer in 1..=3 {
+        response.extend([10, 0, 0, peer, 0x1a, 0xe1]);
+      }
+      // A duplicate and a peer without a port, both dropped
+      response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+      response.extend([10, 0, 0, 4, 0, 0]);
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    let peers = tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    responder.await.unwrap();
+    assert_eq!(peers.len(), 3);
+
+    let status = tracker.status();
+    assert!(status.last_announce.is_some());
+    assert_eq!(status.last_error, None);
+    assert_eq!(status.consecutive_failures, 0);
+    assert_eq!(status.last_peer_count, peers.len());
+    assert_eq!(status.total_peers, peers.len() as u64);
+    assert_eq!(status.dropped_peers, 2);
+    assert_eq!(status.seeders, Some(3));
+    assert_eq!(status.leechers, Some(2));
+    assert_eq!(status.interval, Some(Duration::from_secs(1800)));
+    assert_eq!(status.next_announce, Some(status.last_announce.unwrap() + Duration::from_secs(1800)));
+  }
+
+  #[tokio::test]
+  async fn find_peers_records_failures() {
+    let (mock, mut tracker) = mock_tracker().await;
+    let torrent = Torrent::from_pieces("failures", 16, &[0; 32]);
+
+    // Nothing is listening once the mock is dropped, so every announce is refused
+    drop(mock);
+    tracker.min_announce_interval = Duration::ZERO;
+
+    for _ in 0..2 {
+      assert!(tracker.find_peers(&torrent, "-MY0001-123456654321").await.is_err());
+    }
+
+    let status = tracker.status();
+    assert_eq!(status.consecutive_failures, 2);
+    assert_eq!(status.total_failures, 2);
+    assert!(status.last_error.is_some());
+  }
+
+  #[tokio::test]
+  async fn find_peers_records_error_action() {
+    let (mock, mut tracker) = mock_tracker().await;
+    let torrent = Torrent::from_pieces("error_action", 16, &[0; 32]);
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 128];
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+
+      let mut response: Vec<u8> = vec![];
+      response.extend(3_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend("x".repeat(1000).as_bytes());
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    assert!(tracker.find_peers(&torrent, "-MY0001-123456654321").await.is_err());
+    responder.await.unwrap();
+
+    let last_error = tracker.status().last_error.clone().unwrap();
+    assert!(last_error.starts_with("tracker responded with an error, xxx"));
+    assert_eq!(last_error.chars().count(), MAX_ERROR_LENGTH);
+  }
+
+  #[tokio::test]
+  async fn connect_with_other_transaction_id_is_rejected() {
+    let (mock, mut tracker) = mock_tracker().await;
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 16];
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+
+      let transaction_id = i32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+      let mut response: Vec<u8> = vec![];
+      response.extend(0_i32.to_be_bytes());
+      response.extend(transaction_id.wrapping_add(1).to_be_bytes());
+      response.extend(7_i64.to_be_bytes());
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    let err = tracker.get_connection_id().await.unwrap_err().to_string();
+    responder.await.unwrap();
+
+    assert!(err.contains("responded to transaction"), "{err}");
+    assert_eq!(tracker.connection_id(), None);
+  }
+
+  #[tokio::test]
+  async fn announce_with_other_transaction_id_is_rejected() {
+    let (mock, mut tracker) = mock_tracker().await;
+    let torrent = Torrent::from_pieces("transaction_id", 16, &[0; 32]);
+    tracker.seed_connection_id(42, SystemTime::now());
+
+    let responder = tokio::spawn(async move {
+      let mut buf = [0; 128];
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+
+      let transaction_id = i32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+      let mut response: Vec<u8> = vec![];
+      response.extend(1_i32.to_be_bytes());
+      response.extend(transaction_id.wrapping_add(1).to_be_bytes());
+      response.extend([0; 12]);
+      response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    let result = tracker.find_peers(&torrent, "-MY0001-123456654321").await;
+    responder.await.unwrap();
+
+    assert!(result.is_err());
+    assert_eq!(tracker.status().consecutive_failures, 1);
+    assert_eq!(tracker.status().last_peer_count, 0);
+  }
+
+  #[tokio::test]
+  async fn unanswered_request_is_retransmitted() {
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.retransmit_timeout = Duration::from_millis(50);
+
+    let responder = tokio::spawn(async move {
+      let mut first = [0; 16];
+      mock.recv_from(&mut first).await.unwrap();
+
+      // The same request is sent again once the first goes unanswered
+      let mut buf = [0; 16];
+      let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+      assert_eq!(buf, first);
+
+      let mut response: Vec<u8> = vec![];
+      response.extend(0_i32.to_be_bytes());
+      response.extend(&buf[12..16]);
+      response.extend(7_i64.to_be_bytes());
+      mock.send_to(&response, from).await.unwrap();
+    });
+
+    let connection_id = timeout(Duration::from_secs(1), tracker.get_connection_id()).await.map(Result::ok);
+    responder.await.unwrap();
+
+    assert_eq!(connection_id, Ok(Some(7)));
+  }
+
+  #[tokio::test]
+  async fn retransmits_back_off_then_give_up() {
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.retransmit_timeout = Duration::from_millis(40);
+    tracker.max_retransmits = 2;
+
+    let counter = tokio::spawn(async move {
+      let start = tokio::time::Instant::now();
+      let mut received = vec![];
+      while let Ok(Ok(_)) = timeout(Duration::from_millis(500), mock.recv_from(&mut [0; 16])).await {
+        received.push(start.elapsed());
+      }
+      received
+    });
+
+    let result = tracker.get_connection_id().await;
+    let received = counter.await.unwrap();
+
+    assert!(matches!(result, Err(Error::TrackerError(reason)) if reason.ends_with("didn't respond, sent 3 times")));
+    assert_eq!(received.len(), 3);
+    // Waited 40ms, then 80ms, before sending again
+    assert!(received[1] >= Duration::from_millis(40), "{received:?}");
+    assert!(received[2] - received[1] >= Duration::from_millis(80), "{received:?}");
+  }
+
+  #[tokio::test]
+  async fn retransmits_fit_request_timeout() {
+    let (_mock, mut tracker) = mock_tracker().await;
+
+    // 2 retransmits wait 7 times the timeout, twice over
+    tracker.fit_retransmits(Duration::from_secs(14));
+    assert_eq!(tracker.retransmit_timeout, Duration::from_secs(1));
+
+    // The timeout is never lengthened
+    tracker.fit_retransmits(Duration::from_secs(3600));
+    assert_eq!(tracker.retransmit_timeout, Duration::from_secs(1));
+  }
+
+  #[test]
+  fn transaction_ids_are_random() {
+    let ids: HashSet<i32> = (0..16).map(|_| new_transaction_id()).collect();
+
+    assert!(ids.len() > 1);
+  }
+
+  /// Answers a connect and then an announce, returning the ip field of the announce
+  async fn announced_ip(mock: UdpSocket) -> [u8; 4] {
+    let mut buf = [0; 128];
+
+    let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+    let mut response: Vec<u8> = vec![];
+    response.extend(0_i32.to_be_bytes());
+    response.extend(&buf[12..16]);
+    response.extend(7_i64.to_be_bytes());
+    mock.send_to(&response, from).await.unwrap();
+
+    let (_, from) = mock.recv_from(&mut buf).await.unwrap();
+    let mut response: Vec<u8> = vec![];
+    response.extend(1_i32.to_be_bytes());
+    response.extend(&buf[12..16]);
+    response.extend([0; 12]);
+    mock.send_to(&response, from).await.unwrap();
+
+    [buf[84], buf[85], buf[86], buf[87]]
+  }
+
+  #[tokio::test]
+  async fn announce_ip_override() {
+    let torrent = Torrent::from_pieces("announce_ip", 16, &[0; 32]);
+
+    let (mock, mut tracker) = mock_tracker().await;
+    let responder = tokio::spawn(announced_ip(mock));
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(responder.await.unwrap(), [0, 0, 0, 0]);
+
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.announce_ip = Some(String::from("203.0.113.7"));
+    let responder = tokio::spawn(announced_ip(mock));
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(responder.await.unwrap(), [203, 0, 113, 7]);
+
+    let (mock, mut tracker) = mock_tracker().await;
+    tracker.announce_ip = Some(String::from("localhost"));
+    let responder = tokio::spawn(announced_ip(mock));
+    tracker.find_peers(&torrent, "-MY0001-123456654321").await.unwrap();
+    assert_eq!(responder.await.unwrap(), [127, 0, 0, 1]);
+  }
+
+  #[tokio::test]
+  async fn unresolvable_announce_ip_lets_tracker_infer() {
+    assert_eq!(resolve_ipv4(&SystemResolver, "not a hostname").await, None);
+    assert_eq!(resolve_ipv4(&SystemResolver, "10.1.2.3").await, Some(Ipv4Addr::new(10, 1, 2, 3)));
+  }
+
+  #[tokio::test]
+  async fn dictionary_peers_keep_their_peer_ids() {
+    let body = b"d8:intervali1800e5:peersl\
+      d2:ip8:10.0.0.17:peer id20:-XX0001-0000000000014:porti6881ee\
+      d2:ip15:::ffff:10.0.0.24:porti6882ee\
+      d2:ip11:two.example4:porti6883ee\
+      d2:ip15:tracker.example4:porti6884ee\
+      d2:ip8:10.0.0.17:peer id20:-XX0001-0000000000024:porti6881ee\
+      ee";
+
+    let response = AnnounceMessageResponse::from_bencode(body, &MockResolver).await.unwrap();
+
+    // Hostnames are resolved or dropped, mapped addresses made IPv4 and the first listing of a peer kept
+    assert_eq!(response.peers(), vec![
+      PeerCandidate { address: "10.0.0.1:6881".parse().unwrap(), peer_id: Some(String::from("-XX0001-000000000001")) },
+      PeerCandidate { address: "10.0.0.2:6882".parse().unwrap(), peer_id: None },
+      PeerCandidate { address: "10.0.0.2:6883".parse().unwrap(), peer_id: None },
+    ]);
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.skipped_peers, 1);
+  }
+
+  #[tokio::test]
+  async fn empty_peer_lists() {
+    assert_eq!(parse_peers(b"0:", &MockResolver).await.unwrap(), vec![]);
+    assert_eq!(parse_peers(b"le", &MockResolver).await.unwrap(), vec![]);
+    assert!(parse_peers(b"i6881e", &MockResolver).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn malformed_peers_are_skipped() {
+    let peers = b"l\
+      d2:ip8:10.0.0.1e\
+      d2:ip8:10.0.0.24:port4:6882e\
+      d2:ip8:10.0.0.34:porti70000ee\
+      i6884e\
+      d4:porti6885ee\
+      d2:ip8:10.0.0.67:peer idi1e4:porti6886ee\
+      e";
+
+    // Only the last peer has a usable address and port, its peer id of the wrong type is ignored
+    assert_eq!(parse_peers(peers, &MockResolver).await.unwrap(), vec![PeerCandidate::from("10.0.0.6:6886".parse::<SocketAddrV4>().unwrap())]);
+
+    let compact = [10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0];
+    let mut peers = b"9:".to_vec();
+    peers.extend(compact);
+    assert_eq!(parse_peers(&peers, &MockResolver).await.unwrap(), vec![PeerCandidate::from("10.0.0.1:6881".parse::<SocketAddrV4>().unwrap())]);
+  }
+
+  #[test]
+  fn short_announce_response_is_an_error() {
+    let mut response: Vec<u8> = vec![];
+    response.extend(1_i32.to_be_bytes());
+    response.extend(132_i32.to_be_bytes());
+    response.extend(1800_i32.to_be_bytes());
+
+    assert_eq!(response.len(), 12);
+    assert!(AnnounceMessageResponse::from_buffer(&response).is_err());
+  }
+
+  #[test]
+  fn high_bit_interval_is_capped() {
+    let mut response: Vec<u8> = vec![];
+    response.extend(1_i32.to_be_bytes());
+    response.extend(132_i32.to_be_bytes());
+    response.extend(0x8000_0708_u32.to_be_bytes());
+    response.extend(u32::MAX.to_be_bytes());
+    response.extend(0x8000_0000_u32.to_be_bytes());
+    let response = AnnounceMessageResponse::from_buffer(&response).unwrap();
+
+    let mut status = TrackerStatus::new(TrackerEndpoint::Udp(SocketAddr::from(([127, 0, 0, 1], 6969))));
+    let now = SystemTime::now();
+    status.last_announce = Some(now);
+    status.record_success(&response, 0);
+
+    assert_eq!(status.interval, Some(MAX_ANNOUNCE_INTERVAL));
+    assert_eq!(status.next_announce, Some(now + MAX_ANNOUNCE_INTERVAL));
+    assert_eq!(status.leechers, Some(u32::MAX));
+    assert_eq!(status.seeders, Some(1 << 31));
+  }
+
+  #[test]
+  fn short_connect_response_is_an_error() {
+    assert!(ConnectionMessage::from_buffer(&[0; 8]).is_err());
+  }
+
+  #[test]
+  fn announce_response_keeps_every_peer() {
+    let mut response: Vec<u8> = vec![];
+    response.extend(1_i32.to_be_bytes());
+    response.extend(132_i32.to_be_bytes());
+    response.extend(1800_i32.to_be_bytes());
+    response.extend(0_i32.to_be_bytes());
+    response.extend(0_i32.to_be_bytes());
+
+    let empty = AnnounceMessageResponse::from_buffer(&response).unwrap();
+    assert!(empty.ips.is_empty());
+
+    response.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+    response.extend([10, 0, 0, 2, 0x1a, 0xe1]);
+
+    let response = AnnounceMessageResponse::from_buffer(&response).unwrap();
+    assert_eq!(response.ips, vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]);
+    assert_eq!(response.ports, vec![6881, 6881]);
   }
 }
\ No newline at end of file
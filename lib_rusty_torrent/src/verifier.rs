@@ -0,0 +1,57 @@
+//! Verification of downloaded pieces against their hashes
+
+use async_trait::async_trait;
+
+use crate::torrent::Torrent;
+
+/// Decides whether a downloaded piece is correct.
+///
+/// The default `LocalVerifier` hashes pieces itself, an embedder whose storage already hashes
+/// data can provide an implementation that asks the storage instead.
+#[async_trait]
+pub trait PieceVerifier: Send + Sync {
+    /// Returns whether the piece is correct.
+    ///
+    /// # Arguments
+    ///
+    /// * `torrent` - The torrent the piece belongs to.
+    /// * `index` - The index of the piece.
+    /// * `piece` - The downloaded piece, or the piece as read back from disk.
+    async fn verify(&self, torrent: &Torrent, index: u32, piece: &[u8]) -> bool;
+
+    /// Returns whether the verifier hashes pieces locally. Using a verifier that doesn't has to
+    /// be acknowledged in the `DownloadConfig`.
+    fn is_local(&self) -> bool {
+        false
+    }
+}
+
+/// Verifies pieces by hashing them locally with SHA-1.
+#[derive(Debug, Default)]
+pub struct LocalVerifier;
+
+#[async_trait]
+impl PieceVerifier for LocalVerifier {
+    async fn verify(&self, torrent: &Torrent, index: u32, piece: &[u8]) -> bool {
+        torrent.check_piece(piece, index)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
+
+/// Accepts every piece without checking it.
+///
+/// Only for transfers where both ends already trust the data and the transport, such as
+/// between machines on a private network. Corrupt or malicious data is written to disk as is
+/// and never detected.
+#[derive(Debug, Default)]
+pub struct SkipVerification;
+
+#[async_trait]
+impl PieceVerifier for SkipVerification {
+    async fn verify(&self, _torrent: &Torrent, _index: u32, _piece: &[u8]) -> bool {
+        true
+    }
+}
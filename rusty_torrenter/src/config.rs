@@ -0,0 +1,369 @@
+//! The configuration, from a configuration file, environment variables and flags
+//!
+//! Each source overrides the ones before it: defaults, the configuration file, environment
+//! variables, then flags.
+
+use std::{
+  collections::HashMap,
+  fs,
+  io,
+  path::PathBuf,
+  time::Duration
+};
+
+// Crate Imports
+use lib_rusty_torrent::config::{ optional_duration, parse_duration, DownloadConfig, VerifyPolicy };
+
+// External imports
+use clap::Args;
+use serde::{ Deserialize, Serialize };
+use toml::{ Table, Value };
+
+/// The prefix of the environment variables that set configuration keys
+const ENV_PREFIX: &str = "RUSTY_TORRENT_";
+
+/// The configuration of a run, the download's configuration along with the settings of the
+/// client itself
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+  /// The file logs are written to
+  pub log_file_path: String,
+  /// Whether to print the status of each tracker after announcing
+  pub show_trackers: bool,
+  /// How long the download can take before giving up, unbounded when `None`
+  #[serde(with = "optional_duration")]
+  pub timeout: Option<Duration>,
+  /// How the torrent is downloaded, its keys sit alongside the others
+  #[serde(flatten)]
+  pub download: DownloadConfig,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      log_file_path: String::from("./log/rustytorrent.log"),
+      show_trackers: false,
+      timeout: None,
+      download: DownloadConfig::default(),
+    }
+  }
+}
+
+/// The settings that can be given as flags, `None` where a flag isn't given
+#[derive(Args, Clone, Debug, Default)]
+pub struct Flags {
+  /// The file logs are written to [default: ./log/rustytorrent.log]
+  #[arg(short, long)]
+  pub log_file_path: Option<String>,
+
+  /// The directory the torrent is downloaded to [default: .]
+  #[arg(short, long)]
+  pub download_path: Option<String>,
+
+  /// Write pieces as they arrive and verify them by reading them back, using less memory
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  pub verify_after_write: Option<bool>,
+
+  /// Don't check pieces against their hashes, only for trusted transfers as corrupt data is written as is
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  pub skip_verification: Option<bool>,
+
+  /// Print the status of each tracker after announcing
+  #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+  pub show_trackers: Option<bool>,
+
+  /// Announce this address or hostname as our ip instead of letting trackers infer it
+  #[arg(long)]
+  pub announce_ip: Option<String>,
+
+  /// Give up with exit status 2 if the download hasn't completed in time, e.g. `90s`, `15m` or `2h`
+  #[arg(long, value_parser = parse_duration)]
+  pub timeout: Option<Duration>,
+
+  /// Give up with exit status 3 if the trackers return fewer distinct peers than this between them [default: 1]
+  #[arg(long)]
+  pub min_peers: Option<usize>,
+
+  /// How long to wait for the trackers to return peers before giving up with exit status 3
+  #[arg(long, value_parser = parse_duration)]
+  pub peer_wait: Option<Duration>,
+
+  /// The type of service byte set on every socket, e.g. 32 (CS1) to mark the traffic as background
+  #[arg(long)]
+  pub tos: Option<u8>,
+}
+
+impl Flags {
+  /// Overrides the configuration with the flags that were given
+  ///
+  /// # Arguments
+  ///
+  /// * `config` - The configuration from the other sources.
+  pub fn apply(&self, config: &mut Config) {
+    if let Some(log_file_path) = &self.log_file_path {
+      config.log_file_path = log_file_path.clone();
+    }
+    if let Some(download_path) = &self.download_path {
+      config.download.download_path = download_path.clone();
+    }
+    if let Some(after_write) = self.verify_after_write {
+      config.download.verify_policy = if after_write { VerifyPolicy::AfterWrite } else { VerifyPolicy::BeforeWrite };
+    }
+    if let Some(skip) = self.skip_verification {
+      config.download.verify_pieces = !skip;
+    }
+    if let Some(show_trackers) = self.show_trackers {
+      config.show_trackers = show_trackers;
+    }
+    if let Some(announce_ip) = &self.announce_ip {
+      config.download.announce_ip = Some(announce_ip.clone());
+    }
+    if let Some(timeout) = self.timeout {
+      config.timeout = Some(timeout);
+    }
+    if let Some(min_peers) = self.min_peers {
+      config.download.min_peers = min_peers;
+    }
+    if let Some(peer_wait) = self.peer_wait {
+      config.download.peer_wait = Some(peer_wait);
+    }
+    if let Some(tos) = self.tos {
+      config.download.socket_options.tos = Some(tos);
+    }
+  }
+}
+
+/// Loads the configuration from every source
+///
+/// # Arguments
+///
+/// * `flags` - The settings given as flags.
+/// * `path` - The configuration file given, the default one is used when `None`.
+/// * `env` - The environment variables, only those starting `RUSTY_TORRENT_` are read.
+///
+/// # Returns
+///
+/// * The configuration and any unknown keys that were ignored, or an error naming the source
+///   and key of a value that couldn't be used.
+pub fn load(flags: &Flags, path: Option<&PathBuf>, env: &HashMap<String, String>) -> Result<(Config, Vec<String>), String> {
+  let mut table = match path.cloned().or_else(|| default_config_path(env)) {
+    None => Table::new(),
+    Some(file) => match fs::read_to_string(&file) {
+      // Only a configuration file that was asked for has to exist
+      Err(err) if err.kind() == io::ErrorKind::NotFound && path.is_none() => Table::new(),
+      Err(err) => return Err(format!("unable to read {}, {err}", file.display())),
+      Ok(contents) => {
+        let table = contents.parse().map_err(|err: toml::de::Error| format!("{}, {}", file.display(), err.message()))?;
+        check(&table, |_| file.display().to_string())?;
+        table
+      }
+    }
+  };
+
+  let env = env_table(env);
+  check(&env, |key| format!("{ENV_PREFIX}{}", key.to_uppercase()))?;
+  merge(&mut table, env);
+
+  let mut config: Config = Value::Table(table.clone()).try_into().map_err(|err: toml::de::Error| err.message().to_string())?;
+  flags.apply(&mut config);
+  config.download.check().map_err(|err| err.to_string())?;
+
+  // Keys that are set but don't come back out of the configuration weren't read
+  let known = Table::try_from(&config).map_err(|err| err.to_string())?;
+  let unknown = unknown_keys(&table, &known, "");
+
+  Ok((config, unknown))
+}
+
+/// Returns the keys set that aren't known, looking into tables such as `socket_options`
+///
+/// # Arguments
+///
+/// * `table` - The keys that were set.
+/// * `known` - The keys the configuration reads back as.
+/// * `prefix` - The dotted path of the table, empty at the top level.
+fn unknown_keys(table: &Table, known: &Table, prefix: &str) -> Vec<String> {
+  table.iter()
+    .flat_map(|(key, value)| match (value, known.get(key)) {
+      (Value::Table(table), Some(Value::Table(known))) => unknown_keys(table, known, &format!("{prefix}{key}.")),
+      (_, Some(_)) => vec![],
+      (_, None) => vec![format!("{prefix}{key}")]
+    })
+    .collect()
+}
+
+/// Checks each key of a source alone, so a mistake is reported against the key it was made in
+///
+/// # Arguments
+///
+/// * `table` - The keys the source sets.
+/// * `source` - Names where a key was set.
+fn check(table: &Table, source: impl Fn(&str) -> String) -> Result<(), String> {
+  for (key, value) in table {
+    let single = Table::from_iter([(key.clone(), value.clone())]);
+
+    if let Err(err) = Value::Table(single).try_into::<Config>() {
+      return Err(format!("{}, invalid value for `{key}`, {}", source(key), err.message()))
+    }
+  }
+
+  Ok(())
+}
+
+/// Returns the keys set by `RUSTY_TORRENT_` environment variables, each value read as a TOML
+/// value, or as a string if it isn't one
+fn env_table(env: &HashMap<String, String>) -> Table {
+  env.iter()
+    .filter_map(|(name, value)| Some((name.strip_prefix(ENV_PREFIX)?.to_lowercase(), value)))
+    // RUSTY_TORRENT_CONFIG names the configuration file rather than setting a key in it
+    .filter(|(key, _)| key != "config")
+    .map(|(key, value)| {
+      let parsed = format!("value = {value}").parse::<Table>().ok().and_then(|mut table| table.remove("value"));
+      (key, parsed.unwrap_or_else(|| Value::String(value.clone())))
+    })
+    .collect()
+}
+
+/// Sets the keys of `from` in `into`, merging tables set in both
+fn merge(into: &mut Table, from: Table) {
+  for (key, value) in from {
+    match (into.get_mut(&key), value) {
+      (Some(Value::Table(into)), Value::Table(from)) => merge(into, from),
+      (_, value) => {
+        into.insert(key, value);
+      }
+    }
+  }
+}
+
+/// Returns the path of the configuration file in the platform's config directory
+///
+/// # Arguments
+///
+/// * `env` - The environment variables the config directory is found from.
+pub fn default_config_path(env: &HashMap<String, String>) -> Option<PathBuf> {
+  let var = |name: &str| env.get(name).map(PathBuf::from);
+
+  let config_dir = if cfg!(windows) {
+    var("APPDATA")?
+  } else if cfg!(target_os = "macos") {
+    var("HOME")?.join("Library/Application Support")
+  } else {
+    match var("XDG_CONFIG_HOME") {
+      Some(dir) => dir,
+      None => var("HOME")?.join(".config")
+    }
+  };
+
+  Some(config_dir.join("rusty_torrent").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use clap::Parser;
+
+  #[derive(Parser)]
+  struct Cli {
+    #[command(flatten)]
+    flags: Flags,
+  }
+
+  /// Returns an environment with the given variables
+  fn env(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+  }
+
+  /// Loads the configuration from a file with the given contents and no other source
+  fn from_file(test: &str, contents: &str) -> Result<(Config, Vec<String>), String> {
+    let path = std::env::temp_dir().join(format!("rusty_torrent_{test}_{}.toml", std::process::id()));
+    fs::write(&path, contents).unwrap();
+
+    let loaded = load(&Flags::default(), Some(&path), &HashMap::new());
+    fs::remove_file(&path).unwrap();
+    loaded
+  }
+
+  #[test]
+  fn precedence() {
+    let file = "
+      download_path = 'from_file'
+      announce_ip = 'from_file'
+      min_peers = 2
+      verify_pieces = false
+      timeout = '15m'
+      socket_options = { tos = 4, nodelay = true }
+    ";
+    let path = std::env::temp_dir().join(format!("rusty_torrent_precedence_{}.toml", std::process::id()));
+    fs::write(&path, file).unwrap();
+
+    let env = env(&[
+      ("RUSTY_TORRENT_MIN_PEERS", "3"),
+      ("RUSTY_TORRENT_ANNOUNCE_IP", "from_env"),
+      ("RUSTY_TORRENT_PEER_WAIT", "30s"),
+      ("RUSTY_TORRENT_CONFIG", "ignored.toml"),
+      ("HOME", "/nonexistent"),
+    ]);
+    let flags = Cli::parse_from(["rusty_torrenter", "--announce-ip", "from_flag", "--skip-verification=false", "--tos", "32"]).flags;
+
+    let (config, unknown) = load(&flags, Some(&path), &env).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(unknown.is_empty(), "{unknown:?}");
+    assert_eq!(config.log_file_path, "./log/rustytorrent.log");
+    assert_eq!(config.download.download_path, "from_file");
+    assert_eq!(config.timeout, Some(Duration::from_secs(15 * 60)));
+    assert_eq!(config.download.min_peers, 3);
+    assert_eq!(config.download.peer_wait, Some(Duration::from_secs(30)));
+    assert_eq!(config.download.announce_ip.as_deref(), Some("from_flag"));
+    assert!(config.download.verify_pieces);
+    assert_eq!(config.download.socket_options.tos, Some(32));
+    assert_eq!(config.download.socket_options.nodelay, Some(true));
+  }
+
+  #[test]
+  fn errors_name_the_source_and_key() {
+    let err = from_file("min_peers", "download_path = 'downloads'\nmin_peers = 'ten'").unwrap_err();
+    assert!(err.contains("invalid value for `min_peers`"), "{err}");
+
+    let err = from_file("peer_wait", "peer_wait = '2 days'").unwrap_err();
+    assert!(err.contains("invalid value for `peer_wait`"), "{err}");
+
+    let err = load(&Flags::default(), None, &env(&[("RUSTY_TORRENT_VERIFY_POLICY", "sometimes")])).unwrap_err();
+    assert!(err.starts_with("RUSTY_TORRENT_VERIFY_POLICY, invalid value for `verify_policy`"), "{err}");
+
+    assert!(load(&Flags::default(), Some(&PathBuf::from("./missing.toml")), &HashMap::new()).is_err());
+
+    // Values that are each valid can still conflict
+    let err = from_file("inverted", "adaptive_timeout = { min = '10s', max = '1s' }").unwrap_err();
+    assert!(err.contains("min of 10000ms is above its max of 1000ms"), "{err}");
+  }
+
+  #[test]
+  fn unknown_keys_are_ignored() {
+    let (config, unknown) = from_file("unknown", "min_peers = 4\nverify_policy = 'after_write'\nfuture_option = true").unwrap();
+
+    assert_eq!(config.download.min_peers, 4);
+    assert_eq!(config.download.verify_policy, VerifyPolicy::AfterWrite);
+    assert_eq!(unknown, vec![String::from("future_option")]);
+  }
+
+  #[test]
+  fn unknown_nested_keys_are_ignored() {
+    let (config, unknown) = from_file("unknown_nested", "[socket_options]\ntos = 32\nbogus = 1").unwrap();
+
+    assert_eq!(config.download.socket_options.tos, Some(32));
+    assert_eq!(unknown, vec![String::from("socket_options.bogus")]);
+  }
+
+  #[test]
+  fn checked_config_reads_back() {
+    let (config, _) = load(&Flags::default(), None, &env(&[("RUSTY_TORRENT_TIMEOUT", "2h")])).unwrap();
+    let written = toml::to_string(&config).unwrap();
+
+    let (read, unknown) = from_file("reads_back", &written).unwrap();
+    assert!(unknown.is_empty(), "{unknown:?}");
+    assert_eq!(toml::to_string(&read).unwrap(), written);
+  }
+}
@@ -1,115 +1,556 @@
 //! The root of the crate
 //! 
 //! Currently:
+//! Loads the configuration
 //! Creates the logger
-//! Handles peer connection
-//! Handles torrent information
-//! Creates Files
-//! Requests pieces
-//! Checks piece hashes
-//! Writes to torrent file
+//! Reads the torrent file
+//! Runs a `Download` of it, logging its events
+//! Exits with a status describing how it ended
 
-use std::net::SocketAddr;
+mod config;
+mod rate_limit;
+
+use std::{
+  collections::HashMap,
+  env,
+  fmt,
+  path::PathBuf,
+  process::ExitCode,
+  sync::{ Arc, Mutex },
+  time::{ Duration, Instant, SystemTime }
+};
 
 // Crate Imports
+#[cfg(feature = "wire-debug")]
+use lib_rusty_torrent::peer_wire_protocol::Direction;
 use lib_rusty_torrent::{
-    files::Files,
-    peer::*,
+    download::{ Download, DownloadEvent },
+    error::{ DownloadError, Error },
     torrent::Torrent,
-    tracker::Tracker,
-    tracker::ConnectionMessage,
-    tracker::AnnounceMessage,
-    tracker::AnnounceMessageResponse,
-    tracker::FromBuffer
+    tracker::TrackerStatus
 };
 
 // External Ipmorts
-use clap::Parser;
-use log::{ debug, info, LevelFilter };
+use clap::{ Parser, Subcommand };
+use config::{ Config, Flags };
+use log::{ debug, error, info, warn, LevelFilter };
+use rate_limit::{ suppressed_suffix, RateLimiter };
+use serde::Serialize;
+use tokio::time::{ interval_at, timeout };
+
+/// How often the number of messages suppressed by the rate limiter is logged
+const SUPPRESSION_REPORT_INTERVAL: Duration = Duration::from_secs(60);
 
 /// Struct Respresenting needed arguments
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 struct Args {
-  #[arg(short, long)]
-  log_file_path: Option<String>,
-  
-  #[arg(short, long)]
-  torrent_file_path: String,
-  
-  #[arg(short, long)]
-  download_path: String,
+  #[command(subcommand)]
+  command: Option<Command>,
+
+  /// The configuration file [default: rusty_torrent/config.toml in the platform's config directory]
+  #[arg(short, long, env = "RUSTY_TORRENT_CONFIG")]
+  config: Option<PathBuf>,
+
+  #[arg(short, long, required = true)]
+  torrent_file_path: Option<String>,
+
+  /// Print a JSON summary of how the run ended to stdout
+  #[arg(long)]
+  json: bool,
+
+  #[command(flatten)]
+  flags: Flags,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Work with the configuration
+  Config {
+    #[command(subcommand)]
+    command: ConfigCommand,
+  },
+  /// Print the messages in a capture of the frames exchanged with a peer
+  #[cfg(feature = "wire-debug")]
+  Replay {
+    /// The capture to print
+    capture: String,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+  /// Check the configuration and print the configuration it results in
+  Check,
+}
+
+/// Why a run ended without completing the download, each with its own exit status and the
+/// condition the JSON summary names:
+///
+/// * 0 - The download completed
+/// * 1 - `config` or `unreadable_torrent`, usage errors found before a download starts, such as
+///   a mistyped config value or torrent path
+/// * 2 - `incomplete`, `panicked` or `timeout`
+/// * 3 - `discovery`
+/// * 4 - `disk`
+/// * 5 - `invalid_torrent`
+#[derive(Debug)]
+enum Failure {
+  /// The configuration file couldn't be read, or a value in it or the environment has the wrong type
+  Config(String),
+  /// The torrent file couldn't be read, e.g. a mistyped path, a usage error like `Config`
+  UnreadableTorrent(String),
+  /// The torrent file isn't valid
+  InvalidTorrent(String),
+  /// The download didn't complete within `--timeout`
+  TimedOut(String),
+  /// The download ended early
+  Download(DownloadError),
+}
+
+impl Failure {
+  /// Returns the exit status for the failure
+  fn exit_code(&self) -> u8 {
+    match self {
+      Failure::Config(_) | Failure::UnreadableTorrent(_) | Failure::Download(DownloadError::InvalidConfig(_)) => 1,
+      Failure::TimedOut(_) => 2,
+      Failure::Download(DownloadError::Peer(_) | DownloadError::Incomplete(_) | DownloadError::PeersPanicked { .. }) => 2,
+      Failure::Download(DownloadError::Discovery(_)) => 3,
+      Failure::Download(DownloadError::Storage { .. } | DownloadError::Locked(_) | DownloadError::Files(_)) => 4,
+      Failure::InvalidTorrent(_) | Failure::Download(DownloadError::InvalidTorrent(_)) => 5,
+    }
+  }
+
+  /// Returns the condition that ended the run, as named in the JSON summary
+  fn condition(&self) -> &'static str {
+    match self {
+      Failure::Config(_) | Failure::Download(DownloadError::InvalidConfig(_)) => "config",
+      Failure::UnreadableTorrent(_) => "unreadable_torrent",
+      Failure::TimedOut(_) => "timeout",
+      Failure::Download(DownloadError::Peer(_) | DownloadError::Incomplete(_)) => "incomplete",
+      Failure::Download(DownloadError::Discovery(_)) => "discovery",
+      Failure::Download(DownloadError::Storage { .. } | DownloadError::Locked(_) | DownloadError::Files(_)) => "disk",
+      Failure::InvalidTorrent(_) | Failure::Download(DownloadError::InvalidTorrent(_)) => "invalid_torrent",
+      Failure::Download(DownloadError::PeersPanicked { .. }) => "panicked",
+    }
+  }
+}
+
+/// How a run ended, printed to stdout with `--json`
+#[derive(Debug, Serialize)]
+struct Summary {
+  /// Whether the download completed
+  completed: bool,
+  /// The exit status
+  exit_code: u8,
+  /// The condition that ended the run, see `Failure`, `None` if the download completed
+  condition: Option<&'static str>,
+  /// What went wrong, `None` if the download completed
+  error: Option<String>,
+}
+
+impl Summary {
+  /// Summarises the result of a run
+  fn new(result: &Result<(), Failure>) -> Self {
+    match result {
+      Ok(()) => Self { completed: true, exit_code: 0, condition: None, error: None },
+      Err(failure) => Self {
+        completed: false,
+        exit_code: failure.exit_code(),
+        condition: Some(failure.condition()),
+        error: Some(failure.to_string()),
+      }
+    }
+  }
+}
+
+impl fmt::Display for Failure {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Failure::Config(reason) => write!(f, "invalid configuration, {reason}"),
+      Failure::UnreadableTorrent(reason) | Failure::InvalidTorrent(reason) => write!(f, "{reason}"),
+      Failure::TimedOut(reason) => write!(f, "download incomplete, {reason}"),
+      Failure::Download(err) => write!(f, "download failed, {err}"),
+    }
+  }
+}
+
+impl From<DownloadError> for Failure {
+  fn from(err: DownloadError) -> Self {
+    Failure::Download(err)
+  }
 }
 
 /// The root function
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
   let args = Args::parse();
-  
+
+  // Variables that aren't unicode can't be configuration values, so are left out
+  let env: HashMap<String, String> = env::vars_os()
+    .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+    .collect();
+
+  let config = match config::load(&args.flags, args.config.as_ref(), &env) {
+    Err(err) => return exit_status(Err(Failure::Config(err)), args.json),
+    Ok((config, unknown)) => {
+      for key in unknown {
+        eprintln!("Ignoring unknown configuration key `{key}`");
+      }
+
+      config
+    }
+  };
+
+  if let Some(Command::Config { command: ConfigCommand::Check }) = args.command {
+    return match toml::to_string(&config) {
+      Ok(config) => {
+        print!("{config}");
+        ExitCode::SUCCESS
+      }
+      Err(err) => exit_status(Err(Failure::Config(err.to_string())), args.json)
+    }
+  }
+
+  #[cfg(feature = "wire-debug")]
+  if let Some(Command::Replay { capture }) = &args.command {
+    return replay(capture).await
+  }
+
   // Creates a log file to handle large amounts of data
-  let log_path = args.log_file_path.unwrap_or(String::from("./log/rustytorrent.log"));
-  simple_logging::log_to_file(&log_path, LevelFilter::Info).unwrap();
-  
+  if let Err(err) = simple_logging::log_to_file(&config.log_file_path, LevelFilter::Info) {
+    return exit_status(Err(Failure::Config(format!("unable to open log file {}, {err}", config.log_file_path))), args.json)
+  }
+
+  // Required unless a subcommand was given
+  let torrent_file_path = args.torrent_file_path.unwrap_or_default();
+
+  exit_status(run(&torrent_file_path, config).await, args.json)
+}
+
+/// The single exit path, reports how the run ended and returns its exit status
+///
+/// # Arguments
+///
+/// * `result` - How the run ended.
+/// * `json` - Whether to print a `Summary` of the run to stdout.
+fn exit_status(result: Result<(), Failure>, json: bool) -> ExitCode {
+  if json {
+    match serde_json::to_string(&Summary::new(&result)) {
+      Ok(summary) => println!("{summary}"),
+      Err(err) => eprintln!("error: unable to write summary, {err}")
+    }
+  }
+
+  match result {
+    Ok(()) => {
+      info!("Successfully completed download");
+      ExitCode::SUCCESS
+    }
+    Err(failure) => {
+      error!("{failure}");
+      eprintln!("error: {failure}");
+      ExitCode::from(failure.exit_code())
+    }
+  }
+}
+
+/// Downloads a torrent with the given configuration
+///
+/// # Arguments
+///
+/// * `torrent_file_path` - The torrent file to download.
+/// * `config` - The configuration from every source, with defaults filled in.
+async fn run(torrent_file_path: &str, config: Config) -> Result<(), Failure> {
   // Read the Torrent File
-  let torrent = Torrent::from_torrent_file(&args.torrent_file_path).await.unwrap();
+  let torrent = Torrent::from_torrent_file(torrent_file_path).await.map_err(|err| match err {
+    Error::UnreadableTorrent { .. } => Failure::UnreadableTorrent(err.to_string()),
+    err => Failure::InvalidTorrent(err.to_string())
+  })?;
   info!("Sucessfully read torrent file");
-  
-  // Create the files that will be written to
-  let mut files = Files::new();
-  files.create_files(&torrent, &args.download_path).await;
-  
-  // Gets peers from the given tracker
-  let addresses = torrent.get_trackers().unwrap();
-  let (remote_hostname, remote_port) = ("tracker.opentrackr.org", 1337);
-  debug!("{}:{}", remote_hostname, remote_port);
-  
-  let mut tracker = Tracker::new("0.0.0.0:61389".parse().unwrap(), SocketAddr::V4(addresses[0])).await.unwrap();
-  info!("Successfully connected to tracker {}:{}", remote_hostname, remote_port);
-  let connection_message = ConnectionMessage::from_buffer(
-    &tracker.send_message(&ConnectionMessage::create_basic_connection()).await
-  );
-  
-  debug!("{:?}", connection_message);
-  
-  let announce_message_response = AnnounceMessageResponse::from_buffer(
-    &tracker.send_message(&AnnounceMessage::new(
-      connection_message.connection_id, 
-      &torrent.get_info_hash(), 
-      "-MY0001-123456654321", 
-      torrent.get_total_length() as i64
-    )).await
+
+  download(torrent, config).await
+}
+
+/// Downloads a torrent, logging its events, giving up if it isn't complete within
+/// `config.timeout`
+///
+/// # Arguments
+///
+/// * `torrent` - The torrent to download.
+/// * `config` - The configuration from every source, with defaults filled in.
+async fn download(torrent: Torrent, config: Config) -> Result<(), Failure> {
+  let show_trackers = config.show_trackers;
+  let limiter = Arc::new(Mutex::new(RateLimiter::new(Duration::from_secs(10))));
+  let mut download = Download::new(torrent, config.download);
+
+  let hook_limiter = limiter.clone();
+  download.set_event_hook(Arc::new(move |event: &DownloadEvent| log_event(event, show_trackers, &hook_limiter)));
+
+  // Counts of suppressed messages are logged as the download goes, not only once it ends
+  let report_limiter = limiter.clone();
+  let reporter = tokio::spawn(async move {
+    let mut reports = interval_at(tokio::time::Instant::now() + SUPPRESSION_REPORT_INTERVAL, SUPPRESSION_REPORT_INTERVAL);
+    loop {
+      reports.tick().await;
+      for (key, suppressed) in report_limiter.lock().unwrap().report() {
+        info!("Suppressed {suppressed} repeated {key} messages in the last {}s", SUPPRESSION_REPORT_INTERVAL.as_secs());
+      }
+    }
+  });
+
+  let result = match config.timeout {
+    None => download.run().await.map_err(Failure::from),
+    Some(limit) => match timeout(limit, download.run()).await {
+      Ok(result) => result.map_err(Failure::from),
+      Err(_) => Err(Failure::TimedOut(format!("not complete after {}s", limit.as_secs())))
+    }
+  };
+  reporter.abort();
+
+  for (key, suppressed) in limiter.lock().unwrap().summary() {
+    info!("Suppressed {suppressed} repeated {key} messages");
+  }
+
+  result
+}
+
+/// Prints every frame in a capture, one per line, decoded with the library's own parsers
+#[cfg(feature = "wire-debug")]
+async fn replay(capture: &str) -> ExitCode {
+  let records = match lib_rusty_torrent::capture::read_capture(capture).await {
+    Ok(records) => records,
+    Err(err) => {
+      eprintln!("error: unable to read capture {capture}, {err}");
+      return ExitCode::FAILURE
+    }
+  };
+
+  let Some(start) = records.first().map(|record| record.timestamp) else {
+    return ExitCode::SUCCESS
+  };
+
+  for record in records {
+    let elapsed = record.timestamp.duration_since(start).unwrap_or_default();
+    let arrow = match record.direction {
+      Direction::Inbound => "<-",
+      Direction::Outbound => "->"
+    };
+
+    println!("{:>10.6}s {arrow} {:>6} bytes  {}", elapsed.as_secs_f64(), record.frame.len(), record.describe());
+  }
+
+  ExitCode::SUCCESS
+}
+
+/// Logs the events of a download, printing the status of trackers as they're announced to if asked.
+/// Events that can repeat many times are rate limited.
+fn log_event(event: &DownloadEvent, show_trackers: bool, limiter: &Mutex<RateLimiter>) {
+  let allow = |key| limiter.lock().unwrap().allow(key, Instant::now());
+
+  match event {
+    DownloadEvent::TrackerSkipped(url) => if let Some(suppressed) = allow("skipped tracker") {
+      debug!("Skipping unsupported tracker {url:?}{}", suppressed_suffix(suppressed))
+    }
+    DownloadEvent::TrackerDenied(url) => if let Some(suppressed) = allow("denied tracker") {
+      debug!("Skipping denied tracker {url:?}{}", suppressed_suffix(suppressed))
+    }
+    DownloadEvent::TrackersResolved(trackers) => debug!("Found trackers {trackers:?}"),
+    DownloadEvent::Announced(status) => {
+      debug!("{status:?}");
+      if status.consecutive_failures > 0 {
+        if let Some(suppressed) = allow("tracker failure") {
+          let reason = status.last_error.as_deref().unwrap_or_default();
+          warn!("Announce to {} failed: {reason}{}", status.address, suppressed_suffix(suppressed))
+        }
+      } else if status.dropped_peers > 0 {
+        if let Some(suppressed) = allow("dropped peers") {
+          debug!("Dropped {} peers listed by {}{}", status.dropped_peers, status.address, suppressed_suffix(suppressed))
+        }
+      }
+      if show_trackers {
+        print_trackers(&[status]);
+      }
+    }
+    DownloadEvent::PeerConnected { address, peer_id } => info!("Successfully Created Connection with peer: {peer_id} at {address}"),
+    DownloadEvent::PieceCompleted(index) => debug!("Downloaded piece {index}"),
+    DownloadEvent::PieceFailed { index, reason, dump } => if let Some(suppressed) = allow("piece failure") {
+      let dumped = dump.as_ref().map_or(String::new(), |path| format!(", dumped to {}", path.display()));
+      error!("Failed to download piece {index}: {reason}{dumped}{}", suppressed_suffix(suppressed))
+    }
+    DownloadEvent::PeerTolerated { address, reason } => info!("Tolerating protocol deviations from {address}: {reason}"),
+    DownloadEvent::BlocksDiscarded { address, bytes } => if let Some(suppressed) = allow("discarded blocks") {
+      debug!("Discarded {bytes} bytes of unrequested blocks from {address}{}", suppressed_suffix(suppressed))
+    }
+    DownloadEvent::PeerDisconnected { address, wasted_bytes, failed_hash_bytes } => {
+      info!("Discarded {wasted_bytes} bytes of unrequested blocks and {failed_hash_bytes} bytes of corrupt pieces from {address}")
+    }
+    DownloadEvent::PeerPanicked { address, peer_id, message } => error!("Task of peer {peer_id} at {address} panicked: {message}"),
+    DownloadEvent::SocketOptionUnsupported(option) => warn!("{option}"),
+  }
+}
+
+/// Prints a table of tracker statuses to stdout
+fn print_trackers(statuses: &[&TrackerStatus]) {
+  println!(
+    "{:<22} {:>14} {:>14} {:>8} {:>8} {:>8} {:>9}  LAST ERROR",
+    "TRACKER", "LAST ANNOUNCE", "NEXT ANNOUNCE", "SEEDERS", "LEECHERS", "PEERS", "FAILURES"
   );
-  
-  debug!("{:?}", announce_message_response);
-  info!("Found Peers");
-  
-  // Creates an assumed peer connection to the `SocketAddr` given
-  let mut peer = match Peer::create_connection(format!("{}:{}", announce_message_response.ips[0], announce_message_response.ports[0]).parse().unwrap()).await {
-    Err(_) => { return },
-    Ok(peer) => peer
-  }; 
-  
-  let num_pieces = torrent.info.pieces.len() / 20;
-  peer.handshake(&torrent).await.unwrap();
-  peer.keep_alive_until_unchoke().await.unwrap();
-  
-  info!("Successfully Created Connection with peer: {}", peer.peer_id);
-  
-  let mut len = 0;
-  
-  for index in 0..num_pieces {
-    let piece= peer.request_piece(
-      index as u32, torrent.info.piece_length as u32, 
-      &mut len, torrent.get_total_length() as u32
-    ).await.unwrap();
-    
-    if torrent.check_piece(&piece, index as u32) {
-      files.write_piece(piece).await;
-    } else {
-      break
-    }
-  }
-  
-  peer.disconnect().await.unwrap();
-  info!("Successfully completed download");
-}
\ No newline at end of file
+
+  for status in statuses {
+    println!(
+      "{:<22} {:>14} {:>14} {:>8} {:>8} {:>8} {:>9}  {}",
+      status.address.to_string(),
+      status.last_announce.map_or(String::from("never"), relative_time),
+      status.next_announce.map_or(String::from("-"), relative_time),
+      status.seeders.map_or(String::from("-"), |n| n.to_string()),
+      status.leechers.map_or(String::from("-"), |n| n.to_string()),
+      status.total_peers,
+      status.total_failures,
+      status.last_error.as_deref().unwrap_or("-")
+    );
+  }
+}
+
+/// Formats a time relative to now, e.g. `12s ago` or `in 1800s`
+fn relative_time(time: SystemTime) -> String {
+  match time.elapsed() {
+    Ok(elapsed) => format!("{}s ago", elapsed.as_secs()),
+    Err(err) => format!("in {}s", err.duration().as_secs())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use lib_rusty_torrent::{ error::IncompletePieces, testing::{ mock_seed, mock_tracker } };
+  use std::net::SocketAddrV4;
+  use tokio::net::UdpSocket;
+
+  /// Returns a configuration downloading into a new directory for the test
+  async fn test_config(test: &str) -> Config {
+    let path = env::temp_dir().join(format!("rusty_torrenter_{test}_{}", std::process::id()));
+    let _ = tokio::fs::remove_dir_all(&path).await;
+    tokio::fs::create_dir_all(&path).await.unwrap();
+
+    let mut config = Config::default();
+    config.download.download_path = path.to_str().unwrap().to_string();
+    config
+  }
+
+  /// Downloads `data` from the peers a mock tracker returns, the way `run` downloads a torrent file
+  async fn run_against(test: &str, data: &[u8], piece_length: u64, peers: Vec<SocketAddrV4>, mut config: Config) -> Result<(), Failure> {
+    let tracker = mock_tracker(peers).await;
+    let mut torrent = Torrent::from_pieces(test, piece_length, data);
+    torrent.announce = Some(format!("udp://{tracker}/announce"));
+
+    config.download.listen_address = "127.0.0.1:0".parse().unwrap();
+    download(torrent, config).await
+  }
+
+  /// Asserts a run ended with the exit status and the condition its JSON summary states
+  fn assert_ended(result: Result<(), Failure>, exit_code: u8, condition: Option<&str>) {
+    let summary = serde_json::to_value(Summary::new(&result)).unwrap();
+
+    assert_eq!(summary["exit_code"], exit_code, "{summary}");
+    assert_eq!(summary["condition"].as_str(), condition, "{summary}");
+    assert_eq!(summary["completed"], condition.is_none(), "{summary}");
+  }
+
+  #[tokio::test]
+  async fn completed_download_exits_0() {
+    let data: Vec<u8> = (0..40_000).map(|byte| (byte % 251) as u8).collect();
+    let seed = mock_seed(data.clone(), 16_384, Duration::ZERO).await;
+    let config = test_config("completed").await;
+
+    assert_ended(run_against("completed", &data, 16_384, vec![seed], config).await, 0, None);
+  }
+
+  #[test]
+  fn invalid_config_exits_1() {
+    let env = HashMap::from([(String::from("RUSTY_TORRENT_MIN_PEERS"), String::from("ten"))]);
+    let result = config::load(&Flags::default(), None, &env).map(|_| ()).map_err(Failure::Config);
+
+    assert_ended(result, 1, Some("config"));
+  }
+
+  #[tokio::test]
+  async fn slow_swarm_exits_2() {
+    let data = vec![0; 16_384];
+    let seed = mock_seed(data.clone(), 16_384, Duration::from_secs(30)).await;
+    let config = Config { timeout: Some(Duration::from_secs(1)), ..test_config("timeout").await };
+
+    assert_ended(run_against("timeout", &data, 16_384, vec![seed], config).await, 2, Some("timeout"));
+  }
+
+  #[tokio::test]
+  async fn empty_swarm_exits_3() {
+    let config = test_config("discovery").await;
+
+    assert_ended(run_against("discovery", &[0; 16_384], 16_384, vec![], config).await, 3, Some("discovery"));
+  }
+
+  #[tokio::test]
+  async fn dead_swarm_exits_3_after_peer_wait() {
+    // Never answers, so it returns fewer than --min-peers peers
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let mut torrent = Torrent::from_pieces("dead_swarm", 16_384, &[0; 16_384]);
+    torrent.announce = Some(format!("udp://{}/announce", silent.local_addr().unwrap()));
+    let mut config = test_config("dead_swarm").await;
+    config.download.listen_address = "127.0.0.1:0".parse().unwrap();
+    config.download.min_peers = 2;
+    config.download.peer_wait = Some(Duration::from_secs(1));
+
+    let started = Instant::now();
+    let result = download(torrent, config).await;
+
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(900) && elapsed < Duration::from_millis(1_800), "{elapsed:?}");
+    assert_ended(result, 3, Some("discovery"));
+  }
+
+  #[tokio::test]
+  async fn unwritable_download_path_exits_4() {
+    let mut config = test_config("disk").await;
+    // A file where the download directory should be
+    config.download.download_path.push_str("/file");
+    tokio::fs::write(&config.download.download_path, b"").await.unwrap();
+
+    assert_ended(run_against("disk", &[0; 16_384], 16_384, vec![], config).await, 4, Some("disk"));
+  }
+
+  #[tokio::test]
+  async fn invalid_piece_length_exits_5() {
+    let config = test_config("invalid").await;
+
+    // Pieces shorter than a block aren't allowed
+    assert_ended(run_against("invalid", &[0; 64], 8, vec![], config).await, 5, Some("invalid_torrent"));
+  }
+
+  #[tokio::test]
+  async fn missing_torrent_exits_1() {
+    let config = test_config("missing").await;
+    let failure = run("./missing.torrent", config).await.unwrap_err();
+
+    assert_eq!(failure.to_string(), "unable to read file at ./missing.torrent");
+    assert_ended(Err(failure), 1, Some("unreadable_torrent"));
+  }
+
+  #[tokio::test]
+  async fn corrupt_torrent_exits_5() {
+    let config = test_config("corrupt").await;
+    let path = format!("{}/corrupt.torrent", config.download.download_path);
+    tokio::fs::write(&path, b"not bencode").await.unwrap();
+
+    assert_ended(run(&path, config).await, 5, Some("invalid_torrent"));
+  }
+
+  #[test]
+  fn panicked_peers_exit_2() {
+    let failure = Failure::Download(DownloadError::PeersPanicked { panics: 1, missing: IncompletePieces { missing: vec![0] } });
+
+    // The download is incomplete, the summary names why
+    assert_ended(Err(failure), 2, Some("panicked"));
+  }
+}
@@ -0,0 +1,137 @@
+//! Limits how often repetitive log lines are written, so they don't drown out the rest of the log
+
+use std::{
+  collections::HashMap,
+  time::{ Duration, Instant }
+};
+
+/// The lines suppressed for a single call site
+#[derive(Debug)]
+struct Window {
+  /// When a line was last let through
+  last: Instant,
+  /// The lines suppressed since then
+  suppressed: u64,
+  /// The lines suppressed since the last report
+  unreported: u64,
+  /// The lines suppressed in total
+  total: u64,
+}
+
+/// Lets at most one line through per call site in each interval, counting the rest.
+#[derive(Debug)]
+pub struct RateLimiter {
+  interval: Duration,
+  windows: HashMap<&'static str, Window>,
+}
+
+impl RateLimiter {
+  /// Creates a limiter letting a line through per call site every `interval`
+  pub fn new(interval: Duration) -> Self {
+    Self { interval, windows: HashMap::new() }
+  }
+
+  /// Returns whether a line can be logged
+  ///
+  /// # Arguments
+  ///
+  /// * `key` - Identifies the call site.
+  /// * `now` - The time the line would be logged at.
+  ///
+  /// # Returns
+  ///
+  /// * The number of lines suppressed since the last one let through, or `None` if this one
+  ///   should be suppressed.
+  pub fn allow(&mut self, key: &'static str, now: Instant) -> Option<u64> {
+    let Some(window) = self.windows.get_mut(key) else {
+      self.windows.insert(key, Window { last: now, suppressed: 0, unreported: 0, total: 0 });
+      return Some(0)
+    };
+
+    if now.duration_since(window.last) < self.interval {
+      window.suppressed += 1;
+      window.unreported += 1;
+      window.total += 1;
+      return None
+    }
+
+    window.last = now;
+    Some(std::mem::take(&mut window.suppressed))
+  }
+
+  /// Returns the number of lines suppressed in total for each call site that had any, by key
+  pub fn summary(&self) -> Vec<(&'static str, u64)> {
+    let mut summary: Vec<(&'static str, u64)> = self.windows.iter()
+      .filter(|(_, window)| window.total > 0)
+      .map(|(key, window)| (*key, window.total))
+      .collect();
+    summary.sort();
+
+    summary
+  }
+
+  /// Returns the number of lines suppressed since the last report for each call site that had
+  /// any, by key, and starts counting again
+  pub fn report(&mut self) -> Vec<(&'static str, u64)> {
+    let mut report: Vec<(&'static str, u64)> = self.windows.iter_mut()
+      .filter(|(_, window)| window.unreported > 0)
+      .map(|(key, window)| (*key, std::mem::take(&mut window.unreported)))
+      .collect();
+    report.sort();
+
+    report
+  }
+}
+
+/// Formats the suffix noting how many lines were suppressed before this one, if any
+pub fn suppressed_suffix(suppressed: u64) -> String {
+  match suppressed {
+    0 => String::new(),
+    n => format!(" ({n} similar messages suppressed)")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn suppression_window() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Duration::from_secs(10));
+
+    assert_eq!(limiter.allow("noisy", start), Some(0));
+    assert_eq!(limiter.allow("noisy", start + Duration::from_secs(1)), None);
+    assert_eq!(limiter.allow("noisy", start + Duration::from_secs(9)), None);
+
+    // Other call sites have their own window
+    assert_eq!(limiter.allow("quiet", start + Duration::from_secs(9)), Some(0));
+
+    // The next line through reports the ones suppressed before it
+    assert_eq!(limiter.allow("noisy", start + Duration::from_secs(10)), Some(2));
+    assert_eq!(limiter.allow("noisy", start + Duration::from_secs(11)), None);
+    assert_eq!(limiter.allow("noisy", start + Duration::from_secs(30)), Some(1));
+  }
+
+  #[test]
+  fn summary_counts() {
+    let start = Instant::now();
+    let mut limiter = RateLimiter::new(Duration::from_secs(10));
+
+    for second in 0..25 {
+      limiter.allow("noisy", start + Duration::from_secs(second));
+    }
+    limiter.allow("quiet", start);
+
+    assert_eq!(limiter.summary(), vec![("noisy", 22)]);
+
+    // Reports only count the lines suppressed since the last one
+    assert_eq!(limiter.report(), vec![("noisy", 22)]);
+    assert_eq!(limiter.report(), vec![]);
+    limiter.allow("noisy", start + Duration::from_secs(25));
+    assert_eq!(limiter.report(), vec![("noisy", 1)]);
+    assert_eq!(limiter.summary(), vec![("noisy", 23)]);
+    assert_eq!(suppressed_suffix(0), "");
+    assert_eq!(suppressed_suffix(22), " (22 similar messages suppressed)");
+  }
+}